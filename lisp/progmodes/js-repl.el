@@ -0,0 +1,199 @@
+;;; js-repl.el --- REPL for the embedded JavaScript runtime  -*- lexical-binding: t -*-
+
+;; Keywords: javascript, processes
+
+;; This file is part of emacs-ng.
+
+;; emacs-ng is free software: you can redistribute it and/or modify
+;; it under the terms of the GNU General Public License as published by
+;; the Free Software Foundation, either version 3 of the License, or
+;; (at your option) any later version.
+
+;; emacs-ng is distributed in the hope that it will be useful,
+;; but WITHOUT ANY WARRANTY; without even the implied warranty of
+;; MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+;; GNU General Public License for more details.
+
+;; You should have received a copy of the GNU General Public License
+;; along with emacs-ng.  If not, see <https://www.gnu.org/licenses/>.
+
+;;; Commentary:
+
+;; An interactive JavaScript prompt, evaluated by the same runtime as
+;; `eval-js'.  Input is handled by comint, as in IELM, and evaluated by
+;; helpers defined in prelim.js.  Inputs may span several lines and use
+;; top level await; Lisp objects are shown in their printed form.
+
+;; To start: M-x js-repl.
+
+;;; Code:
+
+(require 'cl-lib)
+(require 'comint)
+(require 'json)
+(eval-when-compile (require 'subr-x))
+
+(defgroup js-repl nil
+  "Interaction mode for the embedded JavaScript runtime."
+  :group 'js)
+
+(defcustom js-repl-prompt "js> "
+  "Prompt used in `js-repl'."
+  :type 'string)
+
+(defcustom js-repl-history-file (locate-user-emacs-file "js-repl-history")
+  "File in which `js-repl' input history is saved, or nil to not save it."
+  :type '(choice (const :tag "Don't save history" nil) file))
+
+(defvar js-repl-header
+  "*** Welcome to the JavaScript REPL ***  Type (await) expressions and press RET.\n"
+  "Message to display when `js-repl' is started.")
+
+(defvar js-repl-buffer-name "*js-repl*"
+  "Name of the `js-repl' buffer.")
+
+(defvar js-repl-map
+  (let ((map (make-sparse-keymap)))
+    (define-key map "\C-m" 'js-repl-return)
+    (define-key map "\C-j" 'js-repl-send-input)
+    (define-key map "\t" 'completion-at-point)
+    map)
+  "Keymap for `js-repl-mode'.")
+
+(defvar js-repl--input nil
+  "The input sent by `comint-send-input', see `js-repl-input-sender'.")
+
+(defun js-repl--call (function &rest args)
+  "Call the global JavaScript FUNCTION with ARGS, encoded as JSON."
+  (eval-js-literally
+   (format "%s(%s)" function (mapconcat #'json-encode args ", "))))
+
+(defun js-repl-process ()
+  "Return the process of the `js-repl' buffer."
+  (let ((buffer (get-buffer js-repl-buffer-name)))
+    (and buffer (get-buffer-process buffer))))
+
+(defun js-repl--output (string)
+  "Insert STRING, followed by a new prompt, into the `js-repl' buffer.
+Called from JavaScript once an asynchronous input completes."
+  (let ((proc (js-repl-process)))
+    (when proc
+      (with-current-buffer (process-buffer proc)
+        (comint-output-filter proc (concat string js-repl-prompt))))))
+
+(defun js-repl-input-sender (_proc input)
+  ;; Just sets `js-repl--input', which is bound by `js-repl-send-input'.
+  (setq js-repl--input input))
+
+(defun js-repl-send-input ()
+  "Evaluate the JavaScript input after the prompt."
+  (interactive)
+  (let (js-repl--input)
+    (comint-send-input)
+    (js-repl-eval-input js-repl--input)))
+
+(defun js-repl--input-incomplete-p ()
+  "Return non-nil if the input after the prompt is an incomplete statement."
+  (let ((input (buffer-substring-no-properties
+                (process-mark (js-repl-process)) (point-max))))
+    (and (not (string-blank-p input))
+         (eq (js-repl--call "__replIncomplete" input) t))))
+
+(defun js-repl-return ()
+  "Evaluate the input after the prompt, or insert a newline if it is incomplete."
+  (interactive)
+  (if (and (>= (point) (process-mark (js-repl-process)))
+           (js-repl--input-incomplete-p))
+      (newline)
+    (js-repl-send-input)))
+
+(defun js-repl-eval-input (input)
+  "Evaluate INPUT and insert its result, followed by a new prompt.
+Inputs using await insert their result once it is available."
+  (let ((proc (js-repl-process)))
+    (if (string-blank-p input)
+        (comint-output-filter proc js-repl-prompt)
+      (let ((output (condition-case err
+                        (js-repl--call "__replEval" input)
+                      (error (concat (error-message-string err) "\n")))))
+        (unless (equal output "")
+          (comint-output-filter proc (concat output js-repl-prompt)))))))
+
+(defun js-repl-completion-at-point ()
+  "Complete property names of the live object before point.
+This is used in `completion-at-point-functions'."
+  (when (>= (point) (process-mark (js-repl-process)))
+    (save-excursion
+      (let ((end (point)))
+        (skip-chars-backward "[:alnum:]_$.")
+        (let* ((text (buffer-substring-no-properties (point) end))
+               (start (+ (point) (or (cl-position ?. text :from-end t) -1) 1))
+               (names (js-repl--call "__replComplete" text)))
+          (when (and (stringp names) (not (string-empty-p names)))
+            (list start end (split-string names "\n" t))))))))
+
+(define-derived-mode js-repl-mode comint-mode "JS-REPL"
+  "Major mode for interactively evaluating JavaScript.
+Uses the interface provided by `comint-mode' (which see).
+
+Input is evaluated by the runtime used by `eval-js', in its global
+scope, and the result is inspected with `Deno.inspect'.  Lisp objects
+are shown in their printed form.
+
+* \\<js-repl-map>\\[js-repl-return] evaluates the input, or inserts a newline
+  if the input is incomplete, so that functions and blocks can be
+  entered over several lines.
+* \\[js-repl-send-input] evaluates the input, even if it is incomplete.
+* \\[completion-at-point] completes the properties of the object before
+  point, by enumerating it in the running runtime.
+
+Inputs may use top level await; their result is inserted once they
+complete.  Top level `const' and `let' declarations are turned into
+globals, so that they remain visible to later inputs.
+
+Input history is saved in `js-repl-history-file'.
+
+\\{js-repl-map}"
+  (setq comint-prompt-regexp (concat "^" (regexp-quote js-repl-prompt)))
+  (setq comint-input-sender 'js-repl-input-sender)
+  (setq comint-process-echoes nil)
+  (setq-local comint-prompt-read-only t)
+  (setq-local comint-input-ring-file-name js-repl-history-file)
+  (setq-local comint-input-ignoredups t)
+  (add-hook 'completion-at-point-functions #'js-repl-completion-at-point nil t)
+  (add-hook 'kill-buffer-hook #'comint-write-input-ring nil t)
+  (comint-read-input-ring t)
+
+  ;; A dummy process to keep comint happy. It will never get any input.
+  (unless (comint-check-proc (current-buffer))
+    (condition-case nil
+        (start-process "js-repl" (current-buffer) "hexl")
+      (file-error (start-process "js-repl" (current-buffer) "cat")))
+    (set-process-query-on-exit-flag (js-repl-process) nil)
+    (goto-char (point-max))
+    (setq-local comint-inhibit-carriage-motion t)
+    (insert js-repl-header)
+    (set-marker (process-mark (js-repl-process)) (point-max))
+    (unless comint-use-prompt-regexp
+      (let ((inhibit-read-only t))
+        (add-text-properties
+         (point-min) (point-max)
+         '(rear-nonsticky t field output inhibit-line-move-field-capture t))))
+    (comint-output-filter (js-repl-process) js-repl-prompt)
+    (set-marker comint-last-input-start (process-mark (js-repl-process)))
+    (set-process-filter (js-repl-process) 'comint-output-filter)))
+
+;;;###autoload
+(defun js-repl ()
+  "Interactively evaluate JavaScript in the embedded runtime.
+Switches to the `*js-repl*' buffer, creating it if needed.
+See `js-repl-mode' for details."
+  (interactive)
+  (unless (comint-check-proc js-repl-buffer-name)
+    (with-current-buffer (get-buffer-create js-repl-buffer-name)
+      (js-repl-mode)))
+  (pop-to-buffer-same-window js-repl-buffer-name))
+
+(provide 'js-repl)
+
+;;; js-repl.el ends here
@@ -113,6 +113,20 @@ impl LispFrameRef {
             - 2 * self.internal_border_width()
     }
 
+    pub fn text_to_pixel_width(self, width: i32) -> i32 {
+        width
+            + self.scroll_bar_area_width()
+            + self.total_fringe_width()
+            + 2 * self.internal_border_width()
+    }
+
+    pub fn text_to_pixel_height(self, height: i32) -> i32 {
+        height
+            + self.top_margin_height()
+            + self.horizontal_scroll_bar_height()
+            + 2 * self.internal_border_width()
+    }
+
     pub fn face_from_id(self, id: face_id) -> Option<*mut face> {
         let cache = self.face_cache;
 
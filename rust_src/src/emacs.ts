@@ -0,0 +1,150 @@
+// The built-in "emacs:" module. This is inserted into Deno's file cache
+// when the JavaScript runtime is initialized, and can be imported by any
+// module evaluated by emacs-ng:
+//
+//     import { lisp, funcall, withCurrentBuffer } from "emacs:";
+//
+// Everything here is a thin, typed wrapper around the global `lisp`
+// object defined in prelim.js.
+
+/** A proxy to an object owned by the Lisp VM. */
+export interface LispObject {
+    /** Attempts to convert this object into plain JSON. */
+    json(): any;
+}
+
+/** Values that Lisp functions return to JavaScript. */
+export type LispValue = LispObject | string | number | boolean | null;
+
+/** Values that can be passed to Lisp functions from JavaScript. */
+export type LispArg =
+    | LispValue
+    | ((...args: any[]) => any)
+    | LispArg[]
+    | { [key: string]: LispArg };
+
+export interface LispMake {
+    hashtable(obj: { [key: string]: LispArg }): LispObject;
+    alist(obj: { [key: string]: LispArg }): LispObject;
+    plist(obj: { [key: string]: LispArg }): LispObject;
+    array(arr: LispArg[]): LispObject;
+    list(arr: LispArg[]): LispObject;
+    string(str: string): LispObject;
+}
+
+export interface DefunOptions {
+    name: string | LispObject;
+    docString?: string;
+    interactive?: boolean;
+    args?: string;
+    func: (...args: any[]) => any;
+}
+
+export interface LispAsync {
+    /**
+     * Resolves with all output PROC produces from now on,
+     * once the process exits.
+     */
+    process_output(proc: LispObject): Promise<string>;
+    /** Any other Lisp function, called outside of JavaScript. */
+    [name: string]: (...args: LispArg[]) => Promise<any>;
+}
+
+/**
+ * Reads and edits buffer text directly, without converting it to a Lisp
+ * string first. BUFFER is a buffer, a buffer name, or undefined for the
+ * current buffer. Positions are character positions, as in Lisp, and
+ * default to the accessible portion of the buffer.
+ */
+export interface EmacsBuffer {
+    /** Returns a copy of the text between START and END. */
+    text(buffer?: string | LispObject, start?: number, end?: number): string;
+    /**
+     * Returns a copy of the text between START and END in Emacs'
+     * internal encoding, which is UTF-8 for everything but raw bytes.
+     */
+    bytes(buffer?: string | LispObject, start?: number, end?: number): Uint8Array;
+    /** Returns `[point-min, point-max]`. */
+    bounds(buffer?: string | LispObject): [number, number];
+    /** Inserts TEXT at POS, returning the position after it. */
+    insert(buffer: string | LispObject | undefined, pos: number, text: string): number;
+    /** Deletes the text between START and END, returning START. */
+    delete(buffer?: string | LispObject, start?: number, end?: number): number;
+    /** Replaces the text between START and END with TEXT, returning the position after it. */
+    replace(buffer: string | LispObject | undefined, start: number | undefined, end: number | undefined, text: string): number;
+}
+
+export interface Lisp {
+    /** Interns symbols, i.e. `lisp.symbols.foo_bar` is `foo-bar`. */
+    symbols: { [name: string]: LispObject };
+    /** Interns keywords, i.e. `lisp.keywords.foo_bar` is `:foo-bar`. */
+    keywords: { [name: string]: LispObject };
+    make: LispMake;
+    /** Calls Lisp functions asynchronously, returning Promises. */
+    async: LispAsync;
+    specialForms: { [name: string]: any };
+    defun(options: DefunOptions): void;
+    defun(name: string | LispObject, func: (...args: any[]) => any): void;
+    setq(symbol: LispObject, value: LispArg): LispValue;
+    defvar(symbol: LispObject, value?: LispArg, docString?: string): LispValue;
+    quote(arg: LispArg): LispObject;
+    list(...args: LispArg[]): LispObject;
+    with_current_buffer<T extends LispValue>(buffer: string | LispObject, f: () => T): T;
+    with_temp_buffer<T extends LispValue>(f: () => T): T;
+    /** Any other Lisp function, with `-` replaced by `_`. */
+    [name: string]: any;
+}
+
+export const lisp: Lisp = (globalThis as any).lisp;
+export const buffer: EmacsBuffer = (globalThis as any).emacs.buffer;
+export const symbols = lisp.symbols;
+export const keywords = lisp.keywords;
+export const make = lisp.make;
+
+/** Returns the symbol named NAME, exactly as written. */
+export function intern(name: string): LispObject {
+    return lisp.intern(name);
+}
+
+/** Calls FN, a function name or a function object, with ARGS. */
+export function funcall(fn: string | LispObject, ...args: LispArg[]): LispValue {
+    const f = typeof fn === "string" ? intern(fn) : fn;
+    return lisp.funcall(f, ...args);
+}
+
+/** Returns the value of SYMBOL, a symbol or a symbol name. */
+export function symbolValue(symbol: string | LispObject): LispValue {
+    const sym = typeof symbol === "string" ? intern(symbol) : symbol;
+    return lisp.symbol_value(sym);
+}
+
+/** Sets the value of SYMBOL, a symbol or a symbol name, to VALUE. */
+export function setq(symbol: string | LispObject, value: LispArg): LispValue {
+    const sym = typeof symbol === "string" ? intern(symbol) : symbol;
+    return lisp.setq(sym, value);
+}
+
+/** Defines NAME as a Lisp function implemented by FUNC. */
+export function defun(options: DefunOptions): void {
+    lisp.defun(options);
+}
+
+/** Displays a message in the echo area, see `message`. */
+export function message(format: string, ...args: LispArg[]): string {
+    return lisp.message(format, ...args);
+}
+
+/** Calls F with BUFFER temporarily current. */
+export function withCurrentBuffer<T extends LispValue>(buffer: string | LispObject, f: () => T): T {
+    return lisp.with_current_buffer(buffer, f);
+}
+
+/** Calls F with a fresh temporary buffer current. */
+export function withTempBuffer<T extends LispValue>(f: () => T): T {
+    return lisp.with_temp_buffer(f);
+}
+
+/** Returns the contents of BUFFER, or the current buffer, as a string. */
+export function bufferString(buf?: string | LispObject): string {
+    return buffer.text(buf);
+}
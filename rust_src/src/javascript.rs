@@ -1,8 +1,10 @@
+use crate::ng_async::{to_owned_userdata, EmacsPipe, PipeDataOption, UserData};
 use crate::parsing::{ArrayType, ObjectType};
+use crossbeam::channel::Sender;
 use lisp::lisp::LispObject;
 use lisp::list::{LispCons, LispConsCircularChecks, LispConsEndChecks};
 use lisp::multibyte::LispStringRef;
-use lisp::remacs_sys::{EmacsUint, Ffuncall};
+use lisp::remacs_sys::{EmacsInt, EmacsUint, Ffuncall};
 use lisp_macros::lisp_fn;
 use rusty_v8 as v8;
 use std::cell::RefCell;
@@ -11,7 +13,8 @@ use std::convert::TryInto;
 use std::ffi::CString;
 use std::io::Result;
 use std::mem::MaybeUninit;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 
 #[derive(Clone)]
 struct EmacsJsOptions {
@@ -25,6 +28,10 @@ struct EmacsJsOptions {
     no_check: bool,
     no_remote: bool,
     loops_per_tick: EmacsUint,
+    event_driven: bool,
+    vendor_dir: Option<String>,
+    import_map: Option<String>,
+    buffer_import_map: Option<String>,
 }
 
 /// In order to smoothly interface with the Lisp VM,
@@ -86,6 +93,9 @@ struct EmacsMainJsRuntime {
     /// Proxies are created by a global template, stored in this
     /// field
     proxy_template: Option<v8::Global<v8::ObjectTemplate>>,
+    /// The FinalizationRegistry every proxy is registered with, which
+    /// releases a proxy's slot once V8 has collected it.
+    proxy_registry: Option<v8::Global<v8::Object>>,
     /// The deno program state for our worker. Usually not touched,
     /// it may be sometimes references to refer to certain variables
     /// not stored in EmacsJsOptions.
@@ -97,8 +107,67 @@ struct EmacsMainJsRuntime {
     /// If currently have a pending tick of the JS event loop scheduled.
     /// We will only schedule one tick at a time.
     tick_scheduled: bool,
+    /// Used to wake the lisp thread when the JS event loop has
+    /// pending work. Only present when the runtime is event driven.
+    /// Unlike the deno_worker, this outlives calls to js-cleanup.
+    event_loop_waker: Option<Arc<EventLoopWaker>>,
+    /// The buffers and positions that the anonymous modules created by
+    /// eval-js-buffer and eval-js-region were read from, oldest first.
+    /// Used to map error locations back to those buffers.
+    module_origins: Vec<(String, ModuleOrigin)>,
+    /// The inspector session running V8's CPU profiler, between
+    /// js-profile-start and js-profile-stop.
+    profiler_session: Option<Box<deno_runtime::inspector::InspectorSession>>,
+    /// V8's inspector, attached to the worker the first time the
+    /// profiler needs it. Unset when Deno attached its own for :inspect.
+    inspector: Option<Box<deno_runtime::inspector::DenoInspector>>,
 }
 
+/// Where the source of an anonymous module came from. LINE and
+/// COLUMN are where the module starts in the buffer, with LINE
+/// starting at 1 and COLUMN at 0. WIDE_LINES holds the 0-based
+/// index and text of every line of the module with characters
+/// outside the BMP, as it was evaluated.
+#[derive(Clone)]
+struct ModuleOrigin {
+    buffer_name: String,
+    file_name: Option<String>,
+    line: i64,
+    column: i64,
+    wide_lines: Vec<(i64, String)>,
+}
+
+impl ModuleOrigin {
+    /// Maps a 1-based LINE and COLUMN in the module to the buffer.
+    /// V8 counts COLUMN in UTF-16 code units, which only differ from
+    /// characters on lines with characters outside the BMP. This is
+    /// called while V8 formats stack traces, so it must not call lisp.
+    fn map(&self, line: i64, column: i64) -> (i64, i64) {
+        let column = self
+            .wide_lines
+            .iter()
+            .find(|(index, _)| *index == line - 1)
+            .map(|(_, text)| utf16_column_to_chars(text, column))
+            .unwrap_or(column);
+        if line == 1 {
+            (self.line, self.column + column)
+        } else {
+            (self.line + line - 1, column)
+        }
+    }
+
+    /// Formats a location so that compilation-mode can visit it.
+    fn location(&self, line: i64, column: i64) -> String {
+        let (line, column) = self.map(line, column);
+        let name = self.file_name.as_ref().unwrap_or(&self.buffer_name);
+        format!("{}:{}:{}", name, line, column)
+    }
+}
+
+// We only keep this many module origins around. Errors are
+// usually thrown shortly after a module is evaluated.
+const MAX_MODULE_ORIGINS: usize = 64;
+
 impl Default for EmacsMainJsRuntime {
     fn default() -> Self {
         Self {
@@ -109,9 +178,14 @@ impl Default for EmacsMainJsRuntime {
             stacked_v8_handle: None,
             options: EmacsJsOptions::default(),
             proxy_template: None,
+            proxy_registry: None,
             program_state: None,
             within_toplevel: false,
             tick_scheduled: false,
+            event_loop_waker: None,
+            module_origins: Vec::new(),
+            profiler_session: None,
+            inspector: None,
         }
     }
 }
@@ -129,6 +203,10 @@ impl Default for EmacsJsOptions {
             no_check: false,
             no_remote: false,
             loops_per_tick: 1000,
+            event_driven: true,
+            vendor_dir: None,
+            import_map: None,
+            buffer_import_map: None,
         }
     }
 }
@@ -221,6 +299,14 @@ impl EmacsMainJsRuntime {
         Self::access(|main| main.proxy_template.clone().unwrap())
     }
 
+    fn set_proxy_registry(global: v8::Global<v8::Object>) {
+        Self::access(move |main| main.proxy_registry = Some(global));
+    }
+
+    fn get_proxy_registry() -> v8::Global<v8::Object> {
+        Self::access(|main| main.proxy_registry.clone().unwrap())
+    }
+
     fn get_options() -> EmacsJsOptions {
         Self::set_default_perms_if_unset();
         Self::access(|main| main.options.clone())
@@ -268,6 +354,20 @@ impl EmacsMainJsRuntime {
         Self::access(move |main| main.stacked_v8_handle = o);
     }
 
+    fn add_module_origin(module: String, origin: ModuleOrigin) {
+        Self::access(move |main| {
+            if main.module_origins.len() >= MAX_MODULE_ORIGINS {
+                main.module_origins.remove(0);
+            }
+
+            main.module_origins.push((module, origin));
+        });
+    }
+
+    fn get_module_origins() -> Vec<(String, ModuleOrigin)> {
+        Self::access(|main| main.module_origins.clone())
+    }
+
     fn inc_module_counter() -> u64 {
         Self::access(|main| {
             main.module_counter += 1;
@@ -293,9 +393,11 @@ impl EmacsMainJsRuntime {
 
     fn enter_runtime() {
         Self::_set_within_runtime(true);
+        QUIT_WATCHDOG.set_executing(true);
     }
 
     fn exit_runtime() {
+        QUIT_WATCHDOG.set_executing(false);
         Self::_set_within_runtime(false);
     }
 
@@ -321,19 +423,49 @@ impl EmacsMainJsRuntime {
 
     fn destroy_worker() {
         Self::access(|main| {
+            // The session refers to the worker's inspector, which
+            // refers to the worker, so they must go first.
+            main.profiler_session = None;
+            main.inspector = None;
             main.proxy_template = None;
+            main.proxy_registry = None;
             main.deno_worker = None;
+            // A buffer's import map only lasts as long as the runtime
+            // it was created with.
+            main.options.buffer_import_map = None;
         });
+        QUIT_WATCHDOG.watch(None);
+        PROXIES.with(|proxies| proxies.borrow_mut().clear());
     }
 
-    fn get_deno_worker() -> MainWorkerHandle {
-        Self::access(|main| MainWorkerHandle::new(main.deno_worker.take().unwrap()))
+    // The worker is taken while it runs, so this fails if lisp called
+    // from JS tries to run the worker again.
+    fn get_deno_worker() -> Result<MainWorkerHandle> {
+        Self::access(|main| main.deno_worker.take())
+            .map(MainWorkerHandle::new)
+            .ok_or_else(|| {
+                into_ioerr(
+                    "The JavaScript environment is already running, and cannot be entered again",
+                )
+            })
     }
 
     fn set_deno_worker(worker: deno_runtime::worker::MainWorker) {
         Self::access(move |main| main.deno_worker = Some(worker));
     }
 
+    fn set_profiler_session(session: Option<Box<deno_runtime::inspector::InspectorSession>>) {
+        Self::access(move |main| main.profiler_session = session);
+    }
+
+    fn take_profiler_session() -> Option<Box<deno_runtime::inspector::InspectorSession>> {
+        Self::access(|main| main.profiler_session.take())
+    }
+
+    fn is_profiling() -> bool {
+        Self::access(|main| main.profiler_session.is_some())
+    }
+
     fn is_main_worker_active() -> bool {
         Self::access(|main| main.deno_worker.is_some())
     }
@@ -357,6 +489,219 @@ impl EmacsMainJsRuntime {
     fn get_loops_per_tick() -> EmacsUint {
         Self::access(|main| main.options.loops_per_tick)
     }
+
+    fn get_event_loop_waker() -> Option<Arc<EventLoopWaker>> {
+        Self::access(|main| main.event_loop_waker.clone())
+    }
+
+    fn init_event_loop_waker() {
+        let is_live = Self::access(|main| {
+            main.event_loop_waker
+                .as_ref()
+                .map(|waker| waker.is_live())
+                .unwrap_or(false)
+        });
+
+        if !is_live {
+            let waker = EventLoopWaker::new();
+            Self::access(move |main| main.event_loop_waker = Some(waker));
+        }
+    }
+}
+
+/// Wakes the lisp thread when the JS event loop has work to do.
+/// Deno's ops and timers are driven by the tokio worker threads,
+/// which invoke this waker when a future polled by 'tick_js' becomes
+/// ready. Waking writes to an EmacsPipe, and the filter for that pipe
+/// advances the event loop on the lisp thread. Promises resolve as
+/// soon as their op completes, and an idle Emacs does no JS work.
+struct EventLoopWaker {
+    pipe: Mutex<(EmacsPipe, Sender<String>)>,
+    proc: LispObject,
+    /// Set once a notification has been written to the pipe, and
+    /// cleared when the lisp thread polls the event loop. Used to
+    /// coalesce multiple wake ups into a single filter invocation.
+    notified: AtomicBool,
+}
+
+impl EventLoopWaker {
+    fn new() -> Arc<Self> {
+        let (pipe, proc) = EmacsPipe::with_handler(
+            lisp::remacs_sys::Qjs__wake_event_loop,
+            PipeDataOption::STRING,
+            PipeDataOption::STRING,
+        );
+
+        // This process is an implementation detail, the user
+        // should not be asked about it when exiting emacs.
+        unsafe { lisp::remacs_sys::Fset_process_query_on_exit_flag(proc, lisp::remacs_sys::Qnil) };
+
+        let sender = pipe.get_sender();
+        Arc::new(Self {
+            pipe: Mutex::new((pipe, sender)),
+            proc,
+            notified: AtomicBool::new(false),
+        })
+    }
+
+    // If the user has deleted our pipe process, we need
+    // to create a new one. A deleted process is removed from
+    // the process alist, so we never touch a dead process object.
+    fn is_live(&self) -> bool {
+        unsafe { lisp::remacs_sys::Frassq(self.proc, lisp::remacs_sys::Vprocess_alist) }
+            .is_not_nil()
+    }
+
+    fn consume(&self) {
+        self.notified.store(false, Ordering::SeqCst);
+    }
+}
+
+impl futures::task::ArcWake for EventLoopWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        if arc_self.notified.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if let Ok(mut guard) = arc_self.pipe.lock() {
+            let (pipe, sender) = &mut *guard;
+            if pipe.message_lisp(sender, String::new()).is_err() {
+                arc_self.notified.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Lets C-g interrupt runaway JavaScript. While the lisp thread is
+/// inside V8 it never reads input, so while JS is running a watchdog
+/// thread asks V8 to call 'check_for_quit' on the lisp thread every
+/// QUIT_POLL_INTERVAL. That reads pending input, as Emacs does when
+/// polling, and terminates execution if the user has quit. The
+/// termination reaches lisp as a quit signal, see 'signal_quit_if_requested'.
+/// While no JS is running, the watchdog thread sleeps on ARMED.
+struct QuitWatchdog {
+    isolate: Mutex<Option<v8::IsolateHandle>>,
+    executing: Mutex<bool>,
+    armed: Condvar,
+    quit_requested: AtomicBool,
+    checking: AtomicBool,
+}
+
+const QUIT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+lazy_static::lazy_static! {
+    static ref QUIT_WATCHDOG: QuitWatchdog = {
+        std::thread::spawn(|| QUIT_WATCHDOG.run());
+
+        QuitWatchdog {
+            isolate: Mutex::new(None),
+            executing: Mutex::new(false),
+            armed: Condvar::new(),
+            quit_requested: AtomicBool::new(false),
+            checking: AtomicBool::new(false),
+        }
+    };
+}
+
+impl QuitWatchdog {
+    fn watch(&self, isolate: Option<v8::IsolateHandle>) {
+        *self.isolate.lock().unwrap() = isolate;
+    }
+
+    fn set_executing(&self, executing: bool) {
+        *self.executing.lock().unwrap() = executing;
+        self.armed.notify_one();
+    }
+
+    // The watchdog thread. Only interrupts V8 once JS has been running
+    // for a whole QUIT_POLL_INTERVAL.
+    fn run(&self) {
+        let mut executing = self.executing.lock().unwrap();
+        loop {
+            while !*executing {
+                executing = self.armed.wait(executing).unwrap();
+            }
+
+            let (guard, timeout) = self
+                .armed
+                .wait_timeout(executing, QUIT_POLL_INTERVAL)
+                .unwrap();
+            executing = guard;
+            if *executing && timeout.timed_out() {
+                if let Some(isolate) = self.isolate.lock().unwrap().as_ref() {
+                    isolate.request_interrupt(check_for_quit, std::ptr::null_mut());
+                }
+            }
+        }
+    }
+
+    // Terminates the JS currently running, and remembers to
+    // signal quit once it has unwound back to lisp.
+    fn terminate(&self, isolate: &mut v8::Isolate) {
+        self.quit_requested.store(true, Ordering::SeqCst);
+        isolate.terminate_execution();
+    }
+
+    fn is_quit_requested(&self) -> bool {
+        self.quit_requested.load(Ordering::SeqCst)
+    }
+
+    fn take_quit_request(&self) -> bool {
+        let requested = self.quit_requested.swap(false, Ordering::SeqCst);
+        if requested {
+            if let Some(isolate) = self.isolate.lock().unwrap().as_ref() {
+                isolate.cancel_terminate_execution();
+            }
+        }
+
+        requested
+    }
+
+    // True while 'check_for_quit' reads input. V8 must not be
+    // re-entered from an interrupt, so lisp run by input handlers
+    // can't call JS during that time.
+    fn is_checking(&self) -> bool {
+        self.checking.load(Ordering::SeqCst)
+    }
+}
+
+unsafe extern "C" fn gobble_input_springboard(_arg1: *mut ::libc::c_void) -> LispObject {
+    lisp::remacs_sys::gobble_input();
+    lisp::remacs_sys::Qnil
+}
+
+// Runs on the lisp thread, between two JS instructions. Reading input
+// can run lisp, which must not unwind through V8's frames, so any
+// non-local exit is caught here.
+extern "C" fn check_for_quit(isolate: &mut v8::Isolate, _data: *mut ::libc::c_void) {
+    unsafe {
+        if is_interactive() && lisp::remacs_sys::globals.Vquit_flag.is_nil() {
+            QUIT_WATCHDOG.checking.store(true, Ordering::SeqCst);
+            lisp::remacs_sys::internal_catch_all(
+                Some(gobble_input_springboard),
+                std::ptr::null_mut(),
+                Some(lisp_handler),
+            );
+            QUIT_WATCHDOG.checking.store(false, Ordering::SeqCst);
+        }
+
+        if lisp::remacs_sys::globals.Vquit_flag.is_not_nil()
+            && lisp::remacs_sys::globals.Vinhibit_quit.is_nil()
+        {
+            QUIT_WATCHDOG.terminate(isolate);
+        }
+    }
+}
+
+// If JS was terminated because the user quit, signals quit instead of
+// reporting the termination as a JS error.
+fn signal_quit_if_requested() {
+    if QUIT_WATCHDOG.take_quit_request() {
+        unsafe {
+            lisp::remacs_sys::globals.Vquit_flag = lisp::remacs_sys::Qnil;
+        }
+        lisp::eval::signal_rust(lisp::remacs_sys::Qquit, lisp::remacs_sys::Qnil);
+    }
 }
 
 fn is_interactive() -> bool {
@@ -402,26 +747,111 @@ const PLIST: u32 = 2;
 const ARRAY: u32 = 3;
 const LIST: u32 = 4;
 
+// Lisp objects referenced from JS proxies. Each proxy stores the index
+// of its slot in its internal field, and Emacs's GC marks the occupied
+// slots through mark_js_proxies, so nothing needs to be rebuilt when
+// proxies come and go. make_proxy! registers every proxy with a
+// FinalizationRegistry, which releases its slot once V8 has collected
+// it; the rusty_v8 we build against has no weak handles for V8 to tell
+// us about dead proxies directly.
+#[derive(Default)]
+struct ProxyTable {
+    slots: Vec<Option<LispObject>>,
+    free: Vec<u32>,
+    created: u64,
+    released: u64,
+}
+
+impl ProxyTable {
+    fn retain(&mut self, obj: LispObject) -> u32 {
+        self.created += 1;
+        if let Some(slot) = self.free.pop() {
+            self.slots[slot as usize] = Some(obj);
+            slot
+        } else {
+            self.slots.push(Some(obj));
+            (self.slots.len() - 1) as u32
+        }
+    }
+
+    fn get(&self, slot: u32) -> Option<LispObject> {
+        self.slots.get(slot as usize).copied().flatten()
+    }
+
+    fn release(&mut self, slot: u32) {
+        if let Some(entry) = self.slots.get_mut(slot as usize) {
+            if entry.take().is_some() {
+                self.released += 1;
+                self.free.push(slot);
+            }
+        }
+    }
+
+    fn live(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    fn clear(&mut self) {
+        self.released += self.live() as u64;
+        self.slots.clear();
+        self.free.clear();
+    }
+}
+
+thread_local! {
+    static PROXIES: RefCell<ProxyTable> = RefCell::new(ProxyTable::default());
+}
+
+fn retain_proxy(obj: LispObject) -> u32 {
+    PROXIES.with(|proxies| proxies.borrow_mut().retain(obj))
+}
+
+fn proxy_object(slot: u32) -> LispObject {
+    PROXIES
+        .with(|proxies| proxies.borrow().get(slot))
+        .unwrap_or(lisp::remacs_sys::Qnil)
+}
+
+/// Called by Emacs's GC to mark the Lisp objects referenced by live
+/// JS proxies.
+#[cfg(feature = "javascript")]
+#[no_mangle]
+pub extern "C" fn mark_js_proxies() {
+    PROXIES.with(|proxies| {
+        for obj in proxies.borrow().slots.iter().flatten() {
+            unsafe { lisp::remacs_sys::mark_object(*obj) };
+        }
+    });
+}
+
+// Registers PROXY, which holds SLOT, with the proxy registry, so that
+// SLOT is released once V8 has collected PROXY.
+fn register_proxy(scope: &mut v8::HandleScope, proxy: v8::Local<v8::Object>, slot: u32) {
+    let registry = EmacsMainJsRuntime::get_proxy_registry();
+    let registry = registry.get(scope);
+    let name = v8::String::new(scope, "register").unwrap();
+    let register = registry
+        .get(scope, name.into())
+        .and_then(|register| v8::Local::<v8::Function>::try_from(register).ok());
+    let held = v8::Integer::new_from_unsigned(scope, slot);
+    // This fails when JS is being terminated, as on quit. SLOT then stays
+    // retained: leaking its object is better than freeing one that PROXY
+    // still refers to.
+    if let Some(register) = register {
+        let _ = register.call(scope, registry.into(), &[proxy.into(), held.into()]);
+    }
+}
+
 macro_rules! make_proxy {
     ($scope:expr, $lisp:expr) => {{
         let template = EmacsMainJsRuntime::get_proxy_template();
         let tpl = template.get($scope);
         let obj = tpl.new_instance($scope).unwrap();
-        let value = v8::String::new($scope, &$lisp.to_C_unsigned().to_string()).unwrap();
+        let slot = retain_proxy($lisp);
+        let value = v8::Integer::new_from_unsigned($scope, slot);
         let inserted = obj.set_internal_field(0, v8::Local::<v8::Value>::try_from(value).unwrap());
         assert!(inserted);
-
-        unsafe {
-            if lisp::remacs_sys::globals.Vjs_retain_map == lisp::remacs_sys::Qnil {
-                lisp::remacs_sys::globals.Vjs_retain_map =
-                    LispObject::cons($lisp, lisp::remacs_sys::Qnil);
-                lisp::remacs_sys::staticpro(&lisp::remacs_sys::globals.Vjs_retain_map);
-            } else {
-                lisp::remacs_sys::globals.Vjs_retain_map =
-                    LispObject::cons($lisp, lisp::remacs_sys::globals.Vjs_retain_map);
-            }
-        }
-
+        register_proxy($scope, obj, slot);
         obj
     }};
 }
@@ -429,11 +859,7 @@ macro_rules! make_proxy {
 macro_rules! unproxy {
     ($scope:expr, $obj:expr) => {{
         let internal = $obj.get_internal_field($scope, 0).unwrap();
-        let ptrstr = internal
-            .to_string($scope)
-            .unwrap()
-            .to_rust_string_lossy($scope);
-        LispObject::from_C_unsigned(ptrstr.parse::<lisp::remacs_sys::EmacsUint>().unwrap())
+        proxy_object(internal.uint32_value($scope).unwrap())
     }};
 }
 
@@ -723,22 +1149,21 @@ pub fn lisp_json(
     }
 }
 
-pub fn finalize(
+// The callback of the proxy registry. Releases the slots passed as
+// arguments, whose proxies have been collected by V8.
+pub fn release_proxies(
     scope: &mut v8::HandleScope,
     args: v8::FunctionCallbackArguments,
     _retval: v8::ReturnValue,
 ) {
-    let len = args.length();
-    let mut new_list = LispObject::cons(lisp::remacs_sys::Qnil, lisp::remacs_sys::Qnil);
-    for i in 0..len {
-        let arg = args.get(i);
-        if arg.is_object() {
-            let lispobj = unproxy!(scope, arg.to_object(scope).unwrap());
-            new_list = LispObject::cons(lispobj, new_list);
+    PROXIES.with(|proxies| {
+        let mut proxies = proxies.borrow_mut();
+        for i in 0..args.length() {
+            if let Some(slot) = args.get(i).uint32_value(scope) {
+                proxies.release(slot);
+            }
         }
-    }
-
-    unsafe { lisp::remacs_sys::globals.Vjs_retain_map = new_list };
+    });
 }
 
 pub fn is_proxy(
@@ -844,7 +1269,29 @@ pub fn lisp_invoke(
         let cons: LispCons = results.into();
         if cons.car() == lisp::remacs_sys::Qjs_lisp_error {
             // Lisp has thrown, so we want to throw a JS exception.
-            let lisp_error_string = unsafe { lisp::remacs_sys::Ferror_message_string(cons.cdr()) };
+            // If that was a JS error thrown further down the stack,
+            // we rethrow its original message.
+            let error = cons.cdr();
+            // A quit in lisp, including one caused by terminating JS
+            // further down the stack, has to unwind all of the JS
+            // above it, so we terminate instead of throwing something
+            // JS could catch.
+            if error
+                .as_cons()
+                .map_or(false, |e| e.car() == lisp::remacs_sys::Qquit)
+            {
+                QUIT_WATCHDOG.terminate(scope);
+                return;
+            }
+
+            let lisp_error_string = match error.as_cons() {
+                Some(error) if error.car() == lisp::remacs_sys::Qjs_error => error
+                    .cdr()
+                    .as_cons()
+                    .map(|data| data.car())
+                    .unwrap_or(lisp::remacs_sys::Qnil),
+                _ => unsafe { lisp::remacs_sys::Ferror_message_string(error) },
+            };
             let lisp_ref: LispStringRef = lisp_error_string.into();
             let err = lisp_ref.to_utf8();
             let error = v8::String::new(scope, &err).unwrap();
@@ -931,6 +1378,9 @@ fn permissions_from_args(args: &[LispObject]) -> EmacsJsOptions {
                     options.tick_rate = lisp::remacs_sys::XFLOAT_DATA(value);
                 }
             },
+            lisp::remacs_sys::QCjs_event_driven => {
+                options.event_driven = value.is_not_nil();
+            }
             lisp::remacs_sys::QCjs_error_handler => {
                 options.error_handler = value;
             }
@@ -968,6 +1418,24 @@ fn permissions_from_args(args: &[LispObject]) -> EmacsJsOptions {
                     options.no_remote = true;
                 }
             }
+            lisp::remacs_sys::QCimport_map => {
+                if value.is_not_nil() {
+                    let expanded = unsafe {
+                        lisp::remacs_sys::Fexpand_file_name(value, lisp::remacs_sys::Qnil)
+                    };
+                    let sref: LispStringRef = expanded.into();
+                    options.import_map = Some(sref.to_utf8());
+                }
+            }
+            lisp::remacs_sys::QCvendor_dir => {
+                if value.is_not_nil() {
+                    let expanded = unsafe {
+                        lisp::remacs_sys::Fexpand_file_name(value, lisp::remacs_sys::Qnil)
+                    };
+                    let sref: LispStringRef = expanded.into();
+                    options.vendor_dir = Some(sref.to_utf8());
+                }
+            }
 
             _ => error!(JS_PERMS_ERROR),
         }
@@ -1004,6 +1472,230 @@ fn is_typescript(s: &str) -> bool {
     !s.ends_with("js")
 }
 
+// Modules can be imported from 'js-load-path' and 'load-path' with the
+// "emacs:" scheme, i.e. import { x } from "emacs:my-package". Deno
+// does not let us resolve custom schemes ourselves, so when the worker
+// is created, we give it an import map resolving every module we can
+// find in those directories. The specifier "emacs:" itself resolves to the
+// built-in module defined in emacs.ts.
+const EMACS_SCHEME: &str = "emacs:";
+const EMACS_BUILTIN_MODULE: &str = "./$emacs$builtin.ts";
+const MODULE_EXTENSIONS: [&str; 2] = ["ts", "js"];
+const MODULE_ENTRY_POINTS: [&str; 4] = ["mod.ts", "mod.js", "index.ts", "index.js"];
+
+fn js_load_path() -> Vec<std::path::PathBuf> {
+    let paths = unsafe {
+        [
+            lisp::remacs_sys::globals.Vjs_load_path,
+            lisp::remacs_sys::globals.Vload_path,
+        ]
+    };
+
+    paths
+        .iter()
+        .flat_map(|path| path.iter_cars(LispConsEndChecks::off, LispConsCircularChecks::safe))
+        .filter(|dir| dir.is_string())
+        .map(|dir| {
+            let expanded =
+                unsafe { lisp::remacs_sys::Fexpand_file_name(dir, lisp::remacs_sys::Qnil) };
+            let path: LispStringRef = expanded.into();
+            std::path::PathBuf::from(path.to_utf8())
+        })
+        .filter(|dir| dir.is_dir())
+        .collect()
+}
+
+fn is_module_file(path: &std::path::Path) -> bool {
+    let name = path.to_string_lossy();
+    path.is_file()
+        && !name.ends_with(".d.ts")
+        && path
+            .extension()
+            .map(|ext| MODULE_EXTENSIONS.iter().any(|e| ext == *e))
+            .unwrap_or(false)
+}
+
+fn module_entry_point(dir: &std::path::Path) -> Option<std::path::PathBuf> {
+    MODULE_ENTRY_POINTS
+        .iter()
+        .map(|entry| dir.join(entry))
+        .find(|path| path.is_file())
+}
+
+// Collects the "emacs:" imports provided by DIR. A file "foo.ts"
+// provides "emacs:foo", and a directory "foo" with an entry point
+// provides both "emacs:foo" and the prefix "emacs:foo/". Directories
+// earlier in the load path take precedence.
+fn collect_emacs_imports(
+    dir: &std::path::Path,
+    imports: &mut serde_json::Map<String, serde_json::Value>,
+) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if is_module_file(&path) {
+            let stem = path.file_stem().unwrap().to_string_lossy().to_string();
+            if let Ok(url) = deno_core::url::Url::from_file_path(&path) {
+                imports
+                    .entry(format!("{}{}", EMACS_SCHEME, stem))
+                    .or_insert_with(|| serde_json::Value::String(url.to_string()));
+            }
+        } else if path.is_dir() {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if let Some(entry_point) = module_entry_point(&path) {
+                if let Ok(url) = deno_core::url::Url::from_file_path(&entry_point) {
+                    imports
+                        .entry(format!("{}{}", EMACS_SCHEME, name))
+                        .or_insert_with(|| serde_json::Value::String(url.to_string()));
+                }
+
+                if let Ok(url) = deno_core::url::Url::from_directory_path(&path) {
+                    imports
+                        .entry(format!("{}{}/", EMACS_SCHEME, name))
+                        .or_insert_with(|| serde_json::Value::String(url.to_string()));
+                }
+            }
+        }
+    }
+}
+
+// Merges the import map at PATH into IMPORTS and SCOPES. Since the
+// import map we hand to Deno is built in memory, relative keys and
+// addresses are resolved against the directory of PATH first.
+fn merge_import_map(
+    path: &std::path::Path,
+    imports: &mut serde_json::Map<String, serde_json::Value>,
+    scopes: &mut serde_json::Map<String, serde_json::Value>,
+) -> Result<()> {
+    let contents = std::fs::read_to_string(path)?;
+    let map: serde_json::Value = serde_json::from_str(&contents).map_err(|e| into_ioerr(e))?;
+    let path = path.canonicalize()?;
+    let base = deno_core::url::Url::from_file_path(&path).map_err(|_| {
+        into_ioerr(format!(
+            "Invalid import map path: {}",
+            path.to_string_lossy()
+        ))
+    })?;
+
+    merge_import_map_json(&map, &base, imports, scopes);
+    Ok(())
+}
+
+// Like merge_import_map, for the import map MAP read from BASE.
+fn merge_import_map_json(
+    map: &serde_json::Value,
+    base: &deno_core::url::Url,
+    imports: &mut serde_json::Map<String, serde_json::Value>,
+    scopes: &mut serde_json::Map<String, serde_json::Value>,
+) {
+    // Keys that look like relative URLs are resolved like addresses,
+    // bare specifiers are kept as they are.
+    let resolve_key = |key: &str| {
+        if key.starts_with('/') || key.starts_with("./") || key.starts_with("../") {
+            base.join(key)
+                .map(|url| url.to_string())
+                .unwrap_or_else(|_| key.to_string())
+        } else {
+            key.to_string()
+        }
+    };
+
+    let resolve = |entries: &serde_json::Value| {
+        let mut resolved = serde_json::Map::new();
+        if let Some(entries) = entries.as_object() {
+            for (key, address) in entries {
+                if let Some(address) = address.as_str() {
+                    let address = base
+                        .join(address)
+                        .map(|url| url.to_string())
+                        .unwrap_or_else(|_| address.to_string());
+                    resolved.insert(resolve_key(key), serde_json::Value::String(address));
+                }
+            }
+        }
+        resolved
+    };
+
+    for (key, address) in resolve(&map["imports"]) {
+        imports.entry(key).or_insert(address);
+    }
+
+    if let Some(map_scopes) = map["scopes"].as_object() {
+        for (scope, entries) in map_scopes {
+            let scope = base
+                .join(scope)
+                .map(|url| url.to_string())
+                .unwrap_or_else(|_| scope.clone());
+            scopes
+                .entry(scope)
+                .or_insert_with(|| serde_json::Value::Object(resolve(entries)));
+        }
+    }
+}
+
+fn build_import_map(
+    builtin: &deno_core::ModuleSpecifier,
+    js_options: &EmacsJsOptions,
+) -> Result<serde_json::Value> {
+    let mut imports = serde_json::Map::new();
+    let mut scopes = serde_json::Map::new();
+    imports.insert(
+        EMACS_SCHEME.to_string(),
+        serde_json::Value::String(builtin.as_url().to_string()),
+    );
+
+    for dir in js_load_path() {
+        collect_emacs_imports(&dir, &mut imports);
+    }
+
+    // Earlier maps take precedence, so a buffer's import map can
+    // override the one the runtime was initialized with.
+    let maps = js_options
+        .buffer_import_map
+        .iter()
+        .chain(js_options.import_map.iter())
+        .map(std::path::PathBuf::from)
+        .chain(
+            js_options
+                .vendor_dir
+                .iter()
+                .map(|dir| std::path::Path::new(dir).join(VENDOR_IMPORT_MAP)),
+        );
+
+    for path in maps {
+        merge_import_map(&path, &mut imports, &mut scopes)?;
+    }
+
+    Ok(json!({ "imports": imports, "scopes": scopes }))
+}
+
+fn parse_import_map(
+    builtin: &deno_core::ModuleSpecifier,
+    import_map: &serde_json::Value,
+) -> Result<deno::import_map::ImportMap> {
+    deno::import_map::ImportMap::from_json(builtin.as_url().as_str(), &import_map.to_string())
+        .map_err(|e| into_ioerr(e))
+}
+
+fn insert_builtin_module(
+    program: &deno::program_state::ProgramState,
+    builtin: &deno_core::ModuleSpecifier,
+) {
+    let file = deno::file_fetcher::File {
+        local: builtin.as_url().to_file_path().unwrap(),
+        maybe_types: None,
+        media_type: deno::media_type::MediaType::TypeScript,
+        source: include_str!("emacs.ts").to_string(),
+        specifier: builtin.clone(),
+    };
+
+    program.file_fetcher.insert_cached(file);
+}
+
 /// Evaluates CODE as JavaScript on the main emacs thread.
 ///
 /// If :typescript t is passed as an argument, evaluate
@@ -1020,59 +1712,442 @@ fn is_typescript(s: &str) -> bool {
 #[cfg(feature = "javascript")]
 #[lisp_fn(min = "1")]
 pub fn eval_js(args: &[LispObject]) -> LispObject {
-    let string_obj: LispStringRef = args[0].into();
-    let ops = EmacsMainJsRuntime::get_options();
-    let name = unique_module!("./$anon$lisp${}{}.ts");
-    let string = string_obj.to_utf8();
     let is_typescript = args.len() == 3
         && args[1] == lisp::remacs_sys::QCtypescript
         && args[2] == lisp::remacs_sys::Qt;
 
-    run_module(&name, Some(string), &ops, is_typescript)
+    eval_js_from(args[0], is_typescript, None)
 }
 
-/// Evaluates JS in the global context and returns the value
-/// of the latest expression with in the statement. This is
-/// a wrapper around JavaScript's global `eval` function,
-/// and follows the same rules.
-///
-/// Using global `eval` does not support ES6 import
-/// statements or top-level await
-#[cfg(feature = "javascript")]
-#[lisp_fn(intspec = "MEval JS: ")]
-pub fn eval_js_literally(js: LispStringRef) -> LispObject {
+fn eval_js_from(js: LispObject, is_typescript: bool, origin: Option<ModuleOrigin>) -> LispObject {
+    let string_obj: LispStringRef = js.into();
     let ops = EmacsMainJsRuntime::get_options();
-    js_init_sys("init.js", &ops).unwrap_or_else(|e| {
-        error!("JS Failed to initialize with error: {}", e);
-    });
+    let name = unique_module!("./$anon$lisp${}{}.ts");
+    let mut string = string_obj.to_utf8();
+    if let Some(mut origin) = origin {
+        origin.wide_lines = wide_lines(&string);
+        EmacsMainJsRuntime::add_module_origin(module_file_name(&name).to_string(), origin);
+    }
+    if is_typescript {
+        string = with_lisp_declarations(string);
+    }
 
-    let result = execute_with_current_scope(move |scope| eval_literally_inner(scope, js))
-        .unwrap_or_else(|e| handle_error_inner_invokation(e));
-    tick_and_schedule_if_required();
-    result
+    run_module(&name, Some(string), &ops, is_typescript)
 }
 
-/// Evaluate the contents of BUFFER as JavaScript
-/// in the global context and returns the value
-/// of the latest expression with in the statement. This is
-/// a wrapper around JavaScript's global `eval` function,
-/// and follows the same rules.
-///
-/// Using global `eval` does not support ES6 import
-/// statements or top-level await
-#[cfg(feature = "javascript")]
-#[lisp_fn(min = "0", intspec = "")]
-pub fn eval_js_buffer_literally(buffer: LispObject) -> LispObject {
-    let lisp_string = get_buffer_contents(buffer);
-    eval_js_literally(lisp_string.into())
+fn module_file_name(module: &str) -> &str {
+    module.rsplit('/').next().unwrap_or(module)
 }
 
-/// Evaluate the contents of REGION as JavaScript
-/// in the global context and returns the value
-/// of the latest expression with in the statement. This is
-/// a wrapper around JavaScript's global `eval` function,
-/// and follows the same rules.
-///
+// Returns where POS is in BUFFER, ignoring any narrowing.
+fn module_origin(buffer: LispObject, pos: LispObject) -> ModuleOrigin {
+    unsafe {
+        let current = lisp::remacs_sys::Fcurrent_buffer();
+        lisp::remacs_sys::Fset_buffer(buffer);
+        let mut args = vec![
+            lisp::remacs_sys::Qline_number_at_pos,
+            pos,
+            lisp::remacs_sys::Qt,
+        ];
+        let line = Ffuncall(args.len().try_into().unwrap(), args.as_mut_ptr());
+        let saved = lisp::remacs_sys::save_restriction_save();
+        lisp::remacs_sys::Fwiden();
+        let point = lisp::remacs_sys::Fpoint();
+        lisp::remacs_sys::Fgoto_char(pos);
+        let bol = lisp::remacs_sys::Fline_beginning_position(lisp::remacs_sys::Qnil);
+        lisp::remacs_sys::Fgoto_char(point);
+        lisp::remacs_sys::save_restriction_restore(saved);
+        let buffer_name: LispStringRef = lisp::remacs_sys::Fbuffer_name(buffer).into();
+        let file_name = lisp::remacs_sys::Fbuffer_file_name(buffer);
+        lisp::remacs_sys::Fset_buffer(current);
+
+        ModuleOrigin {
+            buffer_name: buffer_name.to_utf8(),
+            file_name: file_name.as_string().map(|file_name| file_name.to_utf8()),
+            line: line.as_fixnum_or_error() as i64,
+            column: (pos.as_fixnum_or_error() - bol.as_fixnum_or_error()) as i64,
+            wide_lines: vec![],
+        }
+    }
+}
+
+// Returns the lines of SOURCE that have characters outside the BMP,
+// with their 0-based indices.
+fn wide_lines(source: &str) -> Vec<(i64, String)> {
+    source
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.chars().any(|c| c.len_utf16() > 1))
+        .map(|(index, line)| (index as i64, line.to_string()))
+        .collect()
+}
+
+// Turns a 1-based COLUMN counted in UTF-16 code units, as V8 reports
+// it, into a 1-based column counted in characters of TEXT.
+fn utf16_column_to_chars(text: &str, column: i64) -> i64 {
+    if column < 1 {
+        return column;
+    }
+
+    let mut units = 0;
+    let mut chars = 0;
+    for c in text.chars() {
+        if units >= column - 1 {
+            break;
+        }
+
+        units += c.len_utf16() as i64;
+        chars += 1;
+    }
+
+    chars + (column - 1 - units).max(0) + 1
+}
+
+// Replaces every "URL:LINE:COLUMN" of an anonymous module in TEXT
+// with the location in the buffer the module was evaluated from.
+fn remap_module_locations(text: &str) -> String {
+    let mut text = text.to_string();
+    for (module, origin) in EmacsMainJsRuntime::get_module_origins() {
+        let mut result = String::new();
+        let mut rest = text.as_str();
+        while let Some(idx) = rest.find(&module) {
+            let url_start = rest[..idx]
+                .rfind(|c: char| c.is_whitespace() || c == '(' || c == '"' || c == '\'')
+                .map(|i| i + 1)
+                .unwrap_or(0);
+            let after = &rest[idx + module.len()..];
+            let mut numbers = after.splitn(3, ':').skip(1).map(|n| {
+                let digits: String = n.chars().take_while(|c| c.is_ascii_digit()).collect();
+                digits
+            });
+
+            match (numbers.next(), numbers.next()) {
+                (Some(line), Some(column))
+                    if after.starts_with(':') && !line.is_empty() && !column.is_empty() =>
+                {
+                    let consumed = 2 + line.len() + column.len();
+                    result.push_str(&rest[..url_start]);
+                    result.push_str(
+                        &origin.location(line.parse().unwrap_or(1), column.parse().unwrap_or(0)),
+                    );
+                    rest = &after[consumed..];
+                }
+                _ => {
+                    result.push_str(&rest[..idx + module.len()]);
+                    rest = after;
+                }
+            }
+        }
+
+        result.push_str(rest);
+        text = result;
+    }
+
+    text
+}
+
+fn find_module_origin(file_name: &str) -> Option<ModuleOrigin> {
+    let module = module_file_name(file_name);
+    EmacsMainJsRuntime::get_module_origins()
+        .into_iter()
+        .rev()
+        .find(|(name, _)| name == module)
+        .map(|(_, origin)| origin)
+}
+
+// Called from the Error.prepareStackTrace installed by prelim.js, so
+// that stack traces seen from JS point at buffers too.
+pub fn lisp_remap_locations(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut retval: v8::ReturnValue,
+) {
+    let text = args.get(0).to_rust_string_lossy(scope);
+    let remapped = remap_module_locations(&text);
+    let r = v8::Local::<v8::Value>::try_from(v8::String::new(scope, &remapped).unwrap()).unwrap();
+    retval.set(r);
+}
+
+fn throw_buffer_error(scope: &mut v8::HandleScope, message: &str, range: bool) {
+    let message = v8::String::new(scope, message).unwrap();
+    let exception = if range {
+        v8::Exception::range_error(scope, message)
+    } else {
+        v8::Exception::type_error(scope, message)
+    };
+    scope.throw_exception(exception);
+}
+
+// The buffer argument of the emacs.buffer functions: a buffer proxy,
+// the name of a buffer, or undefined for the current buffer.
+fn js_buffer_arg(scope: &mut v8::HandleScope, arg: v8::Local<v8::Value>) -> Option<LispObject> {
+    let buffer = if arg.is_null_or_undefined() {
+        unsafe { lisp::remacs_sys::Fcurrent_buffer() }
+    } else if arg.is_string() {
+        let name = arg.to_rust_string_lossy(scope);
+        unsafe { lisp::remacs_sys::Fget_buffer(make_lisp_string(&name)) }
+    } else if arg.is_object() && arg.to_object(scope).unwrap().internal_field_count() > 0 {
+        unproxy!(scope, arg.to_object(scope).unwrap())
+    } else {
+        lisp::remacs_sys::Qnil
+    };
+
+    if unsafe { lisp::remacs_sys::Fbuffer_live_p(buffer) }.is_nil() {
+        throw_buffer_error(scope, "Expected a live buffer or buffer name", false);
+        return None;
+    }
+
+    Some(buffer)
+}
+
+fn buffer_ptr(buffer: LispObject) -> *mut lisp::remacs_sys::buffer {
+    buffer.get_untaggedptr() as *mut lisp::remacs_sys::buffer
+}
+
+// The accessible portion of BUFFER, like BUF_BEGV and BUF_ZV. The
+// fields of a buffer that isn't current are kept in markers if it
+// has them, as indirect buffers and their bases do.
+fn buffer_bounds(buffer: LispObject) -> (isize, isize) {
+    let b = buffer_ptr(buffer);
+    unsafe {
+        if buffer == lisp::remacs_sys::Fcurrent_buffer() || (*b).begv_marker_.is_nil() {
+            ((*b).begv, (*b).zv)
+        } else {
+            (
+                lisp::remacs_sys::marker_position((*b).begv_marker_),
+                lisp::remacs_sys::marker_position((*b).zv_marker_),
+            )
+        }
+    }
+}
+
+// Reads START and END from ARGS, defaulting to the accessible portion of
+// BUFFER. Like Emacs, the positions may be given in either order.
+fn js_buffer_range(
+    scope: &mut v8::HandleScope,
+    args: &v8::FunctionCallbackArguments,
+    index: i32,
+    buffer: LispObject,
+) -> Option<(isize, isize)> {
+    let (begv, zv) = buffer_bounds(buffer);
+    let mut position = |i: i32, default: isize| {
+        let arg = args.get(i);
+        if arg.is_null_or_undefined() {
+            Some(default)
+        } else if arg.is_number() {
+            arg.integer_value(scope).map(|n| n as isize)
+        } else {
+            None
+        }
+    };
+
+    let (start, end) = match (position(index, begv), position(index + 1, zv)) {
+        (Some(start), Some(end)) => (start.min(end), start.max(end)),
+        _ => {
+            throw_buffer_error(scope, "Buffer positions must be integers", false);
+            return None;
+        }
+    };
+
+    if start < begv || end > zv {
+        let message = format!("Args out of range: {}, {}", start, end);
+        throw_buffer_error(scope, &message, true);
+        return None;
+    }
+
+    Some((start, end))
+}
+
+// Copies the text between the character positions START and END out of
+// the gap buffer of B, in Emacs' internal encoding.
+unsafe fn buffer_bytes(b: *mut lisp::remacs_sys::buffer, start: isize, end: isize) -> Vec<u8> {
+    let from = lisp::remacs_sys::buf_charpos_to_bytepos(b, start);
+    let to = lisp::remacs_sys::buf_charpos_to_bytepos(b, end);
+    let text = &*(*b).text;
+    let mut bytes = Vec::with_capacity((to - from) as usize);
+
+    // Byte positions start at 1, and text after the gap is offset by its size.
+    let before_gap = to.min(text.gpt_byte);
+    if from < before_gap {
+        let ptr = text.beg.offset(from - 1);
+        bytes.extend_from_slice(std::slice::from_raw_parts(
+            ptr,
+            (before_gap - from) as usize,
+        ));
+    }
+
+    let after_gap = from.max(text.gpt_byte);
+    if after_gap < to {
+        let ptr = text.beg.offset(after_gap - 1 + text.gap_size);
+        bytes.extend_from_slice(std::slice::from_raw_parts(ptr, (to - after_gap) as usize));
+    }
+
+    bytes
+}
+
+// Backs emacs.buffer.text and emacs.buffer.bytes. The text is copied once,
+// straight from the gap buffer, without creating a Lisp string or going
+// through JSON. Later changes to the buffer are not reflected in it.
+pub fn lisp_buffer_text(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut retval: v8::ReturnValue,
+) {
+    let buffer = match js_buffer_arg(scope, args.get(0)) {
+        Some(buffer) => buffer,
+        None => return,
+    };
+    let (start, end) = match js_buffer_range(scope, &args, 1, buffer) {
+        Some(range) => range,
+        None => return,
+    };
+
+    let b = buffer_ptr(buffer);
+    let bytes = unsafe { buffer_bytes(b, start, end) };
+    if args.get(3).boolean_value(scope) {
+        let len = bytes.len();
+        let store = v8::ArrayBuffer::new_backing_store_from_boxed_slice(bytes.into_boxed_slice())
+            .make_shared();
+        let array_buffer = v8::ArrayBuffer::with_backing_store(scope, &store);
+        let array = v8::Uint8Array::new(scope, array_buffer, 0, len).unwrap();
+        retval.set(array.into());
+        return;
+    }
+
+    let multibyte = unsafe { (*b).enable_multibyte_characters_ }.is_not_nil();
+    let text = if multibyte {
+        String::from_utf8_lossy(&bytes).into_owned()
+    } else {
+        bytes.iter().map(|&byte| byte as char).collect()
+    };
+
+    let r = v8::Local::<v8::Value>::try_from(v8::String::new(scope, &text).unwrap()).unwrap();
+    retval.set(r);
+}
+
+// Backs emacs.buffer.bounds, returning [point-min, point-max].
+pub fn lisp_buffer_bounds(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut retval: v8::ReturnValue,
+) {
+    let buffer = match js_buffer_arg(scope, args.get(0)) {
+        Some(buffer) => buffer,
+        None => return,
+    };
+
+    let (begv, zv) = buffer_bounds(buffer);
+    let begv = v8::Number::new(scope, begv as f64);
+    let zv = v8::Number::new(scope, zv as f64);
+    let array = v8::Array::new_with_elements(scope, &[begv.into(), zv.into()]);
+    retval.set(array.into());
+}
+
+unsafe extern "C" fn replace_springboard(arg1: *mut ::libc::c_void) -> LispObject {
+    let (start, end, text): (isize, isize, LispObject) =
+        *Box::from_raw(arg1 as *mut (isize, isize, LispObject));
+    lisp::remacs_sys::replace_range(start, end, text, true, false, true, false);
+    lisp::remacs_sys::Qnil
+}
+
+// Backs emacs.buffer.insert, delete and replace, which all replace the
+// text between START and END with TEXT. This runs the same hooks and
+// records the same undo information as the equivalent Lisp. Returns the
+// position after the new text.
+pub fn lisp_buffer_replace(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut retval: v8::ReturnValue,
+) {
+    let buffer = match js_buffer_arg(scope, args.get(0)) {
+        Some(buffer) => buffer,
+        None => return,
+    };
+    let (start, end) = match js_buffer_range(scope, &args, 1, buffer) {
+        Some(range) => range,
+        None => return,
+    };
+
+    let string = args.get(3).to_rust_string_lossy(scope);
+    let text = unsafe {
+        lisp::remacs_sys::make_string_from_utf8(
+            string.as_ptr() as *const ::libc::c_char,
+            string.len().try_into().unwrap(),
+        )
+    };
+    let chars = unsafe { lisp::remacs_sys::Flength(text) };
+
+    let boxed = Box::new((start, end, text));
+    let result = unsafe {
+        let current = lisp::remacs_sys::Fcurrent_buffer();
+        lisp::remacs_sys::Fset_buffer(buffer);
+        let result = lisp::remacs_sys::internal_catch_all(
+            Some(replace_springboard),
+            Box::into_raw(boxed) as *mut ::libc::c_void,
+            Some(lisp_handler),
+        );
+        lisp::remacs_sys::Fset_buffer(current);
+        result
+    };
+
+    if let Some(cons) = result.as_cons() {
+        if cons.car() == lisp::remacs_sys::Qjs_lisp_error {
+            let message = unsafe { lisp::remacs_sys::Ferror_message_string(cons.cdr()) };
+            let message: LispStringRef = message.into();
+            let error = v8::String::new(scope, &message.to_utf8()).unwrap();
+            let exception = v8::Exception::error(scope, error);
+            scope.throw_exception(exception);
+            return;
+        }
+    }
+
+    let position = start + chars.as_fixnum().unwrap_or(0) as isize;
+    let r = v8::Number::new(scope, position as f64);
+    retval.set(r.into());
+}
+
+/// Evaluates JS in the global context and returns the value
+/// of the latest expression with in the statement. This is
+/// a wrapper around JavaScript's global `eval` function,
+/// and follows the same rules.
+///
+/// Using global `eval` does not support ES6 import
+/// statements or top-level await
+#[cfg(feature = "javascript")]
+#[lisp_fn(intspec = "MEval JS: ")]
+pub fn eval_js_literally(js: LispStringRef) -> LispObject {
+    let ops = EmacsMainJsRuntime::get_options();
+    js_init_sys("init.js", &ops).unwrap_or_else(|e| {
+        error!("JS Failed to initialize with error: {}", e);
+    });
+
+    let result = execute_with_current_scope(move |scope| eval_literally_inner(scope, js))
+        .unwrap_or_else(|e| handle_error_inner_invokation(e));
+    tick_and_schedule_if_required();
+    result
+}
+
+/// Evaluate the contents of BUFFER as JavaScript
+/// in the global context and returns the value
+/// of the latest expression with in the statement. This is
+/// a wrapper around JavaScript's global `eval` function,
+/// and follows the same rules.
+///
+/// Using global `eval` does not support ES6 import
+/// statements or top-level await
+#[cfg(feature = "javascript")]
+#[lisp_fn(min = "0", intspec = "")]
+pub fn eval_js_buffer_literally(buffer: LispObject) -> LispObject {
+    let lisp_string = get_buffer_contents(buffer);
+    eval_js_literally(lisp_string.into())
+}
+
+/// Evaluate the contents of REGION as JavaScript
+/// in the global context and returns the value
+/// of the latest expression with in the statement. This is
+/// a wrapper around JavaScript's global `eval` function,
+/// and follows the same rules.
+///
 /// Using global `eval` does not support ES6 import
 /// statements or top-level await
 #[cfg(feature = "javascript")]
@@ -1162,22 +2237,80 @@ pub fn eval_js_file(args: &[LispObject]) -> LispObject {
     run_module(&module, Some(import), &ops, is_typescript)
 }
 
-fn get_buffer_contents(mut buffer: LispObject) -> LispObject {
+// Deno resolves imports with a single import map per runtime, which is
+// fixed when the runtime is created. If BUFFER sets 'js-import-map', the
+// runtime is created with it, and if the runtime already uses a different
+// one we signal an error rather than tearing it down behind the user's
+// back. Buffers without an import map of their own use whatever runtime
+// there is.
+// Returns the absolute path of BUFFER's 'js-import-map', if it has one.
+fn buffer_import_map(buffer: LispObject) -> Option<String> {
+    unsafe {
+        let value = lisp::remacs_sys::Fbuffer_local_value(lisp::remacs_sys::Qjs_import_map, buffer);
+        if value.is_nil() {
+            None
+        } else {
+            let directory =
+                lisp::remacs_sys::Fbuffer_local_value(lisp::remacs_sys::Qdefault_directory, buffer);
+            let expanded: LispStringRef =
+                lisp::remacs_sys::Fexpand_file_name(value, directory).into();
+            Some(expanded.to_utf8())
+        }
+    }
+}
+
+fn use_buffer_import_map(mut buffer: LispObject) {
     if buffer.is_nil() {
         buffer = unsafe { lisp::remacs_sys::Fcurrent_buffer() };
     }
 
-    unsafe {
+    let import_map = buffer_import_map(buffer);
+    let mut options = EmacsMainJsRuntime::get_options();
+    if options.buffer_import_map == import_map {
+        return;
+    }
+
+    if !EmacsMainJsRuntime::is_main_worker_active() {
+        options.buffer_import_map = import_map;
+        EmacsMainJsRuntime::set_options(options);
+    } else if let Some(import_map) = import_map {
+        let name: LispStringRef = unsafe { lisp::remacs_sys::Fbuffer_name(buffer) }.into();
+        error!(
+            "{} uses the import map {}, but the JavaScript environment was initialized with {}; call `js-cleanup' to reinitialize it",
+            name.to_utf8(),
+            import_map,
+            options
+                .buffer_import_map
+                .or(options.import_map)
+                .unwrap_or_else(|| "none".to_string())
+        );
+    }
+}
+
+fn get_buffer_contents(mut buffer: LispObject) -> (LispObject, ModuleOrigin) {
+    if buffer.is_nil() {
+        buffer = unsafe { lisp::remacs_sys::Fcurrent_buffer() };
+    }
+
+    let (lstring, start) = unsafe {
         let current = lisp::remacs_sys::Fcurrent_buffer();
         lisp::remacs_sys::Fset_buffer(buffer);
         let lstring = lisp::remacs_sys::Fbuffer_string();
+        let start = lisp::remacs_sys::Fpoint_min();
         lisp::remacs_sys::Fset_buffer(current);
-        lstring
-    }
+        (lstring, start)
+    };
+
+    (lstring, module_origin(buffer, start))
 }
 
 /// Evaluate the contents of BUFFER as JavaScript.
 ///
+/// If BUFFER has a local value for 'js-import-map', that import map
+/// takes precedence over the one passed to 'js-initialize'. If the
+/// JavaScript environment is already running with a different import
+/// map, an error is signaled; call 'js-cleanup' to reinitialize it.
+///
 /// If the evaluated JavaScript generates a top-level
 /// Promise rejection, the JavaScript environment will be
 /// reset and reinitalized lazily. If that happens, all
@@ -1186,12 +2319,18 @@ fn get_buffer_contents(mut buffer: LispObject) -> LispObject {
 #[cfg(feature = "javascript")]
 #[lisp_fn(min = "0", intspec = "")]
 pub fn eval_js_buffer(buffer: LispObject) -> LispObject {
-    let lisp_string = get_buffer_contents(buffer);
-    eval_js(&[lisp_string])
+    use_buffer_import_map(buffer);
+    let (lisp_string, origin) = get_buffer_contents(buffer);
+    eval_js_from(lisp_string, false, Some(origin))
 }
 
 /// Evaluate the contents of BUFFER as TypeScript.
 ///
+/// If BUFFER has a local value for 'js-import-map', that import map
+/// takes precedence over the one passed to 'js-initialize'. If the
+/// JavaScript environment is already running with a different import
+/// map, an error is signaled; call 'js-cleanup' to reinitialize it.
+///
 /// If the evaluated JavaScript generates a top-level
 /// Promise rejection, the JavaScript environment will be
 /// reset and reinitalized lazily. If that happens, all
@@ -1200,12 +2339,25 @@ pub fn eval_js_buffer(buffer: LispObject) -> LispObject {
 #[cfg(feature = "javascript")]
 #[lisp_fn(min = "0", intspec = "")]
 pub fn eval_ts_buffer(buffer: LispObject) -> LispObject {
-    let lisp_string = get_buffer_contents(buffer);
-    eval_js(&[
-        lisp_string,
-        lisp::remacs_sys::QCtypescript,
-        lisp::remacs_sys::Qt,
-    ])
+    use_buffer_import_map(buffer);
+    let (lisp_string, origin) = get_buffer_contents(buffer);
+    eval_js_from(lisp_string, true, Some(origin))
+}
+
+// START and END may be markers, and in either order.
+fn region_start(start: LispObject, end: LispObject) -> LispObject {
+    unsafe {
+        if lisp::remacs_sys::arithcompare(
+            start,
+            end,
+            lisp::remacs_sys::Arith_Comparison::ARITH_LESS_OR_EQUAL,
+        ) == lisp::remacs_sys::Qt
+        {
+            start
+        } else {
+            end
+        }
+    }
 }
 
 fn get_region(start: LispObject, end: LispObject) -> LispObject {
@@ -1229,7 +2381,11 @@ fn get_region(start: LispObject, end: LispObject) -> LispObject {
 #[lisp_fn(intspec = "r")]
 pub fn eval_js_region(start: LispObject, end: LispObject) -> LispObject {
     let lisp_string = get_region(start, end);
-    eval_js(&[lisp_string])
+    let origin = module_origin(
+        unsafe { lisp::remacs_sys::Fcurrent_buffer() },
+        region_start(start, end),
+    );
+    eval_js_from(lisp_string, false, Some(origin))
 }
 
 /// Evaluate the contents of REGION as TypeScript.
@@ -1243,11 +2399,335 @@ pub fn eval_js_region(start: LispObject, end: LispObject) -> LispObject {
 #[lisp_fn(intspec = "r")]
 pub fn eval_ts_region(start: LispObject, end: LispObject) -> LispObject {
     let lisp_string = get_region(start, end);
-    eval_js(&[
-        lisp_string,
-        lisp::remacs_sys::QCtypescript,
-        lisp::remacs_sys::Qt,
-    ])
+    let origin = module_origin(
+        unsafe { lisp::remacs_sys::Fcurrent_buffer() },
+        region_start(start, end),
+    );
+    eval_js_from(lisp_string, true, Some(origin))
+}
+
+fn diagnostic_message(diagnostic: &deno::diagnostics::Diagnostic) -> String {
+    fn flatten(chain: &deno::diagnostics::DiagnosticMessageChain, depth: usize, out: &mut String) {
+        if depth > 0 {
+            out.push('\n');
+            out.push_str(&"  ".repeat(depth));
+        }
+        out.push_str(&chain.message_text);
+        for next in chain.next.iter().flatten() {
+            flatten(next, depth + 1, out);
+        }
+    }
+
+    match (&diagnostic.message_text, &diagnostic.message_chain) {
+        (Some(text), _) => text.clone(),
+        (None, Some(chain)) => {
+            let mut message = String::new();
+            flatten(chain, 0, &mut message);
+            message
+        }
+        (None, None) => String::new(),
+    }
+}
+
+// TypeScript positions are a 0-based line, and a 0-based column counted
+// in UTF-16 code units. LINE_STARTS holds the character offset of every
+// line in the checked source.
+fn diagnostic_offset(
+    source: &[char],
+    line_starts: &[usize],
+    position: &deno::diagnostics::Position,
+) -> usize {
+    let line = std::cmp::min(position.line as usize, line_starts.len() - 1);
+    let start = line_starts[line];
+    let mut units = 0;
+    let mut offset = start;
+    while offset < source.len() && source[offset] != '\n' && units < position.character as usize {
+        units += source[offset].len_utf16();
+        offset += 1;
+    }
+
+    offset
+}
+
+// Type-checked buffers are all checked as this module, each in a
+// program state of its own, so that nothing is left in a cache.
+const TYPECHECK_MODULE: &str = "./$check$lisp.ts";
+
+// A diagnostic of a type-checked buffer, with BEG and END as buffer
+// positions.
+struct BufferDiagnostic {
+    beg: EmacsInt,
+    end: EmacsInt,
+    category: deno::diagnostics::DiagnosticCategory,
+    code: EmacsInt,
+    message: String,
+}
+
+// Everything needed to type-check a buffer away from the lisp thread.
+struct TypecheckRequest {
+    source: String,
+    start: EmacsInt,
+    import_map: serde_json::Value,
+    ts_config: Option<String>,
+    no_remote: bool,
+}
+
+impl TypecheckRequest {
+    fn new(buffer: LispObject) -> Self {
+        let (lisp_string, _) = get_buffer_contents(buffer);
+        let start = unsafe {
+            let current = lisp::remacs_sys::Fcurrent_buffer();
+            lisp::remacs_sys::Fset_buffer(buffer);
+            let start = lisp::remacs_sys::Fpoint_min();
+            lisp::remacs_sys::Fset_buffer(current);
+            start.as_fixnum_or_error()
+        };
+
+        // The buffer's import map is only used for this check, the
+        // JavaScript environment is left alone.
+        let mut options = EmacsMainJsRuntime::get_options();
+        options.buffer_import_map = buffer_import_map(buffer);
+        let import_map = deno_core::ModuleSpecifier::resolve_url_or_path(EMACS_BUILTIN_MODULE)
+            .map_err(|e| into_ioerr(e))
+            .and_then(|builtin| build_import_map(&builtin, &options))
+            .unwrap_or_else(|e| error!("Failed to type-check buffer: {}", e));
+
+        let string: LispStringRef = lisp_string.into();
+        Self {
+            source: string.to_utf8(),
+            start,
+            import_map,
+            ts_config: options.ts_config.clone(),
+            no_remote: options.no_remote || options.vendor_dir.is_some(),
+        }
+    }
+
+    // Runs on a thread of its own, as the type-checker runs its own V8
+    // isolate and can take seconds.
+    fn check(self, checked_source: String) -> Result<Vec<BufferDiagnostic>> {
+        set_v8_flags();
+        let flags = deno::flags::Flags {
+            unstable: true,
+            no_remote: self.no_remote,
+            config_path: self.ts_config.clone(),
+            ..Default::default()
+        };
+
+        let builtin = deno_core::ModuleSpecifier::resolve_url_or_path(EMACS_BUILTIN_MODULE)
+            .map_err(|e| into_ioerr(e))?;
+        let mut program =
+            deno::program_state::ProgramState::new(flags).map_err(|e| into_ioerr(e))?;
+        Arc::get_mut(&mut program)
+            .ok_or_else(|| into_ioerr("The program state is already in use"))?
+            .maybe_import_map = Some(parse_import_map(&builtin, &self.import_map)?);
+        insert_builtin_module(&program, &builtin);
+
+        let specifier = deno_core::ModuleSpecifier::resolve_url_or_path(TYPECHECK_MODULE)
+            .map_err(|e| into_ioerr(e))?;
+        program
+            .file_fetcher
+            .insert_cached(deno::file_fetcher::File {
+                local: specifier.as_url().to_file_path().unwrap(),
+                maybe_types: None,
+                media_type: deno::media_type::MediaType::TypeScript,
+                source: checked_source,
+                specifier: specifier.clone(),
+            });
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .enable_time()
+            .build()?;
+        let diagnostics = runtime.block_on(async {
+            let handler = Arc::new(Mutex::new(
+                deno::specifier_handler::FetchHandler::new(
+                    &program,
+                    deno_runtime::permissions::Permissions::allow_all(),
+                )
+                .map_err(|e| into_ioerr(e))?,
+            ));
+            let mut builder = deno::module_graph::GraphBuilder::new(
+                handler,
+                program.maybe_import_map.clone(),
+                program.lockfile.clone(),
+            );
+            builder
+                .add(&specifier, false)
+                .await
+                .map_err(|e| into_ioerr(e))?;
+            let result = builder
+                .get_graph()
+                .check(deno::module_graph::CheckOptions {
+                    debug: false,
+                    emit: false,
+                    lib: deno::module_graph::TypeLib::UnstableDenoWindow,
+                    maybe_config_path: self.ts_config.clone(),
+                    reload: false,
+                })
+                .map_err(|e| into_ioerr(e))?;
+            Ok::<_, std::io::Error>(result.diagnostics.0)
+        })?;
+
+        let chars: Vec<char> = self.source.chars().collect();
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(
+                chars
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| **c == '\n')
+                    .map(|(i, _)| i + 1),
+            )
+            .collect();
+        let module = module_file_name(TYPECHECK_MODULE);
+
+        Ok(diagnostics
+            .iter()
+            .filter(|d| {
+                d.file_name
+                    .as_ref()
+                    .map(|file_name| module_file_name(file_name) == module)
+                    .unwrap_or(false)
+            })
+            .map(|d| {
+                let beg = d
+                    .start
+                    .as_ref()
+                    .map(|p| diagnostic_offset(&chars, &line_starts, p))
+                    .unwrap_or(0);
+                let end = d
+                    .end
+                    .as_ref()
+                    .map(|p| diagnostic_offset(&chars, &line_starts, p))
+                    .unwrap_or(beg);
+                BufferDiagnostic {
+                    beg: self.start + beg as EmacsInt,
+                    end: self.start + end as EmacsInt,
+                    category: d.category.clone(),
+                    code: d.code as EmacsInt,
+                    message: diagnostic_message(d),
+                }
+            })
+            .collect())
+    }
+}
+
+fn buffer_diagnostics_to_lisp(diagnostics: &[BufferDiagnostic]) -> LispObject {
+    diagnostics
+        .iter()
+        .rev()
+        .fold(lisp::remacs_sys::Qnil, |rest, d| {
+            let severity = match d.category {
+                deno::diagnostics::DiagnosticCategory::Error => lisp::remacs_sys::QCerror,
+                deno::diagnostics::DiagnosticCategory::Warning => lisp::remacs_sys::QCwarning,
+                _ => lisp::remacs_sys::QCnote,
+            };
+
+            let diagnostic = list!(
+                LispObject::from_fixnum(d.beg),
+                LispObject::from_fixnum(d.end),
+                severity,
+                LispObject::from_fixnum(d.code),
+                make_lisp_string(&d.message)
+            );
+            LispObject::cons(diagnostic, rest)
+        })
+}
+
+/// Type-checks the contents of BUFFER as TypeScript without evaluating
+/// it, and returns a list of diagnostics. BUFFER defaults to the current
+/// buffer. Each diagnostic is a list (BEG END SEVERITY CODE MESSAGE),
+/// where BEG and END are buffer positions, SEVERITY is one of :error,
+/// :warning or :note, and CODE is the TypeScript error code. Only
+/// diagnostics within BUFFER are returned, errors in imported modules are
+/// not.
+///
+/// If CALLBACK is non-nil, BUFFER is checked in the background and
+/// this returns nil right away. CALLBACK is later called with the list
+/// of diagnostics, or with a string describing why the check failed.
+/// This is suitable for use as a flymake backend:
+///
+/// (defun my-ts-flymake (report-fn &rest _)
+///   (let ((buffer (current-buffer)))
+///     (js-typecheck-buffer
+///      buffer
+///      (lambda (diagnostics)
+///        (if (stringp diagnostics)
+///            (funcall report-fn :panic :explanation diagnostics)
+///          (funcall report-fn
+///                   (mapcar (lambda (d)
+///                             (pcase-let ((`(,beg ,end ,type ,_ ,text) d))
+///                               (flymake-make-diagnostic buffer beg end type text)))
+///                           diagnostics)))))))
+///
+/// Type-checking uses the :ts-config passed to 'js-initialize' and
+/// BUFFER's 'js-import-map', and ignores :no-check. It does not need
+/// or change the JavaScript environment.
+#[cfg(feature = "javascript")]
+#[lisp_fn(min = "0")]
+pub fn js_typecheck_buffer(buffer: LispObject, callback: LispObject) -> LispObject {
+    let buffer = if buffer.is_nil() {
+        unsafe { lisp::remacs_sys::Fcurrent_buffer() }
+    } else {
+        buffer
+    };
+    let request = TypecheckRequest::new(buffer);
+    let checked_source = with_lisp_declarations(request.source.clone());
+
+    if callback.is_nil() {
+        let diagnostics = std::thread::spawn(move || request.check(checked_source))
+            .join()
+            .unwrap_or_else(|_| Err(into_ioerr("The type-checker panicked")))
+            .unwrap_or_else(|e| error!("Failed to type-check buffer: {}", e));
+        return buffer_diagnostics_to_lisp(&diagnostics);
+    }
+
+    let (mut pipe, proc) = EmacsPipe::with_handler(
+        lisp::remacs_sys::Qjs__typecheck_done,
+        PipeDataOption::USER_DATA,
+        PipeDataOption::USER_DATA,
+    );
+    unsafe {
+        lisp::remacs_sys::Fset_process_query_on_exit_flag(proc, lisp::remacs_sys::Qnil);
+        let plist = lisp::remacs_sys::Fprocess_plist(proc);
+        lisp::remacs_sys::Fset_process_plist(
+            proc,
+            lisp::remacs_sys::Fplist_put(plist, lisp::remacs_sys::QCcallback, callback),
+        );
+    }
+
+    let sender = pipe.get_sender();
+    std::thread::spawn(move || {
+        let result = request.check(checked_source).map_err(|e| e.to_string());
+        // The pipe is the only way back to lisp. If it is gone, so is
+        // anyone waiting for the results.
+        let _ = pipe.message_lisp(&sender, UserData::new(result));
+    });
+
+    lisp::remacs_sys::Qnil
+}
+
+// The handler of the pipe an asynchronous 'js-typecheck-buffer'
+// reports through. Calls the callback passed to it with the result.
+#[cfg(feature = "javascript")]
+#[lisp_fn]
+pub fn js__typecheck_done(proc: LispObject, data: LispObject) -> LispObject {
+    let result: std::result::Result<Vec<BufferDiagnostic>, String> =
+        unsafe { to_owned_userdata(data).unpack() };
+    let callback = unsafe {
+        let callback = lisp::remacs_sys::Fplist_get(
+            lisp::remacs_sys::Fprocess_plist(proc),
+            lisp::remacs_sys::QCcallback,
+        );
+        lisp::remacs_sys::Fdelete_process(proc);
+        callback
+    };
+
+    let value = match result {
+        Ok(diagnostics) => buffer_diagnostics_to_lisp(&diagnostics),
+        Err(e) => make_lisp_string(&format!("Failed to type-check buffer: {}", e)),
+    };
+    let mut args = vec![callback, value];
+    unsafe { Ffuncall(args.len().try_into().unwrap(), args.as_mut_ptr()) }
 }
 
 /// Initalizes the JavaScript runtime. If this function is not
@@ -1279,7 +2759,10 @@ pub fn eval_ts_region(start: LispObject, end: LispObject) -> LispObject {
 /// prior to their code being executed.
 ///
 /// :js-error-handler 'function - A function to call if a JS error occures, including
-/// TypeScript compile errors. If not specified, will default to (error ...)
+/// TypeScript compile errors. It is called with the error message, and if it
+/// accepts a second argument, the error in the same form as 'condition-case'
+/// would bind it, see 'js-error'. If not specified, the 'js-error' condition
+/// is signaled.
 ///
 /// :ts-config PATH - Specifies the file path to your custom tsconfig json file
 /// see https://www.typescriptlang.org/docs/handbook/tsconfig-json.html
@@ -1290,12 +2773,30 @@ pub fn eval_ts_region(start: LispObject, end: LispObject) -> LispObject {
 /// :no-remote t - disables the import of remote files via import statements.
 /// This option still allows network options via calls to fetch(...)
 ///
+/// :import-map PATH - Specifies the file path to an import map, used to
+/// resolve bare specifiers such as "lodash" to local or remote modules
+/// see https://deno.land/manual/linking_to_external_code/import_maps
+///
+/// :vendor-dir PATH - Loads remote modules from the vendor directory PATH,
+/// populated by 'js-vendor-module', using its import map. Implies :no-remote.
+///
+/// Modules can be imported from the directories in 'js-load-path' and
+/// 'load-path' using the "emacs:" scheme, i.e. "emacs:foo" resolves to
+/// foo.ts, foo.js, or foo/mod.ts within those directories. "emacs:" on
+/// its own is the built-in module of typed helpers for the 'lisp' object.
+/// These paths are read when the runtime is initialized.
+///
 /// The following flags will be changed upon a call to 'js-initialize',
 /// even if the JS environment has already been initialized.
 ///
-/// :js-tick-rate - Defaults to 0.25. This is the interval that js
-/// will evaluate if there are any resolved pending async operations
-/// and execute callbacks.
+/// :js-event-driven - Defaults to t. When non-nil, the JS event loop
+/// notifies Emacs through a pipe whenever async operations or timers
+/// are ready, and callbacks are executed immediately. When nil, the
+/// event loop is instead polled on a timer every :js-tick-rate seconds.
+///
+/// :js-tick-rate - Defaults to 0.25. When :js-event-driven is nil, this
+/// is the interval that js will evaluate if there are any resolved pending
+/// async operations and execute callbacks.
 #[cfg(feature = "javascript")]
 #[lisp_fn]
 pub fn js_initialize(args: &[LispObject]) -> LispObject {
@@ -1311,6 +2812,10 @@ pub fn js_initialize(args: &[LispObject]) -> LispObject {
 fn js_init_sys(filename: &str, js_options: &EmacsJsOptions) -> Result<()> {
     init_tokio()?;
     init_worker(filename, js_options)?;
+    if js_options.event_driven {
+        EmacsMainJsRuntime::init_event_loop_waker();
+    }
+
     Ok(())
 }
 
@@ -1433,6 +2938,7 @@ fn tick_and_schedule_if_required() {
 }
 
 fn handle_error_inner_invokation(e: std::io::Error) -> LispObject {
+    signal_quit_if_requested();
     if !EmacsMainJsRuntime::is_within_runtime() {
         let js_options = EmacsMainJsRuntime::get_options();
         handle_error(e, js_options.error_handler)
@@ -1442,7 +2948,7 @@ fn handle_error_inner_invokation(e: std::io::Error) -> LispObject {
         // above us that called back into JS. If we were to just call the error handler,
         // we would be returning the error handlers value back UP the stack, which would
         // lead to undesirable behavior.
-        error!("{}", e.to_string())
+        lisp::eval::signal_rust(lisp::remacs_sys::Qjs_error, js_error_data(&e))
     }
 }
 
@@ -1480,26 +2986,289 @@ fn execute_with_current_scope<F, R: Sized>(f: F) -> R
 where
     F: Fn(&mut v8::HandleScope) -> R,
 {
+    if QUIT_WATCHDOG.is_checking() {
+        error!("JavaScript cannot be called while checking for quit");
+    }
+
     let result;
     if !EmacsMainJsRuntime::is_within_runtime() {
         result = block_on(async move {
-            let mut worker_handle = EmacsMainJsRuntime::get_deno_worker();
+            let mut worker_handle = EmacsMainJsRuntime::get_deno_worker()?;
             let worker = worker_handle.as_mut_ref();
             let runtime = &mut worker.js_runtime;
             let context = runtime.global_context();
             let scope = &mut v8::HandleScope::with_context(runtime.v8_isolate(), context);
             let retval = f(scope);
 
-            Ok(retval)
-        })
-        .unwrap(); // Safe due to the fact we set this to Ok
-    } else {
-        let scope = EmacsMainJsRuntime::peek_stack();
-        result = f(scope);
-        EmacsMainJsRuntime::restore_stack(Some(scope));
-    }
+            Ok(retval)
+        })
+        .unwrap_or_else(|e| error!("{}", e));
+    } else {
+        let scope = EmacsMainJsRuntime::peek_stack();
+        result = f(scope);
+        EmacsMainJsRuntime::restore_stack(Some(scope));
+    }
+
+    result
+}
+
+// Deno only attaches V8's inspector to the worker for :inspect and
+// :inspect-brk. Otherwise we attach one the first time the profiler
+// needs it, instead of paying for it in every session.
+fn create_inspector_session(
+    worker: &mut deno_runtime::worker::MainWorker,
+) -> Box<deno_runtime::inspector::InspectorSession> {
+    let options = EmacsMainJsRuntime::get_options();
+    if options.inspect.is_some() || options.inspect_brk.is_some() {
+        return worker.create_inspector_session();
+    }
+
+    EmacsMainJsRuntime::access(|main| {
+        let inspector = main.inspector.get_or_insert_with(|| {
+            deno_runtime::inspector::DenoInspector::new(&mut worker.js_runtime, None)
+        });
+        deno_runtime::inspector::InspectorSession::new(&mut **inspector)
+    })
+}
+
+/// Starts profiling the CPU usage of JavaScript, using V8's sampling
+/// profiler. Call 'js-profile-stop' to stop the profiler and see the
+/// results.
+#[cfg(feature = "javascript")]
+#[lisp_fn(intspec = "")]
+pub fn js_profile_start() -> LispObject {
+    let ops = EmacsMainJsRuntime::get_options();
+    js_init_sys("init.js", &ops).unwrap_or_else(|e| {
+        error!("JS Failed to initialize with error: {}", e);
+    });
+
+    if EmacsMainJsRuntime::is_profiling() {
+        error!("The JavaScript profiler is already running");
+    }
+
+    let session = block_on(async move {
+        let mut worker_handle = EmacsMainJsRuntime::get_deno_worker()?;
+        let mut session = create_inspector_session(worker_handle.as_mut_ref());
+        session
+            .post_message("Profiler.enable", None)
+            .await
+            .map_err(|e| into_ioerr(e))?;
+        session
+            .post_message("Profiler.start", None)
+            .await
+            .map_err(|e| into_ioerr(e))?;
+        Ok(session)
+    })
+    .unwrap_or_else(|e| error!("Failed to start the JavaScript profiler: {}", e));
+
+    EmacsMainJsRuntime::set_profiler_session(Some(session));
+    lisp::remacs_sys::Qnil
+}
+
+/// Stops the profiler started by 'js-profile-start', and writes the
+/// profile to FILE in the .cpuprofile format understood by Chrome's
+/// DevTools.
+///
+/// A top-down call tree of the profile is shown in the buffer
+/// "*js-profile*". Each line shows the share of the samples spent in
+/// a function and the functions it called, followed by the share spent
+/// in the function itself. Functions below 0.5% are left out.
+///
+/// Returns FILE.
+#[cfg(feature = "javascript")]
+#[lisp_fn(intspec = "FWrite profile to file: ")]
+pub fn js_profile_stop(file: LispStringRef) -> LispObject {
+    let mut session = EmacsMainJsRuntime::take_profiler_session()
+        .unwrap_or_else(|| error!("The JavaScript profiler is not running"));
+    let result = block_on(async move {
+        let result = session
+            .post_message("Profiler.stop", None)
+            .await
+            .map_err(|e| into_ioerr(e))?;
+        session
+            .post_message("Profiler.disable", None)
+            .await
+            .map_err(|e| into_ioerr(e))?;
+        Ok(result)
+    })
+    .unwrap_or_else(|e| error!("Failed to stop the JavaScript profiler: {}", e));
+
+    let profile = &result["profile"];
+    let path = unsafe { lisp::remacs_sys::Fexpand_file_name(file.into(), lisp::remacs_sys::Qnil) };
+    let path_str: LispStringRef = path.into();
+    std::fs::write(path_str.to_utf8(), profile.to_string())
+        .unwrap_or_else(|e| error!("Failed to write {}: {}", path_str.to_utf8(), e));
+
+    show_call_tree(&render_call_tree(profile));
+    path
+}
+
+// Below this share of the samples, nodes are left out of the call tree.
+const MIN_CALL_TREE_SHARE: f64 = 0.005;
+
+struct ProfileNode {
+    name: String,
+    location: String,
+    hits: u64,
+    children: Vec<u64>,
+}
+
+// Renders the nodes of a V8 CPU profile as a top-down call tree.
+fn render_call_tree(profile: &serde_json::Value) -> String {
+    let empty = vec![];
+    let nodes = profile["nodes"].as_array().unwrap_or(&empty);
+    let mut by_id = std::collections::HashMap::new();
+    for node in nodes {
+        if let Some(id) = node["id"].as_u64() {
+            let frame = &node["callFrame"];
+            let name = match frame["functionName"].as_str() {
+                Some("") | None => "(anonymous)".to_string(),
+                Some(name) => name.to_string(),
+            };
+            let url = frame["url"].as_str().unwrap_or("");
+            let location = if url.is_empty() {
+                String::new()
+            } else {
+                // Line and column numbers are 0-based in profiles.
+                remap_module_locations(&format!(
+                    "{}:{}:{}",
+                    url,
+                    frame["lineNumber"].as_i64().unwrap_or(0) + 1,
+                    frame["columnNumber"].as_i64().unwrap_or(0) + 1
+                ))
+            };
+            let children = node["children"]
+                .as_array()
+                .map(|children| children.iter().filter_map(|child| child.as_u64()).collect())
+                .unwrap_or_default();
+            by_id.insert(
+                id,
+                ProfileNode {
+                    name,
+                    location,
+                    hits: node["hitCount"].as_u64().unwrap_or(0),
+                    children,
+                },
+            );
+        }
+    }
+
+    fn total_hits(
+        id: u64,
+        by_id: &std::collections::HashMap<u64, ProfileNode>,
+        totals: &mut std::collections::HashMap<u64, u64>,
+    ) -> u64 {
+        let node = match by_id.get(&id) {
+            Some(node) => node,
+            None => return 0,
+        };
+        let mut total = node.hits;
+        for child in &node.children {
+            total += total_hits(*child, by_id, totals);
+        }
+        totals.insert(id, total);
+        total
+    }
+
+    fn render(
+        id: u64,
+        depth: usize,
+        sum: f64,
+        by_id: &std::collections::HashMap<u64, ProfileNode>,
+        totals: &std::collections::HashMap<u64, u64>,
+        out: &mut String,
+    ) {
+        let node = match by_id.get(&id) {
+            Some(node) => node,
+            None => return,
+        };
+        let total = totals[&id] as f64 / sum;
+        if total < MIN_CALL_TREE_SHARE {
+            return;
+        }
+
+        out.push_str(&format!(
+            "{:>6.1}% {:>6.1}%  {}{} {}\n",
+            total * 100.0,
+            node.hits as f64 / sum * 100.0,
+            "  ".repeat(depth),
+            node.name,
+            node.location
+        ));
+
+        let mut children = node.children.clone();
+        children.sort_by_key(|child| std::cmp::Reverse(totals.get(child).copied().unwrap_or(0)));
+        for child in children {
+            render(child, depth + 1, sum, by_id, totals, out);
+        }
+    }
+
+    let mut out = String::from("  Total    Self  Function\n");
+    let root = match nodes.first().and_then(|node| node["id"].as_u64()) {
+        Some(root) => root,
+        None => return out,
+    };
+
+    let mut totals = std::collections::HashMap::new();
+    let sum = total_hits(root, &by_id, &mut totals);
+    if sum == 0 {
+        out.push_str("No samples were recorded.\n");
+        return out;
+    }
+
+    render(root, 0, sum as f64, &by_id, &totals, &mut out);
+    out
+}
+
+fn show_call_tree(text: &str) {
+    unsafe {
+        let buffer = lisp::remacs_sys::Fget_buffer_create(
+            LispObject::from("*js-profile*"),
+            lisp::remacs_sys::Qnil,
+        );
+        let current = lisp::remacs_sys::Fcurrent_buffer();
+        lisp::remacs_sys::Fset_buffer(buffer);
+        let inhibit_read_only = lisp::remacs_sys::globals.Vinhibit_read_only;
+        lisp::remacs_sys::globals.Vinhibit_read_only = lisp::remacs_sys::Qt;
+        lisp::remacs_sys::Ferase_buffer();
+        let mut contents = vec![make_lisp_string(text)];
+        lisp::remacs_sys::Finsert(1, contents.as_mut_ptr());
+        lisp::remacs_sys::globals.Vinhibit_read_only = inhibit_read_only;
+        lisp::remacs_sys::Fgoto_char(LispObject::from_fixnum(1));
+        let mut mode = vec![lisp::remacs_sys::Qspecial_mode];
+        Ffuncall(1, mode.as_mut_ptr());
+        lisp::remacs_sys::Fset_buffer(current);
+        let mut display = vec![lisp::remacs_sys::Qdisplay_buffer, buffer];
+        Ffuncall(2, display.as_mut_ptr());
+    }
+}
+
+/// Writes a snapshot of the JavaScript heap to FILE, in the
+/// .heapsnapshot format understood by Chrome's DevTools.
+///
+/// Returns FILE.
+#[cfg(feature = "javascript")]
+#[lisp_fn(intspec = "FWrite heap snapshot to file: ")]
+pub fn js_heap_snapshot(file: LispStringRef) -> LispObject {
+    let ops = EmacsMainJsRuntime::get_options();
+    js_init_sys("init.js", &ops).unwrap_or_else(|e| {
+        error!("JS Failed to initialize with error: {}", e);
+    });
+
+    let snapshot = execute_with_current_scope(|scope| {
+        let mut snapshot = vec![];
+        scope.take_heap_snapshot(|chunk| {
+            snapshot.extend_from_slice(chunk);
+            true
+        });
+        snapshot
+    });
 
-    result
+    let path = unsafe { lisp::remacs_sys::Fexpand_file_name(file.into(), lisp::remacs_sys::Qnil) };
+    let path_str: LispStringRef = path.into();
+    std::fs::write(path_str.to_utf8(), snapshot)
+        .unwrap_or_else(|e| error!("Failed to write {}: {}", path_str.to_utf8(), e));
+    path
 }
 
 /// Internal function used for cleanup. Do not call directly.
@@ -1530,16 +3299,17 @@ fn block_on<R: Sized, T: Sized + std::future::Future<Output = Result<R>>>(fnc: T
     }
 }
 
+// Deno's platform never runs the cleanup tasks V8 posts for a
+// FinalizationRegistry, so we run the callbacks of the proxies V8 has
+// collected since the last sweep ourselves. Only those are visited.
 fn js_sweep_inner(scope: &mut v8::HandleScope) {
-    let context = scope.get_current_context();
-    let global = context.global(scope);
-
-    let name = v8::String::new(scope, "__sweep").unwrap();
-    let fnc: v8::Local<v8::Function> = global.get(scope, name.into()).unwrap().try_into().unwrap();
-    let recv =
-        v8::Local::<v8::Value>::try_from(v8::String::new(scope, "lisp_invoke").unwrap()).unwrap();
-    let v8_args = vec![];
-    fnc.call(scope, recv, v8_args.as_slice()).unwrap();
+    let registry = EmacsMainJsRuntime::get_proxy_registry();
+    let registry = registry.get(scope);
+    let name = v8::String::new(scope, "cleanupSome").unwrap();
+    let cleanup = registry.get(scope, name.into()).unwrap();
+    if let Ok(cleanup) = v8::Local::<v8::Function>::try_from(cleanup) {
+        cleanup.call(scope, registry.into(), &[]);
+    }
 }
 
 /// Internal function called by 'garbage-collect'. Do not call directly.
@@ -1553,14 +3323,66 @@ pub fn js__sweep() -> LispObject {
     lisp::remacs_sys::Qnil
 }
 
+/// Returns statistics about the Lisp objects referenced from JavaScript,
+/// as a plist:
+///
+/// :live is the number of objects currently referenced by a JS proxy.
+/// :free is the number of slots waiting to be reused for new proxies.
+/// :created is the number of proxies created since Emacs started.
+/// :released is the number of those that have since been released.
+///
+/// A proxy is released by the first 'garbage-collect' after V8 has
+/// collected it, so :live can lag behind the proxies JS still uses.
+#[cfg(feature = "javascript")]
+#[lisp_fn]
+pub fn js_proxy_stats() -> LispObject {
+    let (live, free, created, released) = PROXIES.with(|proxies| {
+        let proxies = proxies.borrow();
+        (
+            proxies.live(),
+            proxies.free.len(),
+            proxies.created,
+            proxies.released,
+        )
+    });
+
+    list!(
+        lisp::remacs_sys::QClive,
+        LispObject::from_fixnum(live as EmacsInt),
+        lisp::remacs_sys::QCfree,
+        LispObject::from_fixnum(free as EmacsInt),
+        lisp::remacs_sys::QCcreated,
+        LispObject::from_fixnum(created as EmacsInt),
+        lisp::remacs_sys::QCreleased,
+        LispObject::from_fixnum(released as EmacsInt)
+    )
+}
+
 fn tick_js() -> Result<bool> {
     let mut is_complete = false;
     let is_complete_ref = &mut is_complete;
+    let waker = if EmacsMainJsRuntime::get_options().event_driven {
+        EmacsMainJsRuntime::get_event_loop_waker()
+    } else {
+        None
+    };
+
     block_on(async move {
         futures::future::poll_fn(|cx| {
-            let mut worker_handle = EmacsMainJsRuntime::get_deno_worker();
+            let mut worker_handle = EmacsMainJsRuntime::get_deno_worker()?;
             let w = worker_handle.as_mut_ref();
-            let polled = w.poll_event_loop(cx);
+            // When event driven, we poll using our own waker instead of
+            // the one provided by block_on. Any future that is pending
+            // will then notify the lisp thread once it is ready.
+            let polled = match &waker {
+                Some(event_loop_waker) => {
+                    event_loop_waker.consume();
+                    let waker = futures::task::waker(event_loop_waker.clone());
+                    let mut event_loop_cx = std::task::Context::from_waker(&waker);
+                    w.poll_event_loop(&mut event_loop_cx)
+                }
+                None => w.poll_event_loop(cx),
+            };
             match polled {
                 std::task::Poll::Ready(r) => {
                     *is_complete_ref = true;
@@ -1627,21 +3449,37 @@ fn init_worker(filepath: &str, js_options: &EmacsJsOptions) -> Result<()> {
         std::env::set_var("NO_COLOR", "1");
     }
 
+    let builtin = deno_core::ModuleSpecifier::resolve_url_or_path(EMACS_BUILTIN_MODULE)
+        .map_err(|e| into_ioerr(e))?;
+    let import_map = parse_import_map(&builtin, &build_import_map(&builtin, js_options)?)?;
+
     let flags = deno::flags::Flags {
-        unstable: true, // Needed for deno in WebWorkers
+        unstable: true, // Needed for deno in WebWorkers, and import maps
         no_check: js_options.no_check,
-        no_remote: js_options.no_remote,
+        // When loading from a vendor directory, we never want to reach
+        // out to the network for modules.
+        no_remote: js_options.no_remote || js_options.vendor_dir.is_some(),
         config_path: js_options.ts_config.clone(),
         inspect,
         inspect_brk,
         ..Default::default()
     };
 
-    let program = deno::program_state::ProgramState::new(flags).map_err(|e| into_ioerr(e))?;
+    let mut program = deno::program_state::ProgramState::new(flags).map_err(|e| into_ioerr(e))?;
+    // Deno only reads import maps from files, so we set ours before
+    // anything else holds on to the program state.
+    Arc::get_mut(&mut program)
+        .ok_or_else(|| into_ioerr("The program state is already in use"))?
+        .maybe_import_map = Some(import_map);
+    insert_builtin_module(&program, &builtin);
     EmacsMainJsRuntime::set_program_state(program.clone());
+    set_v8_flags();
     let mut worker = deno::create_main_worker(&program, main_module.clone(), permissions);
-    let result: Result<deno_runtime::worker::MainWorker> = runtime.block_on(async move {
+    let result: Result<(deno_runtime::worker::MainWorker, bool)> = runtime.block_on(async move {
         let runtime = &mut worker.js_runtime;
+        // Whether the proxy registry can be swept, see js_sweep_inner.
+        let sweeps;
+        QUIT_WATCHDOG.watch(Some(runtime.v8_isolate().thread_safe_handle()));
         {
             let context = runtime.global_context();
             let scope = &mut v8::HandleScope::with_context(runtime.v8_isolate(), context);
@@ -1656,9 +3494,21 @@ fn init_worker(filepath: &str, js_options: &EmacsJsOptions) -> Result<()> {
                 let obj = v8::Object::new(scope);
                 global.set(scope, name.into(), obj.into());
             }
+            {
+                let name = v8::String::new(scope, "FinalizationRegistry").unwrap();
+                let constructor: v8::Local<v8::Function> =
+                    global.get(scope, name.into()).unwrap().try_into().unwrap();
+                let release = v8::Function::new(scope, release_proxies).unwrap();
+                let registry = constructor.new_instance(scope, &[release.into()]).unwrap();
+                let name = v8::String::new(scope, "cleanupSome").unwrap();
+                sweeps = registry
+                    .get(scope, name.into())
+                    .map_or(false, |cleanup| cleanup.is_function());
+                let glob = v8::Global::new(scope, registry);
+                EmacsMainJsRuntime::set_proxy_registry(glob);
+            }
             bind_global_fn!(scope, global, lisp_invoke);
             bind_global_fn!(scope, global, is_proxy);
-            bind_global_fn!(scope, global, finalize);
             bind_global_fn!(scope, global, lisp_json);
             bind_global_fn!(scope, global, lisp_intern);
             bind_global_fn!(scope, global, lisp_make_finalizer);
@@ -1668,6 +3518,10 @@ fn init_worker(filepath: &str, js_options: &EmacsJsOptions) -> Result<()> {
             bind_global_fn!(scope, global, lisp_make_lambda);
             bind_global_fn!(scope, global, lisp_list);
             bind_global_fn!(scope, global, json_lisp);
+            bind_global_fn!(scope, global, lisp_remap_locations);
+            bind_global_fn!(scope, global, lisp_buffer_text);
+            bind_global_fn!(scope, global, lisp_buffer_bounds);
+            bind_global_fn!(scope, global, lisp_buffer_replace);
         }
         {
             runtime
@@ -1675,14 +3529,196 @@ fn init_worker(filepath: &str, js_options: &EmacsJsOptions) -> Result<()> {
                 .map_err(|e| into_ioerr(e))?
         }
 
-        Ok(worker)
+        Ok((worker, sweeps))
     });
 
-    let worker = result?;
+    let (worker, sweeps) = result?;
     EmacsMainJsRuntime::set_deno_worker(worker);
+    if !sweeps {
+        funcall_or_none(vec![
+            lisp::remacs_sys::Qdisplay_warning,
+            lisp::remacs_sys::Qjs_error,
+            LispObject::from(
+                "This V8 has no FinalizationRegistry.prototype.cleanupSome, \
+                 so the Lisp objects passed to JS are never released",
+            ),
+        ]);
+    }
     Ok(())
 }
 
+// V8 only reads its flags before it starts, so we set them once, before
+// the first isolate, be it the main worker or the type-checker.
+fn set_v8_flags() {
+    static V8_FLAGS: std::sync::Once = std::sync::Once::new();
+    V8_FLAGS.call_once(|| {
+        // Exposes FinalizationRegistry.prototype.cleanupSome, see js_sweep_inner.
+        v8::V8::set_flags_from_string("--harmony-weak-refs-with-cleanup-some");
+    });
+}
+
+const VENDOR_IMPORT_MAP: &str = "import_map.json";
+
+// Remote modules are vendored to DIR/HOST/PATH, so that relative imports
+// between vendored files keep working without the import map. The path
+// is kept as it is, even without an extension, since other vendored
+// modules may import it by that name. Only a URL naming a directory
+// gets a file name, derived from the media type of the module.
+fn vendor_path(
+    dir: &std::path::Path,
+    url: &deno_core::url::Url,
+    media_type: &str,
+) -> std::path::PathBuf {
+    let mut path = dir.to_path_buf();
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}_{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => "_".to_string(),
+    };
+    path.push(host);
+
+    for segment in url
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty() && *s != "..")
+    {
+        path.push(segment);
+    }
+
+    if url.path().ends_with('/') {
+        let extension = match media_type {
+            "TypeScript" => "ts",
+            "TSX" => "tsx",
+            "JSX" => "jsx",
+            "Dts" => "d.ts",
+            "Json" => "json",
+            _ => "js",
+        };
+        path.push(format!("index.{}", extension));
+    }
+
+    path
+}
+
+async fn fetch_module_graph(
+    program: &Arc<deno::program_state::ProgramState>,
+    specifier: &deno_core::ModuleSpecifier,
+) -> Result<serde_json::Value> {
+    let handler = Arc::new(Mutex::new(
+        deno::specifier_handler::FetchHandler::new(
+            program,
+            deno_runtime::permissions::Permissions::allow_all(),
+        )
+        .map_err(|e| into_ioerr(e))?,
+    ));
+    let mut builder = deno::module_graph::GraphBuilder::new(
+        handler,
+        program.maybe_import_map.clone(),
+        program.lockfile.clone(),
+    );
+    builder
+        .add(specifier, false)
+        .await
+        .map_err(|e| into_ioerr(e))?;
+    let info = builder.get_graph().info().map_err(|e| into_ioerr(e))?;
+    serde_json::to_value(&info).map_err(|e| into_ioerr(e))
+}
+
+fn vendor_module(specifier: &str, dir: &std::path::Path) -> Result<std::path::PathBuf> {
+    let options = EmacsMainJsRuntime::get_options();
+    let flags = deno::flags::Flags {
+        unstable: true,
+        config_path: options.ts_config.clone(),
+        ..Default::default()
+    };
+
+    let program = deno::program_state::ProgramState::new(flags).map_err(|e| into_ioerr(e))?;
+    let root =
+        deno_core::ModuleSpecifier::resolve_url_or_path(specifier).map_err(|e| into_ioerr(e))?;
+
+    init_tokio()?;
+    let graph = block_on(fetch_module_graph(&program, &root))?;
+
+    let import_map_path = dir.join(VENDOR_IMPORT_MAP);
+    let mut import_map: serde_json::Value = if import_map_path.is_file() {
+        let contents = std::fs::read_to_string(&import_map_path)?;
+        serde_json::from_str(&contents).map_err(|e| into_ioerr(e))?
+    } else {
+        json!({ "imports": {} })
+    };
+
+    let imports = import_map["imports"].as_object_mut().ok_or_else(|| {
+        into_ioerr(format!(
+            "Invalid import map: {}",
+            import_map_path.to_string_lossy()
+        ))
+    })?;
+
+    let modules = graph["modules"].as_array().cloned().unwrap_or_default();
+    for module in modules {
+        let (specifier, local) = match (module["specifier"].as_str(), module["local"].as_str()) {
+            (Some(specifier), Some(local)) => (specifier, local),
+            _ => continue,
+        };
+
+        let url = deno_core::url::Url::parse(specifier).map_err(|e| into_ioerr(e))?;
+        // Local modules are already available offline.
+        if url.scheme() != "http" && url.scheme() != "https" {
+            continue;
+        }
+
+        let media_type = module["mediaType"].as_str().unwrap_or("JavaScript");
+        let destination = vendor_path(dir, &url, media_type);
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(local, &destination)?;
+
+        let relative = destination
+            .strip_prefix(dir)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/");
+        imports.insert(
+            specifier.to_string(),
+            serde_json::Value::String(format!("./{}", relative)),
+        );
+    }
+
+    std::fs::create_dir_all(dir)?;
+    let contents = serde_json::to_string_pretty(&import_map).map_err(|e| into_ioerr(e))?;
+    std::fs::write(&import_map_path, contents)?;
+    Ok(import_map_path)
+}
+
+/// Fetches SPECIFIER and all of its dependencies into the vendor
+/// directory DIR, which defaults to "vendor" within 'default-directory'.
+/// Remote modules are written to DIR/HOST/PATH, and an import map
+/// mapping their URLs to the vendored copies is written to
+/// DIR/import_map.json. If DIR already contains an import map, the new
+/// modules are added to it.
+///
+/// Pass DIR to 'js-initialize' as :vendor-dir to load modules from the
+/// vendor directory without accessing the network.
+///
+/// Returns the path to the import map.
+#[cfg(feature = "javascript")]
+#[lisp_fn(min = "1")]
+pub fn js_vendor_module(specifier: LispObject, dir: LispObject) -> LispObject {
+    let sref: LispStringRef = specifier.into();
+    let dir = if dir.is_nil() {
+        LispObject::from("vendor")
+    } else {
+        dir
+    };
+    let dir: LispStringRef =
+        unsafe { lisp::remacs_sys::Fexpand_file_name(dir, lisp::remacs_sys::Qnil) }.into();
+
+    vendor_module(&sref.to_utf8(), std::path::Path::new(&dir.to_utf8()))
+        .map(|path| LispObject::from(path.to_string_lossy().as_ref()))
+        .unwrap_or_else(|e| error!("Failed to vendor {}: {}", sref.to_utf8(), e))
+}
+
 fn run_module_inner(
     filepath: &str,
     additional_js: Option<String>,
@@ -1692,7 +3728,7 @@ fn run_module_inner(
     js_init_sys(filepath, js_options)?;
 
     block_on(async move {
-        let mut worker_handle = EmacsMainJsRuntime::get_deno_worker();
+        let mut worker_handle = EmacsMainJsRuntime::get_deno_worker()?;
         let w = worker_handle.as_mut_ref();
         let main_module =
             deno_core::ModuleSpecifier::resolve_url_or_path(filepath).map_err(|e| into_ioerr(e))?;
@@ -1743,22 +3779,149 @@ fn run_module(
     result
 }
 
+fn make_lisp_string(string: &str) -> LispObject {
+    let len = string.len();
+    let cstr = CString::new(string).expect("Failed to allocate CString");
+    unsafe { lisp::remacs_sys::make_string_from_utf8(cstr.as_ptr(), len.try_into().unwrap()) }
+}
+
+// Errors thrown by JS reach us as a JsError, or as a PrettyJsError
+// if they were formatted by the main worker. Other errors, like
+// TypeScript diagnostics or failures to load a module, have no
+// stack frames.
+fn as_js_error(e: &std::io::Error) -> Option<&deno_core::error::JsError> {
+    let inner = e.get_ref()?;
+    inner
+        .downcast_ref::<deno_core::error::JsError>()
+        .or_else(|| {
+            inner
+                .downcast_ref::<deno::fmt_errors::PrettyJsError>()
+                .map(|pretty| &**pretty)
+        })
+}
+
+// V8 formats exception messages as "Uncaught TypeError: message",
+// so we take the class name from there.
+fn js_error_class(message: &str) -> Option<&str> {
+    let message = message
+        .trim_start_matches("Uncaught ")
+        .trim_start_matches("(in promise) ");
+    let class = message.split(':').next()?;
+    if !class.is_empty()
+        && class
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '$')
+    {
+        Some(class)
+    } else {
+        None
+    }
+}
+
+fn js_error_frames(frames: &[deno_core::error::JsStackFrame]) -> LispObject {
+    let string_or_nil = |s: &Option<String>| {
+        s.as_ref()
+            .map(|s| make_lisp_string(s))
+            .unwrap_or(lisp::remacs_sys::Qnil)
+    };
+    let fixnum_or_nil = |n: Option<i64>| {
+        n.map(|n| LispObject::from_fixnum(n as EmacsInt))
+            .unwrap_or(lisp::remacs_sys::Qnil)
+    };
+
+    frames
+        .iter()
+        .rev()
+        .fold(lisp::remacs_sys::Qnil, |rest, frame| {
+            let origin = frame.file_name.as_deref().and_then(find_module_origin);
+            let frame = match origin {
+                // Frames within eval-js-buffer and eval-js-region point at
+                // the buffer the code came from.
+                Some(origin) => {
+                    let (line, column) = origin.map(
+                        frame.line_number.unwrap_or(1),
+                        frame.column_number.unwrap_or(0),
+                    );
+                    list!(
+                        lisp::remacs_sys::QCfile,
+                        string_or_nil(&origin.file_name),
+                        lisp::remacs_sys::QCbuffer,
+                        make_lisp_string(&origin.buffer_name),
+                        lisp::remacs_sys::QCline,
+                        LispObject::from_fixnum(line as EmacsInt),
+                        lisp::remacs_sys::QCcolumn,
+                        LispObject::from_fixnum(column as EmacsInt),
+                        lisp::remacs_sys::QCfunction,
+                        string_or_nil(&frame.function_name)
+                    )
+                }
+                None => list!(
+                    lisp::remacs_sys::QCfile,
+                    string_or_nil(&frame.file_name),
+                    lisp::remacs_sys::QCbuffer,
+                    lisp::remacs_sys::Qnil,
+                    lisp::remacs_sys::QCline,
+                    fixnum_or_nil(frame.line_number),
+                    lisp::remacs_sys::QCcolumn,
+                    fixnum_or_nil(frame.column_number),
+                    lisp::remacs_sys::QCfunction,
+                    string_or_nil(&frame.function_name)
+                ),
+            };
+            LispObject::cons(frame, rest)
+        })
+}
+
+// Builds the data of a 'js-error' condition, (MESSAGE CLASS FRAMES).
+// FRAMES is a list of plists with the keys :file, :buffer, :line,
+// :column and :function, innermost frame first.
+fn js_error_data(e: &std::io::Error) -> LispObject {
+    let message = remap_module_locations(&e.to_string());
+    let (class, frames) = match as_js_error(e) {
+        Some(js_error) => (
+            js_error_class(&js_error.message),
+            js_error_frames(&js_error.frames),
+        ),
+        None => (js_error_class(&message), lisp::remacs_sys::Qnil),
+    };
+
+    list!(
+        make_lisp_string(&message),
+        class
+            .map(make_lisp_string)
+            .unwrap_or(lisp::remacs_sys::Qnil),
+        frames
+    )
+}
+
 fn handle_error(e: std::io::Error, handler: LispObject) -> LispObject {
-    let err_string = e.to_string();
+    signal_quit_if_requested();
+    let data = js_error_data(&e);
     if handler.is_nil() {
-        error!(err_string);
+        lisp::eval::signal_rust(lisp::remacs_sys::Qjs_error, data);
     } else {
-        unsafe {
-            let len = err_string.len();
-            let cstr = CString::new(err_string).expect("Failed to allocate CString");
-            let lstring =
-                lisp::remacs_sys::make_string_from_utf8(cstr.as_ptr(), len.try_into().unwrap());
-            let mut args = vec![handler, lstring];
-            Ffuncall(args.len().try_into().unwrap(), args.as_mut_ptr())
+        // Handlers are passed the message, and the error as
+        // 'condition-case' would bind it if they take a second argument.
+        let message = data
+            .as_cons()
+            .map_or(lisp::remacs_sys::Qnil, |data| data.car());
+        let mut args = vec![handler, message];
+        if takes_error_argument(handler) {
+            args.push(LispObject::cons(lisp::remacs_sys::Qjs_error, data));
         }
+        unsafe { Ffuncall(args.len().try_into().unwrap(), args.as_mut_ptr()) }
     }
 }
 
+fn takes_error_argument(handler: LispObject) -> bool {
+    funcall_or_none(vec![lisp::remacs_sys::Qfunc_arity, handler])
+        .and_then(|arity| arity.as_cons())
+        .map_or(false, |arity| {
+            arity.cdr() == lisp::remacs_sys::Qmany
+                || arity.cdr().as_fixnum().map_or(false, |max| max >= 2)
+        })
+}
+
 /// Gets the current tick rate of JavaScript.
 #[cfg(feature = "javascript")]
 #[lisp_fn]
@@ -1770,6 +3933,9 @@ pub fn js_get_tick_rate() -> LispObject {
 /// Sets F to be the current js tick rate. Every F seconds, javascript
 /// will attempt to evaluate the JS event loop. It will advance the
 /// event loop LOOPS_PER_TICK iterations.
+///
+/// The tick rate is only used when the runtime was initialized with
+/// :js-event-driven nil. See 'js-initialize'.
 #[cfg(feature = "javascript")]
 #[lisp_fn(min = "1")]
 pub fn js_set_tick_rate(f: LispObject, loops_per_tick: LispObject) {
@@ -1788,6 +3954,20 @@ pub fn js_set_tick_rate(f: LispObject, loops_per_tick: LispObject) {
 }
 
 fn schedule_tick() {
+    // If we are event driven, the event loop needs to be polled once
+    // with our waker. After that, the event loop will notify us
+    // whenever it has more work to do.
+    if let Some(waker) = EmacsMainJsRuntime::get_event_loop_waker() {
+        if EmacsMainJsRuntime::get_options().event_driven {
+            futures::task::ArcWake::wake_by_ref(&waker);
+            return;
+        }
+    }
+
+    schedule_timer_tick();
+}
+
+fn schedule_timer_tick() {
     // We do not want to schedule a tick if one is already scheduled.
     if EmacsMainJsRuntime::get_tick_scheduled() {
         return;
@@ -1832,9 +4012,11 @@ fn tick_and_handle_error(handler: LispObject) -> bool {
         .unwrap_or_else(|e| {
             // If handler is nil, we need to manually
             // schedule tick since handle_error isn't
-            // going to return.
-            if handler.is_nil() {
-                schedule_tick();
+            // going to return. We use a timer even if we are event
+            // driven, so that a persistently failing event loop does
+            // not wake us up in a busy loop.
+            if handler.is_nil() || QUIT_WATCHDOG.is_quit_requested() {
+                schedule_timer_tick();
             }
 
             handle_error(e, error_handler);
@@ -1847,10 +4029,11 @@ fn tick_and_handle_error(handler: LispObject) -> bool {
 /// HANDLER is a custom error handler that will be invoked
 /// if the event loop's javascript throws. Default to invoking
 /// 'error' if not specified. See 'js-initialize'.
-/// This will be called via a timer. Once this function is called,
-/// we will advance the loop by a certain number of iterations (default 1000).
-/// This function is safe to call directly, and can even be set up to be
-/// invoked regularly with custom logic.
+/// This will be called when the event loop notifies Emacs that it has
+/// work ready, or via a timer if the runtime is not event driven. In the
+/// latter case, we will advance the loop by a certain number of
+/// iterations (default 1000). This function is safe to call directly,
+/// and can even be set up to be invoked regularly with custom logic.
 #[cfg(feature = "javascript")]
 #[lisp_fn(min = "0")]
 pub fn js_tick_event_loop(handler: LispObject) -> LispObject {
@@ -1869,11 +4052,20 @@ pub fn js_tick_event_loop(handler: LispObject) -> LispObject {
     // anyone can do about it. Just defer the event loop until
     // we are out of the runtime.
     if EmacsMainJsRuntime::is_within_runtime() {
-        schedule_tick();
+        schedule_timer_tick();
         return lisp::remacs_sys::Qnil;
     }
 
-    let num_loops = EmacsMainJsRuntime::get_loops_per_tick();
+    // When event driven, a single poll drives every ready operation,
+    // and we will be notified again once more work is ready.
+    let event_driven = EmacsMainJsRuntime::get_options().event_driven
+        && EmacsMainJsRuntime::get_event_loop_waker().is_some();
+    let num_loops = if event_driven {
+        1
+    } else {
+        EmacsMainJsRuntime::get_loops_per_tick()
+    };
+
     let mut is_complete = false;
     for _ in 0..num_loops {
         is_complete = tick_and_handle_error(handler);
@@ -1882,27 +4074,437 @@ pub fn js_tick_event_loop(handler: LispObject) -> LispObject {
         }
     }
 
-    if !is_complete {
+    if !is_complete && !event_driven {
         schedule_tick();
     }
 
     lisp::remacs_sys::Qnil
 }
 
+/// Internal function called when the JS event loop has
+/// work ready. Do not call directly.
+#[cfg(feature = "javascript")]
+#[lisp_fn]
+pub fn js__wake_event_loop(_proc: LispObject, _data: LispObject) -> LispObject {
+    js_tick_event_loop(lisp::remacs_sys::Qnil)
+}
+
+// The parts of the 'lisp' object that prelim.js implements itself,
+// and the types they need. Everything else is generated from the obarray.
+const LISP_DECLARATIONS_HEADER: &str = r#"// Generated by js-generate-declarations, do not edit.
+export {};
+
+declare global {
+    interface LispObject {
+        json(): any;
+    }
+
+    type LispValue = LispObject | string | number | boolean | null;
+    type LispArg =
+        | LispValue
+        | ((...args: any[]) => any)
+        | LispArg[]
+        | { [key: string]: LispArg };
+
+    interface LispDefunOptions {
+        name: string | LispObject;
+        docString?: string;
+        interactive?: boolean;
+        args?: string;
+        func: (...args: any[]) => any;
+    }
+
+    interface LispFunctions {
+        symbols: { [name: string]: LispObject };
+        keywords: { [name: string]: LispObject };
+        q: { [name: string]: LispObject };
+        k: { [name: string]: LispObject };
+        make: {
+            hashtable(obj: { [key: string]: LispArg }): LispObject;
+            alist(obj: { [key: string]: LispArg }): LispObject;
+            plist(obj: { [key: string]: LispArg }): LispObject;
+            array(arr: LispArg[]): LispObject;
+            list(arr: LispArg[]): LispObject;
+            string(str: string): LispObject;
+        };
+        async: {
+            process_output(proc: LispObject): Promise<string>;
+            [name: string]: (...args: LispArg[]) => Promise<any>;
+        };
+        specialForms: { [name: string]: any };
+        defun(options: LispDefunOptions): void;
+        defun(name: string | LispObject, func: (...args: any[]) => any): void;
+        defun(name: string | LispObject, docString: string, func: (...args: any[]) => any): void;
+        setq(symbol: LispObject, value: LispArg): any;
+        defvar(symbol: LispObject, value?: LispArg, docString?: string): any;
+        define_key(keymap: LispArg, key: LispArg, def: LispArg): any;
+        define_minor_mode(...args: LispArg[]): any;
+        quote(arg: LispArg): LispObject;
+        list(...args: LispArg[]): LispObject;
+        with_current_buffer<T>(buffer: string | LispObject, f: () => T): T;
+        with_temp_buffer<T>(f: () => T): T;
+        let(f: (...args: any[]) => any, ...values: LispArg[]): any;
+    }
+
+    interface Lisp extends LispFunctions {
+"#;
+
+const LISP_DECLARATIONS_FOOTER: &str = r#"    }
+
+    var lisp: Lisp;
+}
+"#;
+
+const JS_RESERVED_WORDS: [&str; 46] = [
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "enum",
+    "export",
+    "extends",
+    "false",
+    "finally",
+    "for",
+    "function",
+    "if",
+    "implements",
+    "import",
+    "in",
+    "instanceof",
+    "interface",
+    "let",
+    "new",
+    "null",
+    "package",
+    "private",
+    "protected",
+    "public",
+    "return",
+    "static",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typeof",
+    "var",
+    "void",
+    "while",
+    "with",
+    "yield",
+];
+
+// The names already declared in LISP_DECLARATIONS_HEADER, and the
+// functions prelim.js refuses to call.
+const LISP_SPECIAL_FORMS: [&str; 20] = [
+    "symbols",
+    "keywords",
+    "q",
+    "k",
+    "make",
+    "async",
+    "specialForms",
+    "defun",
+    "setq",
+    "defvar",
+    "define_key",
+    "define_minor_mode",
+    "quote",
+    "list",
+    "with_current_buffer",
+    "with_temp_buffer",
+    "let",
+    "eval_js",
+    "eval_js_file",
+    "recursive_edit",
+];
+
+fn is_js_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '$' => {}
+        _ => return false,
+    }
+
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+fn js_parameter_name(name: &str, index: usize, seen: &mut Vec<String>) -> String {
+    let mut name = name.to_lowercase().replace('-', "_");
+    if !is_js_identifier(&name) {
+        name = format!("arg{}", index);
+    } else if JS_RESERVED_WORDS.contains(&name.as_str()) {
+        name.push('_');
+    }
+
+    while seen.contains(&name) {
+        name = format!("{}{}", name, index);
+    }
+
+    seen.push(name.clone());
+    name
+}
+
+thread_local! {
+    static OBARRAY_SYMBOLS: RefCell<Vec<LispObject>> = RefCell::new(Vec::new());
+}
+
+unsafe extern "C" fn collect_obarray_symbol(symbol: LispObject, _arg: LispObject) {
+    OBARRAY_SYMBOLS.with(|symbols| symbols.borrow_mut().push(symbol));
+}
+
+fn obarray_symbols() -> Vec<LispObject> {
+    unsafe {
+        lisp::remacs_sys::map_obarray(
+            lisp::remacs_sys::globals.Vobarray,
+            Some(collect_obarray_symbol),
+            lisp::remacs_sys::Qnil,
+        );
+    }
+
+    OBARRAY_SYMBOLS.with(|symbols| std::mem::replace(&mut *symbols.borrow_mut(), Vec::new()))
+}
+
+// Calls a lisp function, returning None instead of signaling if it fails.
+fn funcall_or_none(args: Vec<LispObject>) -> Option<LispObject> {
+    let raw_ptr = Box::into_raw(Box::new(args));
+    let result = unsafe {
+        lisp::remacs_sys::internal_catch_all(
+            Some(lisp_springboard),
+            raw_ptr as *mut ::libc::c_void,
+            Some(lisp_handler),
+        )
+    };
+
+    match result.as_cons() {
+        Some(cons) if cons.car() == lisp::remacs_sys::Qjs_lisp_error => None,
+        _ => Some(result),
+    }
+}
+
+// Builds the parameter list for DEF from its argument list, as given by
+// 'help-function-arglist', falling back to 'func-arity'. Returns None for
+// special forms, which cannot be called from JS.
+fn lisp_function_parameters(def: LispObject) -> Option<String> {
+    let mut seen = vec![];
+    let arglist = funcall_or_none(vec![
+        lisp::remacs_sys::Qhelp_function_arglist,
+        def,
+        lisp::remacs_sys::Qt,
+    ]);
+
+    if let Some(arglist) = arglist.filter(|a| a.is_nil() || a.is_cons()) {
+        let mut params = vec![];
+        let mut optional = false;
+        let mut rest = false;
+        for (i, arg) in arglist
+            .iter_cars(LispConsEndChecks::off, LispConsCircularChecks::safe)
+            .enumerate()
+        {
+            if arg == lisp::remacs_sys::Qand_optional {
+                optional = true;
+            } else if arg == lisp::remacs_sys::Qand_rest {
+                rest = true;
+            } else {
+                let name = arg
+                    .as_symbol()
+                    .map(|sym| {
+                        let name: LispStringRef = sym.symbol_name().into();
+                        name.to_utf8()
+                    })
+                    .unwrap_or_default();
+                let name = js_parameter_name(&name, i, &mut seen);
+                if rest {
+                    params.push(format!("...{}: LispArg[]", name));
+                    break;
+                } else if optional {
+                    params.push(format!("{}?: LispArg", name));
+                } else {
+                    params.push(format!("{}: LispArg", name));
+                }
+            }
+        }
+
+        return Some(params.join(", "));
+    }
+
+    let arity = funcall_or_none(vec![lisp::remacs_sys::Qfunc_arity, def])?.as_cons()?;
+    let min = arity.car().as_fixnum().unwrap_or(0);
+    let mut params: Vec<String> = (0..min).map(|i| format!("arg{}: LispArg", i)).collect();
+    match arity.cdr().as_fixnum() {
+        Some(max) => params.extend((min..max).map(|i| format!("arg{}?: LispArg", i))),
+        None if arity.cdr() == lisp::remacs_sys::Qmany => {
+            params.push("...args: LispArg[]".to_string())
+        }
+        // unevalled
+        None => return None,
+    }
+
+    Some(params.join(", "))
+}
+
+fn lisp_function_declaration(symbol: LispObject) -> Option<String> {
+    let sym = symbol.as_symbol()?;
+    let name: LispStringRef = sym.symbol_name().into();
+    let name = name.to_utf8();
+    // The lisp object maps '_' to '-', so these can't be called from JS.
+    if name.contains('_') || name.is_empty() {
+        return None;
+    }
+
+    let def = unsafe { lisp::remacs_sys::Findirect_function(symbol, lisp::remacs_sys::Qt) };
+    if def.is_nil() {
+        return None;
+    }
+
+    let params = match def.as_cons() {
+        Some(cons) if cons.car() == lisp::remacs_sys::Qmacro => return None,
+        Some(cons) if cons.car() == lisp::remacs_sys::Qautoload => {
+            // (autoload FILE DOC INTERACTIVE TYPE), we don't want to load
+            // the file just to find out the arguments.
+            let kind = cons
+                .cdr()
+                .iter_cars(LispConsEndChecks::off, LispConsCircularChecks::safe)
+                .nth(3)
+                .unwrap_or(lisp::remacs_sys::Qnil);
+            if kind.is_not_nil() {
+                return None;
+            }
+            "...args: LispArg[]".to_string()
+        }
+        _ => lisp_function_parameters(def)?,
+    };
+
+    let key = name.replace('-', "_");
+    if LISP_SPECIAL_FORMS.contains(&key.as_str()) {
+        return None;
+    }
+
+    let key = if is_js_identifier(&key) {
+        key
+    } else {
+        serde_json::Value::String(key).to_string()
+    };
+
+    let mut declaration = String::new();
+    let doc = funcall_or_none(vec![
+        lisp::remacs_sys::Qdocumentation,
+        symbol,
+        lisp::remacs_sys::Qt,
+    ])
+    .and_then(|doc| doc.as_string());
+    if let Some(doc) = doc {
+        declaration.push_str("        /**\n");
+        for line in doc.to_utf8().replace("*/", "*\\/").lines() {
+            declaration.push_str("         * ");
+            declaration.push_str(line);
+            declaration.push('\n');
+        }
+        declaration.push_str("         */\n");
+    }
+
+    declaration.push_str(&format!("        {}({}): any;\n", key, params));
+    Some(declaration)
+}
+
+/// Writes TypeScript declarations for the 'lisp' object to FILE, which
+/// defaults to "lisp.d.ts" in 'user-emacs-directory'. Every function in
+/// the obarray is declared, along with its arguments and documentation,
+/// so code using 'lisp' is type-checked and can be completed by an
+/// editor. Macros and special forms are left out, since they can't be
+/// called from JavaScript.
+///
+/// Sets 'js-lisp-declarations' to FILE, so that TypeScript evaluated by
+/// the eval-ts functions is checked against it. Run this again after
+/// loading new packages to pick up their functions.
+///
+/// Returns FILE.
+#[cfg(feature = "javascript")]
+#[lisp_fn(min = "0", intspec = "FWrite declarations to file: ")]
+pub fn js_generate_declarations(file: LispObject) -> LispObject {
+    let file = if file.is_nil() {
+        unsafe {
+            lisp::remacs_sys::Fexpand_file_name(
+                LispObject::from("lisp.d.ts"),
+                lisp::remacs_sys::Fsymbol_value(lisp::remacs_sys::Quser_emacs_directory),
+            )
+        }
+    } else {
+        unsafe { lisp::remacs_sys::Fexpand_file_name(file, lisp::remacs_sys::Qnil) }
+    };
+    let path: LispStringRef = file.into();
+
+    let mut symbols = obarray_symbols();
+    symbols.sort_by_cached_key(|symbol| {
+        symbol
+            .as_symbol()
+            .map(|sym| {
+                let name: LispStringRef = sym.symbol_name().into();
+                name.to_utf8()
+            })
+            .unwrap_or_default()
+    });
+
+    let mut declarations = String::from(LISP_DECLARATIONS_HEADER);
+    for symbol in symbols {
+        if let Some(declaration) = lisp_function_declaration(symbol) {
+            declarations.push_str(&declaration);
+        }
+    }
+    declarations.push_str(LISP_DECLARATIONS_FOOTER);
+
+    std::fs::write(path.to_utf8(), declarations)
+        .unwrap_or_else(|e| error!("Failed to write {}: {}", path.to_utf8(), e));
+
+    unsafe {
+        lisp::remacs_sys::globals.Vjs_lisp_declarations = file;
+    }
+    file
+}
+
+// If 'js-lisp-declarations' is set, we add a type-only import of it to the
+// end of TypeScript evaluated from Lisp. Being type-only, the import is
+// erased before the code runs, and being at the end, it does not move any
+// of the user's code. It also makes the code a module, so a
+// `declare var lisp: any` in it won't conflict with the declarations.
+fn with_lisp_declarations(mut source: String) -> String {
+    let declarations = unsafe { lisp::remacs_sys::globals.Vjs_lisp_declarations };
+    if let Some(path) = declarations.as_string() {
+        let path = std::path::PathBuf::from(path.to_utf8());
+        if path.is_file() {
+            if let Ok(url) = deno_core::url::Url::from_file_path(&path) {
+                source.push_str(&format!("\nimport type {{}} from \"{}\";\n", url));
+            }
+        }
+    }
+
+    source
+}
+
 // Do NOT call this function, it is just used for macro purposes to
-// generate variables. The user should NOT have direct access to
-// 'js-retain-map' from the scripting engine.
+// generate symbols. Variables that must exist at runtime are defined
+// in syms_of_javascript.
 #[allow(dead_code)]
 fn init_syms() {
-    defvar_lisp!(Vjs_retain_map, "js-retain-map", lisp::remacs_sys::Qnil);
-
     def_lisp_sym!(Qjs_lisp_error, "js-lisp-error");
     def_lisp_sym!(QCallow_net, ":allow-net");
     def_lisp_sym!(QCallow_read, ":allow-read");
     def_lisp_sym!(QCallow_write, ":allow-write");
     def_lisp_sym!(QCallow_run, ":allow-run");
     def_lisp_sym!(QCjs_tick_rate, ":js-tick-rate");
+    def_lisp_sym!(QCjs_event_driven, ":js-event-driven");
     def_lisp_sym!(Qjs_error, "js-error");
+    def_lisp_sym!(Qdisplay_warning, "display-warning");
     def_lisp_sym!(QCjs_error_handler, ":js-error-handler");
     def_lisp_sym!(QCtypescript, ":typescript");
 
@@ -1917,10 +4519,194 @@ fn init_syms() {
     def_lisp_sym!(QCno_check, ":no-check");
     def_lisp_sym!(QCno_remote, ":no-remote");
     def_lisp_sym!(QCloops_per_tick, ":loops-per-tick");
+    def_lisp_sym!(QCvendor_dir, ":vendor-dir");
+    def_lisp_sym!(QCimport_map, ":import-map");
+    def_lisp_sym!(Qjs_import_map, "js-import-map");
+    def_lisp_sym!(Qsafe_local_variable, "safe-local-variable");
+    def_lisp_sym!(Qstring_or_null_p, "string-or-null-p");
+
+    def_lisp_sym!(QCfile, ":file");
+    def_lisp_sym!(QCbuffer, ":buffer");
+    def_lisp_sym!(QCline, ":line");
+    def_lisp_sym!(QCcolumn, ":column");
+    def_lisp_sym!(QCfunction, ":function");
+    def_lisp_sym!(QCerror, ":error");
+    def_lisp_sym!(QCwarning, ":warning");
+    def_lisp_sym!(QCnote, ":note");
+
+    def_lisp_sym!(Qhelp_function_arglist, "help-function-arglist");
+    def_lisp_sym!(Qfunc_arity, "func-arity");
+    def_lisp_sym!(Qdocumentation, "documentation");
+    def_lisp_sym!(Quser_emacs_directory, "user-emacs-directory");
+
+    def_lisp_sym!(QClive, ":live");
+    def_lisp_sym!(QCfree, ":free");
+    def_lisp_sym!(QCcreated, ":created");
+    def_lisp_sym!(QCreleased, ":released");
+
+    def_lisp_sym!(Qspecial_mode, "special-mode");
 
     def_lisp_sym!(Qrun_with_timer, "run-with-timer");
     def_lisp_sym!(Qjs_tick_event_loop, "js-tick-event-loop");
+    def_lisp_sym!(Qjs__wake_event_loop, "js--wake-event-loop");
     def_lisp_sym!(Qeval_expression, "eval-expression");
+    def_lisp_sym!(Qline_number_at_pos, "line-number-at-pos");
+    def_lisp_sym!(Qjs__typecheck_done, "js--typecheck-done");
+    def_lisp_sym!(QCcallback, ":callback");
+}
+
+// Called from emacs.c, unlike init_syms above.
+#[cfg(feature = "javascript")]
+#[no_mangle]
+pub extern "C" fn syms_of_javascript() {
+    // A JavaScript error, including TypeScript compile errors. The
+    // data is (MESSAGE CLASS FRAMES), where CLASS is the name of the
+    // exception's class if known, i.e. "TypeError", and FRAMES is a list
+    // of plists (:file FILE :buffer BUFFER :line LINE :column COLUMN
+    // :function NAME), innermost frame first. BUFFER is only set for
+    // code evaluated by eval-js-buffer or eval-js-region.
+    unsafe {
+        lisp::remacs_sys::Fput(
+            lisp::remacs_sys::Qjs_error,
+            lisp::remacs_sys::Qerror_conditions,
+            list!(lisp::remacs_sys::Qjs_error, lisp::remacs_sys::Qerror),
+        );
+        lisp::remacs_sys::Fput(
+            lisp::remacs_sys::Qjs_error,
+            lisp::remacs_sys::Qerror_message,
+            LispObject::from("JavaScript error"),
+        );
+    }
+
+    // List of directories to search for JavaScript and TypeScript modules
+    // imported with the "emacs:" scheme, i.e. `import { x } from "emacs:foo"`.
+    // These directories are searched before `load-path'. Modules are resolved
+    // when the JavaScript runtime is initialized, call `js-cleanup' to pick up
+    // newly installed modules.
+    defvar_lisp!(Vjs_load_path, "js-load-path", lisp::remacs_sys::Qnil);
+
+    // Path to an import map used by `eval-js-buffer' and `eval-ts-buffer'.
+    // Intended to be set buffer locally, for example in .dir-locals.el,
+    // and takes precedence over the :import-map passed to `js-initialize'.
+    // Relative paths are expanded against the buffer's `default-directory'.
+    // The JavaScript environment is created with the import map of the
+    // buffer that first uses it. Evaluating a buffer with a different
+    // import map signals an error until `js-cleanup' is called.
+    defvar_lisp!(Vjs_import_map, "js-import-map", lisp::remacs_sys::Qnil);
+    unsafe {
+        lisp::remacs_sys::Fput(
+            lisp::remacs_sys::Qjs_import_map,
+            lisp::remacs_sys::Qsafe_local_variable,
+            lisp::remacs_sys::Qstring_or_null_p,
+        );
+    }
+
+    // Path to a TypeScript declaration file for the `lisp' object, as
+    // written by `js-generate-declarations'. When non-nil, TypeScript
+    // evaluated by `eval-ts-buffer', `eval-ts-region' and `eval-js' with
+    // :typescript, and checked by `js-typecheck-buffer', is checked
+    // against it.
+    defvar_lisp!(
+        Vjs_lisp_declarations,
+        "js-lisp-declarations",
+        lisp::remacs_sys::Qnil
+    );
+}
+
+#[test]
+fn test_vendor_path_keeps_module_paths() {
+    let dir = std::path::Path::new("/vendor");
+    let url = deno_core::url::Url::parse("https://deno.land/std/fs/mod.ts").unwrap();
+    assert_eq!(
+        vendor_path(dir, &url, "TypeScript"),
+        std::path::Path::new("/vendor/deno.land/std/fs/mod.ts")
+    );
+
+    // Vendored modules may import each other by relative paths without
+    // extensions.
+    let url = deno_core::url::Url::parse("https://example.com/lib/util").unwrap();
+    assert_eq!(
+        vendor_path(dir, &url, "TypeScript"),
+        std::path::Path::new("/vendor/example.com/lib/util")
+    );
+}
+
+#[test]
+fn test_vendor_path_names_directories() {
+    let dir = std::path::Path::new("/vendor");
+    let url = deno_core::url::Url::parse("http://localhost:8000/lib/").unwrap();
+    assert_eq!(
+        vendor_path(dir, &url, "JavaScript"),
+        std::path::Path::new("/vendor/localhost_8000/lib/index.js")
+    );
+}
+
+#[test]
+fn test_vendor_path_stays_in_dir() {
+    let dir = std::path::Path::new("/vendor");
+    let url = deno_core::url::Url::parse("https://example.com/a/%2E%2E/%2E%2E/b.js").unwrap();
+    assert!(vendor_path(dir, &url, "JavaScript").starts_with("/vendor/example.com"));
+}
+
+#[test]
+fn test_merge_import_map_resolves_relative_urls() {
+    let base = deno_core::url::Url::parse("file:///project/import_map.json").unwrap();
+    let map = json!({
+        "imports": {
+            "lodash": "./vendor/lodash.js",
+            "./src/config.js": "./src/config.dev.js",
+            "/lib/": "../shared/lib/",
+            "https://example.com/mod.js": "/mirror/mod.js"
+        },
+        "scopes": {
+            "./src/": { "lodash": "./vendor/lodash-es.js" }
+        }
+    });
+
+    let mut imports = serde_json::Map::new();
+    let mut scopes = serde_json::Map::new();
+    merge_import_map_json(&map, &base, &mut imports, &mut scopes);
+
+    assert_eq!(
+        serde_json::Value::Object(imports),
+        json!({
+            "lodash": "file:///project/vendor/lodash.js",
+            "file:///project/src/config.js": "file:///project/src/config.dev.js",
+            "file:///lib/": "file:///shared/lib/",
+            "https://example.com/mod.js": "file:///mirror/mod.js"
+        })
+    );
+    assert_eq!(
+        serde_json::Value::Object(scopes),
+        json!({
+            "file:///project/src/": { "lodash": "file:///project/vendor/lodash-es.js" }
+        })
+    );
+}
+
+#[test]
+fn test_merge_import_map_keeps_earlier_entries() {
+    let base = deno_core::url::Url::parse("file:///project/import_map.json").unwrap();
+    let mut imports = serde_json::Map::new();
+    let mut scopes = serde_json::Map::new();
+
+    merge_import_map_json(
+        &json!({ "imports": { "a": "./first.js" } }),
+        &base,
+        &mut imports,
+        &mut scopes,
+    );
+    merge_import_map_json(
+        &json!({ "imports": { "a": "./second.js", "b": "./b.js" } }),
+        &base,
+        &mut imports,
+        &mut scopes,
+    );
+
+    assert_eq!(
+        serde_json::Value::Object(imports),
+        json!({ "a": "file:///project/first.js", "b": "file:///project/b.js" })
+    );
 }
 
 include!(concat!(env!("OUT_DIR"), "/javascript_exports.rs"));
@@ -12,4 +12,12 @@ pub fn js__sweep() -> LispObject {
     Qnil
 }
 
+#[cfg(not(feature = "javascript"))]
+#[no_mangle]
+pub extern "C" fn syms_of_javascript() {}
+
+#[cfg(not(feature = "javascript"))]
+#[no_mangle]
+pub extern "C" fn mark_js_proxies() {}
+
 include!(concat!(env!("OUT_DIR"), "/javascript_stubs_exports.rs"));
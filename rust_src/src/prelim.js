@@ -1,10 +1,26 @@
 (() => {
     let global = (1,eval)('this');
-    let __weak = [];
-    let finalize = global.finalize;
-    delete global.finalize;
     let lisp_json = global.lisp_json;
     delete global.lisp_json;
+    let lisp_remap_locations = global.lisp_remap_locations;
+    delete global.lisp_remap_locations;
+    let lisp_buffer_text = global.lisp_buffer_text;
+    delete global.lisp_buffer_text;
+    let lisp_buffer_bounds = global.lisp_buffer_bounds;
+    delete global.lisp_buffer_bounds;
+    let lisp_buffer_replace = global.lisp_buffer_replace;
+    delete global.lisp_buffer_replace;
+
+    // Code evaluated from a buffer runs as an anonymous module, so
+    // we rewrite stack traces to point at the buffer instead. Deno
+    // has already applied source maps by the time we see the trace.
+    const prepareStackTrace = Error.prepareStackTrace;
+    if (prepareStackTrace) {
+	Error.prepareStackTrace = (error, callSites) => {
+	    const stack = prepareStackTrace(error, callSites);
+	    return typeof stack === 'string' ? lisp_remap_locations(stack) : stack;
+	};
+    }
 
     global.errorFuncs = {
 	eval_js: true,
@@ -147,28 +163,6 @@
 	return result;
     };
 
-    // Hold on you fool, why not use FinalizerRegistry, it
-    // was made for this! That API does not work in Deno
-    // at this time, due to their handling of the DefaultPlatform
-    // Due to this, I opt'd to use weakrefs in a map. Its nice
-    // because I just need to sync that map with a lisp gc root
-    // and my job is done.
-    // This is called from lisp's native garbage_collect function
-    // via the lisp function (js--sweep)
-    global.__sweep = () => {
-        const nw = [];
-        const args = [];
-        __weak.forEach((e) => {
-            let x = e.deref();
-            if (x) {
-                nw.push(e);
-                args.push(x);
-            }
-            finalize.apply(this, args);
-        });
-        __weak = nw;
-    };
-
 
     // Crossing the JS -> Lisp bridge costs time, which we want to save.
     // We can save time by not crossing the bridge if we cache our symbols in a map.
@@ -216,7 +210,7 @@
     const keywordsCached = () => {
 	return new Proxy({}, {
 	    get: function(o, k) {
-		return get_or_cache(k, keywordCache);
+		return get_or_cache(':' + k, keywordCache);
 	    }
 	});
     };
@@ -332,7 +326,6 @@
                 return JSON.parse(lisp_json(result));
             };
 
-            __weak.push(new WeakRef(result));
             retval = result;
         } else {
             retval = JSON.parse(result);
@@ -399,8 +392,82 @@
     const define_key = (...args) => evalForm(lisp.q.define_key, ...args);
     const define_minor_mode = (...args) => evalForm(lisp.q.define_minor_mode, ...args);
 
+    // lisp.async.foo(...args) calls foo from a Lisp timer, outside of
+    // the JavaScript runtime, and returns a Promise for its result.
+    // This means that functions which wait on the user or on processes,
+    // like read-string, will not block JavaScript while they are waiting.
+    // Lisp errors and quits reject the Promise.
+    const asyncCall = (fn, args) => {
+	return new Promise((resolve, reject) => {
+	    const onError = (err) => reject(new Error(lisp.error_message_string(err)));
+	    const call = lisp.list(lisp.q.apply, quote(fn), quote(lisp.list.apply(this, args)));
+	    const form = lisp.list(lisp.q.condition_case, lisp.q.err,
+				   lisp.list(lisp.q.funcall, resolve, call),
+				   lisp.list(lisp.list(lisp.q.error, lisp.q.quit),
+					     lisp.list(lisp.q.funcall, onError, lisp.q.err)));
+	    lisp.run_with_timer(0, lisp.q.nil, lisp.list(lisp.q.lambda, lisp.q.nil, form));
+	});
+    };
+
+    // Resolves with all output PROC produces from now on, once it exits.
+    // The existing filter and sentinel of PROC still run. Our advice is
+    // removed once PROC exits, which lets Lisp's GC release the lambdas.
+    const processOutput = (proc) => {
+	return new Promise((resolve, reject) => {
+	    if (!lisp.processp(proc)) {
+		reject(new Error("process_output expects a process"));
+		return;
+	    }
+
+	    if (!lisp.process_live_p(proc)) {
+		resolve("");
+		return;
+	    }
+
+	    const chunks = [];
+	    // Converted to Lisp once, so that remove-function is passed
+	    // the same functions as add-function.
+	    const [filter, sentinel] = processArgs([
+		(p, output) => { chunks.push(output); },
+		(p, event) => {
+		    if (!lisp.process_live_p(p)) {
+			lisp.eval(lisp.list(lisp.q.remove_function,
+					    lisp.list(lisp.q.process_filter, proc), quote(filter)));
+			lisp.eval(lisp.list(lisp.q.remove_function,
+					    lisp.list(lisp.q.process_sentinel, proc), quote(sentinel)));
+			resolve(chunks.join(''));
+		    }
+		},
+	    ]);
+
+	    lisp.eval(lisp.list(lisp.q.add_function, lisp.k.before,
+				lisp.list(lisp.q.process_filter, proc), quote(filter)));
+	    lisp.eval(lisp.list(lisp.q.add_function, lisp.k.after,
+				lisp.list(lisp.q.process_sentinel, proc), quote(sentinel)));
+	});
+    };
+
+    const asyncForms = {
+	process_output: processOutput,
+    };
+
+    const asyncFuncs = () => {
+	return new Proxy({}, {
+	    get: function(o, k) {
+		if (asyncForms[k]) {
+		    return asyncForms[k];
+		}
+
+		return function() {
+		    return asyncCall(lisp.q[k.replaceAll('_', '-')], Array.from(arguments));
+		};
+	    }
+	});
+    };
+
     const specialForms = {
 	make: makeFuncs,
+	async: asyncFuncs(),
 	q: symbolsCached(),
 	symbols: symbols(),
 	setq,
@@ -443,4 +510,285 @@
 
     const varArgsList = lisp.list(lisp.q['&rest'], lisp.q.alpha);
 
+    // Direct access to buffer text, without going through Lisp strings.
+    // BUFFER may be a buffer, a buffer name, or undefined for the current
+    // buffer. Positions are character positions, as in Lisp, and default
+    // to the accessible portion of the buffer.
+    const buffer = {
+	text: (buffer, start, end) => lisp_buffer_text(buffer, start, end, false),
+	bytes: (buffer, start, end) => lisp_buffer_text(buffer, start, end, true),
+	bounds: (buffer) => lisp_buffer_bounds(buffer),
+	insert: (buffer, pos, text) => {
+	    if (typeof pos !== 'number') {
+		throw new TypeError("emacs.buffer.insert requires a position");
+	    }
+
+	    return lisp_buffer_replace(buffer, pos, pos, text);
+	},
+	delete: (buffer, start, end) => lisp_buffer_replace(buffer, start, end, ""),
+	replace: (buffer, start, end, text) => lisp_buffer_replace(buffer, start, end, text),
+    };
+
+    global.emacs = { buffer };
+
+    // Support for js-repl. Results are returned as strings, so that
+    // js-repl.el never has to interpret JS values.
+    const replInspect = (value) => {
+	if (typeof value === 'object' && value !== null && is_proxy(value)) {
+	    return lisp.prin1_to_string(value);
+	}
+
+	return Deno.inspect(value, { depth: 4 });
+    };
+
+    const replError = (e) => {
+	return "Uncaught " + (e instanceof Error && e.stack ? e.stack : replInspect(e));
+    };
+
+    const AsyncFunction = Object.getPrototypeOf(async function () {}).constructor;
+
+    // Calls F with every word and punctuation character of CODE outside
+    // strings, comments, regular expressions and template text, its
+    // position, and how deeply it is nested in brackets and template
+    // substitutions. A slash starts a regular expression unless it
+    // follows something that ends an expression.
+    const replScan = (code, f) => {
+	const stack = [];
+	let prev = '';
+	let i = 0;
+	const skipQuoted = (quote) => {
+	    for (i++; i < code.length && code[i] !== quote; i++) {
+		if (code[i] === '\\') {
+		    i++;
+		}
+	    }
+	    i++;
+	};
+	const skipTemplate = () => {
+	    for (; i < code.length; i++) {
+		if (code[i] === '\\') {
+		    i++;
+		} else if (code[i] === '`') {
+		    i++;
+		    return;
+		} else if (code[i] === '$' && code[i + 1] === '{') {
+		    stack.push('${');
+		    i += 2;
+		    return;
+		}
+	    }
+	};
+	const skipRegExp = () => {
+	    let inClass = false;
+	    for (i++; i < code.length && code[i] !== '\n'; i++) {
+		if (code[i] === '\\') {
+		    i++;
+		} else if (code[i] === '[') {
+		    inClass = true;
+		} else if (code[i] === ']') {
+		    inClass = false;
+		} else if (code[i] === '/' && !inClass) {
+		    break;
+		}
+	    }
+	    for (i++; i < code.length && /[\w$]/.test(code[i]); i++);
+	};
+
+	while (i < code.length) {
+	    const c = code[i];
+	    if (c === '/' && code[i + 1] === '/') {
+		i = code.indexOf('\n', i);
+		i = i < 0 ? code.length : i;
+	    } else if (c === '/' && code[i + 1] === '*') {
+		i = code.indexOf('*/', i + 2);
+		i = i < 0 ? code.length : i + 2;
+	    } else if (c === '"' || c === "'") {
+		skipQuoted(c);
+		prev = 'string';
+	    } else if (c === '`') {
+		i++;
+		skipTemplate();
+		prev = 'string';
+	    } else if (c === '/' && !/^(?:[\w$]+|\)|\]|string)$/.test(prev)
+		       || c === '/' && /^(?:return|typeof|case|do|else|in|of|new|delete|void|throw|yield|await)$/.test(prev)) {
+		skipRegExp();
+		prev = 'string';
+	    } else if (/[\w$]/.test(c)) {
+		const word = /^[\w$]+/.exec(code.slice(i))[0];
+		f(word, i, stack.length);
+		prev = word;
+		i += word.length;
+	    } else if (/\s/.test(c)) {
+		i++;
+	    } else {
+		if (c === '}' && stack[stack.length - 1] === '${') {
+		    stack.pop();
+		    i++;
+		    skipTemplate();
+		    prev = 'string';
+		    continue;
+		}
+
+		if ('([{'.includes(c)) {
+		    stack.push(c);
+		} else if (')]}'.includes(c)) {
+		    stack.pop();
+		}
+
+		f(c, i, stack.length);
+		prev = c;
+		i++;
+	    }
+	}
+    };
+
+    // The positions of the const, let and var keywords that start a
+    // declaration at the top level of CODE.
+    const replDeclarations = (code, keywords) => {
+	const found = [];
+	let prev = ';';
+	let prevEnd = 0;
+	replScan(code, (token, pos, depth) => {
+	    const statementStart = /^[;{}]$/.test(prev) || code.slice(prevEnd, pos).includes('\n');
+	    if (depth === 0 && statementStart && keywords.includes(token)
+		&& /^\s+[\w$]/.test(code.slice(pos + token.length))) {
+		found.push({ pos, token });
+	    }
+
+	    prev = token;
+	    prevEnd = pos + token.length;
+	});
+	return found;
+    };
+
+    // Evaluates CODE in the global scope, returning the inspected result.
+    // If CODE uses await, it is run in an async function and this returns
+    // an empty string; the result is passed to js-repl--output once the
+    // function completes. Top level declarations are rewritten so that
+    // they outlive the input they were declared in.
+    global.__replEval = (code) => {
+	let usesAwait = false;
+	replScan(code, (token) => usesAwait = usesAwait || token === 'await');
+
+	if (!usesAwait) {
+	    for (const { pos, token } of replDeclarations(code, ['const', 'let']).reverse()) {
+		code = code.slice(0, pos) + 'var' + code.slice(pos + token.length);
+	    }
+
+	    try {
+		return replInspect((0, eval)(code)) + "\n";
+	    } catch (e) {
+		return replError(e) + "\n";
+	    }
+	}
+
+	// Declarations become assignments to globals, since the async
+	// function would keep them to itself.
+	let body = code;
+	for (const { pos, token } of replDeclarations(body, ['const', 'let', 'var']).reverse()) {
+	    const rest = body.slice(pos + token.length);
+	    const assignment = /^\s+([\w$]+)\s*=/.exec(rest);
+	    if (assignment) {
+		body = body.slice(0, pos) + `globalThis.${assignment[1]} =`
+		    + rest.slice(assignment[0].length);
+	    }
+	}
+
+	// The value of the last statement, if it is an expression, is the
+	// result, as it would be for eval.
+	const starts = [0];
+	replScan(body, (token, pos, depth) => {
+	    if (depth === 0 && token === ';') {
+		starts.push(pos + 1);
+	    }
+	});
+
+	let source = body;
+	for (const start of starts.reverse()) {
+	    const last = body.slice(start).trim().replace(/;$/, '');
+	    if (!last) {
+		continue;
+	    }
+
+	    const candidate = `${body.slice(0, start)}\nreturn (${last}\n);`;
+	    try {
+		new AsyncFunction(candidate);
+		source = candidate;
+	    } catch (e) {
+		// Not an expression.
+	    }
+	    break;
+	}
+
+	let promise;
+	try {
+	    promise = new AsyncFunction(source)();
+	} catch (e) {
+	    return replError(e) + "\n";
+	}
+
+	promise.then(replInspect, replError)
+	    .then((output) => lisp.js_repl__output(output + "\n"));
+	return "";
+    };
+
+    // Whether CODE is the start of a valid input, rather than a complete
+    // one or one with a syntax error.
+    global.__replIncomplete = (code) => {
+	try {
+	    new AsyncFunction(code);
+	    return false;
+	} catch (e) {
+	    return e instanceof SyntaxError
+		&& /end of input|Unterminated template/.test(e.message);
+	}
+    };
+
+    // Completes TEXT, a property access like "Deno.re", by enumerating the
+    // properties of the live object it refers to. Returns the completions,
+    // one per line.
+    global.__replComplete = (text) => {
+	const match = /^(?:([\w$.]*)\.)?([\w$]*)$/.exec(text);
+	if (!match) {
+	    return "";
+	}
+
+	const [, path, prefix] = match;
+	let obj = globalThis;
+	try {
+	    if (path) {
+		obj = path.split('.').reduce((o, k) => o[k], globalThis);
+	    }
+	} catch (e) {
+	    return "";
+	}
+
+	if (obj === null || obj === undefined) {
+	    return "";
+	}
+
+	if (obj === lisp) {
+	    const names = lisp.mapconcat(lisp.q.identity,
+					 lisp.all_completions(prefix.replaceAll('_', '-'),
+							      lisp.symbol_value(lisp.q.obarray),
+							      lisp.q.fboundp),
+					 "\n");
+	    return names.split("\n")
+		.filter((name) => name && !name.includes('_'))
+		.map((name) => name.replaceAll('-', '_'))
+		.join("\n");
+	}
+
+	const names = new Set();
+	for (let o = Object(obj); o !== null; o = Object.getPrototypeOf(o)) {
+	    for (const name of Object.getOwnPropertyNames(o)) {
+		if (name.startsWith(prefix) && /^[\w$]+$/.test(name)) {
+		    names.add(name);
+		}
+	    }
+	}
+
+	return Array.from(names).sort().join("\n");
+    };
+
 })();
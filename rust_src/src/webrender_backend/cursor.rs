@@ -37,7 +37,9 @@ pub fn draw_hollow_box_cursor(mut window: LispWindowRef, row: *mut glyph_row) {
     let frame = window.get_frame();
     let output: OutputRef = unsafe { frame.output_data.wr.into() };
 
-    output.canvas().draw_hollow_box_cursor(x, y, width, height);
+    output
+        .row_canvas(window, row)
+        .draw_hollow_box_cursor(x, y, width, height);
 }
 
 pub fn draw_bar_cursor(
@@ -116,5 +118,7 @@ pub fn draw_bar_cursor(
         (x, y, width, height)
     };
 
-    output.canvas().draw_bar_cursor(face, x, y, width, height);
+    output
+        .row_canvas(window, row)
+        .draw_bar_cursor(face, x, y, width, height);
 }
@@ -7,31 +7,33 @@ use lisp::{
     remacs_sys::{wr_display_info, Emacs_GC},
 };
 
-use super::{input::InputProcessor, output::OutputRef, term::TerminalRef};
+use super::{input::InputProcessor, term::TerminalRef, window_server::WindowServer};
 
 pub struct DisplayInfoInner {
     pub terminal: TerminalRef,
     pub focus_frame: LispFrameRef,
 
-    pub output: OutputRef,
+    // Owns the windows of all frames of the display.  Frames on a
+    // headless display render offscreen, without a window.
+    pub window_server: WindowServer,
 
     pub input_processor: InputProcessor,
 
     pub scratch_cursor_gc: Box<Emacs_GC>,
 }
 
-impl Default for DisplayInfoInner {
-    fn default() -> Self {
-        DisplayInfoInner {
+impl DisplayInfoInner {
+    fn new(headless: bool) -> Result<Self, String> {
+        Ok(DisplayInfoInner {
             terminal: TerminalRef::new(ptr::null_mut()),
             focus_frame: LispFrameRef::new(ptr::null_mut()),
-            output: OutputRef::new(ptr::null_mut()),
+            window_server: WindowServer::new(headless)?,
             input_processor: InputProcessor::new(),
             scratch_cursor_gc: Box::new(Emacs_GC {
                 foreground: 0,
                 background: 0,
             }),
-        }
+        })
     }
 }
 
@@ -42,13 +44,13 @@ pub type DisplayInfoInnerRef = ExternalPtr<DisplayInfoInner>;
 pub struct DisplayInfo(wr_display_info);
 
 impl DisplayInfo {
-    pub fn new() -> Self {
+    pub fn new(headless: bool) -> Result<Self, String> {
         let mut df = DisplayInfo::default();
 
-        let inner = Box::new(DisplayInfoInner::default());
+        let inner = Box::new(DisplayInfoInner::new(headless)?);
         df.0.inner = Box::into_raw(inner) as *mut libc::c_void;
 
-        df
+        Ok(df)
     }
 
     pub fn get_inner(&self) -> DisplayInfoInnerRef {
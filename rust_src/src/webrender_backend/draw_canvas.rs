@@ -1,34 +1,61 @@
+use std::slice;
+
 use webrender::{self, api::units::*, api::*};
 
 use super::{
     color::{color_to_pixel, pixel_to_color},
     display_info::DisplayInfoRef,
     font::{WRFont, WRFontRef},
+    fringe,
+    image::{invert_transform, transform_point, PixmapRef, WrPixmap},
     output::OutputRef,
+    scene::RowKey,
     util::HandyDandyRectBuilder,
 };
 
 use lisp::{
-    glyph::GlyphStringRef,
+    glyph::{GlyphRef, GlyphStringRef},
+    lisp::LispObject,
     remacs_sys::{
-        draw_fringe_bitmap_params, draw_glyphs_face, face as Face, face_underline_type, glyph_row,
-        glyph_type, prepare_face_for_display,
+        composition, composition_gstring_from_id, composition_hash_table, composition_method,
+        draw_fringe_bitmap_params, draw_glyphs_face, face as Face, face_box_type,
+        face_underline_type, globals, glyph_row, glyph_row_area, glyph_slice, glyph_type,
+        glyphless_display_method, image_ascent, lglyph_indices, prepare_face_for_display,
+        window_box, window_box_right, Faref, Fchar_table_extra_slot, Fchar_table_p,
+        Lisp_Hash_Table, DEFAULT_TOOL_BAR_BUTTON_RELIEF,
     },
+    window::LispWindowRef,
 };
 
 impl OutputRef {
     pub fn canvas(self) -> DrawCanvas {
-        DrawCanvas::new(self)
+        DrawCanvas::new(self, None)
+    }
+
+    // A canvas drawing into ROW of WINDOW, which is retained and
+    // rebuilt with the row.
+    pub fn row_canvas(self, window: LispWindowRef, row: *const glyph_row) -> DrawCanvas {
+        DrawCanvas::new(self, Some(RowKey::new(window, row)))
     }
 }
 
 pub struct DrawCanvas {
     output: OutputRef,
+    row: Option<RowKey>,
 }
 
 impl DrawCanvas {
-    pub fn new(output: OutputRef) -> DrawCanvas {
-        DrawCanvas { output }
+    pub fn new(output: OutputRef, row: Option<RowKey>) -> DrawCanvas {
+        DrawCanvas { output, row }
+    }
+
+    // Draw with F inside BOUNDS, into the row of this canvas.  OPAQUE
+    // means F paints all of BOUNDS, hiding what was there.
+    fn display<F>(&mut self, bounds: LayoutRect, opaque: bool, f: F)
+    where
+        F: Fn(&mut DisplayListBuilder, SpaceAndClipInfo) + 'static,
+    {
+        self.output.display(self.row, bounds, opaque, f);
     }
 
     pub fn draw_glyph_string(&mut self, mut s: GlyphStringRef) {
@@ -83,111 +110,693 @@ impl DrawCanvas {
             _ => panic!("invalid draw_glyphs_face"),
         }
 
+        // Everything S covers is drawn again, except for the strings
+        // only drawn for their overhangs.
+        if let Some(row) = self.row {
+            if s.for_overlaps() == 0 {
+                let width = s.width.max(s.background_width);
+                let bounds = (s.x, s.y).by(width, Self::visible_height(s));
+
+                self.output.repaint(row, bounds);
+            }
+        }
+
         let type_ = s.first_glyph().type_();
+        let has_box = unsafe { (*s.face).box_() } != face_box_type::FACE_NO_BOX;
+
+        // Draw the box of characters and compositions in advance, so
+        // the text can be drawn over it.
+        let box_drawn = s.for_overlaps() == 0
+            && has_box
+            && (type_ == glyph_type::CHAR_GLYPH || type_ == glyph_type::COMPOSITE_GLYPH);
+
+        if box_drawn {
+            self.draw_glyph_string_background(s);
+            self.draw_glyph_string_box(s);
+        }
 
         match type_ {
             glyph_type::CHAR_GLYPH => self.draw_char_glyph_string(s),
             glyph_type::STRETCH_GLYPH => self.draw_stretch_glyph_string(s),
+            glyph_type::IMAGE_GLYPH => self.draw_image_glyph_string(s),
+            glyph_type::COMPOSITE_GLYPH => self.draw_composite_glyph_string(s),
+            glyph_type::GLYPHLESS_GLYPH => self.draw_glyphless_glyph_string(s),
             _ => {}
         }
+
+        if s.for_overlaps() == 0 {
+            self.draw_glyph_string_underline(s);
+            self.draw_glyph_string_overline(s);
+            self.draw_glyph_string_strike_through(s);
+
+            if has_box && !box_drawn {
+                self.draw_glyph_string_box(s);
+            }
+        }
     }
 
-    fn draw_char_glyph_string(&mut self, s: GlyphStringRef) {
+    fn draw_char_glyph_string(&mut self, mut s: GlyphStringRef) {
+        if s.for_overlaps() != 0 {
+            s.set_background_filled_p(true);
+        } else {
+            self.draw_glyph_string_background(s);
+        }
+
         let font = WRFontRef::new(s.font as *mut WRFont);
 
-        let x_start = s.x;
+        let x_start = Self::text_origin_x(s);
         let y_start = s.y + (font.font.ascent + (s.height - font.font.height) / 2);
 
         let from = 0 as usize;
         let to = s.nchars as usize;
 
-        let gc = s.gc;
+        let glyph_indices: Vec<u32> = s.get_chars()[from..to].iter().map(|c| *c as u32).collect();
 
-        self.output.display(|builder, space_and_clip| {
-            let glyph_indices: Vec<u32> =
-                s.get_chars()[from..to].iter().map(|c| *c as u32).collect();
+        let glyph_dimensions = font.get_glyph_advance_width(glyph_indices.clone());
 
-            let glyph_dimensions = font.get_glyph_advance_width(glyph_indices.clone());
+        let mut glyph_instances: Vec<GlyphInstance> = vec![];
 
-            let mut glyph_instances: Vec<GlyphInstance> = vec![];
+        for (i, index) in glyph_indices.into_iter().enumerate() {
+            let previous_char_width = if i == 0 {
+                0.0
+            } else {
+                let dimension = glyph_dimensions[i - 1];
+                match dimension {
+                    Some(d) => d as f32,
+                    None => 0.0,
+                }
+            };
 
-            for (i, index) in glyph_indices.into_iter().enumerate() {
-                let previous_char_width = if i == 0 {
-                    0.0
-                } else {
-                    let dimension = glyph_dimensions[i - 1];
-                    match dimension {
-                        Some(d) => d as f32,
-                        None => 0.0,
-                    }
-                };
+            let previous_char_start = if i == 0 {
+                x_start as f32
+            } else {
+                glyph_instances[i - 1].point.x
+            };
 
-                let previous_char_start = if i == 0 {
-                    x_start as f32
-                } else {
-                    glyph_instances[i - 1].point.x
-                };
+            let start = previous_char_start + previous_char_width;
+
+            let glyph_instance = GlyphInstance {
+                index,
+                point: LayoutPoint::new(start, y_start as f32),
+            };
+
+            glyph_instances.push(glyph_instance);
+        }
 
-                let start = previous_char_start + previous_char_width;
+        let foreground_color = pixel_to_color(unsafe { (*s.gc).foreground });
 
+        // draw foreground
+        if !glyph_instances.is_empty() {
+            let visible_rect = (s.x, s.y).by(s.width as i32, Self::visible_height(s));
+
+            self.push_glyphs(visible_rect, font, glyph_instances, foreground_color);
+        }
+    }
+
+    // Draw the glyph codes in S's char2b from FROM to TO with the pen
+    // starting at X on baseline Y.  This backs the font driver's `draw',
+    // which compositions and glyphless characters call once per run.
+    pub fn draw_glyph_run(&mut self, s: GlyphStringRef, from: usize, to: usize, x: i32, y: i32) {
+        if to <= from {
+            return;
+        }
+
+        let font = WRFontRef::new(s.font as *mut WRFont);
+
+        let glyph_indices: Vec<u32> =
+            unsafe { slice::from_raw_parts(s.char2b.add(from), to - from) }.to_vec();
+
+        let glyph_dimensions = font.get_glyph_advance_width(glyph_indices.clone());
+
+        let mut pen_x = x as f32;
+        let glyph_instances: Vec<GlyphInstance> = glyph_indices
+            .into_iter()
+            .zip(glyph_dimensions)
+            .map(|(index, advance)| {
                 let glyph_instance = GlyphInstance {
                     index,
-                    point: LayoutPoint::new(start, y_start as f32),
+                    point: LayoutPoint::new(pen_x, y as f32),
                 };
 
-                glyph_instances.push(glyph_instance);
+                pen_x += advance.unwrap_or(0) as f32;
+
+                glyph_instance
+            })
+            .collect();
+
+        let visible_rect = (s.x, s.y).by(s.width as i32, Self::visible_height(s));
+        let foreground_color = pixel_to_color(unsafe { (*s.gc).foreground });
+
+        self.push_glyphs(visible_rect, font, glyph_instances, foreground_color);
+    }
+
+    // Draw GLYPH_INSTANCES in COLOR.  The layers of COLR color glyphs
+    // are drawn one by one in their palette colors instead.
+    fn push_glyphs(
+        &mut self,
+        bounds: LayoutRect,
+        font: WRFontRef,
+        glyph_instances: Vec<GlyphInstance>,
+        color: ColorF,
+    ) {
+        let mut runs: Vec<(Vec<GlyphInstance>, ColorF)> = vec![];
+        let mut run: Vec<GlyphInstance> = vec![];
+
+        for glyph in glyph_instances {
+            match font.color_layers(glyph.index) {
+                Some(layers) => {
+                    if !run.is_empty() {
+                        runs.push((std::mem::take(&mut run), color));
+                    }
+
+                    for (index, layer_color) in layers {
+                        let layer = GlyphInstance {
+                            index,
+                            point: glyph.point,
+                        };
+
+                        runs.push((vec![layer], layer_color.unwrap_or(color)));
+                    }
+                }
+                None => run.push(glyph),
+            }
+        }
+
+        if !run.is_empty() {
+            runs.push((run, color));
+        }
+
+        let font_instance_key = font.font_instance_key;
+
+        self.display(bounds, false, move |builder, space_and_clip| {
+            let info = CommonItemProperties::new(bounds, space_and_clip);
+
+            for (glyphs, color) in &runs {
+                builder.push_text(&info, bounds, glyphs, font_instance_key, *color, None);
             }
+        });
+    }
 
-            let mut s = s.clone();
+    fn draw_composite_glyph_string(&mut self, mut s: GlyphStringRef) {
+        let automatic = unsafe { s.first_glyph().u.cmp.automatic() };
 
-            let x = s.x;
-            let y = s.y;
+        if s.for_overlaps() != 0 || (s.cmp_from > 0 && !automatic) {
+            s.set_background_filled_p(true);
+        } else {
+            self.draw_glyph_string_background(s);
+        }
 
-            let face = s.face;
+        let face = unsafe { &*s.face };
+        let overstrike = face.overstrike();
 
-            let visible_height = if unsafe { (*s.row).mode_line_p() } {
-                unsafe { (*s.row).height }
+        let mut x = Self::text_origin_x(s);
+
+        if s.font_not_found_p() {
+            // The font for the first character of the composition could
+            // not be loaded; show where it would have been.
+            if s.cmp_from == 0 {
+                let color = pixel_to_color(unsafe { (*s.gc).foreground });
+                self.draw_rectangle_outline((x, s.y).by(s.width - 1, s.height - 1), color, 1.0);
+            }
+        } else if !automatic {
+            let cmp = unsafe { &*s.cmp };
+            let y = s.ybase;
+
+            for j in s.cmp_from as usize..(s.cmp_from + s.nchars) as usize {
+                // TAB in a composition means display glyphs with padding
+                // space on the left or right.
+                if Self::composition_glyph(cmp, j) == '\t' as i64 {
+                    continue;
+                }
+
+                let offsets = unsafe { slice::from_raw_parts(cmp.offsets, (j + 1) * 2) };
+                let xx = x + offsets[j * 2] as i32;
+                let yy = y - offsets[j * 2 + 1] as i32;
+
+                self.draw_glyph_run(s, j, j + 1, xx, yy);
+                if overstrike {
+                    self.draw_glyph_run(s, j, j + 1, xx + 1, yy);
+                }
+            }
+        } else {
+            let gstring = unsafe { composition_gstring_from_id(s.cmp_id) }.force_vector();
+            let y = s.ybase;
+            let mut width = 0;
+
+            // Glyphs without an adjustment are drawn in runs; the others
+            // are placed one by one at the offsets the shaper gave them.
+            let (from, to) = (s.cmp_from as usize, s.cmp_to as usize);
+            let mut j = from;
+
+            for i in from..to {
+                let glyph = gstring.get(i + 2).force_vector();
+                let adjustment = glyph.get(lglyph_indices::LGLYPH_IX_ADJUSTMENT as usize);
+
+                if adjustment.is_nil() {
+                    width += glyph
+                        .get(lglyph_indices::LGLYPH_IX_WIDTH as usize)
+                        .as_fixnum_or_error() as i32;
+                    continue;
+                }
+
+                if j < i {
+                    self.draw_glyph_run(s, j, i, x, y);
+                    if overstrike {
+                        self.draw_glyph_run(s, j, i, x + 1, y);
+                    }
+                    x += width;
+                }
+
+                let adjustment = adjustment.force_vector();
+                let xoff = adjustment.get(0).as_fixnum_or_error() as i32;
+                let yoff = adjustment.get(1).as_fixnum_or_error() as i32;
+                let wadjust = adjustment.get(2).as_fixnum_or_error() as i32;
+
+                self.draw_glyph_run(s, i, i + 1, x + xoff, y + yoff);
+                if overstrike {
+                    self.draw_glyph_run(s, i, i + 1, x + xoff + 1, y + yoff);
+                }
+
+                x += wadjust;
+                j = i + 1;
+                width = 0;
+            }
+
+            if j < to {
+                self.draw_glyph_run(s, j, to, x, y);
+                if overstrike {
+                    self.draw_glyph_run(s, j, to, x + 1, y);
+                }
+            }
+        }
+    }
+
+    fn draw_glyphless_glyph_string(&mut self, mut s: GlyphStringRef) {
+        if s.for_overlaps() != 0 {
+            s.set_background_filled_p(true);
+        } else {
+            self.draw_glyph_string_background(s);
+        }
+
+        let mut x = Self::text_origin_x(s);
+
+        let color = pixel_to_color(unsafe { (*s.gc).foreground });
+        let display_table = unsafe { globals.Vglyphless_char_display };
+
+        // The acronyms are drawn from our own buffer of glyph codes, the
+        // way the X backend points char2b at a local array.
+        let mut char2b = [0u32; 8];
+        s.char2b = char2b.as_mut_ptr();
+
+        for i in 0..s.nchars as isize {
+            let glyph = unsafe { &*s.first_glyph.offset(i) };
+            let glyphless = unsafe { &glyph.u.glyphless };
+            let len = glyphless.len() as usize;
+            let ch = glyphless.ch();
+            let method = glyphless.method();
+
+            let label = if method == glyphless_display_method::GLYPHLESS_DISPLAY_ACRONYM {
+                if len > 0 && unsafe { Fchar_table_p(display_table) }.is_not_nil() {
+                    let acronym = if glyphless.for_no_font() {
+                        unsafe { Fchar_table_extra_slot(display_table, LispObject::from(0)) }
+                    } else {
+                        unsafe { Faref(display_table, LispObject::from(ch as i64)) }
+                    };
+
+                    // Entries may be (GRAPHICAL . TEXT), of which
+                    // frames show GRAPHICAL.
+                    let acronym = acronym.as_cons().map_or(acronym, |c| c.car());
+
+                    acronym.as_string().map(|a| a.to_utf8())
+                } else {
+                    None
+                }
+            } else if method == glyphless_display_method::GLYPHLESS_DISPLAY_HEX_CODE {
+                if ch < 0x10000 {
+                    Some(format!("{:04X}", ch))
+                } else {
+                    Some(format!("{:06X}", ch))
+                }
             } else {
-                unsafe { (*s.row).visible_height }
+                None
             };
 
-            // draw background
-            if !s.background_filled_p() {
-                let background_bounds = (x, y).by(s.background_width as i32, visible_height);
+            if let Some(label) = label {
+                let upper_len = (len + 1) / 2;
 
-                let background_color = pixel_to_color(unsafe { (*gc).background } as u64);
+                // The label is ASCII, LEN characters long.
+                let encode_char = unsafe { (*(*s.font).driver).encode_char.unwrap() };
+                for (j, c) in label.bytes().take(len.min(char2b.len())).enumerate() {
+                    char2b[j] = unsafe { encode_char(s.font, c as i32) } & 0xffff;
+                }
 
-                builder.push_rect(
-                    &CommonItemProperties::new(background_bounds, space_and_clip),
-                    background_color,
+                let offsets = unsafe { glyph.slice.glyphless };
+                self.draw_glyph_run(
+                    s,
+                    0,
+                    upper_len,
+                    x + offsets.upper_xoff as i32,
+                    s.ybase + offsets.upper_yoff as i32,
+                );
+                self.draw_glyph_run(
+                    s,
+                    upper_len,
+                    len,
+                    x + offsets.lower_xoff as i32,
+                    s.ybase + offsets.lower_yoff as i32,
                 );
+            }
 
-                s.set_background_filled_p(true);
+            if method != glyphless_display_method::GLYPHLESS_DISPLAY_THIN_SPACE {
+                let bounds = (x, s.ybase - glyph.ascent as i32).by(
+                    glyph.pixel_width as i32 - 1,
+                    (glyph.ascent + glyph.descent) as i32 - 1,
+                );
+                self.draw_rectangle_outline(bounds, color, 1.0);
             }
 
-            let foreground_color = pixel_to_color(unsafe { (*gc).foreground });
+            x += glyph.pixel_width as i32;
+        }
 
-            // draw underline
-            if unsafe { (*face).underline() != face_underline_type::FACE_NO_UNDERLINE } {
-                Self::draw_underline(builder, s, font, foreground_color, face, space_and_clip);
-            }
+        s.char2b = std::ptr::null_mut();
+    }
+
+    fn draw_glyph_string_background(&mut self, mut s: GlyphStringRef) {
+        if s.background_filled_p() {
+            return;
+        }
 
-            // draw foreground
-            if !glyph_instances.is_empty() {
-                let visible_rect = (x, y).by(s.width as i32, visible_height);
+        let box_line_width = std::cmp::max(unsafe { (*s.face).box_horizontal_line_width }, 0);
 
-                builder.push_text(
-                    &CommonItemProperties::new(visible_rect, space_and_clip),
-                    visible_rect,
-                    &glyph_instances,
-                    font.font_instance_key,
+        self.draw_glyph_string_bg_rect(
+            s,
+            s.x,
+            s.y + box_line_width,
+            s.background_width,
+            Self::visible_height(s) - 2 * box_line_width,
+        );
+
+        s.set_background_filled_p(true);
+    }
+
+    // Fill a rectangle with S's background.  A stippled face paints its
+    // bitmap over it in the foreground color, as X's FillOpaqueStippled.
+    fn draw_glyph_string_bg_rect(
+        &mut self,
+        s: GlyphStringRef,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+    ) {
+        let bounds = (x, y).by(width, height);
+
+        let background_color = pixel_to_color(unsafe { (*s.gc).background });
+        let foreground_color = pixel_to_color(unsafe { (*s.gc).foreground });
+
+        let stipple = Self::stipple_pixmap(s).map(|pixmap| {
+            (
+                (pixmap.width as i32, pixmap.height as i32),
+                self.output.image_key_for_pixmap(pixmap, Some(pixmap)),
+            )
+        });
+
+        self.display(bounds, true, move |builder, space_and_clip| {
+            let info = CommonItemProperties::new(bounds, space_and_clip);
+
+            builder.push_rect(&info, background_color);
+
+            if let Some(((tile_width, tile_height), image_key)) = stipple {
+                // Tile from the frame origin, so that neighbouring glyph
+                // strings continue the same pattern.
+                let x0 = x - x.rem_euclid(tile_width);
+                let y0 = y - y.rem_euclid(tile_height);
+
+                builder.push_repeating_image(
+                    &info,
+                    (x0, y0).to(x + width, y + height),
+                    LayoutSize::new(tile_width as f32, tile_height as f32),
+                    LayoutSize::zero(),
+                    ImageRendering::Pixelated,
+                    AlphaType::PremultipliedAlpha,
+                    image_key,
                     foreground_color,
-                    None,
                 );
             }
         });
     }
 
+    // The bitmap of S's face stipple, if S is drawn stippled.
+    fn stipple_pixmap(s: GlyphStringRef) -> Option<PixmapRef> {
+        let stipple = unsafe { (*s.face).stipple };
+
+        if !s.stippled_p() || stipple <= 0 {
+            return None;
+        }
+
+        let output: OutputRef = unsafe { (*s.f).output_data.wr.into() };
+        let mut dpyinfo = DisplayInfoRef::new(output.output.display_info as *mut _);
+
+        let record = unsafe { &*dpyinfo.get_raw().bitmaps.offset(stipple - 1) };
+
+        if record.img.is_null() || record.width <= 0 || record.height <= 0 {
+            None
+        } else {
+            Some(PixmapRef::new(record.img as *mut WrPixmap))
+        }
+    }
+
+    fn draw_glyph_string_underline(&mut self, s: GlyphStringRef) {
+        let face = s.face;
+
+        if s.font.is_null()
+            || unsafe { (*face).underline() } == face_underline_type::FACE_NO_UNDERLINE
+        {
+            return;
+        }
+
+        let font = WRFontRef::new(s.font as *mut WRFont);
+        let foreground_color = pixel_to_color(unsafe { (*s.gc).foreground });
+
+        let (bounds, line_rect, color, style) =
+            Self::underline_geometry(s, font, foreground_color, face);
+
+        self.display(bounds, false, move |builder, space_and_clip| {
+            builder.push_line(
+                &CommonItemProperties::new(bounds, space_and_clip),
+                &line_rect,
+                1.0,
+                LineOrientation::Horizontal,
+                &color,
+                style,
+            );
+        });
+    }
+
+    fn draw_glyph_string_overline(&mut self, s: GlyphStringRef) {
+        let face = unsafe { &*s.face };
+
+        if !face.overline_p() {
+            return;
+        }
+
+        let color = if face.overline_color_defaulted_p() {
+            pixel_to_color(unsafe { (*s.gc).foreground })
+        } else {
+            pixel_to_color(face.overline_color)
+        };
+
+        // Stay inside the top line of the face's box, if it has one.
+        let dy = std::cmp::max(face.box_horizontal_line_width, 0);
+
+        let bounds = (s.x, s.y + dy).by(s.width, 1);
+
+        self.display(bounds, true, move |builder, space_and_clip| {
+            builder.push_rect(&CommonItemProperties::new(bounds, space_and_clip), color);
+        });
+    }
+
+    fn draw_glyph_string_strike_through(&mut self, s: GlyphStringRef) {
+        let face = unsafe { &*s.face };
+
+        if !face.strike_through_p() {
+            return;
+        }
+
+        let color = if face.strike_through_color_defaulted_p() {
+            pixel_to_color(unsafe { (*s.gc).foreground })
+        } else {
+            pixel_to_color(face.strike_through_color)
+        };
+
+        // Strike through the middle of the first glyph rather than of
+        // S, which may be taller when the row holds larger text.
+        let first_glyph = s.first_glyph();
+        let glyph_y = s.ybase - first_glyph.ascent as i32;
+        let glyph_height = (first_glyph.ascent + first_glyph.descent) as i32;
+
+        let bounds = (s.x, glyph_y + (glyph_height - 1) / 2).by(s.width, 1);
+
+        self.display(bounds, true, move |builder, space_and_clip| {
+            builder.push_rect(&CommonItemProperties::new(bounds, space_and_clip), color);
+        });
+    }
+
+    // Draw the box of S's face around it, as X's
+    // `x_draw_glyph_string_box'.  Left and right lines are only drawn at
+    // the ends of the boxed text.
+    fn draw_glyph_string_box(&mut self, s: GlyphStringRef) {
+        let face = unsafe { &*s.face };
+        let row = unsafe { &*s.row };
+        let mut window: LispWindowRef = s.w.into();
+
+        let last_x = if row.full_width_p() && !window.pseudo_window_p() {
+            window.get_frame().internal_border_width() + window.right_pixel_edge()
+        } else {
+            unsafe { window_box_right(window.as_mut(), s.area) }
+        };
+
+        let last_glyph = Self::box_last_glyph(s);
+
+        let vwidth = face.box_vertical_line_width.abs();
+        let hwidth = face.box_horizontal_line_width.abs();
+
+        let left_x = s.x;
+        let right_x = if row.full_width_p() && s.extends_to_end_of_line_p() {
+            last_x - 1
+        } else {
+            std::cmp::min(last_x, s.x + s.background_width) - 1
+        };
+        let top_y = s.y;
+        let bottom_y = top_y + s.height - 1;
+
+        let mouse_face_p = s.hl == draw_glyphs_face::DRAW_MOUSE_FACE;
+        let left_p = s.first_glyph().left_box_line_p()
+            || (mouse_face_p && (s.prev.is_null() || unsafe { (*s.prev).hl } != s.hl));
+        let right_p = last_glyph.right_box_line_p()
+            || (mouse_face_p && (s.next.is_null() || unsafe { (*s.next).hl } != s.hl));
+
+        let shadow_color = if face.use_box_color_for_shadows_p() {
+            face.box_color
+        } else {
+            face.background
+        };
+
+        // WebRender shades outset and inset borders from the base color
+        // the way X derives relief colors, as for image reliefs.
+        let (color, style) = match face.box_() {
+            face_box_type::FACE_SIMPLE_BOX => (face.box_color, BorderStyle::Solid),
+            face_box_type::FACE_RAISED_BOX => (shadow_color, BorderStyle::Outset),
+            _ => (shadow_color, BorderStyle::Inset),
+        };
+
+        let border_side = BorderSide {
+            color: pixel_to_color(color),
+            style,
+        };
+
+        let border_details = BorderDetails::Normal(NormalBorder {
+            top: border_side,
+            right: border_side,
+            bottom: border_side,
+            left: border_side,
+            radius: BorderRadius::uniform(0.0),
+            do_aa: false,
+        });
+
+        let widths = LayoutSideOffsets::new(
+            hwidth as f32,
+            if right_p { vwidth as f32 } else { 0.0 },
+            hwidth as f32,
+            if left_p { vwidth as f32 } else { 0.0 },
+        );
+
+        let bounds = (left_x, top_y).to(right_x + 1, bottom_y + 1);
+
+        self.display(bounds, false, move |builder, space_and_clip| {
+            builder.push_border(
+                &CommonItemProperties::new(bounds, space_and_clip),
+                bounds,
+                widths,
+                border_details.clone(),
+            );
+        });
+    }
+
+    // The glyph of S that may have a right box line.  For static
+    // compositions and images it's the first glyph; for automatic
+    // compositions, the last glyph of the composition in the row.
+    fn box_last_glyph(s: GlyphStringRef) -> GlyphRef {
+        let first_glyph = s.first_glyph();
+
+        if !s.cmp.is_null() || !s.img.is_null() {
+            return first_glyph;
+        }
+
+        if first_glyph.type_() == glyph_type::COMPOSITE_GLYPH
+            && unsafe { first_glyph.u.cmp.automatic() }
+        {
+            let row = unsafe { &*s.row };
+            let area = s.area as usize;
+            let end = unsafe { row.glyphs[area].offset(row.used[area] as isize) };
+
+            let mut last_glyph = s.first_glyph;
+            let mut g = unsafe { last_glyph.offset(1) };
+
+            while g < end
+                && unsafe { (*g).u.cmp.automatic() }
+                && unsafe { (*g).u.cmp.id() } as isize == s.cmp_id as isize
+                && unsafe { (*g).slice.cmp.to } < s.cmp_to
+            {
+                last_glyph = g;
+                g = unsafe { g.offset(1) };
+            }
+
+            return last_glyph.into();
+        }
+
+        unsafe { s.first_glyph.offset(s.nchars as isize - 1) }.into()
+    }
+
+    // If the first glyph of S has a left box line, text starts to the
+    // right of it.
+    fn text_origin_x(s: GlyphStringRef) -> i32 {
+        let face = unsafe { &*s.face };
+
+        if face.box_() != face_box_type::FACE_NO_BOX && s.first_glyph().left_box_line_p() {
+            s.x + std::cmp::max(face.box_vertical_line_width, 0)
+        } else {
+            s.x
+        }
+    }
+
+    fn visible_height(s: GlyphStringRef) -> i32 {
+        if unsafe { (*s.row).mode_line_p() } {
+            unsafe { (*s.row).height }
+        } else {
+            unsafe { (*s.row).visible_height }
+        }
+    }
+
+    // The character of the Nth component of the static composition CMP,
+    // as COMPOSITION_GLYPH in composite.h.
+    fn composition_glyph(cmp: &composition, n: usize) -> i64 {
+        let table = unsafe { &*(composition_hash_table.get_untaggedptr() as *mut Lisp_Hash_Table) };
+        let components = table
+            .key_and_value
+            .force_vector()
+            .get(cmp.hash_index as usize * 2)
+            .force_vector();
+
+        let index = if cmp.method == composition_method::COMPOSITION_WITH_RULE_ALTCHARS {
+            n * 2
+        } else {
+            n
+        };
+
+        components.get(index).as_fixnum_or_error()
+    }
+
     fn draw_stretch_glyph_string(&mut self, mut s: GlyphStringRef) {
         if s.background_filled_p() {
             return;
@@ -195,27 +804,325 @@ impl DrawCanvas {
 
         let visible_height = unsafe { (*s.row).visible_height };
 
-        let background_bounds = (s.x, s.y).by(s.background_width as i32, visible_height);
-        let background_color = pixel_to_color(unsafe { (*s.gc).background } as u64);
+        self.draw_glyph_string_bg_rect(s, s.x, s.y, s.background_width, visible_height);
 
-        self.output.display(|builder, space_and_clip| {
-            builder.push_rect(
-                &CommonItemProperties::new(background_bounds, space_and_clip),
-                background_color,
+        s.set_background_filled_p(true);
+    }
+
+    fn draw_image_glyph_string(&mut self, mut s: GlyphStringRef) {
+        let img = unsafe { &*s.img };
+        let face = unsafe { &*s.face };
+
+        let box_line_hwidth = std::cmp::max(face.box_vertical_line_width, 0);
+        let box_line_vwidth = std::cmp::max(face.box_horizontal_line_width, 0);
+
+        let mut height = s.height;
+        if s.slice.y == 0 {
+            height -= box_line_vwidth;
+        }
+        if s.slice.y + s.slice.height >= img.height {
+            height -= box_line_vwidth;
+        }
+
+        let pixmap = PixmapRef::new(img.pixmap as *mut WrPixmap);
+        let mask = if img.mask.is_null() {
+            None
+        } else {
+            Some(PixmapRef::new(img.mask as *mut WrPixmap))
+        };
+
+        // Fill the background when the image leaves parts of the glyph
+        // uncovered: margins, short slices, masked or translucent pixels.
+        if height > s.slice.height
+            || img.hmargin != 0
+            || img.vmargin != 0
+            || mask.is_some()
+            || pixmap.is_null()
+            || !pixmap.is_opaque()
+            || s.width != s.background_width
+        {
+            let mut x = s.x;
+            if s.first_glyph().left_box_line_p() && s.slice.x == 0 {
+                x += box_line_hwidth;
+            }
+
+            let mut y = s.y;
+            if s.slice.y == 0 {
+                y += box_line_vwidth;
+            }
+
+            self.draw_glyph_string_bg_rect(s, x, y, s.background_width, height);
+
+            s.set_background_filled_p(true);
+        }
+
+        let (x, y) = Self::image_origin(s);
+
+        if pixmap.is_null() {
+            // No pixels to show; draw the outline of the image instead.
+            let color = pixel_to_color(unsafe { (*s.gc).foreground });
+            self.draw_rectangle_outline((x, y).by(s.slice.width, s.slice.height), color, 1.0);
+        } else {
+            self.draw_image(pixmap, mask, s.slice, x, y);
+
+            // Make a block cursor visible on images without a mask, which
+            // would otherwise hide it completely.
+            if s.hl == draw_glyphs_face::DRAW_CURSOR && mask.is_none() {
+                let relief = img.relief.abs();
+                let bounds = (x - relief, y - relief)
+                    .by(s.slice.width + relief * 2, s.slice.height + relief * 2);
+                let color = pixel_to_color(unsafe { (*s.gc).background });
+
+                self.draw_rectangle_outline(bounds, color, 1.0);
+            }
+        }
+
+        if img.relief != 0
+            || s.hl == draw_glyphs_face::DRAW_IMAGE_RAISED
+            || s.hl == draw_glyphs_face::DRAW_IMAGE_SUNKEN
+        {
+            self.draw_image_relief(s, x, y);
+        }
+    }
+
+    // The position of the visible part of S's image, inside the left
+    // box line and the image margins.
+    fn image_origin(mut s: GlyphStringRef) -> (i32, i32) {
+        let img = unsafe { &*s.img };
+        let face = unsafe { &*s.face };
+
+        let mut x = s.x;
+        let mut y = s.ybase - unsafe { image_ascent(s.img, s.face, &mut s.slice) };
+
+        if face.box_() != face_box_type::FACE_NO_BOX
+            && s.first_glyph().left_box_line_p()
+            && s.slice.x == 0
+        {
+            x += std::cmp::max(face.box_vertical_line_width, 0);
+        }
+
+        if s.slice.x == 0 {
+            x += img.hmargin;
+        }
+        if s.slice.y == 0 {
+            y += img.vmargin;
+        }
+
+        (x, y)
+    }
+
+    // Draw the part of PIXMAP selected by SLICE with its top-left corner
+    // at X, Y.  The pixmap's transform (from `:scale', `:rotation' and
+    // friends) is applied through a reference frame, so scaling and
+    // rotation happen on the GPU.
+    fn draw_image(
+        &mut self,
+        pixmap: PixmapRef,
+        mask: Option<PixmapRef>,
+        slice: glyph_slice,
+        x: i32,
+        y: i32,
+    ) {
+        let image_key = self.output.image_key_for_pixmap(pixmap, mask);
+
+        let image_rendering = if pixmap.smoothing {
+            ImageRendering::Auto
+        } else {
+            ImageRendering::Pixelated
+        };
+
+        let m = pixmap.transform;
+        let transform = LayoutTransform::row_major(
+            m[0][0] as f32,
+            m[0][1] as f32,
+            0.0,
+            0.0,
+            m[1][0] as f32,
+            m[1][1] as f32,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            1.0,
+            0.0,
+            m[2][0] as f32,
+            m[2][1] as f32,
+            0.0,
+            1.0,
+        );
+
+        // The slice is given in transformed coordinates; map its corners
+        // back into the pixmap to clip there.
+        let inverse = match invert_transform(&m) {
+            Some(inverse) => inverse,
+            None => return,
+        };
+
+        let corners = [
+            (slice.x, slice.y),
+            (slice.x + slice.width, slice.y),
+            (slice.x, slice.y + slice.height),
+            (slice.x + slice.width, slice.y + slice.height),
+        ]
+        .iter()
+        .map(|&(cx, cy)| transform_point(&inverse, cx as f64, cy as f64))
+        .collect::<Vec<_>>();
+
+        let min_x = corners.iter().map(|c| c.0).fold(f64::INFINITY, f64::min);
+        let min_y = corners.iter().map(|c| c.1).fold(f64::INFINITY, f64::min);
+        let max_x = corners
+            .iter()
+            .map(|c| c.0)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let max_y = corners
+            .iter()
+            .map(|c| c.1)
+            .fold(f64::NEG_INFINITY, f64::max);
+
+        let clip_rect = LayoutRect::new(
+            LayoutPoint::new(min_x as f32, min_y as f32),
+            LayoutSize::new((max_x - min_x) as f32, (max_y - min_y) as f32),
+        );
+
+        let origin = LayoutPoint::new((x - slice.x) as f32, (y - slice.y) as f32);
+        let bounds = (0, 0).by(pixmap.width as i32, pixmap.height as i32);
+
+        // Where the image lands in the frame, for the scene to know what
+        // it covers.
+        let frame_bounds = (x, y).by(slice.width, slice.height);
+
+        self.display(frame_bounds, false, move |builder, space_and_clip| {
+            let spatial_id = builder.push_reference_frame(
+                origin,
+                space_and_clip.spatial_id,
+                TransformStyle::Flat,
+                PropertyBinding::Value(transform),
+                ReferenceFrameKind::Transform,
+            );
+
+            let image_space_and_clip = SpaceAndClipInfo {
+                spatial_id,
+                clip_id: space_and_clip.clip_id,
+            };
+
+            builder.push_image(
+                &CommonItemProperties::new(clip_rect, image_space_and_clip),
+                bounds,
+                image_rendering,
+                AlphaType::PremultipliedAlpha,
+                image_key,
+                ColorF::WHITE,
             );
+
+            builder.pop_reference_frame();
         });
+    }
 
-        s.set_background_filled_p(true);
+    fn draw_image_relief(&mut self, s: GlyphStringRef, x: i32, y: i32) {
+        let img = unsafe { &*s.img };
+        let face = unsafe { &*s.face };
+
+        let (thick, raised_p) = if s.hl == draw_glyphs_face::DRAW_IMAGE_SUNKEN
+            || s.hl == draw_glyphs_face::DRAW_IMAGE_RAISED
+        {
+            let relief = unsafe { globals.tool_bar_button_relief };
+            let thick = if relief >= 0 {
+                relief as i32
+            } else {
+                DEFAULT_TOOL_BAR_BUTTON_RELIEF as i32
+            };
+
+            (thick, s.hl == draw_glyphs_face::DRAW_IMAGE_RAISED)
+        } else {
+            (img.relief.abs(), img.relief > 0)
+        };
+
+        let (mut x0, mut y0) = (x, y);
+        let (mut x1, mut y1) = (x + s.slice.width, y + s.slice.height);
+
+        let mut widths = LayoutSideOffsets::zero();
+
+        if s.slice.x == 0 {
+            x0 -= thick;
+            widths.left = thick as f32;
+        }
+        if s.slice.y == 0 {
+            y0 -= thick;
+            widths.top = thick as f32;
+        }
+        if s.slice.x + s.slice.width == img.width {
+            x1 += thick;
+            widths.right = thick as f32;
+        }
+        if s.slice.y + s.slice.height == img.height {
+            y1 += thick;
+            widths.bottom = thick as f32;
+        }
+
+        // WebRender shades outset and inset borders from the base color
+        // the same way X derives relief colors from the face background.
+        let border_side = BorderSide {
+            color: pixel_to_color(face.background),
+            style: if raised_p {
+                BorderStyle::Outset
+            } else {
+                BorderStyle::Inset
+            },
+        };
+
+        let border_details = BorderDetails::Normal(NormalBorder {
+            top: border_side,
+            right: border_side,
+            bottom: border_side,
+            left: border_side,
+            radius: BorderRadius::uniform(0.0),
+            do_aa: false,
+        });
+
+        let bounds = (x0, y0).to(x1, y1);
+
+        self.display(bounds, false, move |builder, space_and_clip| {
+            builder.push_border(
+                &CommonItemProperties::new(bounds, space_and_clip),
+                bounds,
+                widths,
+                border_details.clone(),
+            );
+        });
     }
 
-    fn draw_underline(
-        builder: &mut DisplayListBuilder,
+    fn draw_rectangle_outline(&mut self, bounds: LayoutRect, color: ColorF, width: f32) {
+        let border_side = BorderSide {
+            color,
+            style: BorderStyle::Solid,
+        };
+
+        let border_details = BorderDetails::Normal(NormalBorder {
+            top: border_side,
+            right: border_side,
+            bottom: border_side,
+            left: border_side,
+            radius: BorderRadius::uniform(0.0),
+            do_aa: false,
+        });
+
+        self.display(bounds, false, move |builder, space_and_clip| {
+            builder.push_border(
+                &CommonItemProperties::new(bounds, space_and_clip),
+                bounds,
+                LayoutSideOffsets::new_all_same(width),
+                border_details.clone(),
+            );
+        });
+    }
+
+    // The clip, line, color and style of the underline of S.
+    fn underline_geometry(
         s: GlyphStringRef,
         font: WRFontRef,
         foreground_color: ColorF,
         face: *mut Face,
-        space_and_clip: SpaceAndClipInfo,
-    ) {
+    ) -> (LayoutRect, LayoutRect, ColorF, LineStyle) {
         let x = s.x;
         let y = s.y;
 
@@ -247,40 +1154,109 @@ impl DrawCanvas {
 
         let visible_height = unsafe { (*s.row).visible_height };
 
-        let info =
-            CommonItemProperties::new((x, y).by(s.width as i32, visible_height), space_and_clip);
+        let bounds = (x, y).by(s.width as i32, visible_height);
 
         let visible_rect = (x, position).by(s.width as i32, thickness);
 
-        builder.push_line(
-            &info,
-            &visible_rect,
-            1.0,
-            LineOrientation::Horizontal,
-            &underline_color,
-            line_type,
-        );
+        (bounds, visible_rect, underline_color, line_type)
     }
 
-    pub fn draw_fringe_bitmap(&mut self, _row: *mut glyph_row, p: *mut draw_fringe_bitmap_params) {
-        let pos_x = unsafe { (*p).bx };
-        let pos_y = unsafe { (*p).by };
+    // Draw the fringe bitmap described by P for ROW of WINDOW, like
+    // `x_draw_fringe_bitmap'.  The bitmap is painted through its own
+    // mask, so overlays leave what is under their cleared bits.
+    pub fn draw_fringe_bitmap(
+        &mut self,
+        mut window: LispWindowRef,
+        row: *mut glyph_row,
+        p: *mut draw_fringe_bitmap_params,
+    ) {
+        let p = unsafe { &*p };
+        let row = unsafe { &*row };
+        let face = unsafe { &*p.face };
+
+        // Clip to the visible part of the row, which may be partially
+        // visible at the top or bottom of the window.
+        let row_clip = {
+            let (mut x, mut width) = (0, 0);
+
+            unsafe {
+                window_box(
+                    window.as_mut(),
+                    glyph_row_area::ANY_AREA,
+                    &mut x,
+                    std::ptr::null_mut(),
+                    &mut width,
+                    std::ptr::null_mut(),
+                )
+            };
 
-        let width = unsafe { (*p).nx };
-        let height = unsafe { (*p).ny };
+            let y = window.frame_pixel_y(std::cmp::max(0, row.y));
 
-        let face = unsafe { (*p).face };
+            (x, y).by(width, row.visible_height)
+        };
 
-        let visible_rect = (pos_x, pos_y).by(width, height);
+        let background_color = pixel_to_color(face.background);
 
-        let background_color = pixel_to_color(unsafe { (*face).background });
+        let mut rects = vec![];
 
-        self.output.display(|builder, space_and_clip| {
-            builder.push_rect(
-                &CommonItemProperties::new(visible_rect, space_and_clip),
-                background_color,
-            );
+        if p.bx >= 0 && !p.overlay_p() {
+            rects.push((p.bx, p.by).by(p.nx, p.ny));
+        }
+
+        let bitmap_rect = (p.x, p.y).by(p.wd, p.h);
+
+        let bitmap = if p.which != 0 {
+            fringe::fringe_bitmap(p.which).filter(|pixmap| pixmap.width > 0 && pixmap.height > 0)
+        } else {
+            None
+        };
+
+        let image = bitmap.map(|pixmap| {
+            let image_key = self.output.image_key_for_pixmap(pixmap, Some(pixmap));
+
+            let color = if p.cursor_p() {
+                if p.overlay_p() {
+                    background_color
+                } else {
+                    self.output.cursor_color
+                }
+            } else {
+                pixel_to_color(face.foreground)
+            };
+
+            // The rows shown start DH rows into the bitmap.
+            let bounds = (p.x, p.y - p.dh).by(pixmap.width as i32, pixmap.height as i32);
+
+            (image_key, bounds, color)
         });
+
+        if image.is_some() && !p.overlay_p() {
+            rects.push(bitmap_rect);
+        }
+
+        for clip in rects.iter().filter_map(|rect| rect.intersection(&row_clip)) {
+            self.display(clip, true, move |builder, space_and_clip| {
+                builder.push_rect(
+                    &CommonItemProperties::new(clip, space_and_clip),
+                    background_color,
+                );
+            });
+        }
+
+        if let Some((image_key, bounds, color)) = image {
+            if let Some(clip) = bitmap_rect.intersection(&row_clip) {
+                self.display(clip, false, move |builder, space_and_clip| {
+                    builder.push_image(
+                        &CommonItemProperties::new(clip, space_and_clip),
+                        bounds,
+                        ImageRendering::Pixelated,
+                        AlphaType::PremultipliedAlpha,
+                        image_key,
+                        color,
+                    );
+                });
+            }
+        }
     }
 
     pub fn draw_vertical_window_border(
@@ -301,7 +1277,7 @@ impl DrawCanvas {
             None => ColorF::BLACK,
         };
 
-        self.output.display(|builder, space_and_clip| {
+        self.display(visible_rect, true, move |builder, space_and_clip| {
             builder.push_rect(
                 &CommonItemProperties::new(visible_rect, space_and_clip),
                 color,
@@ -319,7 +1295,9 @@ impl DrawCanvas {
         y0: i32,
         y1: i32,
     ) {
-        self.output.display(|builder, space_and_clip| {
+        let bounds = (x0, y0).to(x1, y1);
+
+        self.display(bounds, true, move |builder, space_and_clip| {
             if (y1 - y0 > x1 - x0) && (x1 - x0 >= 3) {
                 // A vertical divider, at least three pixels wide: Draw first and
                 // last pixels differently.
@@ -366,7 +1344,7 @@ impl DrawCanvas {
     pub fn clear_area(&mut self, clear_color: ColorF, x: i32, y: i32, width: i32, height: i32) {
         let visible_rect = (x, y).by(width, height);
 
-        self.output.display(|builder, space_and_clip| {
+        self.display(visible_rect, true, move |builder, space_and_clip| {
             builder.push_rect(
                 &CommonItemProperties::new(visible_rect, space_and_clip),
                 clear_color,
@@ -404,25 +1382,10 @@ impl DrawCanvas {
             }
         };
 
-        let copy_rect = DeviceIntRect::new(
-            DeviceIntPoint::new(x as i32, from_y as i32),
-            DeviceIntSize::new(width as i32, height as i32),
-        );
-
-        let image_key = self.output.read_pixels_rgba8_into_image(copy_rect);
-
-        self.output.display(|builder, space_and_clip| {
-            let bounds = (x, to_y).by(width, height);
-
-            builder.push_image(
-                &CommonItemProperties::new(bounds, space_and_clip),
-                bounds,
-                ImageRendering::Auto,
-                AlphaType::PremultipliedAlpha,
-                image_key,
-                ColorF::WHITE,
-            );
-        });
+        // The rows keep their display lists; only where they are shown
+        // changes.
+        self.output
+            .scroll((x, from_y).by(width, height), to_y - from_y);
     }
 
     pub fn draw_hollow_box_cursor(&mut self, x: i32, y: i32, width: i32, height: i32) {
@@ -446,12 +1409,12 @@ impl DrawCanvas {
 
         let bounds = (x, y).by(width, height);
 
-        self.output.display(|builder, space_and_clip| {
+        self.display(bounds, false, move |builder, space_and_clip| {
             builder.push_border(
                 &CommonItemProperties::new(bounds, space_and_clip),
                 bounds,
                 border_widths,
-                border_details,
+                border_details.clone(),
             );
         });
     }
@@ -463,9 +1426,11 @@ impl DrawCanvas {
             self.output.cursor_color
         };
 
-        self.output.display(|builder, space_and_clip| {
+        let bounds = (x, y).by(width, height);
+
+        self.display(bounds, true, move |builder, space_and_clip| {
             builder.push_rect(
-                &CommonItemProperties::new((x, y).by(width, height), space_and_clip),
+                &CommonItemProperties::new(bounds, space_and_clip),
                 cursor_color,
             );
         });
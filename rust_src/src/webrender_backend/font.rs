@@ -1,27 +1,42 @@
-use std::mem::ManuallyDrop;
+use std::{collections::HashMap, convert::TryInto, mem::ManuallyDrop, ptr};
 
 use font_kit::{
     family_name::FamilyName,
+    handle::Handle,
     loaders::default::Font,
     metrics::Metrics,
-    properties::{Properties, Style, Weight},
+    properties::{Properties, Stretch, Style, Weight},
     source::{Source, SystemSource},
 };
 use lazy_static::lazy_static;
 
 use webrender::api::*;
 
-use super::output::OutputRef;
+use super::{
+    font_db::{self, FontDescriptor},
+    output::OutputRef,
+};
 
 use lisp::{
     frame::LispFrameRef,
+    glyph::GlyphStringRef,
     lisp::{ExternalPtr, LispObject},
+    list::{LispConsCircularChecks, LispConsEndChecks},
     multibyte::LispStringRef,
+    obarray::LispObarrayRef,
     remacs_sys::{
-        font, font_driver, font_make_entity, font_make_object, font_metrics, font_property_index,
-        font_style_to_value, frame, glyph_string, Fassoc, Fcdr, Fcons, Fmake_symbol, Fnreverse,
-        Qbold, Qextra_bold, Qextra_light, Qitalic, Qlight, Qnil, Qnormal, Qoblique, Qsemi_bold,
-        Qultra_bold, Qwr, FONT_INVALID_CODE,
+        extract_float, font, font_driver, font_make_entity, font_make_object, font_metrics,
+        font_property_index, font_style_to_value, frame, globals, glyph_string, hb_blob_create,
+        hb_blob_destroy, hb_buffer_add, hb_buffer_content_type_t, hb_buffer_create,
+        hb_buffer_destroy, hb_buffer_get_glyph_infos, hb_buffer_get_length,
+        hb_buffer_guess_segment_properties, hb_buffer_set_content_type, hb_color_t, hb_face_create,
+        hb_face_destroy, hb_feature_t, hb_font_create, hb_font_destroy, hb_font_get_face,
+        hb_font_get_glyph_h_advance, hb_font_set_scale, hb_font_set_variations, hb_font_t,
+        hb_memory_mode_t, hb_ot_color_glyph_get_layers, hb_ot_color_layer_t,
+        hb_ot_color_palette_get_colors, hb_shape, hb_variation_t, hbfont_combining_capability,
+        hbfont_features, hbfont_otf_capability, hbfont_shape, xfree, Fassoc, Fassq, Fcdr, Fcons,
+        Fcopy_sequence, Fnreverse, Fnumberp, QCfeatures, QClang, QCscript, QCvariations, Qascii_0,
+        Qiso10646_1, Qnil, Qunicode_bmp, Qwr, FONT_INVALID_CODE,
     },
     symbol::LispSymbolRef,
 };
@@ -41,11 +56,16 @@ lazy_static! {
         font_driver.list = Some(list);
         font_driver.match_ = Some(match_);
         font_driver.list_family = Some(list_family);
+        font_driver.has_char = Some(has_char);
         font_driver.open_font = Some(open_font);
         font_driver.close_font = Some(close_font);
         font_driver.encode_char = Some(encode_char);
         font_driver.text_extents = Some(text_extents);
         font_driver.draw = Some(draw);
+        font_driver.otf_capability = Some(hbfont_otf_capability);
+        font_driver.shape = Some(hbfont_shape);
+        font_driver.combining_capability = Some(hbfont_combining_capability);
+        font_driver.begin_hb_font = Some(begin_hb_font);
 
         FontDriver(font_driver)
     };
@@ -81,20 +101,53 @@ impl LispFontLike {
         }
     }
 
-    fn get_slant(&self) -> Option<Style> {
-        let slant = self.aref(font_property_index::FONT_SLANT_INDEX);
+    // The numeric value of a style property, as FONT_WEIGHT_NUMERIC and
+    // friends.
+    fn style_numeric(&self, index: font_property_index::Type) -> Option<i64> {
+        self.aref(index).as_fixnum().map(|n| n >> 8)
+    }
+
+    fn get_properties(&self) -> Properties {
+        let mut properties = Properties::new();
+
+        if let Some(weight) = self.style_numeric(font_property_index::FONT_WEIGHT_INDEX) {
+            properties.weight(numeric_to_weight(weight));
+        }
+
+        if let Some(slant) = self.style_numeric(font_property_index::FONT_SLANT_INDEX) {
+            properties.style(numeric_to_slant(slant));
+        }
+
+        if let Some(width) = self.style_numeric(font_property_index::FONT_WIDTH_INDEX) {
+            properties.stretch(Stretch(width as f32 / 100.0));
+        }
+
+        properties
+    }
+
+    fn get_spacing(&self) -> Option<i64> {
+        self.aref(font_property_index::FONT_SPACING_INDEX)
+            .as_fixnum()
+    }
 
-        if slant.is_nil() {
+    fn get_registry(&self) -> LispObject {
+        self.aref(font_property_index::FONT_REGISTRY_INDEX)
+    }
+
+    fn get_extra(&self, key: LispObject) -> LispObject {
+        let extra = self.aref(font_property_index::FONT_EXTRA_INDEX);
+
+        unsafe { Fcdr(Fassq(key, extra)) }
+    }
+
+    fn get_postscript_name(&self) -> Option<String> {
+        let extra = self.aref(font_property_index::FONT_EXTRA_INDEX);
+        let val = unsafe { Fassoc(":postscript-name".into(), extra, Qnil) };
+
+        if val.is_nil() {
             None
         } else {
-            let symbol_or_string = slant.as_symbol_or_string();
-            let string: LispStringRef = symbol_or_string.into();
-            match string.to_string().as_ref() {
-                "Qnormal" => Some(Style::Normal),
-                "Qitalic" => Some(Style::Italic),
-                "Qoblique" => Some(Style::Oblique),
-                _ => Some(Style::Normal),
-            }
+            unsafe { Fcdr(val) }.as_string().map(|s| s.to_string())
         }
     }
 
@@ -131,113 +184,417 @@ extern "C" fn get_cache(f: *mut frame) -> LispObject {
 }
 
 extern "C" fn draw(
-    _s: *mut glyph_string,
-    _from: i32,
-    _to: i32,
-    _x: i32,
-    _y: i32,
+    s: *mut glyph_string,
+    from: i32,
+    to: i32,
+    x: i32,
+    y: i32,
     _with_background: bool,
 ) -> i32 {
-    0
+    let s: GlyphStringRef = s.into();
+    let output: OutputRef = unsafe { (*s.f).output_data.wr.into() };
+
+    output
+        .row_canvas(s.w.into(), s.row)
+        .draw_glyph_run(s, from as usize, to as usize, x, y);
+
+    to - from
 }
 
-extern "C" fn list(frame: *mut frame, font_spec: LispObject) -> LispObject {
-    // FIXME: implment the real list in future
-    match_(frame, font_spec)
+extern "C" fn list(_f: *mut frame, spec: LispObject) -> LispObject {
+    let spec = LispFontLike(spec);
+
+    let query = match FontQuery::from_spec(spec) {
+        Some(query) => query,
+        None => return Qnil,
+    };
+
+    let fonts = spec
+        .get_family()
+        .map_or_else(font_db::all_fonts, |f| font_db::family_fonts(&f));
+
+    let mut list = Qnil;
+
+    for f in fonts
+        .iter()
+        .filter(|f| query.matches_style(f) && query.matches_coverage(f))
+    {
+        list = unsafe { Fcons(make_entity(f, spec), list) };
+    }
+
+    unsafe { Fnreverse(list) }
 }
 
 extern "C" fn match_(_f: *mut frame, spec: LispObject) -> LispObject {
-    let font_spec = LispFontLike(spec);
-    let family = font_spec.get_family();
+    let spec = LispFontLike(spec);
+
+    let query = match FontQuery::from_spec(spec) {
+        Some(query) => query,
+        None => return Qnil,
+    };
+
+    let best_match = |fonts: &[FontDescriptor]| {
+        fonts
+            .iter()
+            .filter(|f| query.matches_coverage(f))
+            .min_by_key(|f| query.distance(f))
+            .map(|f| make_entity(f, spec))
+    };
+
+    match spec.get_family() {
+        Some(family) => best_match(&font_db::family_fonts(&family)),
+        // With no family given, prefer the generic monospace family the
+        // way fontconfig's default configuration does, and look at every
+        // font only when it can't display the characters asked for.
+        None => best_match(&font_db::family_fonts(&FamilyName::Monospace))
+            .or_else(|| best_match(&font_db::all_fonts())),
+    }
+    .unwrap_or(Qnil)
+}
+
+extern "C" fn list_family(_f: *mut frame) -> LispObject {
+    let obarray = LispObarrayRef::global();
+
+    let mut list = Qnil;
+
+    for family in font_db::all_families() {
+        let family: &str = &family;
+        list = unsafe { Fcons(obarray.intern(LispObject::from(family)), list) };
+    }
+
+    unsafe { Fnreverse(list) }
+}
+
+// Return 1 if FONT, an entity or font object, has a glyph for C, 0 if
+// not, and -1 if we can't tell.
+extern "C" fn has_char(font: LispObject, c: i32) -> i32 {
+    let font = LispFontLike(font);
+
+    font.get_postscript_name()
+        .and_then(|name| font_db::font_by_postscript_name(&name))
+        .map_or(-1, |f| font_db::char_coverage(&f, &[c as u32])[0] as i32)
+}
+
+// Make an entity for FONT listed for SPEC.  The entity keeps SPEC's
+// extra properties, so `:features' and `:variations' reach open_font.
+fn make_entity(font: &FontDescriptor, spec: LispFontLike) -> LispObject {
+    let entity: LispFontLike = unsafe { font_make_entity() }.into();
 
-    let fonts = family
-        .and_then(|f| SystemSource::new().select_family_by_generic_name(&f).ok())
-        .map(|f| {
-            f.fonts()
+    let family: &str = &font.family;
+    let postscript_name: &str = &font.postscript_name;
+
+    entity.aset(font_property_index::FONT_TYPE_INDEX, Qwr);
+    entity.aset(
+        font_property_index::FONT_FAMILY_INDEX,
+        LispObarrayRef::global().intern(LispObject::from(family)),
+    );
+    entity.aset(font_property_index::FONT_REGISTRY_INDEX, Qiso10646_1);
+
+    entity.set_style(
+        font_property_index::FONT_WEIGHT_INDEX,
+        weight_to_numeric(font.properties.weight).into(),
+    );
+    entity.set_style(
+        font_property_index::FONT_SLANT_INDEX,
+        slant_to_numeric(font.properties.style).into(),
+    );
+    entity.set_style(
+        font_property_index::FONT_WIDTH_INDEX,
+        width_to_numeric(font.properties.stretch).into(),
+    );
+
+    let spacing = if font.monospace {
+        FONT_SPACING_MONO
+    } else {
+        FONT_SPACING_PROPORTIONAL
+    };
+    entity.aset(font_property_index::FONT_SPACING_INDEX, spacing.into());
+
+    // Every font is scalable.
+    entity.aset(font_property_index::FONT_SIZE_INDEX, (0 as usize).into());
+    entity.aset(
+        font_property_index::FONT_AVGWIDTH_INDEX,
+        (0 as usize).into(),
+    );
+
+    let extra = spec.aref(font_property_index::FONT_EXTRA_INDEX);
+
+    entity.aset(font_property_index::FONT_EXTRA_INDEX, unsafe {
+        Fcons(
+            Fcons(":postscript-name".into(), LispObject::from(postscript_name)),
+            Fcopy_sequence(extra),
+        )
+    });
+
+    entity.as_lisp_object()
+}
+
+const FONT_SPACING_PROPORTIONAL: i64 = 0;
+const FONT_SPACING_MONO: i64 = 100;
+
+// Emacs' numeric weights (see `font-weight-table') for the CSS weights
+// 100 to 900, the same correspondence fontconfig uses.
+const WEIGHT_TABLE: [(f32, i64); 9] = [
+    (100.0, 0),
+    (200.0, 40),
+    (300.0, 50),
+    (400.0, 80),
+    (500.0, 100),
+    (600.0, 180),
+    (700.0, 200),
+    (800.0, 205),
+    (900.0, 210),
+];
+
+fn weight_to_numeric(weight: Weight) -> i64 {
+    WEIGHT_TABLE
+        .iter()
+        .min_by_key(|(css, _)| (css - weight.0).abs() as i64)
+        .map_or(80, |(_, numeric)| *numeric)
+}
+
+fn numeric_to_weight(numeric: i64) -> Weight {
+    WEIGHT_TABLE
+        .iter()
+        .min_by_key(|(_, n)| (n - numeric).abs())
+        .map_or(Weight::NORMAL, |(css, _)| Weight(*css))
+}
+
+fn slant_to_numeric(style: Style) -> i64 {
+    match style {
+        Style::Normal => 100,
+        Style::Italic => 200,
+        Style::Oblique => 210,
+    }
+}
+
+fn numeric_to_slant(numeric: i64) -> Style {
+    if numeric < 150 {
+        Style::Normal
+    } else if numeric < 205 {
+        Style::Italic
+    } else {
+        Style::Oblique
+    }
+}
+
+// Emacs' numeric widths are percentages, like CSS font-stretch.
+fn width_to_numeric(stretch: Stretch) -> i64 {
+    (stretch.0 * 100.0).round() as i64
+}
+
+// Registries other than Unicode, with characters a font must have to
+// be listed for them and the language they imply.  From ftfont.c.
+const REGISTRY_CHARS: &[(&str, &[u32], Option<&str>)] = &[
+    ("iso8859-1", &[0x00A0, 0x00A1, 0x00B4, 0x00BC, 0x00D0], None),
+    ("iso8859-2", &[0x00A0, 0x010E], None),
+    ("iso8859-3", &[0x00A0, 0x0108], None),
+    ("iso8859-4", &[0x00A0, 0x00AF, 0x0128, 0x0156, 0x02C7], None),
+    ("iso8859-5", &[0x00A0, 0x0401], None),
+    ("iso8859-6", &[0x00A0, 0x060C], None),
+    ("iso8859-7", &[0x00A0, 0x0384], None),
+    ("iso8859-8", &[0x00A0, 0x05D0], None),
+    ("iso8859-9", &[0x00A0, 0x00A1, 0x00BC, 0x011E], None),
+    ("iso8859-10", &[0x00A0, 0x00D0, 0x0128, 0x2015], None),
+    ("iso8859-11", &[0x00A0, 0x0E01], None),
+    ("iso8859-13", &[0x00A0, 0x201C], None),
+    ("iso8859-14", &[0x00A0, 0x0174], None),
+    ("iso8859-15", &[0x00A0, 0x00A1, 0x00D0, 0x0152], None),
+    ("iso8859-16", &[0x00A0, 0x0218], None),
+    ("gb2312.1980-0", &[0x4E13], Some("zh-cn")),
+    ("big5-0", &[0x9C21], Some("zh-tw")),
+    ("jisx0208.1983-0", &[0x4E55], Some("ja")),
+    ("ksc5601.1985-0", &[0xAC00], Some("ko")),
+    ("cns11643.1992-1", &[0xFE32], Some("zh-tw")),
+    ("cns11643.1992-2", &[0x4E33, 0x7934], None),
+    ("cns11643.1992-3", &[0x201A9], None),
+    ("cns11643.1992-4", &[0x20057], None),
+    ("cns11643.1992-5", &[0x20000], None),
+    ("cns11643.1992-6", &[0x20003], None),
+    ("cns11643.1992-7", &[0x20055], None),
+    ("gbk-0", &[0x4E06], Some("zh-cn")),
+    ("jisx0212.1990-0", &[0x4E44], None),
+    ("jisx0213.2000-1", &[0xFA10], Some("ja")),
+    ("jisx0213.2000-2", &[0xFA49], None),
+    ("jisx0213.2004-1", &[0x20B9F], None),
+    ("viscii1.1-1", &[0x1EA0, 0x1EAE, 0x1ED2], Some("vi")),
+    ("tis620.2529-1", &[0x0E01], Some("th")),
+    ("microsoft-cp1251", &[0x0401, 0x0490], Some("ru")),
+    ("koi8-r", &[0x0401, 0x2219], Some("ru")),
+    ("mulelao-1", &[0x0E81], Some("lo")),
+    ("unicode-sip", &[0x20000], None),
+];
+
+// Match NAME against an XLFD-style registry PATTERN, where `*' stands
+// for any string and `?' for any character.
+fn registry_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            registry_matches(rest, name)
+                || (!name.is_empty() && registry_matches(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name_rest))) => registry_matches(rest, name_rest),
+        (Some((p, rest)), Some((n, name_rest))) => {
+            p.eq_ignore_ascii_case(n) && registry_matches(rest, name_rest)
+        }
+        _ => false,
+    }
+}
+
+// The language of a locale or language tag, like "ja" for "ja_JP.UTF-8"
+// or "zh" for "zh-cn".
+fn language(tag: &str) -> &str {
+    tag.split(|c| c == '-' || c == '_' || c == '.')
+        .next()
+        .unwrap_or(tag)
+}
+
+// What a font spec asks of a font besides its family.
+struct FontQuery {
+    weight: Option<i64>,
+    slant: Option<i64>,
+    width: Option<i64>,
+    spacing: Option<i64>,
+    // Characters the font must all have, from the registry, `:lang'
+    // and `:script'...
+    required_chars: Vec<u32>,
+    // ...and characters it must have at least one of, when the
+    // script's representative characters are given as a vector.
+    any_chars: Vec<u32>,
+}
+
+impl FontQuery {
+    // Return None when no font could satisfy SPEC.
+    fn from_spec(spec: LispFontLike) -> Option<Self> {
+        let slant = spec.style_numeric(font_property_index::FONT_SLANT_INDEX);
+
+        // Reverse italic and reverse oblique fonts don't exist here.
+        if slant.map_or(false, |s| s < 100) {
+            return None;
+        }
+
+        let mut required_chars = Vec::new();
+        let mut any_chars = Vec::new();
+
+        let registry = spec.get_registry();
+
+        if registry.is_not_nil()
+            && registry != Qascii_0
+            && registry != Qiso10646_1
+            && registry != Qunicode_bmp
+        {
+            let name = LispSymbolRef::from(registry).symbol_name().force_string();
+            let (_, chars, _) = REGISTRY_CHARS
                 .iter()
-                .filter_map(|f| f.load().ok())
-                .collect::<Vec<Font>>()
-        });
-
-    match fonts {
-        Some(fonts) => {
-            let mut list = Qnil;
-
-            for f in fonts {
-                let entity: LispFontLike = unsafe { font_make_entity() }.into();
-
-                // set type
-                entity.aset(font_property_index::FONT_TYPE_INDEX, Qwr);
-
-                let family_name: &str = &f.family_name();
-                // set family
-                entity.aset(font_property_index::FONT_FAMILY_INDEX, unsafe {
-                    Fmake_symbol(LispObject::from(family_name))
-                });
-
-                let weight = f.properties().weight;
-
-                let weight = if weight <= Weight::EXTRA_LIGHT {
-                    Qextra_light
-                } else if weight <= Weight::LIGHT {
-                    Qlight
-                } else if weight <= Weight::NORMAL {
-                    Qnormal
-                } else if weight <= Weight::MEDIUM {
-                    Qsemi_bold
-                } else if weight <= Weight::SEMIBOLD {
-                    Qsemi_bold
-                } else if weight <= Weight::BOLD {
-                    Qbold
-                } else if weight <= Weight::EXTRA_BOLD {
-                    Qextra_bold
-                } else if weight <= Weight::BLACK {
-                    Qultra_bold
-                } else {
-                    Qultra_bold
-                };
-
-                // set weight
-                entity.set_style(font_property_index::FONT_WEIGHT_INDEX, weight);
-
-                let slant = match f.properties().style {
-                    Style::Normal => Qnormal,
-                    Style::Italic => Qitalic,
-                    Style::Oblique => Qoblique,
-                };
-
-                // set slant
-                entity.set_style(font_property_index::FONT_SLANT_INDEX, slant);
-
-                // set width
-                entity.set_style(font_property_index::FONT_WIDTH_INDEX, (0 as usize).into());
-
-                // set size
-                entity.aset(font_property_index::FONT_SIZE_INDEX, (0 as usize).into());
-
-                if let Some(postscript_name) = f.postscript_name() {
-                    let postscript_name: &str = &postscript_name;
-                    // set name
-                    entity.aset(font_property_index::FONT_EXTRA_INDEX, unsafe {
-                        Fcons(
-                            Fcons(":postscript-name".into(), LispObject::from(postscript_name)),
-                            Qnil,
-                        )
-                    });
-                }
+                .find(|(r, _, _)| registry_matches(name.as_slice(), r.as_bytes()))?;
+
+            required_chars.extend_from_slice(chars);
+        }
 
-                list = unsafe { Fcons(entity.as_lisp_object(), list) }
+        let lang = spec.get_extra(QClang);
+        let langs: Vec<LispObject> = if lang.is_cons() {
+            lang.iter_cars(LispConsEndChecks::off, LispConsCircularChecks::safe)
+                .collect()
+        } else if lang.is_not_nil() {
+            vec![lang]
+        } else {
+            vec![]
+        };
+
+        for lang in langs.into_iter().filter(|l| l.is_symbol()) {
+            let name = LispSymbolRef::from(lang)
+                .symbol_name()
+                .force_string()
+                .to_string();
+
+            // An exact match first, so that zh-tw isn't taken for zh-cn.
+            let lang_chars = |matches: &dyn Fn(&str) -> bool| {
+                REGISTRY_CHARS
+                    .iter()
+                    .find(|(_, _, l)| l.map_or(false, |l| matches(l)))
+                    .map(|(_, chars, _)| *chars)
+            };
+
+            let tag = name.replace('_', "-");
+
+            if let Some(chars) = lang_chars(&|l| l.eq_ignore_ascii_case(&tag))
+                .or_else(|| lang_chars(&|l| language(l).eq_ignore_ascii_case(language(&tag))))
+            {
+                required_chars.extend_from_slice(chars);
             }
+        }
+
+        let script = spec.get_extra(QCscript);
+
+        if script.is_not_nil() && required_chars.is_empty() {
+            let chars = unsafe { Fcdr(Fassq(script, globals.Vscript_representative_chars)) };
+
+            let as_char = |c: LispObject| c.as_natnum().map(|c| c as u32);
 
-            unsafe { Fnreverse(list) }
+            if let Some(vector) = chars.as_vector() {
+                any_chars.extend(vector.iter().filter_map(as_char));
+            } else if chars.is_cons() {
+                required_chars.extend(
+                    chars
+                        .iter_cars(LispConsEndChecks::off, LispConsCircularChecks::safe)
+                        .filter_map(as_char),
+                );
+            }
         }
-        None => Qnil,
+
+        Some(FontQuery {
+            weight: spec.style_numeric(font_property_index::FONT_WEIGHT_INDEX),
+            slant,
+            width: spec.style_numeric(font_property_index::FONT_WIDTH_INDEX),
+            spacing: spec.get_spacing(),
+            required_chars,
+            any_chars,
+        })
     }
-}
 
-#[allow(unused_variables)]
-extern "C" fn list_family(f: *mut frame) -> LispObject {
-    unimplemented!();
+    fn matches_style(&self, font: &FontDescriptor) -> bool {
+        let properties = &font.properties;
+
+        self.weight
+            .map_or(true, |w| w == weight_to_numeric(properties.weight))
+            && self
+                .slant
+                .map_or(true, |s| s == slant_to_numeric(properties.style))
+            && self
+                .width
+                .map_or(true, |w| w == width_to_numeric(properties.stretch))
+    }
+
+    fn matches_coverage(&self, font: &FontDescriptor) -> bool {
+        let spacing_ok = match self.spacing {
+            Some(FONT_SPACING_PROPORTIONAL) => !font.monospace,
+            Some(_) => font.monospace,
+            None => true,
+        };
+
+        spacing_ok
+            && font_db::char_coverage(font, &self.required_chars)
+                .into_iter()
+                .all(|has| has)
+            && (self.any_chars.is_empty()
+                || font_db::char_coverage(font, &self.any_chars)
+                    .into_iter()
+                    .any(|has| has))
+    }
+
+    // How far FONT's style is from the one asked for, with width
+    // compared first, then weight, then slant, as in the default
+    // `font-sort-order'.
+    fn distance(&self, font: &FontDescriptor) -> (i64, i64, i64) {
+        let properties = &font.properties;
+
+        (
+            (self.width.unwrap_or(100) - width_to_numeric(properties.stretch)).abs(),
+            (self.weight.unwrap_or(80) - weight_to_numeric(properties.weight)).abs(),
+            (self.slant.unwrap_or(100) - slant_to_numeric(properties.style)).abs(),
+        )
+    }
 }
 
 #[repr(C)]
@@ -253,6 +610,24 @@ pub struct WRFont {
     pub font_instance_key: FontInstanceKey,
 
     pub output: OutputRef,
+
+    // Index of the face within the font file, for collections.
+    pub font_index: u32,
+
+    // Created on the first call to `begin_hb_font', or when the font is
+    // opened if it has variations or color layers.
+    pub hb_font: *mut hb_font_t,
+
+    // From the `:features' and `:variations' font properties.
+    pub features: ManuallyDrop<Vec<hb_feature_t>>,
+    pub variations: ManuallyDrop<Vec<FontVariation>>,
+
+    // Glyphs characters map to once `features' are applied.
+    pub substitutions: ManuallyDrop<HashMap<u32, u32>>,
+
+    // Whether the font has a COLR table of layered color glyphs.
+    // Bitmap color glyphs (CBDT, sbix) are rasterized by WebRender.
+    pub has_color_layers: bool,
 }
 
 impl WRFont {
@@ -267,6 +642,17 @@ impl WRFont {
 
         let scale = pixel_size as f32 / font_metrics.units_per_em as f32;
 
+        // font-kit only knows the default instance of a variable font.
+        if !self.variations.is_empty() && !self.hb_font.is_null() {
+            return glyph_indices
+                .into_iter()
+                .map(|i| {
+                    let advance = unsafe { hb_font_get_glyph_h_advance(self.hb_font, i) };
+                    Some((advance as f32 * scale).round() as i32)
+                })
+                .collect();
+        }
+
         glyph_indices
             .into_iter()
             .map(|i| {
@@ -279,8 +665,187 @@ impl WRFont {
     }
 }
 
+impl WRFont {
+    // HarfBuzz reads the font tables itself from a copy of the font
+    // data.  hb_font_create installs the OpenType functions, so glyph
+    // lookup and advances agree with the rest of the driver.
+    fn hb_font(&mut self) -> *mut hb_font_t {
+        if !self.hb_font.is_null() {
+            return self.hb_font;
+        }
+
+        let data = match self.font_backend.copy_font_data() {
+            Some(data) => data,
+            None => return ptr::null_mut(),
+        };
+
+        let units_per_em = self.font_backend.metrics().units_per_em as i32;
+
+        let variations: Vec<hb_variation_t> = self
+            .variations
+            .iter()
+            .map(|v| hb_variation_t {
+                tag: v.tag,
+                value: v.value,
+            })
+            .collect();
+
+        unsafe {
+            let blob = hb_blob_create(
+                data.as_ptr() as *const libc::c_char,
+                data.len() as u32,
+                hb_memory_mode_t::HB_MEMORY_MODE_DUPLICATE,
+                ptr::null_mut(),
+                None,
+            );
+            let face = hb_face_create(blob, self.font_index);
+
+            self.hb_font = hb_font_create(face);
+            hb_font_set_scale(self.hb_font, units_per_em, units_per_em);
+
+            if !variations.is_empty() {
+                hb_font_set_variations(self.hb_font, variations.as_ptr(), variations.len() as u32);
+            }
+
+            hb_face_destroy(face);
+            hb_blob_destroy(blob);
+        }
+
+        self.hb_font
+    }
+
+    // The glyph C maps to after the font's `:features' are applied,
+    // found by shaping C on its own.  This is how single substitutions
+    // like `ss01' or `zero' reach text that isn't composed.
+    fn substitute_glyph(&mut self, c: u32) -> Option<u32> {
+        if let Some(glyph) = self.substitutions.get(&c) {
+            return Some(*glyph);
+        }
+
+        let hb_font = self.hb_font();
+
+        if hb_font.is_null() {
+            return None;
+        }
+
+        let glyph = unsafe {
+            let buffer = hb_buffer_create();
+
+            hb_buffer_set_content_type(
+                buffer,
+                hb_buffer_content_type_t::HB_BUFFER_CONTENT_TYPE_UNICODE,
+            );
+            hb_buffer_add(buffer, c, 0);
+            hb_buffer_guess_segment_properties(buffer);
+            hb_shape(
+                hb_font,
+                buffer,
+                self.features.as_ptr(),
+                self.features.len() as u32,
+            );
+
+            let glyph = if hb_buffer_get_length(buffer) == 1 {
+                Some((*hb_buffer_get_glyph_infos(buffer, ptr::null_mut())).codepoint)
+            } else {
+                None
+            };
+
+            hb_buffer_destroy(buffer);
+            glyph
+        };
+
+        let glyph = glyph.filter(|g| *g != 0)?;
+
+        self.substitutions.insert(c, glyph);
+
+        Some(glyph)
+    }
+
+    // The layers of GLYPH if it is a COLR color glyph, bottom first, each
+    // with its color.  None stands for the text's foreground color.
+    pub fn color_layers(&self, glyph: GlyphIndex) -> Option<Vec<(GlyphIndex, Option<ColorF>)>> {
+        if !self.has_color_layers || self.hb_font.is_null() {
+            return None;
+        }
+
+        let face = unsafe { hb_font_get_face(self.hb_font) };
+
+        let mut count = unsafe {
+            hb_ot_color_glyph_get_layers(face, glyph, 0, ptr::null_mut(), ptr::null_mut())
+        };
+
+        if count == 0 {
+            return None;
+        }
+
+        let mut layers = vec![
+            hb_ot_color_layer_t {
+                glyph: 0,
+                color_index: 0,
+            };
+            count as usize
+        ];
+        unsafe { hb_ot_color_glyph_get_layers(face, glyph, 0, &mut count, layers.as_mut_ptr()) };
+
+        let mut palette_size =
+            unsafe { hb_ot_color_palette_get_colors(face, 0, 0, ptr::null_mut(), ptr::null_mut()) };
+        let mut palette: Vec<hb_color_t> = vec![0; palette_size as usize];
+        unsafe {
+            hb_ot_color_palette_get_colors(face, 0, 0, &mut palette_size, palette.as_mut_ptr())
+        };
+
+        Some(
+            layers
+                .into_iter()
+                .take(count as usize)
+                .map(|layer| {
+                    // hb_color_t is BGRA, blue in the high byte.
+                    let color = palette.get(layer.color_index as usize).map(|c| {
+                        let channel = |shift: u32| ((c >> shift) & 0xff) as f32 / 255.0;
+                        ColorF::new(channel(8), channel(16), channel(24), channel(0))
+                    });
+
+                    (layer.glyph, color)
+                })
+                .collect(),
+        )
+    }
+}
+
 pub type WRFontRef = ExternalPtr<WRFont>;
 
+// Read the `:variations' font property, an alist of (AXIS . VALUE)
+// where AXIS is a four-letter tag like `wght' or "wdth".
+fn font_variations(font: LispFontLike) -> Vec<FontVariation> {
+    let val = font.get_extra(QCvariations);
+
+    if !val.is_cons() {
+        return vec![];
+    }
+
+    val.iter_cars(LispConsEndChecks::off, LispConsCircularChecks::safe)
+        .filter_map(|axis| {
+            let (tag, value) = axis.as_cons()?.into();
+
+            let tag = if tag.is_symbol() {
+                LispSymbolRef::from(tag).symbol_name()
+            } else {
+                tag
+            };
+            let tag = tag.as_string()?.to_string();
+
+            if tag.len() != 4 || unsafe { Fnumberp(value) }.is_nil() {
+                return None;
+            }
+
+            Some(FontVariation {
+                tag: u32::from_be_bytes(tag.as_bytes().try_into().ok()?),
+                value: unsafe { extract_float(value) } as f32,
+            })
+        })
+        .collect()
+}
+
 extern "C" fn open_font(frame: *mut frame, font_entity: LispObject, pixel_size: i32) -> LispObject {
     let font_entity: LispFontLike = font_entity.into();
 
@@ -323,10 +888,8 @@ extern "C" fn open_font(frame: *mut frame, font_entity: LispObject, pixel_size:
     let font = if val.is_nil() {
         let family = font_entity.get_family().unwrap();
 
-        let slant = font_entity.get_slant().unwrap();
-
         SystemSource::new()
-            .select_best_match(&[family], &Properties::new().style(slant))
+            .select_best_match(&[family], &font_entity.get_properties())
             .unwrap()
     } else {
         let postscript_name = unsafe { Fcdr(val) }.as_string().unwrap().to_string();
@@ -348,10 +911,38 @@ extern "C" fn open_font(frame: *mut frame, font_entity: LispObject, pixel_size:
 
     wr_font.output = output;
     wr_font.font_backend = ManuallyDrop::new(font.load().unwrap());
+    wr_font.font_index = match &font {
+        Handle::Path { font_index, .. } | Handle::Memory { font_index, .. } => *font_index,
+    };
+    wr_font.hb_font = ptr::null_mut();
+
+    let mut features: *mut hb_feature_t = ptr::null_mut();
+    let nfeatures = unsafe { hbfont_features(font_object.as_lisp_object(), &mut features) };
+    wr_font.features = ManuallyDrop::new(if nfeatures > 0 {
+        unsafe { std::slice::from_raw_parts(features, nfeatures as usize) }.to_vec()
+    } else {
+        vec![]
+    });
+    unsafe { xfree(features as *mut libc::c_void) };
+
+    wr_font.variations = ManuallyDrop::new(font_variations(font_object));
+    wr_font.substitutions = ManuallyDrop::new(HashMap::new());
+
+    wr_font.has_color_layers = wr_font
+        .font_backend
+        .load_font_table(u32::from_be_bytes(*b"COLR"))
+        .is_some();
+
+    // Advances of variable instances and color layers come from
+    // HarfBuzz, so it is needed right away for those.
+    if !wr_font.variations.is_empty() || wr_font.has_color_layers {
+        wr_font.hb_font();
+    }
 
     // Create font key in webrender.
     let font_key = output.add_font(&font);
-    wr_font.font_instance_key = output.add_font_instance(font_key, pixel_size as i32);
+    wr_font.font_instance_key =
+        output.add_font_instance(font_key, pixel_size as i32, wr_font.variations.to_vec());
 
     let font_metrics = wr_font.font_backend.metrics();
     let font_advance = wr_font.font_backend.advance(33).unwrap();
@@ -376,14 +967,43 @@ extern "C" fn open_font(frame: *mut frame, font_entity: LispObject, pixel_size:
     font_object.as_lisp_object()
 }
 
-extern "C" fn close_font(_font: *mut font) {}
+extern "C" fn close_font(font: *mut font) {
+    let mut font = WRFontRef::new(font as *mut WRFont);
+
+    if !font.hb_font.is_null() {
+        unsafe { hb_font_destroy(font.hb_font) };
+        font.hb_font = ptr::null_mut();
+    }
+
+    unsafe {
+        ManuallyDrop::drop(&mut font.features);
+        ManuallyDrop::drop(&mut font.variations);
+        ManuallyDrop::drop(&mut font.substitutions);
+    }
+}
+
+extern "C" fn begin_hb_font(font: *mut font, position_unit: *mut f64) -> *mut hb_font_t {
+    let mut font = WRFontRef::new(font as *mut WRFont);
+
+    let units_per_em = font.font_backend.metrics().units_per_em as i32;
+
+    unsafe { *position_unit = font.font.pixel_size as f64 / units_per_em as f64 };
+
+    font.hb_font()
+}
 
 extern "C" fn encode_char(font: *mut font, c: i32) -> u32 {
-    let font = WRFontRef::new(font as *mut WRFont);
+    let mut font = WRFontRef::new(font as *mut WRFont);
+
+    let glyph = std::char::from_u32(c as u32).and_then(|c| font.glyph_for_char(c));
+
+    if glyph.is_some() && !font.features.is_empty() {
+        if let Some(glyph) = font.substitute_glyph(c as u32) {
+            return glyph;
+        }
+    }
 
-    std::char::from_u32(c as u32)
-        .and_then(|c| font.glyph_for_char(c))
-        .unwrap_or(FONT_INVALID_CODE)
+    glyph.unwrap_or(FONT_INVALID_CODE)
 }
 
 #[allow(unused_variables)]
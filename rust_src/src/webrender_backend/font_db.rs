@@ -0,0 +1,235 @@
+//! The fonts installed on the system, as the font driver sees them.
+//!
+//! Loading a font to learn its properties means reading the whole
+//! file, so descriptors are collected once per family (or once for
+//! every font, when a spec names no family, on a thread of its own)
+//! and kept for the session.  Character coverage is cached the same
+//! way, keyed by PostScript name; only the last few fonts loaded to
+//! find it are kept.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    rc::Rc,
+    thread::JoinHandle,
+};
+
+use font_kit::{
+    family_name::FamilyName,
+    font::Font,
+    handle::Handle,
+    properties::Properties,
+    source::{Source, SystemSource},
+};
+
+#[derive(Clone)]
+pub struct FontDescriptor {
+    pub family: String,
+    pub postscript_name: String,
+    pub properties: Properties,
+    pub monospace: bool,
+    pub handle: Handle,
+}
+
+impl FontDescriptor {
+    fn from_handle(handle: Handle) -> Option<Self> {
+        let font = handle.load().ok()?;
+
+        Some(FontDescriptor {
+            family: font.family_name(),
+            postscript_name: font.postscript_name()?,
+            properties: font.properties(),
+            monospace: font.is_monospace(),
+            handle,
+        })
+    }
+}
+
+#[derive(Default)]
+struct FontDatabase {
+    // Keyed by the family name as given in the font spec, downcased.
+    families: HashMap<String, Rc<Vec<FontDescriptor>>>,
+    all: Option<Rc<Vec<FontDescriptor>>>,
+    // Loading the descriptors of every font, see preload_all_fonts.
+    all_loader: Option<JoinHandle<Vec<FontDescriptor>>>,
+    by_postscript_name: HashMap<String, FontDescriptor>,
+    coverage: HashMap<(String, u32), bool>,
+    // The fonts loaded last, most recent first, keyed by PostScript
+    // name.  None for fonts that failed to load.
+    loaded: VecDeque<(String, Option<Rc<Font>>)>,
+}
+
+// How many fonts char_coverage keeps loaded.
+const LOADED_FONTS: usize = 8;
+
+thread_local! {
+    static FONT_DATABASE: RefCell<FontDatabase> = RefCell::new(FontDatabase::default());
+}
+
+impl FontDatabase {
+    fn remember(&mut self, fonts: Vec<FontDescriptor>) -> Rc<Vec<FontDescriptor>> {
+        for f in &fonts {
+            self.by_postscript_name
+                .entry(f.postscript_name.clone())
+                .or_insert_with(|| f.clone());
+        }
+
+        Rc::new(fonts)
+    }
+
+    fn load(&mut self, font: &FontDescriptor) -> Option<Rc<Font>> {
+        let position = self
+            .loaded
+            .iter()
+            .position(|(name, _)| *name == font.postscript_name);
+
+        let entry = match position.and_then(|i| self.loaded.remove(i)) {
+            Some(entry) => entry,
+            None => (
+                font.postscript_name.clone(),
+                font.handle.load().ok().map(Rc::new),
+            ),
+        };
+
+        let loaded = entry.1.clone();
+
+        self.loaded.push_front(entry);
+        self.loaded.truncate(LOADED_FONTS);
+
+        loaded
+    }
+}
+
+fn load_descriptors(handles: Vec<Handle>) -> Vec<FontDescriptor> {
+    handles
+        .into_iter()
+        .filter_map(FontDescriptor::from_handle)
+        .collect()
+}
+
+// The fonts of FAMILY, which may be one of the generic names Emacs
+// uses ("Monospace", "Sans Serif", ...).
+pub fn family_fonts(family: &FamilyName) -> Rc<Vec<FontDescriptor>> {
+    let key = match family {
+        FamilyName::Title(name) => name.to_lowercase(),
+        FamilyName::Serif => "serif".to_string(),
+        FamilyName::SansSerif => "sans serif".to_string(),
+        FamilyName::Monospace => "monospace".to_string(),
+        FamilyName::Cursive => "cursive".to_string(),
+        FamilyName::Fantasy => "fantasy".to_string(),
+    };
+
+    if let Some(fonts) = FONT_DATABASE.with(|db| db.borrow().families.get(&key).cloned()) {
+        return fonts;
+    }
+
+    let source = SystemSource::new();
+    let handle = match family {
+        FamilyName::Title(name) => source.select_family_by_name(name),
+        generic => source.select_family_by_generic_name(generic),
+    };
+
+    let fonts = handle
+        .map(|f| load_descriptors(f.fonts().to_vec()))
+        .unwrap_or_default();
+
+    FONT_DATABASE.with(|db| {
+        let mut db = db.borrow_mut();
+        let fonts = db.remember(fonts);
+
+        db.families.insert(key, fonts.clone());
+        fonts
+    })
+}
+
+fn load_all_descriptors() -> Vec<FontDescriptor> {
+    SystemSource::new()
+        .all_fonts()
+        .map(load_descriptors)
+        .unwrap_or_default()
+}
+
+// Start loading the descriptors of every font on a thread of its own,
+// as that reads every font file.  Done when a display is opened, so
+// that the first spec naming no family finds them ready.
+pub fn preload_all_fonts() {
+    FONT_DATABASE.with(|db| {
+        let mut db = db.borrow_mut();
+
+        if db.all.is_none() && db.all_loader.is_none() {
+            db.all_loader = Some(std::thread::spawn(load_all_descriptors));
+        }
+    });
+}
+
+pub fn all_fonts() -> Rc<Vec<FontDescriptor>> {
+    if let Some(fonts) = FONT_DATABASE.with(|db| db.borrow().all.clone()) {
+        return fonts;
+    }
+
+    let loader = FONT_DATABASE.with(|db| db.borrow_mut().all_loader.take());
+
+    let fonts = loader
+        .and_then(|loader| loader.join().ok())
+        .unwrap_or_else(load_all_descriptors);
+
+    FONT_DATABASE.with(|db| {
+        let mut db = db.borrow_mut();
+        let fonts = db.remember(fonts);
+
+        db.all = Some(fonts.clone());
+        fonts
+    })
+}
+
+pub fn all_families() -> Vec<String> {
+    SystemSource::new().all_families().unwrap_or_default()
+}
+
+pub fn font_by_postscript_name(postscript_name: &str) -> Option<FontDescriptor> {
+    FONT_DATABASE.with(|db| db.borrow().by_postscript_name.get(postscript_name).cloned())
+}
+
+// Which of CHARS FONT has glyphs for, in order.  The font file is read
+// the first time some character hasn't been asked about before.
+pub fn char_coverage(font: &FontDescriptor, chars: &[u32]) -> Vec<bool> {
+    let cached: Vec<Option<bool>> = FONT_DATABASE.with(|db| {
+        let db = db.borrow();
+
+        chars
+            .iter()
+            .map(|c| {
+                db.coverage
+                    .get(&(font.postscript_name.clone(), *c))
+                    .copied()
+            })
+            .collect()
+    });
+
+    if cached.iter().all(Option::is_some) {
+        return cached.into_iter().map(Option::unwrap).collect();
+    }
+
+    let loaded = FONT_DATABASE.with(|db| db.borrow_mut().load(font));
+
+    let coverage: Vec<bool> = chars
+        .iter()
+        .map(|c| {
+            loaded.as_ref().map_or(false, |f| {
+                std::char::from_u32(*c)
+                    .and_then(|c| f.glyph_for_char(c))
+                    .map_or(false, |g| g != 0)
+            })
+        })
+        .collect();
+
+    FONT_DATABASE.with(|db| {
+        let mut db = db.borrow_mut();
+
+        for (c, has) in chars.iter().zip(&coverage) {
+            db.coverage.insert((font.postscript_name.clone(), *c), *has);
+        }
+    });
+
+    coverage
+}
@@ -8,13 +8,14 @@ use lisp::{
     },
 };
 
-use super::{display_info::DisplayInfoRef, output::Output};
+use super::{display_info::DisplayInfoRef, output::Output, window_server::WindowOptions};
 
 pub fn create_frame(
     display: LispObject,
     dpyinfo: DisplayInfoRef,
     tem: LispObject,
     mut kb: KeyboardRef,
+    options: WindowOptions,
 ) -> LispFrameRef {
     let frame = if tem.eq(Qnone) || tem.is_nil() {
         unsafe { make_frame_without_minibuffer(Qnil, kb.as_mut(), display) }
@@ -26,19 +27,40 @@ pub fn create_frame(
         unsafe { make_frame(true) }
     };
 
-    let mut frame = LispFrameRef::new(frame);
+    let frame = LispFrameRef::new(frame);
+
+    set_up_output(frame, dpyinfo, options);
+
+    frame
+}
+
+// Tooltip frames have no minibuffer and no mode line.
+pub fn create_tip_frame(dpyinfo: DisplayInfoRef) -> LispFrameRef {
+    let mut frame = LispFrameRef::new(unsafe { make_frame(false) });
+
+    frame.set_wants_modeline(false);
+    frame.set_tooltip(true);
+
+    set_up_output(frame, dpyinfo, WindowOptions::tooltip());
+
+    frame
+}
+
+fn set_up_output(mut frame: LispFrameRef, dpyinfo: DisplayInfoRef, options: WindowOptions) {
+    let window_server = dpyinfo.get_inner().window_server.clone();
+
+    // The frame isn't on any list yet, so it is simply garbage when
+    // this fails.
+    let output = Output::new(window_server, options)
+        .unwrap_or_else(|e| error!("Cannot create a WebRender frame: {}", e));
 
     frame.terminal = dpyinfo.get_inner().terminal.as_mut();
     frame.set_output_method(output_method::output_wr);
 
-    let mut output = Box::new(Output::new());
+    let mut output = Box::new(output);
     output.set_display_info(dpyinfo);
 
     // Remeber to destory the Output object when frame destoried.
     let output = Box::into_raw(output);
     frame.output_data.wr = output as *mut wr_output;
-
-    dpyinfo.get_inner().output = unsafe { frame.output_data.wr.into() };
-
-    frame
 }
@@ -0,0 +1,41 @@
+//! Fringe bitmaps, as defined by fringe.c.
+//!
+//! Each bitmap is kept as a depth 1 pixmap that is its own mask, so
+//! the draw canvas can paint it in any color.  Bitmap numbers are
+//! shared by all frames, like the bitmaps themselves.
+
+use std::{cell::RefCell, collections::HashMap};
+
+use super::image::{PixmapRef, WrPixmap};
+
+thread_local! {
+    static FRINGE_BITMAPS: RefCell<HashMap<i32, PixmapRef>> = RefCell::new(HashMap::new());
+}
+
+// Define bitmap WHICH from BITS, one row per element, the leftmost
+// pixel in bit WIDTH - 1.  Returns the pixmap it replaces, if any.
+pub fn define_fringe_bitmap(which: i32, bits: &[u16], width: i32) -> Option<PixmapRef> {
+    let height = bits.len();
+    let mut pixmap = WrPixmap::new(width as u32, height as u32, 1);
+
+    for (y, row) in bits.iter().enumerate() {
+        for x in 0..width {
+            let pixel = (*row >> (width - 1 - x)) & 1;
+
+            pixmap.put_pixel(x, y as i32, pixel as u64);
+        }
+    }
+
+    let pixmap = PixmapRef::new(pixmap.into_raw());
+
+    FRINGE_BITMAPS.with(|bitmaps| bitmaps.borrow_mut().insert(which, pixmap))
+}
+
+// Forget bitmap WHICH, returning its pixmap for the caller to free.
+pub fn destroy_fringe_bitmap(which: i32) -> Option<PixmapRef> {
+    FRINGE_BITMAPS.with(|bitmaps| bitmaps.borrow_mut().remove(&which))
+}
+
+pub fn fringe_bitmap(which: i32) -> Option<PixmapRef> {
+    FRINGE_BITMAPS.with(|bitmaps| bitmaps.borrow().get(&which).copied())
+}
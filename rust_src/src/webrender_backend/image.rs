@@ -0,0 +1,471 @@
+//! Pixmaps backing Emacs images.
+//!
+//! A `WrPixmap` plays the role of both `Emacs_Pixmap` and
+//! `Emacs_Pix_Container` on the C side, the same way NS images do:
+//! image.c writes pixels into it with `wr_put_pixel`, and the draw
+//! canvas uploads it to WebRender when an image glyph is drawn.
+
+use std::{cell::RefCell, io::Cursor, path::Path};
+
+use image::{codecs::gif::GifDecoder, AnimationDecoder, Frame, Frames, ImageFormat, RgbaImage};
+
+pub type PixmapRef = lisp::lisp::ExternalPtr<WrPixmap>;
+
+pub const IDENTITY_TRANSFORM: [[f64; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+pub struct WrPixmap {
+    pub width: u32,
+    pub height: u32,
+    // 1 for masks, anything else for color images.
+    pub depth: i32,
+    // Straight (not premultiplied) RGBA, 4 bytes per pixel.
+    data: Vec<u8>,
+    has_alpha: bool,
+
+    // Set by `image_set_transform'; maps image pixels to the glyph's
+    // coordinate space.
+    pub transform: [[f64; 3]; 3],
+    pub smoothing: bool,
+
+    // Bumped on every pixel write so cached WebRender images can be
+    // told apart from the current contents.
+    pub generation: u64,
+    refcount: usize,
+}
+
+impl WrPixmap {
+    pub fn new(width: u32, height: u32, depth: i32) -> Self {
+        let data = [0, 0, 0, 0xff].repeat((width * height) as usize);
+
+        Self::from_rgba(width, height, depth, data)
+    }
+
+    fn from_rgba(width: u32, height: u32, depth: i32, data: Vec<u8>) -> Self {
+        let has_alpha = data.chunks_exact(4).any(|p| p[3] != 0xff);
+
+        WrPixmap {
+            width,
+            height,
+            depth,
+            data,
+            has_alpha,
+            transform: IDENTITY_TRANSFORM,
+            smoothing: false,
+            generation: 0,
+            refcount: 1,
+        }
+    }
+
+    fn from_image(image: RgbaImage) -> Self {
+        let (width, height) = image.dimensions();
+
+        Self::from_rgba(width, height, 0, image.into_raw())
+    }
+
+    // Build a pixmap from XBM data: rows padded to whole bytes, least
+    // significant bit first.
+    pub fn from_xbm(bits: &[u8], width: u32, height: u32, fg: u64, bg: u64) -> Self {
+        Self::from_xbm_with_depth(bits, width, height, 0, fg, bg)
+    }
+
+    // Build a bitmap, as used for face stipples, from XBM data.  It is
+    // its own mask: drawn with `image_key_for_pixmap(p, Some(p))', set
+    // bits are white and the others transparent, ready to be tinted.
+    pub fn bitmap_from_xbm(bits: &[u8], width: u32, height: u32) -> Self {
+        Self::from_xbm_with_depth(bits, width, height, 1, 1, 0)
+    }
+
+    fn from_xbm_with_depth(
+        bits: &[u8],
+        width: u32,
+        height: u32,
+        depth: i32,
+        fg: u64,
+        bg: u64,
+    ) -> Self {
+        let mut pixmap = Self::new(width, height, depth);
+        let stride = ((width + 7) / 8) as usize;
+
+        for y in 0..height {
+            for x in 0..width {
+                let byte = bits[y as usize * stride + (x / 8) as usize];
+                let pixel = if byte & (1 << (x % 8)) != 0 { fg } else { bg };
+
+                pixmap.put_pixel(x as i32, y as i32, pixel);
+            }
+        }
+
+        pixmap
+    }
+
+    pub fn into_raw(self) -> *mut WrPixmap {
+        Box::into_raw(Box::new(self))
+    }
+
+    fn offset(&self, x: i32, y: i32) -> Option<usize> {
+        if x < 0 || y < 0 || x as u32 >= self.width || y as u32 >= self.height {
+            return None;
+        }
+
+        Some((y as usize * self.width as usize + x as usize) * 4)
+    }
+
+    pub fn get_pixel(&self, x: i32, y: i32) -> u64 {
+        let i = match self.offset(x, y) {
+            Some(i) => i,
+            None => return 0,
+        };
+
+        if self.depth == 1 {
+            return (self.data[i] != 0) as u64;
+        }
+
+        let channel = |c: u8| c as u64 * 0x101;
+
+        channel(self.data[i + 2]) << 32 | channel(self.data[i + 1]) << 16 | channel(self.data[i])
+    }
+
+    // PIXEL is in the frame's pixel format (16 bits per channel, red
+    // lowest), or PIX_MASK_RETAIN/PIX_MASK_DRAW for masks.
+    pub fn put_pixel(&mut self, x: i32, y: i32, pixel: u64) {
+        let i = match self.offset(x, y) {
+            Some(i) => i,
+            None => return,
+        };
+
+        let rgba = if self.depth == 1 {
+            let v = if pixel != 0 { 0xff } else { 0 };
+            [v, v, v, 0xff]
+        } else {
+            let channel = |shift: u64| ((pixel >> shift) & 0xffff) as u16 >> 8;
+            [channel(0) as u8, channel(16) as u8, channel(32) as u8, 0xff]
+        };
+
+        self.data[i..i + 4].copy_from_slice(&rgba);
+        self.generation += 1;
+    }
+
+    pub fn draw_cross(&mut self, x: i32, y: i32, width: u32, height: u32, color: u64) {
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let (w, h) = ((width - 1) as i32, (height - 1) as i32);
+
+        // Walk along the longer side so both diagonals stay connected.
+        let steps = w.max(h).max(1);
+
+        for i in 0..=steps {
+            let dx = i * w / steps;
+            let dy = i * h / steps;
+
+            self.put_pixel(x + dx, y + dy, color);
+            self.put_pixel(x + dx, y + h - dy, color);
+        }
+    }
+
+    pub fn retain(&mut self) {
+        self.refcount += 1;
+    }
+
+    // Drop a reference to PIXMAP, freeing it along with the last one.
+    pub fn release(pixmap: *mut WrPixmap) -> bool {
+        let freed = unsafe {
+            (*pixmap).refcount -= 1;
+            (*pixmap).refcount == 0
+        };
+
+        if freed {
+            let _ = unsafe { Box::from_raw(pixmap) };
+        }
+
+        freed
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_opaque(&self) -> bool {
+        !self.has_alpha
+    }
+
+    // Premultiplied BGRA8, which every WebRender backend can upload.
+    // MASK, when given, is an Emacs image mask whose cleared pixels
+    // become transparent.
+    pub fn to_bgra8_premultiplied(&self, mask: Option<&WrPixmap>) -> (Vec<u8>, bool) {
+        let mut opaque = true;
+        let mut bytes = Vec::with_capacity(self.data.len());
+
+        for (i, p) in self.data.chunks_exact(4).enumerate() {
+            let mut alpha = p[3] as u32;
+
+            if let Some(mask) = mask {
+                if mask.data.get(i * 4).map_or(false, |v| *v == 0) {
+                    alpha = 0;
+                }
+            }
+
+            if alpha != 0xff {
+                opaque = false;
+            }
+
+            let premultiply = |c: u8| ((c as u32 * alpha + 127) / 255) as u8;
+
+            bytes.extend_from_slice(&[
+                premultiply(p[2]),
+                premultiply(p[1]),
+                premultiply(p[0]),
+                alpha as u8,
+            ]);
+        }
+
+        (bytes, opaque)
+    }
+}
+
+// A GIF being decoded.  Animations ask for their frames one at a time,
+// and each is composited onto the ones before it, so the decoder is
+// kept along with the last frame it produced.
+struct GifFrames {
+    data: Vec<u8>,
+    count: usize,
+    frames: Frames<'static>,
+    // The index and contents of the last frame decoded.
+    current: Option<(usize, Frame)>,
+}
+
+impl GifFrames {
+    fn new(data: &[u8]) -> Result<Self, String> {
+        let count = gif_frame_count(data).ok_or_else(|| "Invalid GIF data".to_string())?;
+
+        let decoder = GifDecoder::new(Cursor::new(data.to_vec())).map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            data: data.to_vec(),
+            count,
+            frames: decoder.into_frames(),
+            current: None,
+        })
+    }
+
+    fn next_index(&self) -> usize {
+        self.current.as_ref().map_or(0, |(i, _)| i + 1)
+    }
+
+    // Decode up to frame INDEX, which can't come before the current one.
+    fn frame(&mut self, index: usize) -> Result<&Frame, String> {
+        while self.next_index() <= index {
+            let frame = self
+                .frames
+                .next()
+                .ok_or_else(|| format!("Invalid image index {}", index))?
+                .map_err(|e| e.to_string())?;
+
+            self.current = Some((self.next_index(), frame));
+        }
+
+        Ok(&self.current.as_ref().unwrap().1)
+    }
+}
+
+// Count the frames of a GIF by walking its blocks, without decoding
+// them.  A truncated GIF has the frames that start before its end.
+fn gif_frame_count(data: &[u8]) -> Option<usize> {
+    // The size of the color table a packed field says follows it.
+    let color_table = |flags: u8| {
+        if flags & 0x80 != 0 {
+            3 << ((flags & 7) + 1)
+        } else {
+            0
+        }
+    };
+
+    // The position after the data sub-blocks starting at I.
+    let skip_sub_blocks = |mut i: usize| {
+        while let Some(&len) = data.get(i) {
+            i += 1 + len as usize;
+
+            if len == 0 {
+                break;
+            }
+        }
+
+        i
+    };
+
+    if !data.starts_with(b"GIF") {
+        return None;
+    }
+
+    let mut i = 13 + color_table(*data.get(10)?);
+    let mut count = 0;
+
+    while let Some(&block) = data.get(i) {
+        match block {
+            // Extension: introducer, label and sub-blocks.
+            0x21 => i = skip_sub_blocks(i + 2),
+            // Image descriptor, color table, LZW code size and sub-blocks.
+            0x2c => {
+                let flags = match data.get(i + 9) {
+                    Some(&flags) => flags,
+                    None => break,
+                };
+                count += 1;
+                i = skip_sub_blocks(i + 10 + color_table(flags) + 1);
+            }
+            0x3b => break,
+            _ => return None,
+        }
+    }
+
+    Some(count)
+}
+
+thread_local! {
+    static GIF_FRAMES: RefCell<Option<GifFrames>> = RefCell::new(None);
+}
+
+pub struct LoadedImage {
+    pub pixmap: WrPixmap,
+    // Number of frames and the delay of the requested one in seconds,
+    // for animated images.
+    pub frames: Option<(usize, Option<f64>)>,
+}
+
+// Image types decoded natively, as named by the `:type' property.
+pub fn can_load_image_type(image_type: &str) -> bool {
+    matches!(image_type, "png" | "jpeg" | "gif" | "tiff" | "svg")
+}
+
+pub fn load_image(
+    data: &[u8],
+    image_type: &str,
+    index: usize,
+    resources_dir: Option<&Path>,
+) -> Result<LoadedImage, String> {
+    match image_type {
+        "gif" => load_gif(data, index),
+        "svg" => load_svg(data, resources_dir),
+        _ => {
+            let format = match image_type {
+                "png" => ImageFormat::Png,
+                "jpeg" => ImageFormat::Jpeg,
+                "tiff" => ImageFormat::Tiff,
+                _ => image::guess_format(data).map_err(|e| e.to_string())?,
+            };
+
+            if index > 0 {
+                return Err(format!("Invalid image index {}", index));
+            }
+
+            let image =
+                image::load_from_memory_with_format(data, format).map_err(|e| e.to_string())?;
+
+            Ok(LoadedImage {
+                pixmap: WrPixmap::from_image(image.into_rgba8()),
+                frames: None,
+            })
+        }
+    }
+}
+
+fn load_gif(data: &[u8], index: usize) -> Result<LoadedImage, String> {
+    GIF_FRAMES.with(|cache| {
+        let mut cache = cache.borrow_mut();
+
+        // Frames before the current one need decoding again.
+        let reusable = match &*cache {
+            Some(gif) => gif.data.as_slice() == data && gif.next_index() <= index + 1,
+            None => false,
+        };
+
+        if !reusable {
+            *cache = Some(GifFrames::new(data)?);
+        }
+
+        let gif = cache.as_mut().unwrap();
+        let count = gif.count;
+
+        if index >= count {
+            return Err(format!("Invalid image index {}", index));
+        }
+
+        let frame = gif.frame(index)?;
+
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay = if numer > 0 && denom > 0 {
+            Some(numer as f64 / denom as f64 / 1000.0)
+        } else {
+            None
+        };
+
+        Ok(LoadedImage {
+            pixmap: WrPixmap::from_image(frame.buffer().clone()),
+            frames: if count > 1 {
+                Some((count, delay))
+            } else {
+                None
+            },
+        })
+    })
+}
+
+fn load_svg(data: &[u8], resources_dir: Option<&Path>) -> Result<LoadedImage, String> {
+    let mut options = usvg::Options::default();
+    options.resources_dir = resources_dir.map(|dir| dir.to_path_buf());
+
+    let tree = usvg::Tree::from_data(data, &options).map_err(|e| e.to_string())?;
+
+    let size = tree.svg_node().size.to_screen_size();
+
+    let mut canvas = tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or_else(|| "Invalid SVG size".to_string())?;
+
+    resvg::render(&tree, usvg::FitTo::Original, canvas.as_mut())
+        .ok_or_else(|| "Failed to render SVG".to_string())?;
+
+    // tiny-skia renders premultiplied pixels; pixmaps hold straight alpha.
+    let mut data = Vec::with_capacity(canvas.data().len());
+
+    for pixel in canvas.pixels() {
+        let c = pixel.demultiply();
+        data.extend_from_slice(&[c.red(), c.green(), c.blue(), c.alpha()]);
+    }
+
+    Ok(LoadedImage {
+        pixmap: WrPixmap::from_rgba(size.width(), size.height(), 0, data),
+        frames: None,
+    })
+}
+
+// Apply the 2D affine part of a row-vector transform to a point.
+pub fn transform_point(m: &[[f64; 3]; 3], x: f64, y: f64) -> (f64, f64) {
+    (
+        x * m[0][0] + y * m[1][0] + m[2][0],
+        x * m[0][1] + y * m[1][1] + m[2][1],
+    )
+}
+
+pub fn invert_transform(m: &[[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let det = m[0][0] * m[1][1] - m[0][1] * m[1][0];
+
+    if det == 0.0 {
+        return None;
+    }
+
+    let a = m[1][1] / det;
+    let b = -m[0][1] / det;
+    let c = -m[1][0] / det;
+    let d = m[0][0] / det;
+
+    Some([
+        [a, b, 0.0],
+        [c, d, 0.0],
+        [
+            -(m[2][0] * a + m[2][1] * c),
+            -(m[2][0] * b + m[2][1] * d),
+            1.0,
+        ],
+    ])
+}
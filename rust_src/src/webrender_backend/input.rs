@@ -123,6 +123,11 @@ impl InputProcessor {
         self.cursor_positon = position;
     }
 
+    // Where the mouse was last seen, relative to the window it moved in.
+    pub fn cursor_position(&self) -> PhysicalPosition<f64> {
+        self.cursor_positon
+    }
+
     pub fn change_modifiers(&mut self, modifiers: ModifiersState) {
         self.modifiers = modifiers;
     }
@@ -2,12 +2,16 @@ pub mod color;
 pub mod display_info;
 pub mod font;
 pub mod frame;
+pub mod image;
 pub mod input;
 pub mod output;
 pub mod term;
+pub mod window_server;
 
 mod cursor;
 mod draw_canvas;
 mod event;
-mod texture;
+mod font_db;
+mod fringe;
+mod scene;
 mod util;
@@ -1,30 +1,15 @@
 use std::{
-    cell::RefCell,
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    ptr,
     rc::Rc,
-    sync::mpsc::{channel, sync_channel, Receiver, SyncSender},
-    thread::JoinHandle,
 };
 
 use font_kit::handle::Handle as FontHandle;
-use gleam::gl::{self, Gl};
 use glutin::{
-    self,
     dpi::{LogicalSize, PhysicalPosition, PhysicalSize},
-    event::{Event, WindowEvent},
-    event_loop::{ControlFlow, EventLoopProxy},
-    monitor::MonitorHandle,
-    window::Window,
-    ContextWrapper, PossiblyCurrent,
+    window::{Window, WindowId},
 };
-use std::{
-    ops::{Deref, DerefMut},
-    ptr,
-};
-
-#[cfg(unix)]
-use glutin::platform::unix::EventLoopExtUnix;
-#[cfg(windows)]
-use glutin::platform::windows::EventLoopExtUnix;
 
 use webrender::{self, api::units::*, api::*};
 
@@ -32,15 +17,9 @@ use lisp::remacs_sys::wr_output;
 
 use super::display_info::DisplayInfoRef;
 use super::font::FontRef;
-use super::texture::TextureResourceManager;
-use super::util::HandyDandyRectBuilder;
-
-pub enum EmacsGUIEvent {
-    Flush(SyncSender<ImageKey>),
-    ReadBytes(DeviceIntRect, SyncSender<ImageKey>),
-}
-
-pub type GUIEvent = Event<'static, EmacsGUIEvent>;
+use super::image::PixmapRef;
+use super::scene::{RowKey, Scene, ROOT_PIPELINE_ID};
+use super::window_server::{WindowOptions, WindowServer, HEADLESS_SIZE};
 
 pub struct Output {
     // Extend `wr_output` struct defined in `wrterm.h`
@@ -49,269 +28,58 @@ pub struct Output {
     pub font: FontRef,
     pub fontset: i32,
 
-    pub render_api: RenderApi,
-    pub loop_thread: JoinHandle<()>,
+    // Shared with the display and its other outputs.
+    window_server: WindowServer,
     pub document_id: DocumentId,
 
-    pub display_list_builder: Option<DisplayListBuilder>,
+    // What has been drawn, kept between flushes.
+    scene: Scene,
 
     pub background_color: ColorF,
     pub cursor_color: ColorF,
     pub cursor_foreground_color: ColorF,
 
-    window: Window,
-
-    event_loop_proxy: EventLoopProxy<EmacsGUIEvent>,
-    color_bits: u8,
+    // Images uploaded for image glyphs, keyed by the addresses of the
+    // pixmap and mask they were built from, along with the pixmap
+    // generations they reflect.
+    image_keys: HashMap<(usize, usize), (ImageKey, (u64, u64))>,
 
-    event_rx: Receiver<GUIEvent>,
+    // None for headless outputs
+    window: Option<Window>,
+    // The size of a headless output, which the window has otherwise.
+    headless_size: PhysicalSize<u32>,
 }
 
 impl Output {
-    pub fn new() -> Self {
-        let (api, window, document_id, loop_thread, event_loop_proxy, color_bits, event_rx) =
-            Self::create_webrender_window();
+    pub fn new(window_server: WindowServer, options: WindowOptions) -> Result<Self, String> {
+        let (window, document_id) = window_server.create_surface(options)?;
+
+        let mut txn = Transaction::new();
+        txn.set_root_pipeline(ROOT_PIPELINE_ID);
+        window_server.api().send_transaction(document_id, txn);
 
-        Self {
+        Ok(Self {
             output: wr_output::default(),
             font: FontRef::new(ptr::null_mut()),
             fontset: 0,
-            render_api: api,
-            loop_thread,
+            window_server,
             document_id,
-            display_list_builder: None,
+            scene: Scene::default(),
             background_color: ColorF::WHITE,
             cursor_color: ColorF::BLACK,
             cursor_foreground_color: ColorF::WHITE,
+            image_keys: HashMap::new(),
             window,
-            event_loop_proxy,
-            color_bits,
-            event_rx,
-        }
-    }
-
-    fn create_webrender_window() -> (
-        RenderApi,
-        Window,
-        DocumentId,
-        JoinHandle<()>,
-        EventLoopProxy<EmacsGUIEvent>,
-        u8,
-        std::sync::mpsc::Receiver<GUIEvent>,
-    ) {
-        let (webrender_tx, webrender_rx) = sync_channel(1);
-
-        let (event_tx, event_rx) = channel::<GUIEvent>();
-
-        let window_loop_thread = std::thread::spawn(move || {
-            let events_loop = glutin::event_loop::EventLoop::new_any_thread();
-            let window_builder = glutin::window::WindowBuilder::new()
-                .with_visible(true)
-                .with_maximized(true);
-
-            let window_context = glutin::ContextBuilder::new()
-                .build_windowed(window_builder, &events_loop)
-                .unwrap();
-
-            let current_context = unsafe { window_context.make_current() }.unwrap();
-            let (current_context, window) = unsafe { current_context.split() };
-
-            let gl = Self::get_gl_api(&current_context);
-
-            let events_loop_proxy = events_loop.create_proxy();
-
-            let device_pixel_ratio = window.scale_factor() as f32;
-
-            let mut device_size = {
-                let size = window.inner_size();
-                DeviceIntSize::new(size.width as i32, size.height as i32)
-            };
-
-            let webrender_opts = webrender::RendererOptions {
-                device_pixel_ratio,
-                clear_color: None,
-                ..webrender::RendererOptions::default()
-            };
-
-            let notifier = Box::new(Notifier::new(events_loop_proxy.clone()));
-
-            let (mut renderer, sender) =
-                webrender::Renderer::new(gl.clone(), notifier, webrender_opts, None, device_size)
-                    .unwrap();
-
-            let texture_resources = Rc::new(RefCell::new(TextureResourceManager::new(
-                gl.clone(),
-                sender.create_api(),
-            )));
-
-            let external_image_handler =
-                texture_resources.borrow_mut().new_external_image_handler();
-
-            renderer.set_external_image_handler(external_image_handler);
-
-            let api = sender.create_api();
-
-            let document_id = api.add_document(device_size, 0 /* layer */);
-
-            let color_bits = current_context.get_pixel_format().color_bits;
-
-            webrender_tx
-                .send((api, window, document_id, color_bits, events_loop_proxy))
-                .unwrap();
-
-            let api = sender.create_api();
-
-            events_loop.run(move |e, _, control_flow| {
-                *control_flow = ControlFlow::Wait;
-
-                let copy_framebuffer_to_texture =
-                    |device_rect: DeviceIntRect,
-                     sender: SyncSender<ImageKey>,
-                     renderer: &webrender::Renderer| {
-                        let mut fb_rect =
-                            FramebufferIntRect::from_untyped(&device_rect.to_untyped());
-
-                        if !renderer.device.surface_origin_is_top_left() {
-                            fb_rect.origin.y =
-                                device_size.height - fb_rect.origin.y - fb_rect.size.height;
-                        }
-
-                        let need_flip = !renderer.device.surface_origin_is_top_left();
-
-                        let (image_key, texture_id) = texture_resources.borrow_mut().new_image(
-                            document_id,
-                            fb_rect.size,
-                            need_flip,
-                        );
-
-                        sender.send(image_key).unwrap();
-
-                        gl.bind_texture(gl::TEXTURE_2D, texture_id);
-
-                        gl.copy_tex_sub_image_2d(
-                            gl::TEXTURE_2D,
-                            0,
-                            0,
-                            0,
-                            fb_rect.origin.x,
-                            fb_rect.origin.y,
-                            fb_rect.size.width,
-                            fb_rect.size.height,
-                        );
-
-                        gl.bind_texture(gl::TEXTURE_2D, 0);
-                    };
-
-                match e {
-                    Event::WindowEvent {
-                        event: WindowEvent::Resized(size),
-                        ..
-                    } => {
-                        device_size = DeviceIntSize::new(size.width as i32, size.height as i32);
-                        api.set_document_view(
-                            document_id,
-                            DeviceIntRect::from_size(device_size),
-                            device_pixel_ratio,
-                        );
-
-                        current_context.resize(size);
-
-                        gl.clear_color(1.0, 1.0, 1.0, 1.0);
-                        gl.clear(self::gl::COLOR_BUFFER_BIT);
-                        gl.flush();
-                        current_context.swap_buffers().ok();
-
-                        event_tx.send(e.to_static().unwrap()).unwrap();
-
-                        unsafe { libc::raise(libc::SIGIO) };
-                    }
-
-                    Event::WindowEvent {
-                        event: WindowEvent::KeyboardInput { .. },
-                        ..
-                    }
-                    | Event::WindowEvent {
-                        event: WindowEvent::ReceivedCharacter(_),
-                        ..
-                    }
-                    | Event::WindowEvent {
-                        event: WindowEvent::ModifiersChanged(_),
-                        ..
-                    }
-                    | Event::WindowEvent {
-                        event: WindowEvent::MouseInput { .. },
-                        ..
-                    }
-                    | Event::WindowEvent {
-                        event: WindowEvent::CursorMoved { .. },
-                        ..
-                    }
-                    | Event::WindowEvent {
-                        event: WindowEvent::Focused(_),
-                        ..
-                    } => {
-                        event_tx.send(e.to_static().unwrap()).unwrap();
-                        unsafe { libc::raise(libc::SIGIO) };
-                    }
-                    Event::UserEvent(EmacsGUIEvent::Flush(sender)) => {
-                        renderer.update();
-                        renderer.render(device_size).unwrap();
-                        let _ = renderer.flush_pipeline_info();
-                        current_context.swap_buffers().ok();
-
-                        texture_resources.borrow_mut().clear();
-
-                        copy_framebuffer_to_texture(
-                            DeviceIntRect::from_size(device_size),
-                            sender,
-                            &renderer,
-                        );
-                    }
-                    Event::UserEvent(EmacsGUIEvent::ReadBytes(device_rect, sender)) => {
-                        copy_framebuffer_to_texture(device_rect, sender, &renderer);
-                    }
-                    _ => {}
-                };
-            })
-        });
-
-        let (api, window, document_id, color_bits, event_loop_proxy) = webrender_rx.recv().unwrap();
-
-        let pipeline_id = PipelineId(0, 0);
-
-        let mut txn = Transaction::new();
-        txn.set_root_pipeline(pipeline_id);
-        api.send_transaction(document_id, txn);
-
-        (
-            api,
-            window,
-            document_id,
-            window_loop_thread,
-            event_loop_proxy,
-            color_bits,
-            event_rx,
-        )
-    }
-
-    fn get_gl_api(window_context: &ContextWrapper<PossiblyCurrent, ()>) -> Rc<dyn Gl> {
-        match window_context.get_api() {
-            glutin::Api::OpenGl => unsafe {
-                gl::GlFns::load_with(|symbol| window_context.get_proc_address(symbol) as *const _)
-            },
-            glutin::Api::OpenGlEs => unsafe {
-                gl::GlesFns::load_with(|symbol| window_context.get_proc_address(symbol) as *const _)
-            },
-            glutin::Api::WebGl => unimplemented!(),
-        }
+            headless_size: HEADLESS_SIZE,
+        })
     }
 
-    fn get_size(window: &Window) -> (DeviceIntSize, LayoutSize) {
-        let device_pixel_ratio = window.scale_factor() as f32;
+    fn get_size(&self) -> (DeviceIntSize, LayoutSize) {
+        let device_pixel_ratio = self.scale_factor();
 
-        let physical_size = window.inner_size();
+        let physical_size = self.get_physical_size();
 
-        let logical_size = physical_size.to_logical::<f32>(device_pixel_ratio as f64);
+        let logical_size = physical_size.to_logical::<f32>(device_pixel_ratio);
 
         let layout_size = LayoutSize::new(logical_size.width as f32, logical_size.height as f32);
         let device_size =
@@ -320,43 +88,41 @@ impl Output {
         (device_size, layout_size)
     }
 
-    fn new_builder(&mut self, image: Option<(ImageKey, LayoutRect)>) -> DisplayListBuilder {
-        let pipeline_id = PipelineId(0, 0);
-
-        let (_, layout_size) = Self::get_size(&self.window);
-        let mut builder = DisplayListBuilder::new(pipeline_id, layout_size);
-
-        if let Some((image_key, image_rect)) = image {
-            let space_and_clip = SpaceAndClipInfo::root_scroll(pipeline_id);
-
-            let bounds = (0, 0).by(layout_size.width as i32, layout_size.height as i32);
-
-            builder.push_image(
-                &CommonItemProperties::new(bounds, space_and_clip),
-                image_rect,
-                ImageRendering::Auto,
-                AlphaType::PremultipliedAlpha,
-                image_key,
-                ColorF::WHITE,
-            );
-        }
+    fn scale_factor(&self) -> f64 {
+        self.window.as_ref().map_or(1.0, |w| w.scale_factor())
+    }
 
-        builder
+    pub fn is_headless(&self) -> bool {
+        self.window.is_none()
     }
 
     pub fn show_window(&self) {
-        self.window.set_visible(true);
+        if let Some(window) = &self.window {
+            window.set_visible(true);
+        }
     }
     pub fn hide_window(&self) {
-        self.window.set_visible(false);
+        if let Some(window) = &self.window {
+            window.set_visible(false);
+        }
     }
 
     pub fn maximize(&self) {
-        self.window.set_maximized(true);
+        if let Some(window) = &self.window {
+            window.set_maximized(true);
+        }
+    }
+
+    pub fn set_decorations(&self, decorations: bool) {
+        if let Some(window) = &self.window {
+            window.set_decorations(decorations);
+        }
     }
 
     pub fn set_title(&self, title: &str) {
-        self.window.set_title(title);
+        if let Some(window) = &self.window {
+            window.set_title(title);
+        }
     }
 
     pub fn set_display_info(&mut self, mut dpyinfo: DisplayInfoRef) {
@@ -368,102 +134,121 @@ impl Output {
     }
 
     pub fn get_inner_size(&self) -> LogicalSize<f32> {
-        let scale_factor = self.window.scale_factor();
+        let scale_factor = self.scale_factor();
 
-        self.window.inner_size().to_logical(scale_factor)
+        self.get_physical_size().to_logical(scale_factor)
     }
 
     pub fn get_physical_size(&self) -> PhysicalSize<u32> {
-        self.window.inner_size()
+        self.window
+            .as_ref()
+            .map_or(self.headless_size, |w| w.inner_size())
     }
 
-    pub fn display<F>(&mut self, f: F)
+    // Draw with F, which paints inside BOUNDS (and everywhere in
+    // them, if OPAQUE).  F is kept to draw ROW again on later flushes,
+    // so it must own what it draws.
+    pub fn display<F>(&mut self, row: Option<RowKey>, bounds: LayoutRect, opaque: bool, f: F)
     where
-        F: Fn(&mut DisplayListBuilder, SpaceAndClipInfo),
+        F: Fn(&mut DisplayListBuilder, SpaceAndClipInfo) + 'static,
     {
-        if self.display_list_builder.is_none() {
-            let builder = self.new_builder(None);
-
-            self.display_list_builder = Some(builder);
-        }
+        self.scene.push(row, bounds, opaque, Rc::new(f));
+    }
 
-        let pipeline_id = PipelineId(0, 0);
+    // Forget what ROW has drawn over BOUNDS, before drawing it again.
+    pub fn repaint(&mut self, row: RowKey, bounds: LayoutRect) {
+        self.scene.repaint(row, bounds);
+    }
 
-        if let Some(builder) = &mut self.display_list_builder {
-            let space_and_clip = SpaceAndClipInfo::root_scroll(pipeline_id);
+    // Move what is drawn in SOURCE by DY pixels vertically.
+    pub fn scroll(&mut self, source: LayoutRect, dy: i32) {
+        self.scene.scroll(source, dy);
+    }
 
-            f(builder, space_and_clip);
-        }
+    pub fn clear_scene(&mut self) {
+        self.scene.clear();
     }
 
     pub fn flush(&mut self) {
-        let builder = std::mem::replace(&mut self.display_list_builder, None);
+        if !self.scene.is_dirty() {
+            return;
+        }
 
-        if let Some(builder) = builder {
-            let (_, layout_size) = Self::get_size(&self.window);
+        let (_, layout_size) = self.get_size();
 
-            let epoch = Epoch(0);
-            let mut txn = Transaction::new();
+        let mut txn = Transaction::new();
 
-            txn.set_display_list(epoch, None, layout_size, builder.finalize(), true);
+        self.scene.build(&mut txn, layout_size);
 
-            txn.generate_frame();
+        txn.generate_frame();
 
-            self.render_api.send_transaction(self.document_id, txn);
+        self.window_server
+            .api()
+            .send_transaction(self.document_id, txn);
 
-            self.render_api.flush_scene_builder();
+        self.window_server.api().flush_scene_builder();
 
-            let (sender, receiver) = sync_channel(1);
+        self.window_server.flush(self.document_id);
+    }
 
-            let _ = self
-                .event_loop_proxy
-                .send_event(EmacsGUIEvent::Flush(sender));
+    // Copy the pixels of DEVICE_RECT back to the CPU, top row first.
+    pub fn read_pixels_rgba8(&mut self, device_rect: DeviceIntRect) -> Vec<u8> {
+        self.window_server
+            .read_pixels(self.document_id, device_rect)
+    }
 
-            let pre_frame_image_key = receiver.recv().unwrap();
+    pub fn write_png(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let (device_size, _) = self.get_size();
 
-            self.display_list_builder = Some(self.new_builder(Some((
-                pre_frame_image_key,
-                LayoutRect::from_size(layout_size),
-            ))));
-        }
-    }
+        let pixels = self.read_pixels_rgba8(DeviceIntRect::from_size(device_size));
 
-    pub fn read_pixels_rgba8_into_image(&mut self, device_rect: DeviceIntRect) -> ImageKey {
-        let (texture_sender, texture_receiver) = sync_channel(1);
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
 
-        let _ = self
-            .event_loop_proxy
-            .send_event(EmacsGUIEvent::ReadBytes(device_rect, texture_sender));
+        let mut encoder =
+            png::Encoder::new(file, device_size.width as u32, device_size.height as u32);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
 
-        texture_receiver.recv().unwrap()
-    }
+        encoder.write_header()?.write_image_data(&pixels)?;
 
-    pub fn clear_display_list_builder(&mut self) {
-        let _ = std::mem::replace(&mut self.display_list_builder, None);
+        Ok(())
     }
 
-    pub fn add_font_instance(&self, font_key: FontKey, pixel_size: i32) -> FontInstanceKey {
+    pub fn add_font_instance(
+        &self,
+        font_key: FontKey,
+        pixel_size: i32,
+        variations: Vec<FontVariation>,
+    ) -> FontInstanceKey {
         let mut txn = Transaction::new();
 
-        let font_instance_key = self.render_api.generate_font_instance_key();
+        let font_instance_key = self.window_server.api().generate_font_instance_key();
+
+        // Embedded bitmaps carry the glyphs of CBDT and sbix color fonts.
+        let options = FontInstanceOptions {
+            flags: FontInstanceOptions::default().flags | FontInstanceFlags::EMBEDDED_BITMAPS,
+            ..Default::default()
+        };
 
         txn.add_font_instance(
             font_instance_key,
             font_key,
             app_units::Au::from_px(pixel_size),
+            Some(options),
             None,
-            None,
-            vec![],
+            variations,
         );
 
-        self.render_api.send_transaction(self.document_id, txn);
+        self.window_server
+            .api()
+            .send_transaction(self.document_id, txn);
         font_instance_key
     }
 
     pub fn add_font(&self, font_handle: &FontHandle) -> FontKey {
         let mut txn = Transaction::new();
 
-        let font_key = self.render_api.generate_font_key();
+        let font_key = self.window_server.api().generate_font_key();
         match font_handle {
             FontHandle::Path { path, font_index } => {
                 let font = NativeFontHandle {
@@ -477,36 +262,155 @@ impl Output {
             }
         }
 
-        self.render_api.send_transaction(self.document_id, txn);
+        self.window_server
+            .api()
+            .send_transaction(self.document_id, txn);
 
         font_key
     }
 
-    pub fn get_color_bits(&self) -> u8 {
-        self.color_bits
-    }
+    // Return the image key showing PIXMAP, uploading its pixels (with
+    // MASK applied as alpha) if they changed since the last call.
+    pub fn image_key_for_pixmap(&mut self, pixmap: PixmapRef, mask: Option<PixmapRef>) -> ImageKey {
+        let cache_key = (
+            pixmap.as_ptr() as usize,
+            mask.map_or(0, |m| m.as_ptr() as usize),
+        );
+        let generation = (pixmap.generation, mask.map_or(0, |m| m.generation));
+
+        let cached = self.image_keys.get(&cache_key).copied();
+
+        if let Some((image_key, cached_generation)) = cached {
+            if cached_generation == generation {
+                return image_key;
+            }
+        }
 
-    pub fn get_available_monitors(&self) -> impl Iterator<Item = MonitorHandle> {
-        self.window.available_monitors()
+        let (bytes, opaque) = pixmap.to_bgra8_premultiplied(mask.as_ref().map(|m| &**m));
+
+        let flags = if opaque {
+            ImageDescriptorFlags::IS_OPAQUE
+        } else {
+            ImageDescriptorFlags::empty()
+        };
+
+        let descriptor = ImageDescriptor::new(
+            pixmap.width as i32,
+            pixmap.height as i32,
+            ImageFormat::BGRA8,
+            flags,
+        );
+
+        let mut txn = Transaction::new();
+
+        let image_key = match cached {
+            Some((image_key, _)) => {
+                txn.update_image(
+                    image_key,
+                    descriptor,
+                    ImageData::new(bytes),
+                    &DirtyRect::All,
+                );
+                image_key
+            }
+            None => {
+                let image_key = self.window_server.api().generate_image_key();
+                txn.add_image(image_key, descriptor, ImageData::new(bytes), None);
+                image_key
+            }
+        };
+
+        self.window_server
+            .api()
+            .send_transaction(self.document_id, txn);
+
+        self.image_keys.insert(cache_key, (image_key, generation));
+
+        image_key
     }
 
-    pub fn get_primary_monitor(&self) -> MonitorHandle {
-        self.window
-            .primary_monitor()
-            .unwrap_or_else(|| -> MonitorHandle { self.window.current_monitor().unwrap() })
+    // Delete the images built from PIXMAP, which is about to be freed.
+    pub fn delete_pixmap_images(&mut self, pixmap: PixmapRef) {
+        let address = pixmap.as_ptr() as usize;
+
+        let mut txn = Transaction::new();
+        let mut deleted = false;
+
+        self.image_keys.retain(|(p, m), (image_key, _)| {
+            let stale = *p == address || *m == address;
+
+            if stale {
+                txn.delete_image(*image_key);
+                deleted = true;
+            }
+
+            !stale
+        });
+
+        if deleted {
+            self.window_server
+                .api()
+                .send_transaction(self.document_id, txn);
+        }
     }
 
     pub fn get_position(&self) -> Option<PhysicalPosition<i32>> {
-        self.window.outer_position().ok()
+        match &self.window {
+            Some(window) => window.outer_position().ok(),
+            None => Some(PhysicalPosition::new(0, 0)),
+        }
     }
-    pub fn poll_events<F>(&mut self, mut f: F)
-    where
-        F: FnMut(GUIEvent),
-    {
-        for e in self.event_rx.try_iter() {
-            f(e);
+
+    pub fn set_position(&self, position: PhysicalPosition<i32>) {
+        if let Some(window) = &self.window {
+            window.set_outer_position(position);
+        }
+    }
+
+    // Where the drawing area of the window is on the screen.  Child
+    // frames are placed relative to it.
+    pub fn get_inner_position(&self) -> Option<PhysicalPosition<i32>> {
+        match &self.window {
+            Some(window) => window.inner_position().ok(),
+            None => Some(PhysicalPosition::new(0, 0)),
+        }
+    }
+
+    pub fn set_inner_size(&mut self, size: PhysicalSize<u32>) {
+        match &self.window {
+            Some(window) => window.set_inner_size(size),
+            None => {
+                self.headless_size = size;
+
+                self.window_server.resize_surface(self.document_id, size);
+            }
         }
     }
+
+    // The window of this output, to match window system events with
+    // their frames.
+    pub fn window_id(&self) -> Option<WindowId> {
+        self.window.as_ref().map(|w| w.id())
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        // Image keys belong to the renderer, which outlives this output.
+        if !self.image_keys.is_empty() {
+            let mut txn = Transaction::new();
+
+            for (image_key, _) in self.image_keys.values() {
+                txn.delete_image(*image_key);
+            }
+
+            self.window_server
+                .api()
+                .send_transaction(self.document_id, txn);
+        }
+
+        self.window_server.destroy_surface(self.document_id);
+    }
 }
 
 #[derive(PartialEq)]
@@ -550,32 +454,3 @@ impl From<*mut wr_output> for OutputRef {
         Self::new(o as *mut Output)
     }
 }
-
-struct Notifier {
-    event_loop_proxy: EventLoopProxy<EmacsGUIEvent>,
-}
-
-impl Notifier {
-    fn new(event_loop_proxy: EventLoopProxy<EmacsGUIEvent>) -> Notifier {
-        Notifier { event_loop_proxy }
-    }
-}
-
-impl RenderNotifier for Notifier {
-    fn clone(&self) -> Box<dyn RenderNotifier> {
-        Box::new(Notifier {
-            event_loop_proxy: self.event_loop_proxy.clone(),
-        })
-    }
-
-    fn wake_up(&self) {}
-
-    fn new_frame_ready(
-        &self,
-        _: DocumentId,
-        _scrolled: bool,
-        _composite_needed: bool,
-        _render_time: Option<u64>,
-    ) {
-    }
-}
@@ -0,0 +1,322 @@
+//! Retained display lists for an output.
+//!
+//! What redisplay draws is kept as fragments, one per glyph row (and
+//! one for drawing done outside any row), each a WebRender pipeline
+//! of its own.  A fragment remembers the operations drawn into it, so
+//! on flush only the fragments that changed are rebuilt; the root
+//! display list just places every fragment with an iframe.
+//!
+//! Operations that paint their whole bounds opaquely drop whatever
+//! they cover, which keeps fragments from growing as rows are redrawn.
+//! Boxes, reliefs and underlines stick out of the backgrounds drawn
+//! over them, so a glyph string about to be drawn also drops what it
+//! overlaps in its row: redisplay draws all of that again.
+//! Scrolling moves fragments by changing their offset, so their
+//! display lists are reused as they are.
+
+use std::rc::Rc;
+
+use webrender::{self, api::units::*, api::*};
+
+use lisp::{remacs_sys::glyph_row, window::LispWindowRef};
+
+pub type DisplayOp = Rc<dyn Fn(&mut DisplayListBuilder, SpaceAndClipInfo)>;
+
+pub const ROOT_PIPELINE_ID: PipelineId = PipelineId(0, 0);
+
+// A glyph row, by its window and its top edge in frame coordinates.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct RowKey {
+    window: usize,
+    y: i32,
+}
+
+impl RowKey {
+    pub fn new(window: LispWindowRef, row: *const glyph_row) -> Self {
+        RowKey {
+            window: window.as_ptr() as usize,
+            y: window.frame_pixel_y(unsafe { (*row).y }),
+        }
+    }
+}
+
+struct RetainedOp {
+    // In frame coordinates, when the op was drawn.
+    bounds: LayoutRect,
+    // The offset of the fragment when the op was drawn.
+    origin: LayoutVector2D,
+    op: DisplayOp,
+}
+
+struct Fragment {
+    pipeline_id: PipelineId,
+    row: Option<RowKey>,
+    ops: Vec<RetainedOp>,
+    // Where the fragment's content has moved since it was drawn.
+    offset: LayoutVector2D,
+    // The bounds of all ops, in frame coordinates.
+    rect: LayoutRect,
+    epoch: Epoch,
+    dirty: bool,
+}
+
+impl Fragment {
+    fn op_bounds(&self, op: &RetainedOp) -> LayoutRect {
+        op.bounds.translate(self.offset - op.origin)
+    }
+
+    fn update_rect(&mut self) {
+        self.rect = self
+            .ops
+            .iter()
+            .map(|op| self.op_bounds(op))
+            .fold(LayoutRect::zero(), |rect, bounds| rect.union(&bounds));
+    }
+
+    // Drop the ops entirely inside BOUNDS, or, if OVERLAPPING, the ops
+    // that intersect it.
+    fn occlude(&mut self, bounds: &LayoutRect, overlapping: bool) {
+        if !bounds.intersects(&self.rect) {
+            return;
+        }
+
+        let offset = self.offset;
+        let count = self.ops.len();
+
+        self.ops.retain(|op| {
+            let op_bounds = op.bounds.translate(offset - op.origin);
+
+            if overlapping {
+                !bounds.intersects(&op_bounds)
+            } else {
+                !bounds.contains_rect(&op_bounds)
+            }
+        });
+
+        if self.ops.len() != count {
+            self.update_rect();
+            self.dirty = true;
+        }
+    }
+
+    fn build(&mut self, layout_size: LayoutSize) -> (PipelineId, LayoutSize, BuiltDisplayList) {
+        let mut builder = DisplayListBuilder::new(self.pipeline_id, layout_size);
+        let space_and_clip = SpaceAndClipInfo::root_scroll(self.pipeline_id);
+
+        for op in &self.ops {
+            if op.origin == LayoutVector2D::zero() {
+                (op.op)(&mut builder, space_and_clip);
+                continue;
+            }
+
+            // Ops drawn after the fragment moved are shifted back, the
+            // iframe moves them along with the rest.
+            let spatial_id = builder.push_reference_frame(
+                LayoutPoint::zero() - op.origin,
+                space_and_clip.spatial_id,
+                TransformStyle::Flat,
+                PropertyBinding::Value(LayoutTransform::identity()),
+                ReferenceFrameKind::Transform,
+            );
+
+            (op.op)(
+                &mut builder,
+                SpaceAndClipInfo {
+                    spatial_id,
+                    clip_id: space_and_clip.clip_id,
+                },
+            );
+
+            builder.pop_reference_frame();
+        }
+
+        self.epoch.0 += 1;
+        self.dirty = false;
+
+        builder.finalize()
+    }
+}
+
+#[derive(Default)]
+pub struct Scene {
+    // Bottom to top.
+    fragments: Vec<Fragment>,
+    removed_pipelines: Vec<PipelineId>,
+    next_pipeline_id: u32,
+    // The fragment of drawing done outside any row.
+    canvas_pipeline: Option<PipelineId>,
+    dirty: bool,
+}
+
+impl Scene {
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    // Add OP, which draws inside BOUNDS, to the fragment of ROW.  An
+    // OPAQUE op hides everything under BOUNDS.
+    pub fn push(&mut self, row: Option<RowKey>, bounds: LayoutRect, opaque: bool, op: DisplayOp) {
+        if opaque {
+            self.occlude(&bounds);
+        }
+
+        // What is drawn last is on top, so the fragment drawn into
+        // moves above the others.
+        let index = match row {
+            Some(row) => self.fragments.iter().position(|f| f.row == Some(row)),
+            None => self
+                .canvas_pipeline
+                .and_then(|id| self.fragments.iter().position(|f| f.pipeline_id == id)),
+        };
+
+        let mut fragment = match index {
+            Some(index) => self.fragments.remove(index),
+            None => self.new_fragment(row),
+        };
+
+        if row.is_none() {
+            self.canvas_pipeline = Some(fragment.pipeline_id);
+        }
+
+        fragment.rect = if fragment.ops.is_empty() {
+            bounds
+        } else {
+            fragment.rect.union(&bounds)
+        };
+
+        fragment.ops.push(RetainedOp {
+            bounds,
+            origin: fragment.offset,
+            op,
+        });
+        fragment.dirty = true;
+
+        self.fragments.push(fragment);
+        self.dirty = true;
+    }
+
+    fn new_fragment(&mut self, row: Option<RowKey>) -> Fragment {
+        self.next_pipeline_id += 1;
+
+        Fragment {
+            pipeline_id: PipelineId(ROOT_PIPELINE_ID.0, self.next_pipeline_id),
+            row,
+            ops: vec![],
+            offset: LayoutVector2D::zero(),
+            rect: LayoutRect::zero(),
+            epoch: Epoch(0),
+            dirty: true,
+        }
+    }
+
+    // Drop what was drawn in ROW and overlaps BOUNDS, which redisplay
+    // is about to draw again.
+    pub fn repaint(&mut self, row: RowKey, bounds: LayoutRect) {
+        if let Some(fragment) = self.fragments.iter_mut().find(|f| f.row == Some(row)) {
+            fragment.occlude(&bounds, true);
+        }
+
+        self.remove_empty_fragments();
+    }
+
+    fn occlude(&mut self, bounds: &LayoutRect) {
+        for fragment in &mut self.fragments {
+            fragment.occlude(bounds, false);
+        }
+
+        self.remove_empty_fragments();
+    }
+
+    fn remove_empty_fragments(&mut self) {
+        let removed_pipelines = &mut self.removed_pipelines;
+
+        self.fragments.retain(|fragment| {
+            if fragment.ops.is_empty() {
+                removed_pipelines.push(fragment.pipeline_id);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    // Move the fragments inside SOURCE down by DY (up when negative),
+    // dropping what they land on, as copying the pixels would.
+    pub fn scroll(&mut self, source: LayoutRect, dy: i32) {
+        let delta = LayoutVector2D::new(0.0, dy as f32);
+        let destination = source.translate(delta);
+
+        let (mut moved, rest): (Vec<Fragment>, Vec<Fragment>) = self
+            .fragments
+            .drain(..)
+            .partition(|f| source.contains_rect(&f.rect));
+
+        self.fragments = rest;
+
+        for fragment in &mut moved {
+            fragment.offset += delta;
+            fragment.rect = fragment.rect.translate(delta);
+            fragment.row = fragment.row.map(|row| RowKey {
+                y: row.y + dy,
+                ..row
+            });
+        }
+
+        self.occlude(&destination);
+
+        // Rows that were only partly covered keep their content, but
+        // no longer own their rows.
+        for fragment in &mut self.fragments {
+            if moved
+                .iter()
+                .any(|m| m.row.is_some() && m.row == fragment.row)
+            {
+                fragment.row = None;
+            }
+        }
+
+        self.fragments.append(&mut moved);
+        self.dirty = true;
+    }
+
+    pub fn clear(&mut self) {
+        let pipelines = self.fragments.drain(..).map(|f| f.pipeline_id);
+
+        self.removed_pipelines.extend(pipelines);
+        self.canvas_pipeline = None;
+        self.dirty = true;
+    }
+
+    // Add the display lists of changed fragments and the root display
+    // list placing them all to TXN.
+    pub fn build(&mut self, txn: &mut Transaction, layout_size: LayoutSize) {
+        for pipeline_id in self.removed_pipelines.drain(..) {
+            txn.remove_pipeline(pipeline_id);
+        }
+
+        let mut root = DisplayListBuilder::new(ROOT_PIPELINE_ID, layout_size);
+        let space_and_clip = SpaceAndClipInfo::root_scroll(ROOT_PIPELINE_ID);
+
+        for fragment in &mut self.fragments {
+            if fragment.dirty {
+                let display_list = fragment.build(layout_size);
+
+                txn.set_display_list(fragment.epoch, None, layout_size, display_list, true);
+            }
+
+            let bounds = LayoutRect::new(LayoutPoint::zero() + fragment.offset, layout_size);
+
+            root.push_iframe(
+                bounds,
+                fragment.rect,
+                &space_and_clip,
+                fragment.pipeline_id,
+                true,
+            );
+        }
+
+        txn.set_display_list(Epoch(0), None, layout_size, root.finalize(), true);
+
+        self.dirty = false;
+    }
+}
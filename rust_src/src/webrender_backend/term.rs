@@ -1,7 +1,11 @@
 use std::ffi::CString;
 use std::ptr;
 
-use glutin::event::{ElementState, Event, KeyboardInput, WindowEvent};
+use glutin::{
+    dpi::{PhysicalPosition, PhysicalSize},
+    event::{ElementState, Event, KeyboardInput, WindowEvent},
+    window::WindowId,
+};
 use lazy_static::lazy_static;
 
 use webrender::api::*;
@@ -11,7 +15,9 @@ use super::{
     cursor::{draw_bar_cursor, draw_filled_cursor, draw_hollow_box_cursor},
     display_info::{DisplayInfo, DisplayInfoRef},
     event::create_emacs_event,
-    output::OutputRef,
+    font_db, fringe,
+    image::{PixmapRef, WrPixmap},
+    output::{Output, OutputRef},
 };
 
 use lisp::{
@@ -24,7 +30,7 @@ use lisp::{
         block_input, change_frame_size, display_and_set_cursor, do_pending_window_change,
         draw_window_fringes, face_id, glyph_row_area, gui_clear_end_of_line,
         gui_clear_window_mouse_face, gui_draw_right_divider, gui_draw_vertical_border,
-        gui_fix_overlapping_area, gui_get_glyph_overhangs, gui_produce_glyphs,
+        gui_fix_overlapping_area, gui_get_glyph_overhangs, gui_init_fringe, gui_produce_glyphs,
         gui_set_bottom_divider_width, gui_set_font, gui_set_font_backend, gui_set_left_fringe,
         gui_set_right_divider_width, gui_set_right_fringe, gui_update_cursor, gui_write_glyphs,
         input_event, kbd_buffer_store_event_hold, note_mouse_highlight, run, store_frame_param,
@@ -34,14 +40,18 @@ use lisp::{
         create_terminal, current_kboard, draw_fringe_bitmap_params, fontset_from_font,
         frame_parm_handler, fullscreen_type, glyph_row, glyph_string, initial_kboard,
         output_method, redisplay_interface, terminal, text_cursor_kinds, xlispstrdup, Emacs_Color,
-        Fcons, Fredraw_frame, Lisp_Frame, Lisp_Window, Qbackground_color, Qfullscreen, Qmaximized,
-        Qnil, Qwr,
+        Emacs_Pixmap, Fcons, Fredraw_frame, Lisp_Frame, Lisp_Window, Qbackground_color,
+        Qfullscreen, Qmaximized, Qnil, Qparent_frame, Qwr,
     },
     window::LispWindowRef,
 };
 
 pub type TerminalRef = ExternalPtr<terminal>;
 
+// Opening a display with this name renders frames offscreen, for
+// example `emacs --display=headless'.
+pub const HEADLESS_DISPLAY_NAME: &str = "headless";
+
 fn get_frame_parm_handlers() -> [frame_parm_handler; 47] {
     // Keep this list in the same order as frame_parms in frame.c.
     // Use None for unsupported frame parameters.
@@ -85,11 +95,11 @@ fn get_frame_parm_handlers() -> [frame_parm_handler; 47] {
         None,
         None,
         None,
+        Some(set_undecorated),
+        Some(set_parent_frame),
         None,
         None,
-        None,
-        None,
-        None,
+        Some(set_no_accept_focus),
         None,
         None,
         None,
@@ -121,8 +131,8 @@ lazy_static! {
             get_glyph_overhangs: Some(gui_get_glyph_overhangs),
             fix_overlapping_area: Some(gui_fix_overlapping_area),
             draw_fringe_bitmap: Some(draw_fringe_bitmap),
-            define_fringe_bitmap: None,
-            destroy_fringe_bitmap: None,
+            define_fringe_bitmap: Some(define_fringe_bitmap),
+            destroy_fringe_bitmap: Some(destroy_fringe_bitmap),
             compute_glyph_string_overhangs: None,
             draw_glyph_string: Some(draw_glyph_string),
             define_frame_cursor: None,
@@ -209,7 +219,7 @@ extern "C" fn draw_glyph_string(s: *mut glyph_string) {
         unsafe { frame.output_data.wr.into() }
     };
 
-    output.canvas().draw_glyph_string(s);
+    output.row_canvas(s.w.into(), s.row).draw_glyph_string(s);
 }
 
 extern "C" fn draw_fringe_bitmap(
@@ -222,7 +232,40 @@ extern "C" fn draw_fringe_bitmap(
 
     let output: OutputRef = unsafe { frame.output_data.wr.into() };
 
-    output.canvas().draw_fringe_bitmap(row, p);
+    output
+        .row_canvas(window, row)
+        .draw_fringe_bitmap(window, row, p);
+}
+
+extern "C" fn define_fringe_bitmap(which: i32, bits: *mut libc::c_ushort, h: i32, wd: i32) {
+    let bits = unsafe { std::slice::from_raw_parts(bits, h as usize) };
+
+    if let Some(old) = fringe::define_fringe_bitmap(which, bits, wd) {
+        release_pixmap(old);
+    }
+}
+
+extern "C" fn destroy_fringe_bitmap(which: i32) {
+    if let Some(pixmap) = fringe::destroy_fringe_bitmap(which) {
+        release_pixmap(pixmap);
+    }
+}
+
+// Drop a reference to PIXMAP.  Fringe bitmaps, stipples and images
+// in the image cache may have been drawn on any WebRender frame, so
+// once PIXMAP is freed its images go from all of them, before another
+// pixmap can take its address.
+pub fn release_pixmap(mut pixmap: PixmapRef) {
+    if !WrPixmap::release(pixmap.as_mut()) {
+        return;
+    }
+
+    for_each_frame!(frame => {
+        if frame.output_method() == output_method::output_wr {
+            let mut output: OutputRef = unsafe { frame.output_data.wr.into() };
+            output.delete_pixmap_images(pixmap);
+        }
+    });
 }
 
 extern "C" fn set_cursor_color(f: *mut Lisp_Frame, arg: LispObject, _old_val: LispObject) {
@@ -399,6 +442,21 @@ extern "C" fn defined_color(
     }
 }
 
+extern "C" fn query_colors(_frame: *mut Lisp_Frame, colors: *mut Emacs_Color, ncolors: i32) {
+    let colors = unsafe { std::slice::from_raw_parts_mut(colors, ncolors as usize) };
+
+    // Pixels hold the 16-bit channels directly, red lowest.
+    for color in colors {
+        color.red = color.pixel as u16;
+        color.green = (color.pixel >> 16) as u16;
+        color.blue = (color.pixel >> 32) as u16;
+    }
+}
+
+extern "C" fn free_pixmap(_f: *mut Lisp_Frame, pixmap: Emacs_Pixmap) {
+    release_pixmap(PixmapRef::new(pixmap as *mut _));
+}
+
 extern "C" fn frame_visible_invisible(frame: *mut Lisp_Frame, is_visible: bool) {
     let mut f: LispFrameRef = frame.into();
 
@@ -432,11 +490,70 @@ extern "C" fn set_background_color(f: *mut Lisp_Frame, arg: LispObject, _old_val
     }
 }
 
+extern "C" fn set_undecorated(f: *mut Lisp_Frame, new_value: LispObject, old_value: LispObject) {
+    let mut frame: LispFrameRef = f.into();
+
+    if new_value.eq(old_value) {
+        return;
+    }
+
+    frame.set_undecorated(new_value.is_not_nil());
+
+    // The window manager only decorates top-level frames.
+    if frame.parent_frame.is_nil() && !frame.override_redirect() {
+        let output: OutputRef = unsafe { frame.output_data.wr.into() };
+
+        output.set_decorations(new_value.is_nil());
+    }
+}
+
+// Child frames keep windows of their own, which are moved along with
+// the window of their parent frame.  The type of a window is fixed when
+// it is created, so a child frame may only move to another parent.
+extern "C" fn set_parent_frame(f: *mut Lisp_Frame, new_value: LispObject, old_value: LispObject) {
+    let mut frame: LispFrameRef = f.into();
+
+    if new_value.is_not_nil()
+        && new_value
+            .as_live_frame()
+            .map_or(true, |p| p.output_method() != output_method::output_wr)
+    {
+        unsafe { store_frame_param(f, Qparent_frame, old_value) };
+        error!("Invalid specification of `parent-frame'");
+    }
+
+    if new_value.eq(frame.parent_frame) {
+        return;
+    }
+
+    if new_value.is_nil() != frame.parent_frame.is_nil() {
+        unsafe { store_frame_param(f, Qparent_frame, old_value) };
+        error!("Cannot change whether a frame is a child frame after it is created");
+    }
+
+    frame.parent_frame = new_value;
+
+    place_window(frame);
+}
+
+// Whether the window manager may focus the window of a frame is decided
+// when the window is created, so this only stops Emacs from switching
+// to the frame when it gets the focus anyway.
+extern "C" fn set_no_accept_focus(
+    f: *mut Lisp_Frame,
+    new_value: LispObject,
+    _old_value: LispObject,
+) {
+    let mut frame: LispFrameRef = f.into();
+
+    frame.set_no_accept_focus(new_value.is_not_nil());
+}
+
 extern "C" fn clear_frame(f: *mut Lisp_Frame) {
     let frame: LispFrameRef = f.into();
     let mut output: OutputRef = unsafe { frame.output_data.wr.into() };
 
-    output.clear_display_list_builder();
+    output.clear_scene();
 
     let width = frame.pixel_width;
     let height = frame.pixel_height;
@@ -481,155 +598,275 @@ extern "C" fn read_input_event(terminal: *mut terminal, hold_quit: *mut input_ev
     let dpyinfo = DisplayInfoRef::new(unsafe { terminal.display_info.wr } as *mut _);
 
     let mut dpyinfo = dpyinfo.get_inner();
-    let mut output = dpyinfo.output;
-
-    let mut top_frame: LispObject = Qnil;
-
-    for_each_frame!(frame => {
-        let frame_output: OutputRef = unsafe { frame.output_data.wr.into() };
-
-        if frame_output == output {
-            top_frame = frame.into();
-            break;
-        }
-    });
+    let window_server = dpyinfo.window_server.clone();
 
     let mut count = 0;
 
-    output.poll_events(|e| match e {
-        Event::WindowEvent {
-            event: WindowEvent::ReceivedCharacter(key_code),
-            ..
-        } => {
-            if let Some(mut iev) = dpyinfo.input_processor.receive_char(key_code, top_frame) {
-                unsafe { kbd_buffer_store_event_hold(&mut iev, hold_quit) };
-                count += 1;
+    window_server.poll_events(|e| {
+        let top_frame = match &e {
+            Event::WindowEvent { window_id, .. } => frame_for_window(*window_id),
+            _ => Qnil,
+        };
+
+        match e {
+            Event::WindowEvent {
+                event: WindowEvent::ReceivedCharacter(key_code),
+                ..
+            } => {
+                if let Some(mut iev) = dpyinfo.input_processor.receive_char(key_code, top_frame) {
+                    unsafe { kbd_buffer_store_event_hold(&mut iev, hold_quit) };
+                    count += 1;
+                }
             }
-        }
 
-        Event::WindowEvent {
-            event: WindowEvent::ModifiersChanged(state),
-            ..
-        } => {
-            dpyinfo.input_processor.change_modifiers(state);
-        }
+            Event::WindowEvent {
+                event: WindowEvent::ModifiersChanged(state),
+                ..
+            } => {
+                dpyinfo.input_processor.change_modifiers(state);
+            }
 
-        Event::WindowEvent {
-            event:
-                WindowEvent::KeyboardInput {
-                    input:
-                        KeyboardInput {
-                            state,
-                            virtual_keycode: Some(key_code),
-                            ..
-                        },
-                    ..
-                },
-            ..
-        } => match state {
-            ElementState::Pressed => {
-                if let Some(mut iev) = dpyinfo.input_processor.key_pressed(key_code, top_frame) {
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state,
+                                virtual_keycode: Some(key_code),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => match state {
+                ElementState::Pressed => {
+                    if let Some(mut iev) = dpyinfo.input_processor.key_pressed(key_code, top_frame)
+                    {
+                        unsafe { kbd_buffer_store_event_hold(&mut iev, hold_quit) };
+                        count += 1;
+                    }
+                }
+                ElementState::Released => dpyinfo.input_processor.key_released(),
+            },
+
+            Event::WindowEvent {
+                event: WindowEvent::MouseInput { state, button, .. },
+                ..
+            } => {
+                if let Some(mut iev) = dpyinfo
+                    .input_processor
+                    .mouse_pressed(button, state, top_frame)
+                {
                     unsafe { kbd_buffer_store_event_hold(&mut iev, hold_quit) };
                     count += 1;
                 }
             }
-            ElementState::Released => dpyinfo.input_processor.key_released(),
-        },
-
-        Event::WindowEvent {
-            event: WindowEvent::MouseInput { state, button, .. },
-            ..
-        } => {
-            if let Some(mut iev) = dpyinfo
-                .input_processor
-                .mouse_pressed(button, state, top_frame)
-            {
-                unsafe { kbd_buffer_store_event_hold(&mut iev, hold_quit) };
-                count += 1;
-            }
-        }
 
-        Event::WindowEvent {
-            event: WindowEvent::CursorMoved { position, .. },
-            ..
-        } => {
-            dpyinfo.input_processor.cursor_move(position);
+            Event::WindowEvent {
+                event: WindowEvent::CursorMoved { position, .. },
+                ..
+            } => {
+                dpyinfo.input_processor.cursor_move(position);
 
-            if top_frame.as_frame().is_none() {
-                return;
-            }
+                if top_frame.as_frame().is_none() {
+                    return;
+                }
 
-            let mut frame: LispFrameRef = top_frame.into();
+                let mut frame: LispFrameRef = top_frame.into();
 
-            unsafe { note_mouse_highlight(frame.as_mut(), position.x as i32, position.y as i32) };
-        }
+                // Tooltips are placed from the mouse position in this frame.
+                let mut dpyinfo =
+                    DisplayInfoRef::new(unsafe { terminal.display_info.wr } as *mut _);
 
-        Event::WindowEvent {
-            event: WindowEvent::Focused(is_focused),
-            ..
-        } => {
-            let mut dpyinfo = DisplayInfoRef::new(unsafe { terminal.display_info.wr } as *mut _);
+                dpyinfo.get_raw().last_mouse_motion_frame = frame.as_mut();
 
-            if top_frame.as_frame().is_none() {
-                return;
+                unsafe {
+                    note_mouse_highlight(frame.as_mut(), position.x as i32, position.y as i32)
+                };
             }
 
-            let mut top_frame = top_frame.as_frame().unwrap();
+            Event::WindowEvent {
+                event: WindowEvent::Focused(is_focused),
+                ..
+            } => {
+                let mut dpyinfo =
+                    DisplayInfoRef::new(unsafe { terminal.display_info.wr } as *mut _);
 
-            let focus_frame = if !top_frame.focus_frame.eq(Qnil) {
-                top_frame.focus_frame.as_frame().unwrap().as_mut()
-            } else {
-                top_frame.as_mut()
-            };
+                if top_frame.as_frame().is_none() {
+                    return;
+                }
 
-            dpyinfo.get_raw().highlight_frame = if is_focused {
-                focus_frame
-            } else {
-                ptr::null_mut()
-            };
+                let mut top_frame = top_frame.as_frame().unwrap();
 
-            let event_type = if is_focused {
-                lisp::remacs_sys::event_kind::FOCUS_IN_EVENT
-            } else {
-                lisp::remacs_sys::event_kind::FOCUS_OUT_EVENT
-            };
+                if is_focused && top_frame.no_accept_focus() {
+                    return;
+                }
 
-            let mut event = create_emacs_event(event_type, top_frame.into());
+                let focus_frame = if !top_frame.focus_frame.eq(Qnil) {
+                    top_frame.focus_frame.as_frame().unwrap().as_mut()
+                } else {
+                    top_frame.as_mut()
+                };
 
-            unsafe { kbd_buffer_store_event_hold(&mut event, hold_quit) };
-            count += 1;
-        }
+                dpyinfo.get_raw().highlight_frame = if is_focused {
+                    focus_frame
+                } else {
+                    ptr::null_mut()
+                };
+
+                let mut inner = dpyinfo.get_inner();
+
+                if is_focused {
+                    inner.focus_frame = top_frame;
+                } else if inner.focus_frame == top_frame {
+                    inner.focus_frame = LispFrameRef::new(ptr::null_mut());
+                }
 
-        Event::WindowEvent {
-            event: WindowEvent::Resized(size),
-            ..
-        } => {
-            if top_frame.as_frame().is_none() {
-                return;
+                let event_type = if is_focused {
+                    lisp::remacs_sys::event_kind::FOCUS_IN_EVENT
+                } else {
+                    lisp::remacs_sys::event_kind::FOCUS_OUT_EVENT
+                };
+
+                let mut event = create_emacs_event(event_type, top_frame.into());
+
+                unsafe { kbd_buffer_store_event_hold(&mut event, hold_quit) };
+                count += 1;
             }
 
-            let mut frame: LispFrameRef = top_frame.into();
-            unsafe {
-                change_frame_size(
-                    frame.as_mut(),
-                    size.width as i32,
-                    size.height as i32 - frame.menu_bar_height,
-                    false,
-                    true,
-                    false,
-                    true,
+            Event::WindowEvent {
+                event: WindowEvent::Resized(size),
+                ..
+            } => {
+                if top_frame.as_frame().is_none() {
+                    return;
+                }
+
+                let mut frame: LispFrameRef = top_frame.into();
+                unsafe {
+                    change_frame_size(
+                        frame.as_mut(),
+                        size.width as i32,
+                        size.height as i32 - frame.menu_bar_height,
+                        false,
+                        true,
+                        false,
+                        true,
+                    );
+
+                    do_pending_window_change(false);
+                }
+            }
+
+            Event::WindowEvent {
+                event: WindowEvent::Moved(position),
+                ..
+            } => {
+                if let Some(mut frame) = top_frame.as_frame() {
+                    // The position of a child frame is relative to its
+                    // parent frame, and only changed by Emacs.
+                    if frame.parent_frame.is_nil() {
+                        frame.left_pos = position.x;
+                        frame.top_pos = position.y;
+                    }
+
+                    for child in child_frames(frame) {
+                        place_window(child);
+                    }
+                }
+            }
+
+            Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                ..
+            } => {
+                if top_frame.as_frame().is_none() {
+                    return;
+                }
+
+                let mut event = create_emacs_event(
+                    lisp::remacs_sys::event_kind::DELETE_WINDOW_EVENT,
+                    top_frame,
                 );
 
-                do_pending_window_change(false);
+                unsafe { kbd_buffer_store_event_hold(&mut event, hold_quit) };
+                count += 1;
             }
-        }
 
-        _ => {}
+            _ => {}
+        }
     });
 
     count
 }
 
+// The frame shown in the window WINDOW_ID, or nil.
+fn frame_for_window(window_id: WindowId) -> LispObject {
+    for_each_frame!(frame => {
+        if frame.output_method() != output_method::output_wr {
+            continue;
+        }
+
+        let output: OutputRef = unsafe { frame.output_data.wr.into() };
+
+        if output.window_id() == Some(window_id) {
+            return frame.into();
+        }
+    });
+
+    Qnil
+}
+
+// The frames whose parent frame is FRAME.
+fn child_frames(frame: LispFrameRef) -> Vec<LispFrameRef> {
+    let mut children = Vec::new();
+
+    for_each_frame!(child => {
+        if child.parent_frame.eq(frame) {
+            children.push(child);
+        }
+    });
+
+    children
+}
+
+// Move the window of FRAME to where Emacs wants it.  Child frames are
+// placed relative to the drawing area of their parent frame.
+fn place_window(frame: LispFrameRef) {
+    let mut position = PhysicalPosition::new(frame.left_pos, frame.top_pos);
+
+    if let Some(parent) = frame.parent_frame.as_frame() {
+        let parent_output: OutputRef = unsafe { parent.output_data.wr.into() };
+
+        if let Some(origin) = parent_output.get_inner_position() {
+            position.x += origin.x;
+            position.y += origin.y;
+        }
+    }
+
+    let output: OutputRef = unsafe { frame.output_data.wr.into() };
+
+    output.set_position(position);
+}
+
+// Child frames are only shown while their parent frame is.
+fn update_child_windows(frame: LispFrameRef, visible: bool) {
+    for child in child_frames(frame) {
+        let output: OutputRef = unsafe { child.output_data.wr.into() };
+
+        let visible = visible && child.is_visible();
+
+        if visible {
+            place_window(child);
+            output.show_window();
+        } else {
+            output.hide_window();
+        }
+
+        update_child_windows(child, visible);
+    }
+}
+
 extern "C" fn fullscreen(f: *mut Lisp_Frame) {
     let frame: LispFrameRef = f.into();
 
@@ -691,11 +928,23 @@ extern "C" fn make_frame_visible_invisible(f: *mut Lisp_Frame, visible: bool) {
 
     let output: OutputRef = unsafe { frame.output_data.wr.into() };
 
-    if visible {
+    let shown = visible
+        && frame
+            .parent_frame
+            .as_frame()
+            .map_or(true, |parent| parent.is_visible());
+
+    if shown {
+        if frame.parent_frame.is_not_nil() {
+            place_window(frame);
+        }
+
         output.show_window();
     } else {
         output.hide_window();
     }
+
+    update_child_windows(frame, shown);
 }
 
 extern "C" fn iconify_frame(f: *mut Lisp_Frame) {
@@ -704,7 +953,81 @@ extern "C" fn iconify_frame(f: *mut Lisp_Frame) {
     frame.set_iconified(true);
 
     let output: OutputRef = unsafe { frame.output_data.wr.into() };
-    output.hide_window()
+    output.hide_window();
+
+    update_child_windows(frame, false);
+}
+
+extern "C" fn set_frame_offset(f: *mut Lisp_Frame, xoff: i32, yoff: i32, change_gravity: i32) {
+    let mut frame: LispFrameRef = f.into();
+
+    if change_gravity > 0 {
+        frame.left_pos = xoff;
+        frame.top_pos = yoff;
+    }
+
+    place_window(frame);
+}
+
+extern "C" fn set_window_size(
+    f: *mut Lisp_Frame,
+    _change_gravity: bool,
+    width: i32,
+    height: i32,
+    pixelwise: bool,
+) {
+    let mut frame: LispFrameRef = f.into();
+
+    let (width, height) = if pixelwise {
+        (width, height)
+    } else {
+        (width * frame.column_width, height * frame.line_height)
+    };
+
+    let mut output: OutputRef = unsafe { frame.output_data.wr.into() };
+
+    output.set_inner_size(PhysicalSize::new(
+        frame.text_to_pixel_width(width) as u32,
+        frame.text_to_pixel_height(height) as u32,
+    ));
+
+    // Without a visible window, no resize event reports the new size.
+    if output.is_headless() || !frame.is_visible() {
+        unsafe { change_frame_size(frame.as_mut(), width, height, false, true, false, true) };
+    }
+}
+
+extern "C" fn delete_frame(f: *mut Lisp_Frame) {
+    let mut frame: LispFrameRef = f.into();
+    let mut output: OutputRef = unsafe { frame.output_data.wr.into() };
+
+    // Forget the frame wherever the display remembers it.
+    let mut dpyinfo = output.display_info();
+
+    {
+        let mut dpyinfo = dpyinfo.get_raw();
+
+        if dpyinfo.highlight_frame == f {
+            dpyinfo.highlight_frame = ptr::null_mut();
+        }
+        if dpyinfo.last_mouse_frame == f {
+            dpyinfo.last_mouse_frame = ptr::null_mut();
+        }
+        if dpyinfo.last_mouse_motion_frame == f {
+            dpyinfo.last_mouse_motion_frame = ptr::null_mut();
+        }
+    }
+
+    let mut inner = dpyinfo.get_inner();
+
+    if inner.focus_frame == frame {
+        inner.focus_frame = LispFrameRef::new(ptr::null_mut());
+    }
+
+    // Dropping the output closes its window.
+    unsafe { Box::from_raw(output.as_mut() as *mut Output) };
+
+    frame.output_data.wr = ptr::null_mut();
 }
 
 fn wr_create_terminal(mut dpyinfo: DisplayInfoRef) -> TerminalRef {
@@ -735,12 +1058,25 @@ fn wr_create_terminal(mut dpyinfo: DisplayInfoRef) -> TerminalRef {
     terminal.get_focus_frame = Some(get_focus_frame);
     terminal.frame_visible_invisible_hook = Some(make_frame_visible_invisible);
     terminal.iconify_frame_hook = Some(iconify_frame);
+    terminal.query_colors = Some(query_colors);
+    terminal.free_pixmap = Some(free_pixmap);
+    terminal.set_frame_offset_hook = Some(set_frame_offset);
+    terminal.set_window_size_hook = Some(set_window_size);
+    terminal.delete_frame_hook = Some(delete_frame);
 
     terminal
 }
 
 pub fn wr_term_init(display_name: LispObject) -> DisplayInfoRef {
-    let dpyinfo = Box::new(DisplayInfo::new());
+    let name = display_name
+        .as_string()
+        .map_or_else(String::new, |name| name.to_utf8());
+
+    let headless = name == HEADLESS_DISPLAY_NAME;
+
+    let dpyinfo = DisplayInfo::new(headless)
+        .unwrap_or_else(|e| error!("Cannot open WebRender display {}: {}", name, e));
+    let dpyinfo = Box::new(dpyinfo);
     let mut dpyinfo_ref = DisplayInfoRef::new(Box::into_raw(dpyinfo));
 
     let mut terminal = wr_create_terminal(dpyinfo_ref);
@@ -775,5 +1111,9 @@ pub fn wr_term_init(display_name: LispObject) -> DisplayInfoRef {
     // Set the name of the terminal.
     terminal.name = unsafe { xlispstrdup(display_name) };
 
+    unsafe { gui_init_fringe(terminal.rif) };
+
+    font_db::preload_all_fonts();
+
     dpyinfo_ref
 }
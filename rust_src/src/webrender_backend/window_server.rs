@@ -0,0 +1,1102 @@
+//! The window server of a display: a thread owning the event loop,
+//! the windows of every frame and the WebRender renderer drawing them.
+//!
+//! winit supports a single event loop per process, and fonts and images
+//! added to WebRender can only be used by documents of the renderer they
+//! were added to, so frames don't get a renderer each.  Every frame is a
+//! document instead.  The renderer draws all documents into one
+//! offscreen framebuffer, each in a slot of its own, and a frame's
+//! window is updated by copying its slot.  The framebuffer can't be
+//! larger than the largest texture, which bounds the number of frames.
+
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    sync::{
+        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
+        Arc, Mutex,
+    },
+};
+
+use gleam::gl::{self, Gl};
+use glutin::{
+    self,
+    dpi::PhysicalSize,
+    event::{Event, WindowEvent},
+    event_loop::{ControlFlow, EventLoop, EventLoopProxy, EventLoopWindowTarget},
+    monitor::MonitorHandle,
+    window::{Window, WindowBuilder, WindowId},
+    Context, PossiblyCurrent, RawContext,
+};
+
+#[cfg(unix)]
+use glutin::platform::unix::EventLoopExtUnix;
+#[cfg(windows)]
+use glutin::platform::windows::EventLoopExtUnix;
+
+use webrender::{self, api::units::*, api::*};
+
+// Size of a headless output, which has no window to take its size
+// from, until its frame is resized.
+pub const HEADLESS_SIZE: PhysicalSize<u32> = PhysicalSize {
+    width: 1280,
+    height: 720,
+};
+
+// What a new frame is shown in.
+#[derive(Clone, Copy, PartialEq)]
+pub enum WindowKind {
+    Toplevel,
+    // Child frames, which Emacs places relative to their parent frame.
+    Child,
+    Tooltip,
+}
+
+// How to create the window of a frame.
+#[derive(Clone, Copy)]
+pub struct WindowOptions {
+    pub kind: WindowKind,
+    // Whether the window manager may decorate a top-level window.
+    pub decorated: bool,
+    // Keep the window manager away from the window.  It then neither
+    // decorates nor focuses it.
+    pub override_redirect: bool,
+}
+
+impl Default for WindowOptions {
+    fn default() -> Self {
+        WindowOptions {
+            kind: WindowKind::Toplevel,
+            decorated: true,
+            override_redirect: false,
+        }
+    }
+}
+
+impl WindowOptions {
+    pub fn tooltip() -> Self {
+        WindowOptions {
+            kind: WindowKind::Tooltip,
+            decorated: false,
+            override_redirect: true,
+        }
+    }
+
+    fn window_builder(self) -> WindowBuilder {
+        let toplevel = self.kind == WindowKind::Toplevel;
+
+        // Child frames and tooltips are mapped once Emacs has placed
+        // them.
+        let window_builder = WindowBuilder::new()
+            .with_visible(toplevel)
+            .with_maximized(toplevel)
+            .with_decorations(toplevel && self.decorated && !self.override_redirect);
+
+        #[cfg(unix)]
+        let window_builder = {
+            use glutin::platform::unix::{WindowBuilderExtUnix, XWindowType};
+
+            let window_type = match self.kind {
+                WindowKind::Toplevel => XWindowType::Normal,
+                WindowKind::Child => XWindowType::Utility,
+                WindowKind::Tooltip => XWindowType::Tooltip,
+            };
+
+            window_builder
+                .with_override_redirect(self.override_redirect)
+                .with_x11_window_type(vec![window_type])
+        };
+
+        window_builder
+    }
+}
+
+pub enum EmacsGUIEvent {
+    CreateSurface(
+        WindowOptions,
+        SyncSender<Result<(Option<Window>, DocumentId), String>>,
+    ),
+    DestroySurface(DocumentId, SyncSender<()>),
+    // Windows report their new size themselves, headless outputs send
+    // it with this.
+    ResizeSurface(DocumentId, PhysicalSize<u32>),
+    Flush(DocumentId, SyncSender<()>),
+    ReadPixels(DocumentId, DeviceIntRect, SyncSender<Vec<u8>>),
+    Monitors(SyncSender<Monitors>),
+}
+
+pub type GUIEvent = Event<'static, EmacsGUIEvent>;
+
+// The monitors of a display, and the primary one if there is one.
+pub type Monitors = (Vec<MonitorHandle>, Option<MonitorHandle>);
+
+// What the window server thread hands back once it is running, or why
+// it couldn't start.
+type ServerInit = Result<(RenderApiSender, GUIEventSender, u8), String>;
+
+// Headless displays have no winit event loop, their render thread
+// waits on a channel instead.
+#[derive(Clone)]
+enum GUIEventSender {
+    EventLoop(EventLoopProxy<EmacsGUIEvent>),
+    Headless(Sender<EmacsGUIEvent>),
+}
+
+impl GUIEventSender {
+    fn send_event(&self, event: EmacsGUIEvent) {
+        match self {
+            GUIEventSender::EventLoop(proxy) => {
+                let _ = proxy.send_event(event);
+            }
+            GUIEventSender::Headless(sender) => {
+                let _ = sender.send(event);
+            }
+        }
+    }
+}
+
+// What Emacs keeps of the window server of a display.  The display and
+// each of its outputs hold a copy.
+#[derive(Clone)]
+pub struct WindowServer {
+    api: Rc<RenderApi>,
+    event_sender: GUIEventSender,
+    events: Rc<Receiver<GUIEvent>>,
+    color_bits: u8,
+}
+
+impl WindowServer {
+    // Start the window server thread, which runs as long as Emacs.
+    pub fn new(headless: bool) -> Result<Self, String> {
+        let (server_tx, server_rx) = sync_channel(1);
+
+        let (event_tx, event_rx) = channel::<GUIEvent>();
+
+        if headless {
+            std::thread::spawn(move || run_headless(server_tx));
+        } else {
+            std::thread::spawn(move || run_event_loop(server_tx, event_tx));
+        }
+
+        // The thread is gone if it panicked before answering.
+        let (api_sender, event_sender, color_bits) = server_rx
+            .recv()
+            .unwrap_or_else(|_| Err("the window server exited".to_string()))?;
+
+        Ok(WindowServer {
+            api: Rc::new(api_sender.create_api()),
+            event_sender,
+            events: Rc::new(event_rx),
+            color_bits,
+        })
+    }
+
+    // All outputs of the display share this API, so fonts and images
+    // added through it can be used by every frame.
+    pub fn api(&self) -> &RenderApi {
+        &self.api
+    }
+
+    pub fn is_headless(&self) -> bool {
+        match self.event_sender {
+            GUIEventSender::EventLoop(_) => false,
+            GUIEventSender::Headless(_) => true,
+        }
+    }
+
+    pub fn color_bits(&self) -> u8 {
+        self.color_bits
+    }
+
+    // Create the window and the document of a new frame.  Headless
+    // displays don't create windows.
+    pub fn create_surface(
+        &self,
+        options: WindowOptions,
+    ) -> Result<(Option<Window>, DocumentId), String> {
+        let (sender, receiver) = sync_channel(1);
+
+        self.event_sender
+            .send_event(EmacsGUIEvent::CreateSurface(options, sender));
+
+        receiver.recv().unwrap()
+    }
+
+    // Forget the document DOCUMENT_ID, before its window is dropped.
+    pub fn destroy_surface(&self, document_id: DocumentId) {
+        let (sender, receiver) = sync_channel(1);
+
+        self.event_sender
+            .send_event(EmacsGUIEvent::DestroySurface(document_id, sender));
+
+        receiver.recv().unwrap()
+    }
+
+    // Give the headless document DOCUMENT_ID a new size.
+    pub fn resize_surface(&self, document_id: DocumentId, size: PhysicalSize<u32>) {
+        self.event_sender
+            .send_event(EmacsGUIEvent::ResizeSurface(document_id, size));
+    }
+
+    // Show DOCUMENT_ID in its window, rendering it first if it changed.
+    pub fn flush(&self, document_id: DocumentId) {
+        let (sender, receiver) = sync_channel(1);
+
+        self.event_sender
+            .send_event(EmacsGUIEvent::Flush(document_id, sender));
+
+        receiver.recv().unwrap()
+    }
+
+    // Copy the pixels of DEVICE_RECT in DOCUMENT_ID back to the CPU, top
+    // row first.
+    pub fn read_pixels(&self, document_id: DocumentId, device_rect: DeviceIntRect) -> Vec<u8> {
+        let (sender, receiver) = sync_channel(1);
+
+        self.event_sender
+            .send_event(EmacsGUIEvent::ReadPixels(document_id, device_rect, sender));
+
+        receiver.recv().unwrap()
+    }
+
+    // Headless displays have no monitors.
+    pub fn monitors(&self) -> Monitors {
+        if self.is_headless() {
+            return (vec![], None);
+        }
+
+        let (sender, receiver) = sync_channel(1);
+
+        self.event_sender
+            .send_event(EmacsGUIEvent::Monitors(sender));
+
+        receiver.recv().unwrap()
+    }
+
+    // Call F with the window system events received for any frame of
+    // the display.
+    pub fn poll_events<F>(&self, mut f: F)
+    where
+        F: FnMut(GUIEvent),
+    {
+        for e in self.events.try_iter() {
+            f(e);
+        }
+    }
+}
+
+fn run_event_loop(server_tx: SyncSender<ServerInit>, event_tx: Sender<GUIEvent>) {
+    let events_loop = EventLoop::new_any_thread();
+
+    // The renderer draws with the context of a window that is never
+    // shown, so it doesn't depend on any frame staying alive.
+    let current_context = glutin::ContextBuilder::new()
+        .build_windowed(WindowBuilder::new().with_visible(false), &events_loop)
+        .map_err(|e| e.to_string())
+        .and_then(|context| unsafe { context.make_current() }.map_err(|(_, e)| e.to_string()));
+
+    let current_context = match current_context {
+        Ok(current_context) => current_context,
+        Err(e) => {
+            let _ = server_tx.send(Err(e));
+            return;
+        }
+    };
+
+    let color_bits = current_context.get_pixel_format().color_bits;
+    let (current_context, window) = unsafe { current_context.split() };
+
+    let event_sender = GUIEventSender::EventLoop(events_loop.create_proxy());
+
+    // Slots are as large as the largest monitor, so that maximized
+    // frames fit in theirs.
+    let slot_size = events_loop.available_monitors().map(|m| m.size()).fold(
+        HEADLESS_SIZE,
+        |size, monitor_size| {
+            PhysicalSize::new(
+                size.width.max(monitor_size.width),
+                size.height.max(monitor_size.height),
+            )
+        },
+    );
+
+    let (mut compositor, api_sender) = Compositor::new(
+        MainContext::Window(current_context, window),
+        event_sender.clone(),
+        slot_size,
+    );
+
+    server_tx
+        .send(Ok((api_sender, event_sender, color_bits)))
+        .unwrap();
+
+    events_loop.run(move |e, target, control_flow| {
+        *control_flow = ControlFlow::Wait;
+
+        match e {
+            Event::WindowEvent {
+                window_id,
+                event: WindowEvent::Resized(size),
+            } => {
+                compositor.resize_surface(window_id, size);
+
+                event_tx.send(e.to_static().unwrap()).unwrap();
+
+                unsafe { libc::raise(libc::SIGIO) };
+            }
+
+            // The frame moved to a monitor with another scale.
+            Event::WindowEvent {
+                window_id,
+                event: WindowEvent::ScaleFactorChanged { scale_factor, .. },
+            } => {
+                compositor.set_scale_factor(window_id, scale_factor);
+            }
+
+            Event::WindowEvent {
+                event: WindowEvent::KeyboardInput { .. },
+                ..
+            }
+            | Event::WindowEvent {
+                event: WindowEvent::ReceivedCharacter(_),
+                ..
+            }
+            | Event::WindowEvent {
+                event: WindowEvent::ModifiersChanged(_),
+                ..
+            }
+            | Event::WindowEvent {
+                event: WindowEvent::MouseInput { .. },
+                ..
+            }
+            | Event::WindowEvent {
+                event: WindowEvent::CursorMoved { .. },
+                ..
+            }
+            | Event::WindowEvent {
+                event: WindowEvent::Focused(_),
+                ..
+            }
+            | Event::WindowEvent {
+                event: WindowEvent::Moved(_),
+                ..
+            }
+            | Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                ..
+            } => {
+                event_tx.send(e.to_static().unwrap()).unwrap();
+                unsafe { libc::raise(libc::SIGIO) };
+            }
+            // Windows mapped or uncovered after their last flush still
+            // need to show it.
+            Event::RedrawRequested(window_id) => compositor.redraw(window_id),
+            Event::UserEvent(event) => compositor.handle_event(event, Some(target)),
+            _ => {}
+        };
+    })
+}
+
+// Render into an offscreen OSMesa context instead of windows, so frames
+// can be drawn and captured on machines without a display server or
+// GPU.
+fn run_headless(server_tx: SyncSender<ServerInit>) {
+    let (gui_tx, gui_rx) = channel::<EmacsGUIEvent>();
+    let event_sender = GUIEventSender::Headless(gui_tx);
+
+    // Documents are drawn into a framebuffer of their own, the size of
+    // this context doesn't limit them.
+    let current_context = match create_headless_context(HEADLESS_SIZE) {
+        Ok(current_context) => current_context,
+        Err(e) => {
+            let _ = server_tx.send(Err(e));
+            return;
+        }
+    };
+
+    let (mut compositor, api_sender) = Compositor::new(
+        MainContext::Headless(current_context),
+        event_sender.clone(),
+        HEADLESS_SIZE,
+    );
+
+    server_tx.send(Ok((api_sender, event_sender, 24))).unwrap();
+
+    for event in gui_rx.iter() {
+        compositor.handle_event(event, None);
+    }
+}
+
+#[cfg(unix)]
+fn create_headless_context(size: PhysicalSize<u32>) -> Result<Context<PossiblyCurrent>, String> {
+    use glutin::platform::unix::HeadlessContextExt;
+
+    let context = glutin::ContextBuilder::new()
+        .build_osmesa(size)
+        .map_err(|e| format!("failed to create an OSMesa context: {}", e))?;
+
+    unsafe { context.make_current() }.map_err(|(_, e)| e.to_string())
+}
+
+#[cfg(not(unix))]
+fn create_headless_context(_size: PhysicalSize<u32>) -> Result<Context<PossiblyCurrent>, String> {
+    Err("headless output requires OSMesa".to_string())
+}
+
+fn get_gl_api(context: &Context<PossiblyCurrent>) -> Rc<dyn Gl> {
+    match context.get_api() {
+        glutin::Api::OpenGl => unsafe {
+            gl::GlFns::load_with(|symbol| context.get_proc_address(symbol) as *const _)
+        },
+        glutin::Api::OpenGlEs => unsafe {
+            gl::GlesFns::load_with(|symbol| context.get_proc_address(symbol) as *const _)
+        },
+        glutin::Api::WebGl => unimplemented!(),
+    }
+}
+
+// The context the renderer draws with.
+enum MainContext {
+    // Kept with its hidden window.
+    Window(RawContext<PossiblyCurrent>, Window),
+    Headless(Context<PossiblyCurrent>),
+}
+
+impl MainContext {
+    fn context(&self) -> &Context<PossiblyCurrent> {
+        match self {
+            MainContext::Window(context, _) => context.context(),
+            MainContext::Headless(context) => context,
+        }
+    }
+
+    // glutin consumes contexts to make them current, and gives them
+    // back even when that fails.
+    fn make_current(self) -> Self {
+        match self {
+            MainContext::Window(context, window) => {
+                let context = unsafe { context.make_current() }.unwrap_or_else(|(c, _)| c);
+                MainContext::Window(context, window)
+            }
+            MainContext::Headless(context) => {
+                MainContext::Headless(unsafe { context.make_current() }.unwrap_or_else(|(c, _)| c))
+            }
+        }
+    }
+}
+
+// The window of a surface, and what is needed to copy into it.
+struct WindowSurface {
+    window_id: WindowId,
+    context: Option<RawContext<PossiblyCurrent>>,
+    gl: Rc<dyn Gl>,
+    // Reads the offscreen framebuffer from this context, framebuffer
+    // objects aren't shared between contexts.
+    read_fbo: gl::GLuint,
+}
+
+impl WindowSurface {
+    fn make_current(&mut self) {
+        let context = self.context.take().unwrap();
+
+        self.context = Some(unsafe { context.make_current() }.unwrap_or_else(|(c, _)| c));
+    }
+}
+
+// Where a document is drawn.
+struct Surface {
+    slot: usize,
+    size: DeviceIntSize,
+    scale_factor: f32,
+    // None on headless displays.
+    window: Option<WindowSurface>,
+}
+
+// The offscreen framebuffer documents are rendered to.
+struct Framebuffer {
+    fbo: gl::GLuint,
+    texture: gl::GLuint,
+    size: DeviceIntSize,
+}
+
+// Owns the renderer on the window server thread.
+struct Compositor {
+    renderer: webrender::Renderer,
+    context: Option<MainContext>,
+    gl: Rc<dyn Gl>,
+    api: RenderApi,
+    framebuffer: Framebuffer,
+    max_texture_size: i32,
+    slot_size: DeviceIntSize,
+    // How many slots fit side by side in the framebuffer.
+    columns: usize,
+    // The document in each slot.
+    slots: Vec<Option<DocumentId>>,
+    surfaces: HashMap<DocumentId, Surface>,
+    // The documents the framebuffer is behind on: added when a new
+    // frame of theirs is ready and when they move to another slot.
+    stale_documents: StaleDocuments,
+}
+
+type StaleDocuments = Arc<Mutex<HashSet<DocumentId>>>;
+
+impl Compositor {
+    fn new(
+        context: MainContext,
+        event_sender: GUIEventSender,
+        slot_size: PhysicalSize<u32>,
+    ) -> (Self, RenderApiSender) {
+        let gl = get_gl_api(context.context());
+
+        let slot_size = DeviceIntSize::new(slot_size.width as i32, slot_size.height as i32);
+
+        let webrender_opts = webrender::RendererOptions {
+            clear_color: None,
+            ..webrender::RendererOptions::default()
+        };
+
+        let stale_documents = StaleDocuments::default();
+
+        let notifier = Box::new(Notifier::new(event_sender, stale_documents.clone()));
+
+        let (renderer, sender) =
+            webrender::Renderer::new(gl.clone(), notifier, webrender_opts, None, slot_size)
+                .unwrap();
+
+        let mut max_texture_size = [0];
+        unsafe { gl.get_integer_v(gl::MAX_TEXTURE_SIZE, &mut max_texture_size) };
+
+        let max_texture_size = max_texture_size[0];
+        let slot_size = slot_size.min(DeviceIntSize::new(max_texture_size, max_texture_size));
+        let columns = std::cmp::max(1, max_texture_size / slot_size.width) as usize;
+
+        let framebuffer = Framebuffer {
+            fbo: gl.gen_framebuffers(1)[0],
+            texture: gl.gen_textures(1)[0],
+            size: DeviceIntSize::zero(),
+        };
+
+        let compositor = Self {
+            renderer,
+            context: Some(context),
+            gl,
+            api: sender.create_api(),
+            framebuffer,
+            max_texture_size,
+            slot_size,
+            columns,
+            slots: vec![],
+            surfaces: HashMap::new(),
+            stale_documents,
+        };
+
+        (compositor, sender)
+    }
+
+    fn handle_event(
+        &mut self,
+        event: EmacsGUIEvent,
+        target: Option<&EventLoopWindowTarget<EmacsGUIEvent>>,
+    ) {
+        match event {
+            EmacsGUIEvent::CreateSurface(options, sender) => {
+                sender.send(self.create_surface(target, options)).unwrap();
+            }
+            EmacsGUIEvent::DestroySurface(document_id, sender) => {
+                self.destroy_surface(document_id);
+
+                sender.send(()).unwrap();
+            }
+            EmacsGUIEvent::ResizeSurface(document_id, size) => {
+                self.resize_document(document_id, size);
+            }
+            EmacsGUIEvent::Flush(document_id, sender) => {
+                self.render_if_needed(document_id);
+                self.present(document_id);
+
+                sender.send(()).unwrap();
+            }
+            EmacsGUIEvent::ReadPixels(document_id, device_rect, sender) => {
+                self.render_if_needed(document_id);
+
+                sender
+                    .send(self.read_pixels(document_id, device_rect))
+                    .unwrap();
+            }
+            EmacsGUIEvent::Monitors(sender) => {
+                let monitors = target.map_or((vec![], None), |target| {
+                    (
+                        target.available_monitors().collect(),
+                        target.primary_monitor(),
+                    )
+                });
+
+                sender.send(monitors).unwrap();
+            }
+        }
+    }
+
+    fn make_current(&mut self) {
+        self.context = self.context.take().map(MainContext::make_current);
+    }
+
+    fn create_surface(
+        &mut self,
+        target: Option<&EventLoopWindowTarget<EmacsGUIEvent>>,
+        options: WindowOptions,
+    ) -> Result<(Option<Window>, DocumentId), String> {
+        let slot = match self.slots.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None if self.slots.len() < self.slot_capacity(self.slot_size) => {
+                self.slots.push(None);
+                self.slots.len() - 1
+            }
+            None => {
+                return Err(format!(
+                    "the display has no room for more than {} frames",
+                    self.slots.len()
+                ))
+            }
+        };
+
+        let (window, surface_window, size, scale_factor) = match target {
+            Some(target) => {
+                let window_builder = options.window_builder();
+
+                let current_context = glutin::ContextBuilder::new()
+                    .with_shared_lists(self.context.as_ref().unwrap().context())
+                    .build_windowed(window_builder, target)
+                    .map_err(|e| e.to_string())
+                    .and_then(|context| {
+                        unsafe { context.make_current() }.map_err(|(_, e)| e.to_string())
+                    });
+
+                let current_context = match current_context {
+                    Ok(current_context) => current_context,
+                    Err(e) => {
+                        self.make_current();
+                        return Err(e);
+                    }
+                };
+
+                let (current_context, window) = unsafe { current_context.split() };
+
+                let gl = get_gl_api(current_context.context());
+                let read_fbo = gl.gen_framebuffers(1)[0];
+
+                self.make_current();
+
+                let surface_window = WindowSurface {
+                    window_id: window.id(),
+                    context: Some(current_context),
+                    gl,
+                    read_fbo,
+                };
+
+                let size = window.inner_size();
+                let scale_factor = window.scale_factor();
+
+                (Some(window), Some(surface_window), size, scale_factor)
+            }
+            None => (None, None, HEADLESS_SIZE, 1.0),
+        };
+
+        let size = DeviceIntSize::new(size.width as i32, size.height as i32);
+
+        self.grow_slots(size);
+
+        let document_id = self.api.add_document(size, 0 /* layer */);
+
+        self.slots[slot] = Some(document_id);
+
+        self.surfaces.insert(
+            document_id,
+            Surface {
+                slot,
+                size,
+                scale_factor: scale_factor as f32,
+                window: surface_window,
+            },
+        );
+
+        self.update_document_view(document_id);
+
+        Ok((window, document_id))
+    }
+
+    fn destroy_surface(&mut self, document_id: DocumentId) {
+        let surface = match self.surfaces.remove(&document_id) {
+            Some(surface) => surface,
+            None => return,
+        };
+
+        self.slots[surface.slot] = None;
+
+        self.api.delete_document(document_id);
+
+        if let Some(mut window) = surface.window {
+            window.make_current();
+            window.gl.delete_framebuffers(&[window.read_fbo]);
+
+            // The context goes before its window, which the output drops.
+            drop(window);
+
+            self.make_current();
+        }
+    }
+
+    fn surface_for_window(&self, window_id: WindowId) -> Option<DocumentId> {
+        self.surfaces
+            .iter()
+            .find(|(_, s)| s.window.as_ref().map(|w| w.window_id) == Some(window_id))
+            .map(|(document_id, _)| *document_id)
+    }
+
+    fn resize_surface(&mut self, window_id: WindowId, size: PhysicalSize<u32>) {
+        if let Some(document_id) = self.surface_for_window(window_id) {
+            self.resize_document(document_id, size);
+        }
+    }
+
+    fn resize_document(&mut self, document_id: DocumentId, size: PhysicalSize<u32>) {
+        let surface = match self.surfaces.get_mut(&document_id) {
+            Some(surface) => surface,
+            None => return,
+        };
+
+        surface.size = DeviceIntSize::new(size.width as i32, size.height as i32);
+
+        if let Some(context) = surface.window.as_ref().and_then(|w| w.context.as_ref()) {
+            context.resize(size);
+        }
+
+        let size = surface.size;
+
+        self.grow_slots(size);
+        self.update_document_view(document_id);
+    }
+
+    // How many slots of SLOT_SIZE the largest framebuffer holds.
+    fn slot_capacity(&self, slot_size: DeviceIntSize) -> usize {
+        let columns = std::cmp::max(1, self.max_texture_size / slot_size.width);
+        let rows = std::cmp::max(1, self.max_texture_size / slot_size.height);
+
+        (columns * rows) as usize
+    }
+
+    // Make the slots large enough for a surface of SIZE, as far as
+    // textures can be and the slots in use still fit.  Every document
+    // moves with its slot.
+    fn grow_slots(&mut self, size: DeviceIntSize) {
+        if size.width <= self.slot_size.width && size.height <= self.slot_size.height {
+            return;
+        }
+
+        let max_size = DeviceIntSize::new(self.max_texture_size, self.max_texture_size);
+        let slot_size = size.max(self.slot_size).min(max_size);
+
+        if self.slot_capacity(slot_size) < self.slots.len() {
+            return;
+        }
+
+        self.slot_size = slot_size;
+        self.columns = std::cmp::max(1, self.max_texture_size / self.slot_size.width) as usize;
+
+        let document_ids: Vec<DocumentId> = self.surfaces.keys().copied().collect();
+
+        for document_id in document_ids {
+            self.update_document_view(document_id);
+        }
+    }
+
+    fn redraw(&mut self, window_id: WindowId) {
+        if let Some(document_id) = self.surface_for_window(window_id) {
+            self.render_if_needed(document_id);
+            self.present(document_id);
+        }
+    }
+
+    fn set_scale_factor(&mut self, window_id: WindowId, scale_factor: f64) {
+        if let Some(document_id) = self.surface_for_window(window_id) {
+            self.surfaces.get_mut(&document_id).unwrap().scale_factor = scale_factor as f32;
+
+            self.update_document_view(document_id);
+        }
+    }
+
+    // The part of the offscreen framebuffer showing SURFACE, in device
+    // pixels from its top left corner.  Windows larger than a slot are
+    // cut to it.
+    fn surface_rect(&self, surface: &Surface) -> DeviceIntRect {
+        let column = (surface.slot % self.columns) as i32;
+        let row = (surface.slot / self.columns) as i32;
+
+        DeviceIntRect::new(
+            DeviceIntPoint::new(column * self.slot_size.width, row * self.slot_size.height),
+            surface.size.min(self.slot_size),
+        )
+    }
+
+    fn update_document_view(&mut self, document_id: DocumentId) {
+        let surface = &self.surfaces[&document_id];
+
+        self.stale_documents.lock().unwrap().insert(document_id);
+
+        self.api.set_document_view(
+            document_id,
+            self.surface_rect(surface),
+            surface.scale_factor,
+        );
+    }
+
+    // Grow the offscreen framebuffer to hold every slot in use, up to
+    // the largest texture size, which create_surface keeps them within.
+    // Its content needn't be kept, all documents are drawn on each
+    // render.
+    fn allocate_framebuffer(&mut self) {
+        let rows = (self.slots.len() + self.columns - 1) / self.columns;
+        let columns = std::cmp::min(self.slots.len(), self.columns);
+
+        let size = DeviceIntSize::new(
+            columns as i32 * self.slot_size.width,
+            rows as i32 * self.slot_size.height,
+        );
+
+        if size.width <= self.framebuffer.size.width && size.height <= self.framebuffer.size.height
+        {
+            return;
+        }
+
+        let max_size = DeviceIntSize::new(self.max_texture_size, self.max_texture_size);
+        let size = size.max(self.framebuffer.size).min(max_size);
+
+        self.gl
+            .bind_texture(gl::TEXTURE_2D, self.framebuffer.texture);
+        self.gl.tex_image_2d(
+            gl::TEXTURE_2D,
+            0,
+            gl::RGBA8 as gl::GLint,
+            size.width,
+            size.height,
+            0,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+            None,
+        );
+        self.gl.bind_texture(gl::TEXTURE_2D, 0);
+
+        self.gl
+            .bind_framebuffer(gl::FRAMEBUFFER, self.framebuffer.fbo);
+        self.gl.framebuffer_texture_2d(
+            gl::FRAMEBUFFER,
+            gl::COLOR_ATTACHMENT0,
+            gl::TEXTURE_2D,
+            self.framebuffer.texture,
+            0,
+        );
+        self.gl.bind_framebuffer(gl::FRAMEBUFFER, 0);
+
+        self.framebuffer.size = size;
+    }
+
+    // Draw every document into the offscreen framebuffer.  WebRender
+    // draws into the framebuffer bound when rendering starts, and
+    // draws all documents of the renderer at once.
+    fn render(&mut self) {
+        self.stale_documents.lock().unwrap().clear();
+
+        self.make_current();
+        self.allocate_framebuffer();
+
+        self.gl
+            .bind_framebuffer(gl::FRAMEBUFFER, self.framebuffer.fbo);
+
+        self.renderer.update();
+        self.renderer.render(self.framebuffer.size).unwrap();
+        let _ = self.renderer.flush_pipeline_info();
+
+        self.gl.bind_framebuffer(gl::FRAMEBUFFER, 0);
+
+        // Window contexts read the result, once they attach the texture
+        // again.  Headless displays read it from this context.
+        if self.surfaces.values().any(|s| s.window.is_some()) {
+            self.gl.flush();
+        }
+    }
+
+    // Render if the framebuffer is behind on DOCUMENT_ID.  WebRender
+    // draws every document at once, so the other documents that
+    // changed catch up along with it.
+    fn render_if_needed(&mut self, document_id: DocumentId) {
+        if self.stale_documents.lock().unwrap().contains(&document_id) {
+            self.render();
+        }
+    }
+
+    // Copy DOCUMENT_ID to its window.
+    fn present(&mut self, document_id: DocumentId) {
+        let rect = match self.surfaces.get(&document_id) {
+            Some(surface) if surface.window.is_some() => self.surface_rect(surface),
+            _ => return,
+        };
+
+        let fb_rect = self.framebuffer_rect(rect);
+        let texture = self.framebuffer.texture;
+
+        let surface = self.surfaces.get_mut(&document_id).unwrap();
+        let window_height = surface.size.height;
+        let window = surface.window.as_mut().unwrap();
+
+        window.make_current();
+
+        let gl = &window.gl;
+
+        gl.bind_framebuffer(gl::READ_FRAMEBUFFER, window.read_fbo);
+        gl.framebuffer_texture_2d(
+            gl::READ_FRAMEBUFFER,
+            gl::COLOR_ATTACHMENT0,
+            gl::TEXTURE_2D,
+            texture,
+            0,
+        );
+        gl.bind_framebuffer(gl::DRAW_FRAMEBUFFER, 0);
+
+        // Both framebuffers count rows from the bottom; keep the top
+        // edges together.
+        let y = window_height - fb_rect.size.height;
+
+        gl.blit_framebuffer(
+            fb_rect.origin.x,
+            fb_rect.origin.y,
+            fb_rect.origin.x + fb_rect.size.width,
+            fb_rect.origin.y + fb_rect.size.height,
+            0,
+            y,
+            fb_rect.size.width,
+            y + fb_rect.size.height,
+            gl::COLOR_BUFFER_BIT,
+            gl::NEAREST,
+        );
+
+        gl.bind_framebuffer(gl::READ_FRAMEBUFFER, 0);
+
+        if let Some(context) = &window.context {
+            context.swap_buffers().ok();
+        }
+
+        self.make_current();
+    }
+
+    // DEVICE_RECT of the offscreen framebuffer, in GL coordinates.
+    fn framebuffer_rect(&self, device_rect: DeviceIntRect) -> FramebufferIntRect {
+        let mut fb_rect = FramebufferIntRect::from_untyped(&device_rect.to_untyped());
+
+        if !self.renderer.device.surface_origin_is_top_left() {
+            fb_rect.origin.y =
+                self.framebuffer.size.height - fb_rect.origin.y - fb_rect.size.height;
+        }
+
+        fb_rect
+    }
+
+    // The pixels of DEVICE_RECT in DOCUMENT_ID, top row first.  Those
+    // outside its slot, which the document can't draw to, are left
+    // transparent.
+    fn read_pixels(&mut self, document_id: DocumentId, device_rect: DeviceIntRect) -> Vec<u8> {
+        let mut pixels = vec![0; device_rect.size.area() as usize * 4];
+
+        let slot_rect = match self.surfaces.get(&document_id) {
+            Some(surface) => self.surface_rect(surface),
+            None => return pixels,
+        };
+
+        let origin = slot_rect.origin.to_vector();
+
+        let rect = match device_rect.translate(origin).intersection(&slot_rect) {
+            Some(rect) => rect,
+            None => return pixels,
+        };
+
+        let read = self.read_framebuffer(rect);
+
+        // Copy the rows read into place.
+        let stride = device_rect.size.width as usize * 4;
+        let row_size = rect.size.width as usize * 4;
+        let offset = rect.origin - origin - device_rect.origin;
+
+        for (y, row) in read.chunks(row_size).enumerate() {
+            let start = (offset.y as usize + y) * stride + offset.x as usize * 4;
+
+            pixels[start..start + row_size].copy_from_slice(row);
+        }
+
+        pixels
+    }
+
+    // The pixels of DEVICE_RECT of the offscreen framebuffer, top row
+    // first.
+    fn read_framebuffer(&mut self, device_rect: DeviceIntRect) -> Vec<u8> {
+        let fb_rect = self.framebuffer_rect(device_rect);
+
+        self.make_current();
+
+        self.gl
+            .bind_framebuffer(gl::READ_FRAMEBUFFER, self.framebuffer.fbo);
+
+        let mut pixels = self.gl.read_pixels(
+            fb_rect.origin.x,
+            fb_rect.origin.y,
+            fb_rect.size.width,
+            fb_rect.size.height,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+        );
+
+        self.gl.bind_framebuffer(gl::READ_FRAMEBUFFER, 0);
+
+        // GL reads rows bottom to top.
+        if !self.renderer.device.surface_origin_is_top_left() {
+            let stride = fb_rect.size.width as usize * 4;
+
+            let rows: Vec<Vec<u8>> = pixels.chunks(stride).rev().map(|r| r.to_vec()).collect();
+            pixels = rows.concat();
+        }
+
+        pixels
+    }
+}
+
+struct Notifier {
+    event_sender: GUIEventSender,
+    stale_documents: StaleDocuments,
+}
+
+impl Notifier {
+    fn new(event_sender: GUIEventSender, stale_documents: StaleDocuments) -> Notifier {
+        Notifier {
+            event_sender,
+            stale_documents,
+        }
+    }
+}
+
+impl RenderNotifier for Notifier {
+    fn clone(&self) -> Box<dyn RenderNotifier> {
+        Box::new(Notifier {
+            event_sender: self.event_sender.clone(),
+            stale_documents: self.stale_documents.clone(),
+        })
+    }
+
+    fn wake_up(&self) {}
+
+    fn new_frame_ready(
+        &self,
+        document_id: DocumentId,
+        _scrolled: bool,
+        _composite_needed: bool,
+        _render_time: Option<u64>,
+    ) {
+        self.stale_documents.lock().unwrap().insert(document_id);
+    }
+}
@@ -1,44 +1,76 @@
 //! wrterm.rs
 
 use std::ffi::CString;
+use std::path::Path;
 use std::ptr;
 
-use glutin::{event::VirtualKeyCode, monitor::MonitorHandle};
+use glutin::{
+    dpi::{PhysicalPosition, PhysicalSize},
+    event::VirtualKeyCode,
+    monitor::MonitorHandle,
+};
 
 use lisp_macros::lisp_fn;
 
 use crate::webrender_backend::{
     color::lookup_color_by_name_or_hex,
     font::{FontRef, FONT_DRIVER},
-    frame::create_frame,
+    frame::{create_frame, create_tip_frame},
+    image::{can_load_image_type, load_image, LoadedImage, PixmapRef, WrPixmap},
     input::winit_keycode_emacs_key_name,
     output::OutputRef,
-    term::wr_term_init,
+    term::{release_pixmap, wr_term_init, TerminalRef, HEADLESS_DISPLAY_NAME},
+    window_server::{WindowKind, WindowOptions, HEADLESS_SIZE},
 };
 
 use lisp::{
     frame::{window_frame_live_or_selected, LispFrameRef},
     lisp::{ExternalPtr, LispObject},
+    list::{LispConsCircularChecks, LispConsEndChecks},
+    multibyte::LispStringRef,
     remacs_sys::globals,
-    remacs_sys::resource_types::{RES_TYPE_NUMBER, RES_TYPE_STRING, RES_TYPE_SYMBOL},
+    remacs_sys::resource_types::{
+        RES_TYPE_BOOLEAN, RES_TYPE_NUMBER, RES_TYPE_STRING, RES_TYPE_SYMBOL,
+    },
     remacs_sys::{
-        adjust_frame_size, block_input, gui_display_get_arg, hashtest_eql, image, init_frame_faces,
-        list3i, make_fixnum, make_hash_table, make_monitor_attribute_list, register_font_driver,
-        unblock_input, Display, EmacsInt, Emacs_Pixmap, Emacs_Rectangle, Fcons, Fcopy_alist,
-        Fmake_vector, Fprovide, MonitorInfo, Pixmap, Qbackground_color, Qfont, Qfont_backend,
-        Qforeground_color, Qleft_fringe, Qminibuffer, Qname, Qnil, Qparent_id, Qright_fringe,
-        Qterminal, Qunbound, Qwr, Qx, Qx_create_frame_1, Qx_create_frame_2, Vframe_list, WRImage,
-        Window, DEFAULT_REHASH_SIZE, DEFAULT_REHASH_THRESHOLD,
+        add_to_log, adjust_frame_glyphs, adjust_frame_size, block_input, bset_left_margin_cols,
+        bset_right_margin_cols, bset_truncate_lines, call1, call2, call3, change_frame_size,
+        clear_glyph_matrix, decode_live_terminal, decode_window_system_frame, delete_frame,
+        flush_frame, gui_display_get_arg, hashtest_eql, image, image_spec_value, init_frame_faces,
+        list3i, make_fixnum, make_float, make_hash_table, make_monitor_attribute_list,
+        output_method, pvec_type, record_unwind_current_buffer, register_font_driver,
+        selected_frame, set_buffer_internal_1, set_window_buffer, specbind, staticpro,
+        store_frame_param, text_pos, try_window, unbind_to, unblock_input, update_single_window,
+        windows_or_buffers_changed, Display, EmacsInt, Emacs_Pixmap, Emacs_Rectangle, Fassq, Fcar,
+        Fcdr, Fcons, Fcopy_alist, Fequal, Fequal_including_properties, Ferase_buffer,
+        Fexpand_file_name, Fframe_parameter, Fget_buffer_create, Finsert, Fmake_frame_invisible,
+        Fmake_frame_visible, Fmake_vector, Fmodify_frame_parameters, Fprovide,
+        Fwindow_text_pixel_size, MonitorInfo, QCindex, QCtype, Qassq_delete_all, Qbackground_color,
+        Qborder_color, Qborder_width, Qbottom, Qbox, Qcancel_timer, Qcount, Qcursor_color,
+        Qcursor_type, Qdelay, Qface_set_after_frame_default, Qfont, Qfont_backend,
+        Qforeground_color, Qheight, Qinhibit_modification_hooks, Qinhibit_point_motion_hooks,
+        Qinhibit_quit, Qinhibit_read_only, Qinhibit_redisplay, Qinternal_border_width, Qleft,
+        Qleft_fringe, Qminibuffer, Qname, Qnil, Qno_accept_focus, Qoverride_redirect,
+        Qparent_frame, Qparent_id, Qright, Qright_fringe, Qrun_at_time, Qt, Qterminal, Qtooltip,
+        Qtop, Qunbound, Qundecorated, Qwidth, Qwr, Qx, Qx_create_frame_1, Qx_create_frame_2,
+        Qx_hide_tip, Vframe_list, Window, DEFAULT_REHASH_SIZE, DEFAULT_REHASH_THRESHOLD,
+        SPECPDL_INDEX, TRY_WINDOW_IGNORE_FONTS_CHANGE, XBUFFER,
     },
 };
 
 pub use crate::webrender_backend::display_info::{DisplayInfo, DisplayInfoRef};
 
 pub type DisplayRef = ExternalPtr<Display>;
-pub type ImageRef = ExternalPtr<WRImage>;
 
 #[no_mangle]
-pub static tip_frame: LispObject = Qnil;
+pub static mut tip_frame: LispObject = Qnil;
+
+// The timer hiding the tooltip, and the frame, string and parameters
+// it was last shown for.
+static mut tip_timer: LispObject = Qnil;
+static mut tip_last_frame: LispObject = Qnil;
+static mut tip_last_string: LispObject = Qnil;
+static mut tip_last_parms: LispObject = Qnil;
 
 #[no_mangle]
 pub static mut wr_display_list: DisplayInfoRef = DisplayInfoRef::new(ptr::null_mut());
@@ -76,16 +108,148 @@ pub extern "C" fn wr_get_baseline_offset(output: OutputRef) -> i32 {
     0
 }
 
-#[allow(unused_variables)]
+#[allow(non_snake_case)]
 #[no_mangle]
-pub extern "C" fn wr_get_pixel(ximg: ImageRef, x: i32, y: i32) -> i32 {
-    unimplemented!();
+pub extern "C" fn wr_image_for_XPM(width: i32, height: i32, depth: i32) -> Emacs_Pixmap {
+    WrPixmap::new(width as u32, height as u32, depth).into_raw() as Emacs_Pixmap
 }
 
-#[allow(unused_variables)]
+#[allow(non_snake_case)]
 #[no_mangle]
-pub extern "C" fn wr_free_pixmap(display: DisplayRef, pixmap: Pixmap) -> i32 {
-    unimplemented!();
+pub extern "C" fn wr_image_from_XBM(
+    bits: *mut libc::c_char,
+    width: i32,
+    height: i32,
+    fg: u64,
+    bg: u64,
+) -> Emacs_Pixmap {
+    let len = ((width + 7) / 8 * height) as usize;
+    let bits = unsafe { std::slice::from_raw_parts(bits as *const u8, len) };
+
+    WrPixmap::from_xbm(bits, width as u32, height as u32, fg, bg).into_raw() as Emacs_Pixmap
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "C" fn wr_bitmap_from_XBM(
+    bits: *mut libc::c_char,
+    width: i32,
+    height: i32,
+) -> Emacs_Pixmap {
+    let len = ((width + 7) / 8 * height) as usize;
+    let bits = unsafe { std::slice::from_raw_parts(bits as *const u8, len) };
+
+    WrPixmap::bitmap_from_xbm(bits, width as u32, height as u32).into_raw() as Emacs_Pixmap
+}
+
+#[no_mangle]
+pub extern "C" fn wr_get_pixel(pixmap: PixmapRef, x: i32, y: i32) -> u64 {
+    pixmap.get_pixel(x, y)
+}
+
+#[no_mangle]
+pub extern "C" fn wr_put_pixel(mut pixmap: PixmapRef, x: i32, y: i32, pixel: u64) {
+    pixmap.put_pixel(x, y, pixel);
+}
+
+#[no_mangle]
+pub extern "C" fn wr_retain_image(mut pixmap: PixmapRef) {
+    pixmap.retain();
+}
+
+#[no_mangle]
+pub extern "C" fn wr_release_image(pixmap: PixmapRef) {
+    release_pixmap(pixmap);
+}
+
+#[no_mangle]
+pub extern "C" fn wr_image_set_transform(mut pixmap: PixmapRef, matrix: *const [f64; 3]) {
+    let matrix = unsafe { std::slice::from_raw_parts(matrix, 3) };
+
+    pixmap.transform.copy_from_slice(matrix);
+}
+
+#[no_mangle]
+pub extern "C" fn wr_image_set_smoothing(mut pixmap: PixmapRef, smooth: bool) {
+    pixmap.smoothing = smooth;
+}
+
+#[no_mangle]
+pub extern "C" fn wr_image_size_in_bytes(pixmap: PixmapRef) -> libc::size_t {
+    pixmap.size_in_bytes()
+}
+
+#[no_mangle]
+pub extern "C" fn wr_can_use_native_image_api(image_type: LispObject) -> bool {
+    image_type
+        .as_symbol()
+        .and_then(|symbol| symbol.symbol_name().as_string())
+        .map_or(false, |name| can_load_image_type(&name.to_utf8()))
+}
+
+// Load IMG from SPEC_FILE, an absolute file name, or SPEC_DATA, a
+// unibyte string.  Failures are logged to *Messages* like other image
+// errors, and leave IMG without a pixmap.
+#[no_mangle]
+pub extern "C" fn wr_load_image(
+    _frame: LispFrameRef,
+    img: *mut image,
+    spec_file: LispObject,
+    spec_data: LispObject,
+) -> bool {
+    let img = unsafe { &mut *img };
+
+    let image_type = unsafe { image_spec_value(img.spec, QCtype, ptr::null_mut()) }
+        .as_symbol()
+        .and_then(|symbol| symbol.symbol_name().as_string())
+        .map_or_else(String::new, |name| name.to_utf8());
+
+    let index = unsafe { image_spec_value(img.spec, QCindex, ptr::null_mut()) }
+        .as_fixnum()
+        .unwrap_or(0) as usize;
+
+    let loaded = if let Some(file) = spec_file.as_string() {
+        let path = file.to_utf8();
+
+        std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| load_image(&data, &image_type, index, Path::new(&path).parent()))
+    } else if let Some(data) = spec_data.as_string() {
+        load_image(data.as_slice(), &image_type, index, None)
+    } else {
+        Err("No image data".to_string())
+    };
+
+    match loaded {
+        Ok(LoadedImage { pixmap, frames }) => {
+            img.width = pixmap.width as i32;
+            img.height = pixmap.height as i32;
+
+            if let Some((count, delay)) = frames {
+                img.lisp_data = list!(Qcount, unsafe { make_fixnum(count as EmacsInt) });
+
+                if let Some(delay) = delay {
+                    img.lisp_data =
+                        unsafe { Fcons(Qdelay, Fcons(make_float(delay), img.lisp_data)) };
+                }
+            }
+
+            img.pixmap = pixmap.into_raw() as Emacs_Pixmap;
+
+            true
+        }
+        Err(message) => {
+            unsafe {
+                add_to_log(
+                    b"Unable to load image %s: %s\0".as_ptr() as *const libc::c_char,
+                    img.spec,
+                    LispObject::from(message.as_str()),
+                )
+            };
+
+            false
+        }
+    }
 }
 
 #[no_mangle]
@@ -99,10 +263,82 @@ pub extern "C" fn get_keysym_name(keysym: i32) -> *mut libc::c_char {
 #[no_mangle]
 pub extern "C" fn check_x_display_info(obj: LispObject) -> DisplayInfoRef {
     if obj.is_nil() {
-        unsafe { wr_display_list }
+        let frame = unsafe { selected_frame }.as_live_frame();
+
+        match frame {
+            Some(frame) if frame.output_method() == output_method::output_wr => {
+                let output: OutputRef = unsafe { frame.output_data.wr.into() };
+                output.display_info()
+            }
+            _ if unsafe { !wr_display_list.is_null() } => unsafe { wr_display_list },
+            _ => error!("WebRender windows are not in use or not initialized"),
+        }
+    } else if obj.is_string() {
+        wr_display_info_for_name(obj)
+    } else if obj
+        .as_vectorlike()
+        .map_or(false, |v| v.is_pseudovector(pvec_type::PVEC_TERMINAL))
+    {
+        let terminal = TerminalRef::new(unsafe { decode_live_terminal(obj) });
+
+        if terminal.type_ != output_method::output_wr {
+            error!("Terminal {} is not a WebRender display", terminal.id);
+        }
+
+        DisplayInfoRef::new(unsafe { terminal.display_info.wr } as *mut _)
     } else {
-        unimplemented!();
+        let frame: LispFrameRef = unsafe { decode_window_system_frame(obj) }.into();
+        let output: OutputRef = unsafe { frame.output_data.wr.into() };
+
+        output.display_info()
+    }
+}
+
+// The display with a window system, if Emacs is connected to one.
+fn windowed_display() -> Option<DisplayInfoRef> {
+    let mut dpyinfo = unsafe { wr_display_list };
+
+    while !dpyinfo.is_null() {
+        if !dpyinfo.get_inner().window_server.is_headless() {
+            return Some(dpyinfo);
+        }
+
+        dpyinfo = DisplayInfoRef::new(dpyinfo.get_raw().next as *mut _);
+    }
+
+    None
+}
+
+// The display named NAME, which is connected to if it isn't yet.
+// Only one display may have a window system.
+fn wr_display_info_for_name(name: LispObject) -> DisplayInfoRef {
+    let name_str = LispStringRef::from(name).to_utf8();
+
+    let find = || {
+        let mut dpyinfo = unsafe { wr_display_list };
+
+        while !dpyinfo.is_null() {
+            let element = dpyinfo.get_raw().name_list_element;
+
+            if let Some(display_name) = element.as_cons().and_then(|c| c.car().as_string()) {
+                if display_name.to_utf8() == name_str {
+                    return Some(dpyinfo);
+                }
+            }
+
+            dpyinfo = DisplayInfoRef::new(dpyinfo.get_raw().next as *mut _);
+        }
+
+        None
+    };
+
+    if let Some(dpyinfo) = find() {
+        return dpyinfo;
     }
+
+    x_open_connection(name, Qnil, Qnil);
+
+    find().unwrap_or_else(|| error!("Cannot connect to WebRender display {}", name_str))
 }
 
 // Move the mouse to position pixel PIX_X, PIX_Y relative to frame F.
@@ -114,29 +350,663 @@ pub extern "C" fn frame_set_mouse_pixel_position(f: LispFrameRef, pix_x: i32, pi
     unsafe { unblock_input() };
 }
 
+// Pixels are written straight into the pixmaps, so there is nothing
+// to sync.
 #[no_mangle]
-pub extern "C" fn image_sync_to_pixmaps(_frame: LispFrameRef, _img: *mut image) {
-    unimplemented!();
-}
+pub extern "C" fn image_sync_to_pixmaps(_frame: LispFrameRef, _img: *mut image) {}
 
 #[no_mangle]
 pub extern "C" fn image_pixmap_draw_cross(
     _frame: LispFrameRef,
-    _pixmap: Emacs_Pixmap,
-    _x: i32,
-    _y: i32,
-    _width: i32,
-    _height: u32,
-    _color: u64,
+    mut pixmap: PixmapRef,
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    color: u64,
 ) {
-    unimplemented!();
+    pixmap.draw_cross(x, y, width, height, color);
+}
+
+// Create a frame for a tooltip on the display DPYINFO.  PARMS is a
+// list of frame parameters.  Value is the frame.
+fn x_create_tip_frame(mut dpyinfo: DisplayInfoRef, parms: LispObject) -> LispObject {
+    if dpyinfo.get_inner().terminal.name == ptr::null_mut() {
+        error!("Terminal is not live, can't create new frames on it");
+    }
+
+    let parms = unsafe { Fcopy_alist(parms) };
+
+    // Get the name of the frame to use for resource lookup.
+    let name = unsafe {
+        gui_display_get_arg(
+            dpyinfo.get_raw().as_mut(),
+            parms,
+            Qname,
+            ptr::null(),
+            ptr::null(),
+            RES_TYPE_STRING,
+        )
+    };
+
+    if !name.is_string() && !name.eq(Qunbound) && !name.is_nil() {
+        error!("Invalid frame name--not a string or nil");
+    }
+
+    let mut frame = create_tip_frame(dpyinfo);
+
+    unsafe {
+        register_font_driver(&FONT_DRIVER.0 as *const _, frame.as_mut());
+    };
+
+    if name.is_string() {
+        frame.name = name;
+        frame.set_explicit_name(true);
+    }
+
+    frame.gui_default_parameter(
+        parms,
+        Qfont_backend,
+        Qnil,
+        "fontBackend",
+        "FontBackend",
+        RES_TYPE_STRING,
+    );
+
+    frame.gui_default_parameter(
+        parms,
+        Qfont,
+        "Monospace".into(),
+        "font",
+        "Font",
+        RES_TYPE_STRING,
+    );
+
+    frame.gui_default_parameter(
+        parms,
+        Qborder_width,
+        0.into(),
+        "borderWidth",
+        "BorderWidth",
+        RES_TYPE_NUMBER,
+    );
+
+    frame.gui_default_parameter(
+        parms,
+        Qinternal_border_width,
+        1.into(),
+        "internalBorderWidth",
+        "internalBorderWidth",
+        RES_TYPE_NUMBER,
+    );
+
+    frame.gui_default_parameter(
+        parms,
+        Qforeground_color,
+        "black".into(),
+        "foreground",
+        "Foreground",
+        RES_TYPE_STRING,
+    );
+    frame.gui_default_parameter(
+        parms,
+        Qbackground_color,
+        "white".into(),
+        "background",
+        "Background",
+        RES_TYPE_STRING,
+    );
+    frame.gui_default_parameter(
+        parms,
+        Qcursor_color,
+        "black".into(),
+        "cursorColor",
+        "Foreground",
+        RES_TYPE_STRING,
+    );
+    frame.gui_default_parameter(
+        parms,
+        Qborder_color,
+        "black".into(),
+        "borderColor",
+        "BorderColor",
+        RES_TYPE_STRING,
+    );
+
+    // Init faces before gui_default_parameter is called for the
+    // cursor-type parameter, which needs the face cache.
+    unsafe { init_frame_faces(frame.as_mut()) };
+
+    frame.gui_default_parameter(
+        parms,
+        Qcursor_type,
+        Qbox,
+        "cursorType",
+        "CursorType",
+        RES_TYPE_SYMBOL,
+    );
+
+    // The window is sized to the tooltip text when it is shown.
+    let output: OutputRef = unsafe { frame.output_data.wr.into() };
+    let output_size = output.get_inner_size();
+
+    let width = frame.pixel_to_text_width(output_size.width as i32);
+    let height = frame.pixel_to_text_height(output_size.height as i32);
+
+    unsafe { change_frame_size(frame.as_mut(), width, height, true, false, false, true) };
+
+    let frame_object: LispObject = frame.into();
+
+    unsafe {
+        // Add `tooltip' frame parameter's default value.
+        if Fframe_parameter(frame_object, Qtooltip).is_nil() {
+            Fmodify_frame_parameters(frame_object, list!(LispObject::cons(Qtooltip, Qt)));
+        }
+
+        // Set up faces after all frame parameters are known.  This call
+        // also merges in face attributes specified for new frames.
+        // Prevent it from changing the background color of the tooltip,
+        // which would show in its internal border.
+        let bg = Fframe_parameter(frame_object, Qbackground_color);
+
+        call2(Qface_set_after_frame_default, frame_object, Qnil);
+
+        if !bg.eq(Fframe_parameter(frame_object, Qbackground_color)) {
+            Fmodify_frame_parameters(frame_object, list!(LispObject::cons(Qbackground_color, bg)));
+        }
+    }
+
+    frame.set_no_split(true);
+
+    // The frame needs to be on Vframe_list for making it visible to
+    // work.
+    unsafe { Vframe_list = Fcons(frame_object, Vframe_list) };
+
+    frame.set_can_set_window_size(true);
+
+    frame_object
+}
+
+// The position of the mouse on the screen, as far as the display of
+// FRAME knows it.
+fn mouse_root_position(frame: LispFrameRef) -> (i32, i32) {
+    let output: OutputRef = unsafe { frame.output_data.wr.into() };
+    let mut dpyinfo = output.display_info();
+
+    let mouse_frame = dpyinfo.get_raw().last_mouse_motion_frame;
+
+    let (frame, position) = if mouse_frame.is_null() {
+        (frame, PhysicalPosition::new(0.0, 0.0))
+    } else {
+        (
+            LispFrameRef::new(mouse_frame),
+            dpyinfo.get_inner().input_processor.cursor_position(),
+        )
+    };
+
+    let output: OutputRef = unsafe { frame.output_data.wr.into() };
+    let origin = output
+        .get_inner_position()
+        .unwrap_or_else(|| PhysicalPosition::new(0, 0));
+
+    (origin.x + position.x as i32, origin.y + position.y as i32)
+}
+
+// Compute where to display tip frame F.  PARMS is the list of frame
+// parameters for F.  DX and DY are specified offsets from the current
+// location of the mouse.  WIDTH and HEIGHT are the width and height of
+// the tooltip.  Return coordinates relative to the screen.
+fn compute_tip_xy(
+    f: LispFrameRef,
+    parms: LispObject,
+    dx: i32,
+    dy: i32,
+    width: i32,
+    height: i32,
+) -> (i32, i32) {
+    let param = |key| {
+        unsafe { Fcdr(Fassq(key, parms)) }
+            .as_fixnum()
+            .map(|n| n as i32)
+    };
+
+    // User-specified position?
+    let left = param(Qleft);
+    let top = param(Qtop);
+    let right = param(Qright);
+    let bottom = param(Qbottom);
+
+    let (mut root_x, mut root_y) = mouse_root_position(f);
+
+    let output: OutputRef = unsafe { f.output_data.wr.into() };
+    let (monitors, _) = output.display_info().get_inner().window_server.monitors();
+
+    // Try to determine the monitor where the mouse pointer is and its
+    // geometry.  Otherwise assume the display is a single screen.
+    let (min_x, min_y, max_x, max_y) = monitors
+        .iter()
+        .map(|monitor| {
+            let position = monitor.position();
+            let size = monitor.size();
+
+            (
+                position.x,
+                position.y,
+                position.x + size.width as i32,
+                position.y + size.height as i32,
+            )
+        })
+        .find(|&(min_x, min_y, max_x, max_y)| {
+            min_x <= root_x && root_x < max_x && min_y <= root_y && root_y < max_y
+        })
+        .unwrap_or_else(|| {
+            (
+                0,
+                0,
+                x_display_pixel_width(f.into()),
+                x_display_pixel_height(f.into()),
+            )
+        });
+
+    root_y = if let Some(top) = top {
+        top
+    } else if let Some(bottom) = bottom {
+        bottom - height
+    } else if root_y + dy <= min_y {
+        // Can happen for negative dy
+        min_y
+    } else if root_y + dy + height <= max_y {
+        // It fits below the pointer
+        root_y + dy
+    } else if height + dy + min_y <= root_y {
+        // It fits above the pointer.
+        root_y - (height + dy)
+    } else {
+        // Put it on the top.
+        min_y
+    };
+
+    root_x = if let Some(left) = left {
+        left
+    } else if let Some(right) = right {
+        right - width
+    } else if root_x + dx <= min_x {
+        // Can happen for negative dx
+        0
+    } else if root_x + dx + width <= max_x {
+        // It fits to the right of the pointer.
+        root_x + dx
+    } else if width + dx + min_x <= root_x {
+        // It fits to the left of the pointer.
+        root_x - (width + dx)
+    } else {
+        // Put it left justified on the screen -- it ought to fit that way.
+        min_x
+    };
+
+    (root_x, root_y)
+}
+
+// Hide the currently visible tooltip and cancel its timer.  Delete
+// tip_frame if DELETE, otherwise make it invisible.  Return whether the
+// tooltip was either deleted or made invisible.
+fn hide_tip(delete: bool) -> bool {
+    unsafe {
+        if tip_timer.is_not_nil() {
+            call1(Qcancel_timer, tip_timer);
+            tip_timer = Qnil;
+        }
+
+        let tip = tip_frame.as_live_frame();
+
+        if tip_frame.is_nil() || (!delete && tip.map_or(false, |f| !f.is_visible())) {
+            return false;
+        }
+
+        let count = SPECPDL_INDEX();
+        specbind(Qinhibit_redisplay, Qt);
+        specbind(Qinhibit_quit, Qt);
+
+        let was_open = tip.is_some();
+
+        if tip.is_some() && !delete {
+            Fmake_frame_invisible(tip_frame, Qt);
+        } else {
+            if tip.is_some() {
+                delete_frame(tip_frame, Qnil);
+            }
+
+            tip_frame = Qnil;
+        }
+
+        unbind_to(count, Qnil);
+
+        was_open
+    }
+}
+
+// Whether N is a number of columns or lines usable for the maximum
+// size of tooltips.
+fn is_tip_extent(n: LispObject) -> bool {
+    n.as_fixnum()
+        .map_or(false, |n| 1 <= n && n <= EmacsInt::from(i32::MAX))
+}
+
+/// Show STRING in a "tooltip" window on frame FRAME.
+/// A tooltip window is a small X window displaying a string.
+///
+/// This is an internal function; Lisp code should call `tooltip-show'.
+///
+/// FRAME nil or omitted means use the selected frame.
+///
+/// PARMS is an optional list of frame parameters which can be used to
+/// change the tooltip's appearance.
+///
+/// Automatically hide the tooltip after TIMEOUT seconds.  TIMEOUT nil
+/// means use the default timeout of 5 seconds.
+///
+/// If the list of frame parameters PARMS contains a `left' parameter,
+/// display the tooltip at that x-position.  If the list of frame parameters
+/// PARMS contains no `left' but a `right' parameter, display the tooltip
+/// right-adjusted at that x-position. Otherwise display it at the
+/// x-position of the mouse, with offset DX added (default is 5 if DX isn't
+/// specified).
+///
+/// Likewise for the y-position: If a `top' frame parameter is specified, it
+/// determines the position of the upper edge of the tooltip window.  If a
+/// `bottom' parameter but no `top' frame parameter is specified, it
+/// determines the position of the lower edge of the tooltip window.
+/// Otherwise display the tooltip window at the y-position of the mouse,
+/// with offset DY added (default is -10).
+///
+/// A tooltip's maximum size is specified by `x-max-tooltip-size'.
+/// Text larger than the specified size is clipped.
+#[lisp_fn(min = "1")]
+pub fn x_show_tip(
+    string: LispStringRef,
+    frame: LispObject,
+    parms: LispObject,
+    timeout: LispObject,
+    dx: LispObject,
+    dy: LispObject,
+) -> LispObject {
+    let count = unsafe { SPECPDL_INDEX() };
+    unsafe { specbind(Qinhibit_redisplay, Qt) };
+
+    let string: LispObject = if string.len_chars() == 0 {
+        " ".into()
+    } else {
+        string.into()
+    };
+
+    let frame = if frame.is_nil() {
+        unsafe { selected_frame }
+    } else {
+        frame
+    };
+    let f: LispFrameRef = unsafe { decode_window_system_frame(frame) }.into();
+
+    let timeout = if timeout.is_nil() {
+        5.into()
+    } else {
+        timeout.as_natnum_or_error();
+        timeout
+    };
+
+    let dx = if dx.is_nil() {
+        5
+    } else {
+        dx.as_fixnum_or_error() as i32
+    };
+
+    let dy = if dy.is_nil() {
+        -10
+    } else {
+        dy.as_fixnum_or_error() as i32
+    };
+
+    let mut parms = parms;
+
+    unsafe {
+        match tip_frame.as_live_frame() {
+            Some(tip_f)
+                if tip_f.is_visible()
+                    && frame.eq(tip_last_frame)
+                    && Fequal_including_properties(tip_last_string, string).is_not_nil()
+                    && Fequal(tip_last_parms, parms).is_not_nil() =>
+            {
+                // Only DX and DY have changed.
+                if tip_timer.is_not_nil() {
+                    call1(Qcancel_timer, tip_timer);
+                    tip_timer = Qnil;
+                }
+
+                let (root_x, root_y) =
+                    compute_tip_xy(tip_f, parms, dx, dy, tip_f.pixel_width, tip_f.pixel_height);
+
+                let output: OutputRef = tip_f.output_data.wr.into();
+                output.set_position(PhysicalPosition::new(root_x, root_y));
+
+                start_tip_timer(timeout);
+
+                return unbind_to(count, Qnil);
+            }
+            Some(_) if globals.tooltip_reuse_hidden_frame && frame.eq(tip_last_frame) => {
+                let is_position =
+                    |parm| parm == Qleft || parm == Qtop || parm == Qright || parm == Qbottom;
+
+                let mut delete = false;
+
+                // Check if every parameter in PARMS has the same value in
+                // tip_last_parms.  This may destruct tip_last_parms which,
+                // however, will be recreated below.
+                for elt in parms.iter_cars(LispConsEndChecks::off, LispConsCircularChecks::off) {
+                    let parm = Fcar(elt);
+
+                    // The left, top, right and bottom parameters are
+                    // handled by compute_tip_xy so they can be ignored
+                    // here.
+                    if !is_position(parm)
+                        && Fequal(Fcdr(elt), Fcdr(Fassq(parm, tip_last_parms))).is_nil()
+                    {
+                        // We lost, delete the old tooltip.
+                        delete = true;
+                        break;
+                    }
+
+                    tip_last_parms = call2(Qassq_delete_all, parm, tip_last_parms);
+                }
+
+                // Now check if every parameter in what is left of
+                // tip_last_parms with a non-nil value has an association
+                // in PARMS.
+                if !delete {
+                    delete = tip_last_parms
+                        .iter_cars(LispConsEndChecks::off, LispConsCircularChecks::off)
+                        .any(|elt| !is_position(Fcar(elt)) && Fcdr(elt).is_not_nil());
+                }
+
+                hide_tip(delete);
+            }
+            _ => {
+                hide_tip(true);
+            }
+        }
+
+        tip_last_frame = frame;
+        tip_last_string = string;
+        tip_last_parms = parms;
+
+        if tip_frame.as_live_frame().is_none() {
+            // Add default values to frame parameters.
+            if Fassq(Qname, parms).is_nil() {
+                parms = LispObject::cons(LispObject::cons(Qname, "tooltip"), parms);
+            }
+            if Fassq(Qinternal_border_width, parms).is_nil() {
+                parms = LispObject::cons(LispObject::cons(Qinternal_border_width, 3), parms);
+            }
+            if Fassq(Qborder_width, parms).is_nil() {
+                parms = LispObject::cons(LispObject::cons(Qborder_width, 1), parms);
+            }
+            if Fassq(Qborder_color, parms).is_nil() {
+                parms = LispObject::cons(LispObject::cons(Qborder_color, "lightyellow"), parms);
+            }
+            if Fassq(Qbackground_color, parms).is_nil() {
+                parms = LispObject::cons(LispObject::cons(Qbackground_color, "lightyellow"), parms);
+            }
+
+            // Create a frame for the tooltip, and record it in the global
+            // variable tip_frame.
+            let output: OutputRef = f.output_data.wr.into();
+            tip_frame = x_create_tip_frame(output.display_info(), parms);
+        }
+
+        let mut tip_f: LispFrameRef = tip_frame.into();
+        let window = tip_f.root_window;
+        let tip_buf = Fget_buffer_create(" *tip*".into(), Qnil);
+
+        // We will mark the tip window a "pseudo-window" below, and such
+        // windows cannot have display margins.
+        bset_left_margin_cols(XBUFFER(tip_buf), make_fixnum(0));
+        bset_right_margin_cols(XBUFFER(tip_buf), make_fixnum(0));
+        set_window_buffer(window, tip_buf, false, false);
+
+        let mut w = tip_f.root_window();
+        w.set_pseudo_window_p(true);
+
+        // Set up the frame's root window.  Note: The following code does
+        // not try to size the window or its frame correctly.  Its only
+        // purpose is to make the subsequent text size calculations work.
+        w.left_col = 0;
+        w.top_line = 0;
+        w.pixel_left = 0;
+        w.pixel_top = 0;
+
+        let (total_cols, total_lines) = match globals.Vx_max_tooltip_size.as_cons() {
+            Some(size) if is_tip_extent(size.car()) && is_tip_extent(size.cdr()) => (
+                size.car().as_fixnum_or_error() as i32,
+                size.cdr().as_fixnum_or_error() as i32,
+            ),
+            _ => (80, 40),
+        };
+
+        w.total_cols = total_cols;
+        w.total_lines = total_lines;
+        w.pixel_width = total_cols * tip_f.column_width;
+        w.pixel_height = total_lines * tip_f.line_height;
+        tip_f.total_cols = total_cols;
+        adjust_frame_glyphs(tip_f.as_mut());
+
+        // Insert STRING into root window's buffer and fit the frame to
+        // the buffer.
+        let count_1 = SPECPDL_INDEX();
+        let old_windows_or_buffers_changed = windows_or_buffers_changed;
+
+        record_unwind_current_buffer();
+        set_buffer_internal_1(XBUFFER(w.contents));
+        bset_truncate_lines(XBUFFER(w.contents), Qnil);
+        specbind(Qinhibit_read_only, Qt);
+        specbind(Qinhibit_modification_hooks, Qt);
+        specbind(Qinhibit_point_motion_hooks, Qt);
+        Ferase_buffer();
+
+        let mut args = [string];
+        Finsert(1, args.as_mut_ptr());
+
+        clear_glyph_matrix(w.desired_matrix);
+        clear_glyph_matrix(w.current_matrix);
+
+        let buffer = XBUFFER(w.contents);
+        let pos = text_pos {
+            charpos: (*buffer).begv,
+            bytepos: (*buffer).begv_byte,
+        };
+        try_window(window, pos, TRY_WINDOW_IGNORE_FONTS_CHANGE as i32);
+
+        // Calculate size of tooltip window.
+        let size = Fwindow_text_pixel_size(
+            window,
+            Qnil,
+            Qnil,
+            Qnil,
+            make_fixnum(w.pixel_height.into()),
+            Qnil,
+        );
+
+        // Add the frame's internal border to calculated size.
+        let width = Fcar(size).as_fixnum_or_error() as i32 + 2 * tip_f.internal_border_width();
+        let height = Fcdr(size).as_fixnum_or_error() as i32 + 2 * tip_f.internal_border_width();
+
+        // Calculate position of tooltip frame.
+        let (root_x, root_y) = compute_tip_xy(tip_f, parms, dx, dy, width, height);
+
+        // Show tooltip frame.
+        let mut output: OutputRef = tip_f.output_data.wr.into();
+        output.set_position(PhysicalPosition::new(root_x, root_y));
+        output.set_inner_size(PhysicalSize::new(width as u32, height as u32));
+        Fmake_frame_visible(tip_frame);
+
+        w.set_must_be_updated_p(true);
+        update_single_window(w.as_mut());
+        flush_frame(tip_f.as_mut());
+
+        unbind_to(count_1, Qnil);
+        windows_or_buffers_changed = old_windows_or_buffers_changed;
+
+        start_tip_timer(timeout);
+    }
+
+    unsafe { unbind_to(count, Qnil) }
+}
+
+// Let the tip disappear after TIMEOUT seconds.
+fn start_tip_timer(timeout: LispObject) {
+    unsafe { tip_timer = call3(Qrun_at_time, timeout, Qnil, Qx_hide_tip) };
 }
 
 /// Hide the current tooltip window, if there is any.
 /// Value is t if tooltip was open, nil otherwise.
 #[lisp_fn]
 pub fn x_hide_tip() -> bool {
-    unimplemented!();
+    hide_tip(unsafe { !globals.tooltip_reuse_hidden_frame })
+}
+
+// Whether the boolean frame parameter PARAM is set in PARMS or the
+// X resources of DPYINFO.
+fn get_boolean_arg(mut dpyinfo: DisplayInfoRef, parms: LispObject, param: LispObject) -> bool {
+    let value = unsafe {
+        gui_display_get_arg(
+            dpyinfo.get_raw().as_mut(),
+            parms,
+            param,
+            ptr::null(),
+            ptr::null(),
+            RES_TYPE_BOOLEAN,
+        )
+    };
+
+    value.is_not_nil() && !value.eq(Qunbound)
+}
+
+// The number frame parameter PARAM in PARMS or the X resources of
+// DPYINFO, if it is set.
+fn get_number_arg(
+    mut dpyinfo: DisplayInfoRef,
+    parms: LispObject,
+    param: LispObject,
+) -> Option<i32> {
+    let value = unsafe {
+        gui_display_get_arg(
+            dpyinfo.get_raw().as_mut(),
+            parms,
+            param,
+            ptr::null(),
+            ptr::null(),
+            RES_TYPE_NUMBER,
+        )
+    };
+
+    value.as_fixnum().map(|n| n as i32)
 }
 
 /// Make a new X window, which is called a "frame" in Emacs terms.
@@ -216,7 +1086,42 @@ pub fn x_create_frame(parms: LispObject) -> LispFrameRef {
         parent = Qnil;
     }
 
-    if parent.is_not_nil() {}
+    let mut parent_frame = unsafe {
+        gui_display_get_arg(
+            dpyinfo.get_raw().as_mut(),
+            parms,
+            Qparent_frame,
+            ptr::null(),
+            ptr::null(),
+            RES_TYPE_SYMBOL,
+        )
+    };
+
+    // Accept parent-frame iff parent-id was not specified.
+    if parent.is_not_nil()
+        || parent_frame
+            .as_live_frame()
+            .map_or(true, |f| f.output_method() != output_method::output_wr)
+    {
+        parent_frame = Qnil;
+    }
+
+    let undecorated = get_boolean_arg(dpyinfo, parms, Qundecorated);
+    let override_redirect = get_boolean_arg(dpyinfo, parms, Qoverride_redirect);
+    let no_accept_focus = get_boolean_arg(dpyinfo, parms, Qno_accept_focus);
+
+    // Managed windows may get the focus however they ask for it, so
+    // child frames that must not are kept from the window manager.
+    let options = WindowOptions {
+        kind: if parent_frame.is_nil() {
+            WindowKind::Toplevel
+        } else {
+            WindowKind::Child
+        },
+        decorated: !undecorated,
+        override_redirect: override_redirect || (parent_frame.is_not_nil() && no_accept_focus),
+    };
+
     let tem = unsafe {
         let lcmb = CString::new("minibuffer").unwrap();
         let ucmb = CString::new("Minibuffer").unwrap();
@@ -230,7 +1135,19 @@ pub fn x_create_frame(parms: LispObject) -> LispFrameRef {
         )
     };
 
-    let mut frame = create_frame(display, dpyinfo, tem, kb.into());
+    let mut frame = create_frame(display, dpyinfo, tem, kb.into(), options);
+
+    frame.parent_frame = parent_frame;
+    frame.set_undecorated(undecorated);
+    frame.set_override_redirect(override_redirect);
+    frame.set_no_accept_focus(no_accept_focus);
+
+    unsafe {
+        store_frame_param(frame.as_mut(), Qparent_frame, parent_frame);
+        store_frame_param(frame.as_mut(), Qundecorated, undecorated.into());
+        store_frame_param(frame.as_mut(), Qoverride_redirect, override_redirect.into());
+        store_frame_param(frame.as_mut(), Qno_accept_focus, no_accept_focus.into());
+    }
 
     unsafe {
         register_font_driver(&FONT_DRIVER.0 as *const _, frame.as_mut());
@@ -289,7 +1206,23 @@ pub fn x_create_frame(parms: LispObject) -> LispFrameRef {
         RES_TYPE_NUMBER,
     );
 
-    let output: OutputRef = unsafe { frame.output_data.wr.into() };
+    let mut output: OutputRef = unsafe { frame.output_data.wr.into() };
+
+    // Headless outputs have no window to take the size of the frame
+    // from, so they are as large as its width and height parameters
+    // ask.
+    if output.is_headless() {
+        let size = output.get_physical_size();
+
+        let width = get_number_arg(dpyinfo, parms, Qwidth).map_or(size.width, |columns| {
+            frame.text_to_pixel_width(columns * frame.column_width) as u32
+        });
+        let height = get_number_arg(dpyinfo, parms, Qheight).map_or(size.height, |lines| {
+            frame.text_to_pixel_height(lines * frame.line_height) as u32
+        });
+
+        output.set_inner_size(PhysicalSize::new(width, height));
+    }
 
     let output_size = output.get_inner_size();
 
@@ -348,6 +1281,19 @@ pub fn x_open_connection(
     _xrm_string: LispObject,
     _must_succeed: LispObject,
 ) -> LispObject {
+    // winit runs a single event loop per process, which the first
+    // display that isn't headless owns.
+    let headless = display
+        .as_string()
+        .map_or(false, |name| name.to_utf8() == HEADLESS_DISPLAY_NAME);
+
+    if !headless && windowed_display().is_some() {
+        error!(
+            "Cannot open WebRender display {}, Emacs is already connected to one",
+            display.force_string()
+        );
+    }
+
     let mut display_info = wr_term_init(display);
 
     // Put this display on the chain.
@@ -434,12 +1380,9 @@ pub fn x_change_window_property(
 /// If omitted or nil, that stands for the selected frame's display.
 #[lisp_fn(min = "0")]
 pub fn x_display_color_cells(obj: LispObject) -> LispObject {
-    // FIXME: terminal object or display name (a string) is not implemented
-    let frame = window_frame_live_or_selected(obj);
-
-    let output: OutputRef = unsafe { frame.output_data.wr.into() };
+    let dpyinfo = check_x_display_info(obj);
 
-    let mut color_bits = output.get_color_bits();
+    let mut color_bits = dpyinfo.get_inner().window_server.color_bits();
 
     // Truncate color_bits to 24 to avoid integer overflow.
     // Some displays says 32, but only 24 bits are actually significant.
@@ -462,12 +1405,9 @@ pub fn x_display_color_cells(obj: LispObject) -> LispObject {
 /// \(On MS Windows, this function does not accept terminal objects.)
 #[lisp_fn(min = "0")]
 pub fn x_display_planes(obj: LispObject) -> LispObject {
-    // FIXME: terminal object or display name (a string) is not implemented
-    let frame = window_frame_live_or_selected(obj);
-
-    let output: OutputRef = unsafe { frame.output_data.wr.into() };
+    let dpyinfo = check_x_display_info(obj);
 
-    let color_bits = output.get_color_bits();
+    let color_bits = dpyinfo.get_inner().window_server.color_bits();
 
     // color_bits as EmacsInt
     unsafe { make_fixnum(color_bits.into()) }
@@ -529,6 +1469,31 @@ pub fn webrender_monitor_to_emacs_monitor(m: MonitorHandle) -> (MonitorInfo, Opt
     (monitor_info, name)
 }
 
+// The resolution a headless display claims, to give its monitor a
+// physical size.
+const HEADLESS_DPI: f64 = 96.0;
+
+// A headless display has no monitors, so describe the framebuffer of
+// its frames as the only one.
+fn headless_monitor(name: &CString) -> MonitorInfo {
+    let to_mm = |pixels: u32| (pixels as f64 * 25.4 / HEADLESS_DPI).round() as i32;
+
+    let geom = Emacs_Rectangle {
+        x: 0,
+        y: 0,
+        width: HEADLESS_SIZE.width,
+        height: HEADLESS_SIZE.height,
+    };
+
+    MonitorInfo {
+        geom,
+        work: geom,
+        mm_width: to_mm(HEADLESS_SIZE.width),
+        mm_height: to_mm(HEADLESS_SIZE.height),
+        name: name.as_ptr() as *mut i8,
+    }
+}
+
 /// Return a list of physical monitor attributes on the X display TERMINAL.
 ///
 /// The optional argument TERMINAL specifies which display to ask about.
@@ -548,15 +1513,14 @@ pub fn webrender_monitor_to_emacs_monitor(m: MonitorHandle) -> (MonitorInfo, Opt
 pub fn x_display_monitor_attributes_list(terminal: LispObject) -> LispObject {
     let dpyinfo = check_x_display_info(terminal);
 
-    let output = dpyinfo.get_inner().output;
+    let window_server = dpyinfo.get_inner().window_server.clone();
 
-    let monitors: Vec<_> = output.get_available_monitors().collect();
-    let primary_monitor = output.get_primary_monitor();
+    let (monitors, primary_monitor) = window_server.monitors();
 
     let mut primary_monitor_index = 0;
 
     for (i, m) in monitors.iter().enumerate() {
-        if m.name() == primary_monitor.name() {
+        if Some(m.name()) == primary_monitor.as_ref().map(|p| p.name()) {
             primary_monitor_index = i;
             break;
         }
@@ -569,11 +1533,24 @@ pub fn x_display_monitor_attributes_list(terminal: LispObject) -> LispObject {
 
     let mut emacs_monitors: Vec<_> = emacs_monitor_infos.iter().map(|(m, _)| m.clone()).collect();
 
-    let n_monitors = monitors.len();
+    let headless_name = CString::new(HEADLESS_DISPLAY_NAME).unwrap();
+
+    if window_server.is_headless() {
+        emacs_monitors.push(headless_monitor(&headless_name));
+    }
+
+    let n_monitors = emacs_monitors.len();
     let mut monitor_frames = unsafe { Fmake_vector(n_monitors.into(), Qnil).as_vector_unchecked() };
 
     for_each_frame!(f => {
         let frame: LispFrameRef = f.into();
+
+        if frame.output_method() != output_method::output_wr
+            || TerminalRef::new(frame.terminal) != dpyinfo.get_inner().terminal
+        {
+            continue;
+        }
+
         let output: OutputRef = unsafe { frame.output_data.wr.into() };
 
         let output_pos = output.get_position().unwrap();
@@ -625,16 +1602,19 @@ pub fn x_display_monitor_attributes_list(terminal: LispObject) -> LispObject {
 pub fn x_display_pixel_width(terminal: LispObject) -> i32 {
     let dpyinfo = check_x_display_info(terminal);
 
-    let output = dpyinfo.get_inner().output;
-
-    let primary_monitor = output.get_primary_monitor();
+    let (_, primary_monitor) = dpyinfo.get_inner().window_server.monitors();
 
-    let dpi_factor = primary_monitor.scale_factor();
+    match primary_monitor {
+        Some(primary_monitor) => {
+            let dpi_factor = primary_monitor.scale_factor();
 
-    let physical_size = primary_monitor.size();
-    let logical_size = physical_size.to_logical::<i32>(dpi_factor);
+            let physical_size = primary_monitor.size();
+            let logical_size = physical_size.to_logical::<i32>(dpi_factor);
 
-    logical_size.width
+            logical_size.width
+        }
+        None => HEADLESS_SIZE.width as i32,
+    }
 }
 
 /// Return the height in pixels of the X display TERMINAL.
@@ -650,16 +1630,19 @@ pub fn x_display_pixel_width(terminal: LispObject) -> i32 {
 pub fn x_display_pixel_height(terminal: LispObject) -> i32 {
     let dpyinfo = check_x_display_info(terminal);
 
-    let output = dpyinfo.get_inner().output;
-
-    let primary_monitor = output.get_primary_monitor();
+    let (_, primary_monitor) = dpyinfo.get_inner().window_server.monitors();
 
-    let dpi_factor = primary_monitor.scale_factor();
+    match primary_monitor {
+        Some(primary_monitor) => {
+            let dpi_factor = primary_monitor.scale_factor();
 
-    let physical_size = primary_monitor.size();
-    let logical_size = physical_size.to_logical::<i32>(dpi_factor);
+            let physical_size = primary_monitor.size();
+            let logical_size = physical_size.to_logical::<i32>(dpi_factor);
 
-    logical_size.height
+            logical_size.height
+        }
+        None => HEADLESS_SIZE.height as i32,
+    }
 }
 
 /// Assert an X selection of type SELECTION and value VALUE.
@@ -704,6 +1687,29 @@ pub fn x_get_selection_internal(
     Qnil
 }
 
+/// Write the contents of FRAME to FILE as a PNG image.
+/// FRAME defaults to the selected frame.  The image is the size of the
+/// frame in device pixels and shows it as it was last redisplayed.
+///
+/// This also works on a headless display, opened by starting Emacs with
+/// `--display=headless', where frames are rendered offscreen.
+///
+/// Returns FILE.
+#[lisp_fn(min = "1", intspec = "FWrite screenshot to file: ")]
+pub fn wr_frame_screenshot(file: LispStringRef, frame: LispObject) -> LispObject {
+    let frame = window_frame_live_or_selected(frame);
+    let mut output: OutputRef = unsafe { frame.output_data.wr.into() };
+
+    let path = unsafe { Fexpand_file_name(file.into(), Qnil) };
+    let path_str: LispStringRef = path.into();
+
+    output
+        .write_png(&path_str.to_utf8())
+        .unwrap_or_else(|e| error!("Failed to write {}: {}", path_str.to_utf8(), e));
+
+    path
+}
+
 fn syms_of_wrfont() {
     unsafe {
         register_font_driver(&FONT_DRIVER.0, ptr::null_mut());
@@ -716,6 +1722,12 @@ pub extern "C" fn syms_of_wrterm() {
     // pretend webrender as a X gui backend, so we can reuse the x-win.el logic
     def_lisp_sym!(Qx, "x");
     def_lisp_sym!(Qwr, "wr");
+
+    def_lisp_sym!(Qcancel_timer, "cancel-timer");
+    def_lisp_sym!(Qassq_delete_all, "assq-delete-all");
+    def_lisp_sym!(Qrun_at_time, "run-at-time");
+    def_lisp_sym!(Qx_hide_tip, "x-hide-tip");
+
     unsafe {
         Fprovide(Qx, Qnil);
         Fprovide(Qwr, Qnil);
@@ -761,6 +1773,19 @@ pub extern "C" fn syms_of_wrterm() {
     #[rustfmt::skip]
     defvar_bool!(Vx_underline_at_descent_line, "x-underline-at-descent-line", false);
 
+    // Maximum size for tooltips.
+    // Value is a pair (COLUMNS . ROWS).  Text larger than this is clipped.
+    #[rustfmt::skip]
+    defvar_lisp!(Vx_max_tooltip_size, "x-max-tooltip-size", unsafe { Fcons(make_fixnum(80), make_fixnum(40)) });
+
+    unsafe {
+        staticpro(&tip_frame);
+        staticpro(&tip_timer);
+        staticpro(&tip_last_frame);
+        staticpro(&tip_last_string);
+        staticpro(&tip_last_parms);
+    }
+
     syms_of_wrfont();
 }
 
@@ -21,6 +21,9 @@
 #include "emacs-module.h"
 #include "epaths.h"
 #include "font.h"
+#ifdef HAVE_HARFBUZZ
+# include <hb-ot.h>
+#endif
 #include "fontset.h"
 #include "frame.h"
 #include "getpagesize.h"
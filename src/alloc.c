@@ -6077,6 +6077,7 @@ garbage_collect (void)
   mark_terminals ();
   mark_kboards ();
   mark_threads ();
+  mark_js_proxies ();
 
 #ifdef USE_GTK
   xg_mark_data ();
@@ -3022,7 +3022,7 @@ struct redisplay_interface
 #ifdef HAVE_WINDOW_SYSTEM
 
 # if (defined USE_CAIRO || defined HAVE_XRENDER \
-      || defined HAVE_NS || defined HAVE_NTGUI)
+      || defined HAVE_NS || defined HAVE_NTGUI || defined USE_WEBRENDER)
 #  define HAVE_NATIVE_TRANSFORMS
 # endif
 
@@ -1989,6 +1989,8 @@ Using an Emacs configured with --with-x-toolkit=lucid does not have this problem
       syms_of_json ();
 #endif
 
+      syms_of_javascript ();
+
       keys_of_keyboard ();
 
 #ifdef HAVE_NATIVE_COMP
@@ -5396,6 +5396,8 @@ syms_of_font (void)
   DEFSYM (QCotf, ":otf");
   DEFSYM (QClang, ":lang");
   DEFSYM (QCscript, ":script");
+  DEFSYM (QCfeatures, ":features");
+  DEFSYM (QCvariations, ":variations");
   DEFSYM (QCantialias, ":antialias");
   DEFSYM (QCfoundry, ":foundry");
   DEFSYM (QCadstyle, ":adstyle");
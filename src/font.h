@@ -895,6 +895,7 @@ extern Lisp_Object font_put_extra (Lisp_Object font, Lisp_Object prop,
 extern Lisp_Object hbfont_otf_capability (struct font *);
 extern Lisp_Object hbfont_shape (Lisp_Object, Lisp_Object);
 extern Lisp_Object hbfont_combining_capability (struct font *);
+extern ptrdiff_t hbfont_features (Lisp_Object, hb_feature_t **);
 #endif
 
 #if defined (HAVE_XFT) || defined (HAVE_FREETYPE)
@@ -73,6 +73,8 @@ DEF_DLL_FN (hb_glyph_position_t *, hb_buffer_get_glyph_positions,
 	    (hb_buffer_t *, unsigned int *));
 DEF_DLL_FN (void, hb_tag_to_string, (hb_tag_t, char *));
 DEF_DLL_FN (hb_face_t *, hb_font_get_face, (hb_font_t *font));
+DEF_DLL_FN (hb_bool_t, hb_feature_from_string,
+	    (const char *, int, hb_feature_t *));
 DEF_DLL_FN (unsigned int, hb_ot_layout_table_get_script_tags,
 	    (hb_face_t *, hb_tag_t, unsigned int, unsigned int *, hb_tag_t *));
 DEF_DLL_FN (unsigned int, hb_ot_layout_table_get_feature_tags,
@@ -108,6 +110,7 @@ DEF_DLL_FN (unsigned int, hb_ot_layout_language_get_feature_tags,
 #define hb_buffer_get_glyph_positions fn_hb_buffer_get_glyph_positions
 #define hb_tag_to_string fn_hb_tag_to_string
 #define hb_font_get_face fn_hb_font_get_face
+#define hb_feature_from_string fn_hb_feature_from_string
 #define hb_ot_layout_table_get_script_tags fn_hb_ot_layout_table_get_script_tags
 #define hb_ot_layout_table_get_feature_tags fn_hb_ot_layout_table_get_feature_tags
 #define hb_ot_layout_script_get_language_tags fn_hb_ot_layout_script_get_language_tags
@@ -142,6 +145,7 @@ hbfont_init_w32_funcs (HMODULE library)
   LOAD_DLL_FN (library, hb_buffer_get_glyph_positions);
   LOAD_DLL_FN (library, hb_tag_to_string);
   LOAD_DLL_FN (library, hb_font_get_face);
+  LOAD_DLL_FN (library, hb_feature_from_string);
   LOAD_DLL_FN (library, hb_ot_layout_table_get_script_tags);
   LOAD_DLL_FN (library, hb_ot_layout_table_get_feature_tags);
   LOAD_DLL_FN (library, hb_ot_layout_script_get_language_tags);
@@ -373,6 +377,40 @@ get_hb_unicode_funcs (void)
   return funcs;
 }
 
+/* Store in *FEATURES the OpenType features that FONT, a font object,
+   asks for with its :features property, and return their number.
+   The property is a feature or a list of them, each a string or
+   symbol in the syntax of hb_feature_from_string, like "ss01",
+   "-calt" or "cv01=2".  *FEATURES is allocated with xmalloc; the
+   caller must free it with xfree.  */
+ptrdiff_t
+hbfont_features (Lisp_Object font, hb_feature_t **features)
+{
+  Lisp_Object val = assq_no_quit (QCfeatures, AREF (font, FONT_EXTRA_INDEX));
+  ptrdiff_t n = 0;
+
+  *features = NULL;
+  if (! CONSP (val) || NILP (XCDR (val)))
+    return 0;
+  val = XCDR (val);
+  if (! CONSP (val))
+    val = list1 (val);
+
+  *features = xnmalloc (list_length (val), sizeof **features);
+  for (; CONSP (val); val = XCDR (val))
+    {
+      Lisp_Object feature = XCAR (val);
+
+      if (SYMBOLP (feature))
+	feature = SYMBOL_NAME (feature);
+      if (STRINGP (feature)
+	  && hb_feature_from_string (SSDATA (feature), SBYTES (feature),
+				     *features + n))
+	n++;
+    }
+  return n;
+}
+
 /* HarfBuzz implementation of shape for font backend.
 
    Shape text in LGSTRING.  See the docstring of
@@ -491,7 +529,11 @@ hbfont_shape (Lisp_Object lgstring, Lisp_Object direction)
   if (!hb_font)
     return make_fixnum (0);
 
-  hb_bool_t success = hb_shape_full (hb_font, hb_buffer, NULL, 0, NULL);
+  hb_feature_t *features;
+  ptrdiff_t nfeatures = hbfont_features (LGSTRING_FONT (lgstring), &features);
+  hb_bool_t success = hb_shape_full (hb_font, hb_buffer, features, nfeatures,
+				     NULL);
+  xfree (features);
   if (font->driver->end_hb_font)
     font->driver->end_hb_font (font, hb_font);
   if (!success)
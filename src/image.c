@@ -132,7 +132,8 @@ typedef struct ns_bitmap_record Bitmap_Record;
 #ifdef USE_WEBRENDER
 typedef struct wr_bitmap_record Bitmap_Record;
 
-#define GET_PIXEL(ximg, x, y) wr_get_pixel(ximg, x, y)
+#define GET_PIXEL(ximg, x, y) wr_get_pixel (ximg, x, y)
+#define PUT_PIXEL wr_put_pixel
 #define NO_PIXMAP 0
 
 #define PIX_MASK_RETAIN	0
@@ -158,6 +159,12 @@ static void free_color_table (void);
 static unsigned long *colors_in_color_table (int *n);
 #endif
 
+#ifdef USE_WEBRENDER
+static char *slurp_file (int, ptrdiff_t *);
+static bool xbm_read_bitmap_data (struct frame *, char *, char *,
+				  int *, int *, char **, bool);
+#endif
+
 #ifdef USE_CAIRO
 
 static Emacs_Pix_Container
@@ -442,9 +449,15 @@ image_create_bitmap_from_data (struct frame *f, char *bits,
       return -1;
 #endif
 
+#ifdef USE_WEBRENDER
+  void *bitmap = wr_bitmap_from_XBM (bits, width, height);
+  if (!bitmap)
+    return -1;
+#endif
+
   id = image_allocate_bitmap_record (f);
 
-#ifdef HAVE_NS
+#if defined HAVE_NS || defined USE_WEBRENDER
   dpyinfo->bitmaps[id - 1].img = bitmap;
   dpyinfo->bitmaps[id - 1].depth = 1;
 #endif
@@ -501,6 +514,51 @@ image_create_bitmap_from_file (struct frame *f, Lisp_Object file)
   return id;
 #endif
 
+#ifdef USE_WEBRENDER
+  ptrdiff_t id, size;
+  int fd, width, height;
+  char *contents, *data = NULL;
+  Lisp_Object found;
+
+  /* Look for an existing bitmap with the same name.  */
+  for (id = 0; id < dpyinfo->bitmaps_last; ++id)
+    {
+      if (dpyinfo->bitmaps[id].refcount
+	  && dpyinfo->bitmaps[id].file
+	  && !strcmp (dpyinfo->bitmaps[id].file, SSDATA (file)))
+	{
+	  ++dpyinfo->bitmaps[id].refcount;
+	  return id + 1;
+	}
+    }
+
+  /* Search bitmap-file-path for the file, if appropriate.  */
+  fd = openp (Vx_bitmap_file_path, file, Qnil, &found,
+	      make_fixnum (R_OK), false);
+  if (fd < 0)
+    return -1;
+
+  contents = slurp_file (fd, &size);
+  if (!contents)
+    return -1;
+
+  if (!xbm_read_bitmap_data (f, contents, contents + size,
+			     &width, &height, &data, true))
+    {
+      xfree (contents);
+      return -1;
+    }
+  xfree (contents);
+
+  id = image_create_bitmap_from_data (f, data, width, height);
+  xfree (data);
+  if (id < 0)
+    return -1;
+
+  dpyinfo->bitmaps[id - 1].file = xlispstrdup (file);
+  return id;
+#endif
+
 #ifdef HAVE_X_WINDOWS
   unsigned int width, height;
   Pixmap bitmap;
@@ -573,6 +631,10 @@ free_bitmap_record (Display_Info *dpyinfo, Bitmap_Record *bm)
   ns_release_object (bm->img);
 #endif
 
+#ifdef USE_WEBRENDER
+  wr_release_image (bm->img);
+#endif
+
   if (bm->file)
     {
       xfree (bm->file);
@@ -1836,6 +1898,12 @@ image_size_in_bytes (struct image *img)
   if (img->mask)
     size += ns_image_size_in_bytes (img->mask);
 
+#elif defined USE_WEBRENDER
+  if (img->pixmap)
+    size += wr_image_size_in_bytes (img->pixmap);
+  if (img->mask)
+    size += wr_image_size_in_bytes (img->mask);
+
 #elif defined HAVE_NTGUI
   if (img->pixmap)
     size += w32_image_size (img->pixmap);
@@ -2230,7 +2298,8 @@ image_set_transform (struct frame *f, struct image *img)
   double rotation = 0.0;
   compute_image_rotation (img, &rotation);
 
-# if defined USE_CAIRO || defined HAVE_XRENDER || defined HAVE_NS
+# if (defined USE_CAIRO || defined HAVE_XRENDER || defined HAVE_NS \
+      || defined USE_WEBRENDER)
   /* We want scale up operations to use a nearest neighbour filter to
      show real pixels instead of munging them, but scale down
      operations to use a blended filter, to avoid aliasing and the like.
@@ -2248,7 +2317,7 @@ image_set_transform (struct frame *f, struct image *img)
 		  : img->width / (double) width),
 	[1][1] = (!IEEE_FLOATING_POINT && height == 0 ? DBL_MAX
 		  : img->height / (double) height),
-# elif defined HAVE_NTGUI || defined HAVE_NS
+# elif defined HAVE_NTGUI || defined HAVE_NS || defined USE_WEBRENDER
 	[0][0] = (!IEEE_FLOATING_POINT && img->width == 0 ? DBL_MAX
 		  : width / (double) img->width),
 	[1][1] = (!IEEE_FLOATING_POINT && img->height == 0 ? DBL_MAX
@@ -2268,7 +2337,7 @@ image_set_transform (struct frame *f, struct image *img)
   else
     {
 # if (defined USE_CAIRO || defined HAVE_XRENDER \
-      || defined HAVE_NTGUI || defined HAVE_NS)
+      || defined HAVE_NTGUI || defined HAVE_NS || defined USE_WEBRENDER)
       int cos_r, sin_r;
       if (rotation == 90)
 	{
@@ -2351,6 +2420,11 @@ image_set_transform (struct frame *f, struct image *img)
      drawing time, so store it for later.  */
   ns_image_set_transform (img->pixmap, matrix);
   ns_image_set_smoothing (img->pixmap, scale_down);
+# elif defined USE_WEBRENDER
+  /* The transform becomes a WebRender reference frame when the image
+     glyph is drawn, so store it on the pixmap like NS does.  */
+  wr_image_set_transform (img->pixmap, matrix);
+  wr_image_set_smoothing (img->pixmap, scale_down);
 # elif defined USE_CAIRO
   cairo_matrix_t cr_matrix = {matrix[0][0], matrix[0][1], matrix[1][0],
 			      matrix[1][1], matrix[2][0], matrix[2][1]};
@@ -2898,6 +2972,18 @@ image_create_x_image_and_pixmap_1 (struct frame *f, int width, int height, int d
   *pimg = *pixmap;
   return 1;
 #endif
+
+#ifdef USE_WEBRENDER
+  *pixmap = wr_image_for_XPM (width, height, depth);
+  if (*pixmap == 0)
+    {
+      *pimg = NULL;
+      image_error ("Unable to allocate pixmap for image");
+      return 0;
+    }
+  *pimg = *pixmap;
+  return 1;
+#endif
 }
 
 
@@ -2922,6 +3008,9 @@ image_destroy_x_image (Emacs_Pix_Container pimg)
 #ifdef HAVE_NS
       ns_release_object (pimg);
 #endif /* HAVE_NS */
+#ifdef USE_WEBRENDER
+      wr_release_image (pimg);
+#endif /* USE_WEBRENDER */
     }
 #endif
 }
@@ -2959,6 +3048,11 @@ gui_put_x_image (struct frame *f, Emacs_Pix_Container pimg,
   eassert (pimg == pixmap);
   ns_retain_object (pimg);
 #endif
+
+#ifdef USE_WEBRENDER
+  eassert (pimg == pixmap);
+  wr_retain_image (pimg);
+#endif
 }
 
 /* Thin wrapper for image_create_x_image_and_pixmap_1, so that it matches
@@ -3082,6 +3176,11 @@ image_get_x_image (struct frame *f, struct image *img, bool mask_p)
 
   ns_retain_object (pixmap);
   return pixmap;
+#elif defined (USE_WEBRENDER)
+  Emacs_Pix_Container pixmap = !mask_p ? img->pixmap : img->mask;
+
+  wr_retain_image (pixmap);
+  return pixmap;
 #endif
 }
 
@@ -3098,6 +3197,8 @@ image_unget_x_image (struct image *img, bool mask_p, Emacs_Pix_Container ximg)
     XDestroyImage (ximg);
 #elif defined (HAVE_NS)
   ns_release_object (ximg);
+#elif defined (USE_WEBRENDER)
+  wr_release_image (ximg);
 #endif
 }
 #endif	/* !HAVE_NTGUI */
@@ -3587,6 +3688,8 @@ Create_Pixmap_From_Bitmap_Data (struct frame *f, struct image *img, char *data,
     convert_mono_to_color_image (f, img, fg, bg);
 #elif defined HAVE_NS
   img->pixmap = ns_image_from_XBM (data, img->width, img->height, fg, bg);
+#elif defined USE_WEBRENDER
+  img->pixmap = wr_image_from_XBM (data, img->width, img->height, fg, bg);
 #endif
 }
 
@@ -4015,7 +4118,8 @@ xbm_load (struct frame *f, struct image *img)
 #endif /* not HAVE_NTGUI */
 #endif /* HAVE_XPM */
 
-#if defined HAVE_XPM || defined USE_CAIRO || defined HAVE_NS
+#if (defined HAVE_XPM || defined USE_CAIRO || defined HAVE_NS \
+     || defined USE_WEBRENDER)
 
 /* Indices of image specification fields in xpm_format, below.  */
 
@@ -4312,7 +4416,7 @@ xpm_image_p (Lisp_Object object)
 	      || xpm_valid_color_symbols_p (fmt[XPM_COLOR_SYMBOLS].value)));
 }
 
-#endif /* HAVE_XPM || USE_CAIRO || HAVE_NS */
+#endif /* HAVE_XPM || USE_CAIRO || HAVE_NS || USE_WEBRENDER */
 
 #if defined HAVE_XPM && defined HAVE_X_WINDOWS && !defined USE_GTK
 ptrdiff_t
@@ -4680,7 +4784,8 @@ xpm_load (struct frame *f, struct image *img)
 
 #endif /* HAVE_XPM && !USE_CAIRO */
 
-#if defined USE_CAIRO || (defined HAVE_NS && !defined HAVE_XPM)
+#if (defined USE_CAIRO || (defined HAVE_NS && !defined HAVE_XPM) \
+     || defined USE_WEBRENDER)
 
 /* XPM support functions for NS where libxpm is not available.
    Only XPM version 3 (without any extensions) is supported.  */
@@ -5158,7 +5263,7 @@ xpm_load (struct frame *f,
   return success_p;
 }
 
-#endif /* HAVE_NS && !HAVE_XPM */
+#endif /* USE_CAIRO || HAVE_NS && !HAVE_XPM || USE_WEBRENDER */
 
 
 
@@ -5421,6 +5526,9 @@ lookup_rgb_color (struct frame *f, int r, int g, int b)
   return PALETTERGB (r >> 8, g >> 8, b >> 8);
 #elif defined USE_CAIRO || defined HAVE_NS
   return RGB_TO_ULONG (r >> 8, g >> 8, b >> 8);
+#elif defined USE_WEBRENDER
+  /* Pixels hold 16-bit channels, red in the lowest bits.  */
+  return ((unsigned long) b << 32) | ((unsigned long) g << 16) | r;
 #else
   xsignal1 (Qfile_error,
 	    build_string ("This Emacs mishandles this image file type"));
@@ -6388,6 +6496,8 @@ image_can_use_native_api (Lisp_Object type)
   return w32_can_use_native_image_api (type);
 # elif defined HAVE_NS
   return ns_can_use_native_image_api (type);
+# elif defined USE_WEBRENDER
+  return wr_can_use_native_image_api (type);
 # else
   return false;
 # endif
@@ -6462,6 +6572,9 @@ native_image_load (struct frame *f, struct image *img)
 # elif defined HAVE_NS
   return ns_load_image (f, img, image_file,
                         image_spec_value (img->spec, QCdata, NULL));
+# elif defined USE_WEBRENDER
+  return wr_load_image (f, img, image_file,
+                        image_spec_value (img->spec, QCdata, NULL));
 # else
   return 0;
 # endif
@@ -10531,7 +10644,8 @@ The list of capabilities can include one or more of the following:
   if (FRAME_WINDOW_P (f))
     {
 #ifdef HAVE_NATIVE_TRANSFORMS
-# if defined HAVE_IMAGEMAGICK || defined (USE_CAIRO) || defined (HAVE_NS)
+# if (defined HAVE_IMAGEMAGICK || defined (USE_CAIRO) || defined (HAVE_NS) \
+      || defined (USE_WEBRENDER))
       return list2 (Qscale, Qrotate90);
 # elif defined (HAVE_X_WINDOWS) && defined (HAVE_XRENDER)
       int event_basep, error_basep;
@@ -10621,7 +10735,7 @@ static struct image_type const image_types[] =
  { SYMBOL_INDEX (Qjpeg), jpeg_image_p, jpeg_load, image_clear_image,
    IMAGE_TYPE_INIT (init_jpeg_functions) },
 #endif
-#if defined HAVE_XPM || defined HAVE_NS
+#if defined HAVE_XPM || defined HAVE_NS || defined USE_WEBRENDER
  { SYMBOL_INDEX (Qxpm), xpm_image_p, xpm_load, image_clear_image,
    IMAGE_TYPE_INIT (init_xpm_functions) },
 #endif
@@ -10762,7 +10876,7 @@ non-numeric, there is no explicit limit on the size of images.  */);
   DEFSYM (Qxbm, "xbm");
   add_image_type (Qxbm);
 
-#if defined (HAVE_XPM) || defined (HAVE_NS)
+#if defined (HAVE_XPM) || defined (HAVE_NS) || defined (USE_WEBRENDER)
   DEFSYM (Qxpm, "xpm");
   add_image_type (Qxpm);
 #endif
@@ -10807,6 +10921,12 @@ non-numeric, there is no explicit limit on the size of images.  */);
 #endif /* HAVE_NTGUI  */
 #endif /* HAVE_RSVG  */
 
+#if defined (USE_WEBRENDER) && !defined (HAVE_RSVG)
+  /* SVG is rendered by the native image API.  */
+  DEFSYM (Qsvg, "svg");
+  add_image_type (Qsvg);
+#endif
+
 #if HAVE_NATIVE_IMAGE_API
   DEFSYM (Qnative_image, "native-image");
 # ifdef HAVE_NTGUI
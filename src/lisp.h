@@ -4706,6 +4706,10 @@ extern void syms_of_xterm (void);
 extern void syms_of_wrterm(void);
 #endif /* USE_WEBRENDER */
 
+/* Defined in javascript.rs.  */
+extern void syms_of_javascript (void);
+extern void mark_js_proxies (void);
+
 #ifdef HAVE_WINDOW_SYSTEM
 /* Defined in xterm.c, nsterm.m, w32term.c.  */
 extern char *get_keysym_name (int);
@@ -7,6 +7,7 @@ struct wr_bitmap_record
 {
   char *file;
   int refcount;
+  void *img;
   int height, width, depth;
 };
 
@@ -130,7 +131,28 @@ extern wr_display_info *wr_get_display_info(wr_output* output);
 extern Display *wr_get_display(wr_display_info* output);
 extern Screen wr_get_screen(wr_display_info* output);
 extern int wr_get_baseline_offset(wr_output* output);
-extern int wr_get_pixel(WRImage *ximg, int x, int y);
+
+/* Images.  Pixmaps are opaque, reference counted objects owned by the
+   Rust side; the same object serves as both the pixmap and the pixel
+   container that image.c writes into.  */
+extern void *wr_image_for_XPM (int width, int height, int depth);
+extern void *wr_image_from_XBM (char *bits, int width, int height,
+				unsigned long fg, unsigned long bg);
+extern void *wr_bitmap_from_XBM (char *bits, int width, int height);
+extern unsigned long wr_get_pixel (void *img, int x, int y);
+extern void wr_put_pixel (void *img, int x, int y, unsigned long pixel);
+extern void wr_retain_image (void *img);
+extern void wr_release_image (void *img);
+extern void wr_image_set_transform (void *img, double m[3][3]);
+extern void wr_image_set_smoothing (void *img, bool smooth);
+extern size_t wr_image_size_in_bytes (void *img);
+extern bool wr_can_use_native_image_api (Lisp_Object type);
+extern bool wr_load_image (struct frame *f, struct image *img,
+			   Lisp_Object spec_file, Lisp_Object spec_data);
+extern void image_sync_to_pixmaps (struct frame *f, struct image *img);
+extern void image_pixmap_draw_cross (struct frame *f, Emacs_Pixmap pixmap,
+				     int x, int y, unsigned int width,
+				     unsigned int height, unsigned long color);
 
 /* This is the `Display *' which frame F is on.  */
 #define FRAME_X_DISPLAY(f) (wr_get_display(FRAME_DISPLAY_INFO (f)))
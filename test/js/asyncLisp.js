@@ -0,0 +1,33 @@
+export function asyncLisp() {
+    return Promise.resolve()
+	.test('asyncFuncall', () => {
+	    return lisp.async.identity("hello").then((result) => {
+		if (result !== "hello") {
+		    throw new Error("Failed to resolve lisp.async call");
+		}
+	    });
+	})
+	.test('asyncError', () => {
+	    return lisp.async.error("async failure").then(
+		() => { throw new Error("lisp.async call did not reject"); },
+		(e) => {
+		    if (!e.message.includes("async failure")) {
+			throw new Error("lisp.async call rejected with wrong error");
+		    }
+		});
+	})
+	.test('asyncProcessOutput', () => {
+	    const proc = lisp.make_process(lisp.keywords.name, "js-async-test",
+					   lisp.keywords.command, lisp.list("echo", "hello"));
+	    return lisp.async.process_output(proc).then((output) => {
+		if (output.trim() !== "hello") {
+		    throw new Error("Failed to collect process output");
+		}
+
+		if (lisp.advice__p(lisp.process_filter(proc))
+		    || lisp.advice__p(lisp.process_sentinel(proc))) {
+		    throw new Error("process_output left its advice on the process");
+		}
+	    });
+	});
+}
@@ -0,0 +1,69 @@
+export function bufferApi() {
+    return Promise.resolve()
+	.test('bufferText', () => {
+	    lisp.with_temp_buffer(() => {
+		lisp.insert("hello world");
+		const text = emacs.buffer.text();
+		if (text !== "hello world") {
+		    throw new Error("Failed to read buffer text");
+		}
+
+		if (emacs.buffer.text(undefined, 7, 12) !== "world") {
+		    throw new Error("Failed to read buffer range");
+		}
+	    });
+	})
+	.test('bufferBytes', () => {
+	    lisp.with_temp_buffer(() => {
+		lisp.insert("héllo");
+		const bytes = emacs.buffer.bytes();
+		if (!(bytes instanceof Uint8Array) || bytes.length !== 6) {
+		    throw new Error("Failed to read buffer bytes");
+		}
+
+		if (new TextDecoder().decode(bytes) !== "héllo") {
+		    throw new Error("Buffer bytes were not UTF-8");
+		}
+	    });
+	})
+	.test('bufferEdit', () => {
+	    const buf = lisp.get_buffer_create("js-buffer-api-test");
+	    emacs.buffer.insert(buf, 1, "hello world");
+	    emacs.buffer.replace("js-buffer-api-test", 1, 6, "goodbye");
+	    const end = emacs.buffer.delete(buf, 8, 14);
+	    const text = emacs.buffer.text(buf);
+	    lisp.kill_buffer(buf);
+	    if (text !== "goodbye" || end !== 8) {
+		throw new Error("Failed to edit buffer: " + text);
+	    }
+	})
+	.test('indirectBufferBounds', () => {
+	    // A buffer that shares its text keeps its bounds in markers
+	    // while it isn't current.
+	    const base = lisp.get_buffer_create("js-buffer-bounds-base");
+	    emacs.buffer.insert(base, 1, "hello world");
+	    const indirect = lisp.make_indirect_buffer(base, "js-buffer-bounds-indirect");
+	    lisp.with_current_buffer(indirect, () => lisp.narrow_to_region(7, 12));
+	    emacs.buffer.insert(base, 1, ">> ");
+	    const [start, end] = emacs.buffer.bounds(indirect);
+	    const text = emacs.buffer.text(indirect);
+	    lisp.kill_buffer(indirect);
+	    lisp.kill_buffer(base);
+	    if (start !== 10 || end !== 15 || text !== "world") {
+		throw new Error(`Wrong bounds of an indirect buffer: ${start}, ${end}, ${text}`);
+	    }
+	})
+	.test('bufferRange', () => {
+	    lisp.with_temp_buffer(() => {
+		try {
+		    emacs.buffer.text(undefined, 1, 100);
+		} catch (e) {
+		    if (e instanceof RangeError) {
+			return;
+		    }
+		}
+
+		throw new Error("Out of range positions did not throw a RangeError");
+	    });
+	});
+}
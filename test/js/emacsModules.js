@@ -0,0 +1,23 @@
+export function emacsModules() {
+    return Promise.resolve()
+	.test('emacsBuiltinModule', () => {
+	    return import("emacs:").then(({ funcall, symbolValue, setq, bufferString, withTempBuffer }) => {
+		setq("js-test-emacs-module", 3);
+		if (symbolValue("js-test-emacs-module") !== 3) {
+		    throw new Error("Failed to set symbol through emacs: module");
+		}
+
+		if (funcall("+", 1, 2) !== 3) {
+		    throw new Error("Failed to funcall through emacs: module");
+		}
+
+		let text = withTempBuffer(() => {
+		    lisp.insert("emacs-ng");
+		    return bufferString();
+		});
+		if (text !== "emacs-ng") {
+		    throw new Error("Failed to read buffer through emacs: module");
+		}
+	    });
+	});
+}
@@ -101,5 +101,95 @@ export function errors() {
 	    if (!thrown) {
 		throw new Error("Eval JS Literally did not throw");
 	    }
+	})
+	.test('jsErrorData', () => {
+	    // The data of a js-error is (MESSAGE CLASS FRAMES).
+	    const err = lisp.eval(lisp.list(lisp.q.condition_case, lisp.q.err,
+					    lisp.list(lisp.q.eval_js_literally, "null.foo"),
+					    lisp.list(lisp.q.js_error, lisp.q.err)));
+
+	    if (!lisp.eq(lisp.car(err), lisp.q.js_error)) {
+		throw new Error("JS error was not signaled as js-error");
+	    }
+
+	    const [message, cls, frames] = [lisp.nth(1, err), lisp.nth(2, err), lisp.nth(3, err)];
+	    if (typeof message !== 'string' || !message.includes("null")) {
+		throw new Error("js-error data does not start with the message");
+	    }
+
+	    if (cls !== "TypeError") {
+		throw new Error("js-error data has the wrong class: " + cls);
+	    }
+
+	    if (!lisp.consp(frames)) {
+		throw new Error("js-error data has no stack frames");
+	    }
+
+	    const frame = lisp.car(frames);
+	    for (const key of ['file', 'buffer', 'line', 'column', 'function']) {
+		if (!lisp.plist_member(frame, lisp.keywords[key])) {
+		    throw new Error("js-error frame is missing :" + key);
+		}
+	    }
+
+	    if (typeof lisp.plist_get(frame, lisp.keywords.line) !== 'number') {
+		throw new Error("js-error frame has no line number");
+	    }
+	})
+	.test('moduleOrigin', () => {
+	    // The emoji is two UTF-16 code units, but one character.
+	    const header = "// Not part of the region\n";
+	    const code = 'const s = "\u{1F600}"; globalThis.__originStack = new Error("origin").stack;';
+	    const buffer = lisp.get_buffer_create("*js-origin-test*");
+	    lisp.eval(lisp.list(lisp.q.with_current_buffer, buffer,
+				lisp.list(lisp.q.insert, header + code)));
+
+	    const start = header.length + 1;
+	    return lisp.async.eval(lisp.list(lisp.q.with_current_buffer, buffer,
+					     lisp.list(lisp.q.eval_js_region, start,
+						       lisp.list(lisp.q.point_max))))
+		.then(() => {
+		    lisp.kill_buffer(buffer);
+		    const column = [...code.slice(0, code.indexOf("new"))].length + 1;
+		    const location = `*js-origin-test*:2:${column}`;
+		    if (!globalThis.__originStack.includes(location)) {
+			throw new Error(`Expected ${location} in ${globalThis.__originStack}`);
+		    }
+		});
+	})
+	.test('moduleOriginAfterKill', () => {
+	    // The stack is only formatted when it is first read, after
+	    // the buffer the code came from is gone.
+	    const code = 'const s = "\u{1F600}"; globalThis.__originError = new Error("origin");';
+	    const buffer = lisp.get_buffer_create("*js-origin-kill-test*");
+	    lisp.eval(lisp.list(lisp.q.with_current_buffer, buffer,
+				lisp.list(lisp.q.insert, code)));
+
+	    return lisp.async.eval(lisp.list(lisp.q.eval_js_buffer, buffer))
+		.then(() => {
+		    lisp.kill_buffer(buffer);
+		    const stack = globalThis.__originError.stack;
+		    const column = [...code.slice(0, code.indexOf("new"))].length + 1;
+		    const location = `*js-origin-kill-test*:1:${column}`;
+		    if (!stack.includes(location)) {
+			throw new Error(`Expected ${location} in ${stack}`);
+		    }
+		});
+	})
+	.test('quitInterruptsBusyLoop', () => {
+	    // Sets quit-flag as C-g would, and then never returns to lisp.
+	    // The watchdog has to notice and turn it into a quit.
+	    const form = lisp.read(`(condition-case nil
+					(eval-js-literally "lisp.set(lisp.q.quit_flag, true); while (true) {}")
+				      (quit 'quit))`);
+	    return lisp.async.eval(form).then((result) => {
+		if (!lisp.eq(result, lisp.q.quit)) {
+		    throw new Error("Busy JS loop was not interrupted by quit");
+		}
+
+		if (lisp.symbol_value(lisp.q.quit_flag)) {
+		    throw new Error("quit-flag was left set");
+		}
+	    });
 	});
 };
@@ -4,6 +4,12 @@ import { webWorkers } from "./webWorkers.js";
 import { webAsm } from "./webAsm.js";
 import { basicTyping } from "./basicTyping.ts";
 import { errors } from "./errors.js";
+import { emacsModules } from "./emacsModules.js";
+import { asyncLisp } from "./asyncLisp.js";
+import { bufferApi } from "./bufferApi.js";
+import { proxyTable } from "./proxyTable.js";
+import { typecheck } from "./typecheck.js";
+import { profiler } from "./profiler.js";
 
 Promise.prototype.test = function(name, f) {
     let now = Date.now();
@@ -23,6 +29,12 @@ Promise.all([
     webAsm(),
     basicTyping(),
     errors(),
+    emacsModules(),
+    asyncLisp(),
+    bufferApi(),
+    proxyTable(),
+    typecheck(),
+    profiler(),
 ])
     .then(() => {
 	console.log("JS Tests Complete, No Errors");
@@ -0,0 +1,49 @@
+export function profiler() {
+    const readJson = (file) => {
+	const json = JSON.parse(Deno.readTextFileSync(file));
+	lisp.delete_file(file);
+	return json;
+    };
+
+    return Promise.resolve()
+	.test('heapSnapshot', () => {
+	    const file = lisp.make_temp_file("js", null, ".heapsnapshot");
+	    lisp.js_heap_snapshot(file);
+	    if (!readJson(file).snapshot) {
+		throw new Error("js-heap-snapshot did not write a heap snapshot");
+	    }
+	})
+	.test('profileReentrant', () => {
+	    // The profiler needs the worker, which is busy running us.
+	    let thrown = false;
+	    try {
+		lisp.js_profile_start();
+	    } catch (e) {
+		thrown = true;
+	    }
+
+	    if (!thrown) {
+		throw new Error("js-profile-start from JavaScript did not throw");
+	    }
+
+	    if (lisp.identity("still alive") !== "still alive") {
+		throw new Error("Lisp calls failed after a re-entrant call");
+	    }
+	})
+	.test('profile', () => {
+	    const file = lisp.make_temp_file("js", null, ".cpuprofile");
+	    return lisp.async.js_profile_start()
+		.then(() => {
+		    let sum = 0;
+		    for (let i = 0; i < 1000000; ++i) {
+			sum += i;
+		    }
+		    return lisp.async.js_profile_stop(file);
+		})
+		.then(() => {
+		    if (!Array.isArray(readJson(file).nodes)) {
+			throw new Error("js-profile-stop did not write a CPU profile");
+		    }
+		});
+	});
+}
@@ -0,0 +1,30 @@
+export function proxyTable() {
+    return Promise.resolve()
+	.test('proxyRoundTrip', () => {
+	    const buf = lisp.get_buffer_create("js-proxy-table-test");
+	    const name = lisp.buffer_name(buf);
+	    lisp.kill_buffer(buf);
+	    if (name !== "js-proxy-table-test") {
+		throw new Error("Failed to pass a proxy back to lisp");
+	    }
+	})
+	.test('proxyStats', () => {
+	    const stat = (stats, key) => lisp.plist_get(stats, lisp.keywords[key]);
+	    const before = lisp.js_proxy_stats();
+	    const proxies = [];
+	    for (let i = 0; i < 100; ++i) {
+		proxies.push(lisp.make_marker());
+	    }
+
+	    lisp.garbage_collect();
+	    const after = lisp.js_proxy_stats();
+	    if (stat(after, "created") < stat(before, "created") + 100
+		|| stat(after, "live") < 100) {
+		throw new Error("Proxies were not counted");
+	    }
+
+	    if (!lisp.markerp(proxies[99])) {
+		throw new Error("Proxy did not survive garbage collection");
+	    }
+	});
+}
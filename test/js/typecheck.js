@@ -0,0 +1,81 @@
+export function typecheck() {
+    const source = 'const x: number = "not a number";\n';
+    const withBuffer = (f) => {
+	const buffer = lisp.get_buffer_create("*js-typecheck-test*");
+	lisp.eval(lisp.list(lisp.q.with_current_buffer, buffer,
+			    lisp.list(lisp.q.erase_buffer),
+			    lisp.list(lisp.q.insert, source)));
+	return f(buffer);
+    };
+
+    // Each diagnostic is (BEG END SEVERITY CODE MESSAGE).
+    const checkDiagnostics = (diagnostics) => {
+	if (lisp.length(diagnostics) !== 1) {
+	    throw new Error("Expected a single diagnostic, got " + lisp.prin1_to_string(diagnostics));
+	}
+
+	const diagnostic = lisp.car(diagnostics);
+	if (lisp.nth(0, diagnostic) !== source.indexOf('x') + 1) {
+	    throw new Error("Diagnostic does not start at x");
+	}
+
+	if (!lisp.eq(lisp.nth(2, diagnostic), lisp.keywords.error)) {
+	    throw new Error("Diagnostic is not an error");
+	}
+
+	if (lisp.nth(3, diagnostic) !== 2322) {
+	    throw new Error("Diagnostic has the wrong code: " + lisp.nth(3, diagnostic));
+	}
+    };
+
+    return Promise.resolve()
+	.test('typecheckBuffer', () => {
+	    withBuffer((buffer) => checkDiagnostics(lisp.js_typecheck_buffer(buffer)));
+	})
+	.test('typecheckBufferAsync', () => {
+	    return withBuffer((buffer) => new Promise((resolve, reject) => {
+		lisp.js_typecheck_buffer(buffer, (diagnostics) => {
+		    try {
+			if (typeof diagnostics === 'string') {
+			    throw new Error(diagnostics);
+			}
+
+			checkDiagnostics(diagnostics);
+			resolve();
+		    } catch (e) {
+			reject(e);
+		    }
+		});
+	    }));
+	})
+	.test('generateDeclarations', () => {
+	    lisp.eval(lisp.read('(defun js-declarations-test (first &optional second &rest more) "Test function." nil)'));
+	    const previous = lisp.symbol_value(lisp.q.js_lisp_declarations);
+	    const file = lisp.make_temp_file("lisp", null, ".d.ts");
+	    try {
+		if (lisp.js_generate_declarations(file) !== file
+		    || lisp.symbol_value(lisp.q.js_lisp_declarations) !== file) {
+		    throw new Error("js-generate-declarations did not set js-lisp-declarations");
+		}
+
+		const text = Deno.readTextFileSync(file);
+		const expected = [
+		    "         * Test function.",
+		    "        js_declarations_test(first: LispArg, second?: LispArg, ...more: LispArg[]): any;",
+		];
+		for (const line of expected) {
+		    if (!text.split('\n').includes(line)) {
+			throw new Error("Declarations are missing: " + line);
+		    }
+		}
+
+		// Macros can't be called from JS.
+		if (text.includes(" when(")) {
+		    throw new Error("Declarations include the macro when");
+		}
+	    } finally {
+		lisp.set(lisp.q.js_lisp_declarations, previous);
+		lisp.delete_file(file);
+	    }
+	});
+}
@@ -0,0 +1,44 @@
+;;; js-repl-tests.el --- Tests for js-repl.el  -*- lexical-binding: t; -*-
+
+;;; Code:
+
+(require 'cl-lib)
+(require 'ert)
+(require 'js-repl)
+
+(defun js-repl-tests--eval (input)
+  "Evaluate INPUT as `js-repl' does and return what it prints.
+Wait for the output of inputs using await."
+  (skip-unless (fboundp 'eval-js-literally))
+  (let* ((output nil)
+         (result (cl-letf (((symbol-function 'js-repl--output)
+                            (lambda (string) (setq output string))))
+                   (let ((result (js-repl--call "__replEval" input)))
+                     (when (equal result "")
+                       (with-timeout (5 (ert-fail "No output from the REPL"))
+                         (while (not output)
+                           (accept-process-output nil 0.05))))
+                     result))))
+    (if (equal result "") output result)))
+
+(ert-deftest js-repl-test-block-declarations ()
+  (js-repl-tests--eval "{\n  let jsReplBlockScoped = 1;\n}")
+  (should (equal (js-repl-tests--eval "typeof jsReplBlockScoped")
+                 "'undefined'\n"))
+  (js-repl-tests--eval
+   "function jsReplFunction() {\n  const jsReplInner = 1;\n  return jsReplInner;\n}")
+  (should (equal (js-repl-tests--eval "typeof jsReplInner") "'undefined'\n"))
+  ;; Not a declaration, so left as it is.
+  (should (equal (js-repl-tests--eval "`\nlet x = ${1 + 1}`")
+                 "'\\nlet x = 2'\n"))
+  ;; Top-level declarations outlive the input.
+  (js-repl-tests--eval "let jsReplTopLevel = 3")
+  (should (equal (js-repl-tests--eval "jsReplTopLevel") "3\n")))
+
+(ert-deftest js-repl-test-await ()
+  (should (equal (js-repl-tests--eval "await Promise.resolve(42);") "42\n"))
+  (js-repl-tests--eval "const jsReplAwaited = await Promise.resolve(1);")
+  (should (equal (js-repl-tests--eval "jsReplAwaited") "1\n")))
+
+(provide 'js-repl-tests)
+;;; js-repl-tests.el ends here
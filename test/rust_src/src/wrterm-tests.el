@@ -0,0 +1,283 @@
+;;; wrterm-tests.el --- Tests for wrterm.rs  -*- lexical-binding: t; -*-
+
+;;; Code:
+
+(require 'ert)
+(require 'seq)
+
+(defun wrterm-tests--headless-frame (parameters)
+  "Make a frame with PARAMETERS on the headless WebRender display.
+Skip the test if Emacs has no WebRender support or the display
+can't be opened, as without OSMesa."
+  (skip-unless (featurep 'wr))
+  (condition-case err
+      ;; Opens the display if it isn't yet.
+      (x-display-pixel-width "headless")
+    (error (ert-skip (error-message-string err))))
+  (let ((frame (x-create-frame (cons '(display . "headless") parameters))))
+    (make-frame-visible frame)
+    frame))
+
+(defun wrterm-tests--png-size (file)
+  "Return the width and height of the PNG image in FILE as a cons."
+  (with-temp-buffer
+    (set-buffer-multibyte nil)
+    (insert-file-contents-literally file nil 0 24)
+    (let ((read-int (lambda (pos)
+                      (let ((n 0))
+                        (dotimes (i 4)
+                          (setq n (+ (* n 256) (char-after (+ pos i)))))
+                        n))))
+      ;; The IHDR chunk follows the signature.
+      (cons (funcall read-int 17) (funcall read-int 21)))))
+
+(defun wrterm-tests--screenshot (frame file)
+  "Redisplay FRAME and return the contents of its screenshot in FILE."
+  (force-window-update)
+  (with-selected-frame frame
+    (redisplay t))
+  (wr-frame-screenshot file frame)
+  (with-temp-buffer
+    (set-buffer-multibyte nil)
+    (insert-file-contents-literally file)
+    (buffer-string)))
+
+(ert-deftest wrterm-test-headless-screenshot ()
+  (let ((frame (wrterm-tests--headless-frame '((width . 40) (height . 10))))
+        (file (make-temp-file "wrterm-test" nil ".png")))
+    (unwind-protect
+        (progn
+          (should (= (frame-width frame) 40))
+          (should (equal (wrterm-tests--png-size (wr-frame-screenshot file frame))
+                         (cons (frame-native-width frame)
+                               (frame-native-height frame))))
+          (set-frame-size frame 60 20)
+          (should (= (frame-width frame) 60))
+          (should (equal (wrterm-tests--png-size (wr-frame-screenshot file frame))
+                         (cons (frame-native-width frame)
+                               (frame-native-height frame)))))
+      (delete-frame frame)
+      (delete-file file))))
+
+(ert-deftest wrterm-test-headless-glyphless-screenshot ()
+  (let* ((frame (wrterm-tests--headless-frame '((width . 40) (height . 10))))
+         (file (make-temp-file "wrterm-test" nil ".png"))
+         (glyphless-char-display (make-char-table 'glyphless-char-display))
+         (screenshot
+          (lambda (method)
+            (aset glyphless-char-display #x200b method)
+            (force-window-update)
+            (with-selected-frame frame
+              (redisplay t))
+            (wr-frame-screenshot file frame)
+            (with-temp-buffer
+              (set-buffer-multibyte nil)
+              (insert-file-contents-literally file)
+              (buffer-string)))))
+    (unwind-protect
+        (with-selected-frame frame
+          (with-temp-buffer
+            (switch-to-buffer (current-buffer))
+            ;; A composed character next to the glyphless one.
+            (insert "e\u0301 \u200b")
+            (compose-region (point-min) (+ (point-min) 2))
+            ;; The acronym of a (GRAPHICAL . TEXT) entry is drawn
+            ;; in a box, which zero-width characters don't get.
+            (should-not (equal (funcall screenshot '("ZWSP" . "zwsp"))
+                               (funcall screenshot 'zero-width)))))
+      (delete-frame frame)
+      (delete-file file))))
+
+;; A 1x1 GIF whose first frame is red and second blue.
+(defconst wrterm-tests--two-frame-gif
+  (unibyte-string
+   ?G ?I ?F ?8 ?9 ?a 1 0 1 0 #x80 0 0
+   #xff 0 0 0 0 #xff
+   #x21 #xf9 4 0 10 0 0 0
+   #x2c 0 0 0 0 1 0 1 0 0 2 2 #x44 1 0
+   #x21 #xf9 4 0 10 0 0 0
+   #x2c 0 0 0 0 1 0 1 0 0 2 2 #x4c 1 0
+   #x3b))
+
+(ert-deftest wrterm-test-headless-gif-frames ()
+  (let ((frame (wrterm-tests--headless-frame '((width . 40) (height . 10))))
+        (file (make-temp-file "wrterm-test" nil ".png")))
+    (unwind-protect
+        (with-selected-frame frame
+          (skip-unless (image-type-available-p 'gif))
+          (let* ((image (lambda (index)
+                          (create-image wrterm-tests--two-frame-gif 'gif t
+                                        :index index)))
+                 (screenshot
+                  (lambda (index)
+                    (erase-buffer)
+                    (insert-image (funcall image index))
+                    (redisplay t)
+                    (wr-frame-screenshot file frame)
+                    (with-temp-buffer
+                      (set-buffer-multibyte nil)
+                      (insert-file-contents-literally file)
+                      (buffer-string)))))
+            (should (equal (image-size (funcall image 1) t frame) '(1 . 1)))
+            (should (equal (image-metadata (funcall image 1) frame)
+                           '(delay 0.1 count 2)))
+            (with-temp-buffer
+              (switch-to-buffer (current-buffer))
+              (let ((first (funcall screenshot 0)))
+                (should-not (equal (funcall screenshot 1) first))
+                ;; Back to the first frame.
+                (should (equal (funcall screenshot 0) first))))))
+      (delete-frame frame)
+      (delete-file file))))
+
+(ert-deftest wrterm-test-headless-list-fonts-weight ()
+  (let ((frame (wrterm-tests--headless-frame '((width . 40) (height . 10)))))
+    (unwind-protect
+        (dolist (font (list-fonts (font-spec :weight 'bold) frame))
+          (should (eq (font-get font :weight) 'bold)))
+      (delete-frame frame))))
+
+(ert-deftest wrterm-test-headless-list-fonts-lang ()
+  (let ((frame (wrterm-tests--headless-frame '((width . 40) (height . 10)))))
+    (unwind-protect
+        (let ((fonts (list-fonts (font-spec :lang 'zh) frame)))
+          ;; A bare language stands for its first registry, zh-cn.
+          (should (equal (mapcar #'font-xlfd-name fonts)
+                         (mapcar #'font-xlfd-name
+                                 (list-fonts (font-spec :lang 'zh-cn) frame))))
+          (dolist (font fonts)
+            (should (aref (font-get-glyphs (open-font font 12 frame)
+                                           0 1 "\u4e13")
+                          0))))
+      (delete-frame frame))))
+
+;; Whether "ffi" looks different with the font's ligatures turned off.
+(ert-deftest wrterm-test-headless-font-features ()
+  (let ((frame (wrterm-tests--headless-frame '((width . 40) (height . 10))))
+        (file (make-temp-file "wrterm-test" nil ".png")))
+    (unwind-protect
+        (with-selected-frame frame
+          (let ((family
+                 (seq-some
+                  (lambda (font)
+                    (let ((otf (font-get (open-font font 20 frame) :otf)))
+                      (and (memq 'liga (flatten-tree (car otf)))
+                           (font-get font :family))))
+                  (list-fonts (font-spec :script 'latin) frame))))
+            (skip-unless family)
+            (with-temp-buffer
+              (switch-to-buffer (current-buffer))
+              (insert "ffi")
+              (let ((screenshot
+                     (lambda (features)
+                       (set-face-attribute 'default frame
+                                           :font (font-spec :family family
+                                                            :size 20
+                                                            :features features))
+                       (wrterm-tests--screenshot frame file))))
+                (should-not (equal (funcall screenshot nil)
+                                   (funcall screenshot '("-liga"))))))))
+      (delete-frame frame)
+      (delete-file file))))
+
+(ert-deftest wrterm-test-headless-box-overline ()
+  (let ((frame (wrterm-tests--headless-frame '((width . 40) (height . 10))))
+        (file (make-temp-file "wrterm-test" nil ".png")))
+    (unwind-protect
+        (with-selected-frame frame
+          (with-temp-buffer
+            (switch-to-buffer (current-buffer))
+            (let ((screenshot
+                   (lambda (face)
+                     (erase-buffer)
+                     (insert (propertize "boxed" 'face face))
+                     (wrterm-tests--screenshot frame file))))
+              ;; The overline is drawn below the top line of the box,
+              ;; rather than hidden by it.
+              (should-not (equal (funcall screenshot
+                                          '(:box (:line-width 1 :color "red")))
+                                 (funcall screenshot
+                                          '(:box (:line-width 1 :color "red")
+                                            :overline "blue")))))))
+      (delete-frame frame)
+      (delete-file file))))
+
+(ert-deftest wrterm-test-headless-fringe-bitmap ()
+  (let ((frame (wrterm-tests--headless-frame '((width . 40) (height . 10))))
+        (file (make-temp-file "wrterm-test" nil ".png")))
+    (define-fringe-bitmap 'wrterm-tests--empty (make-vector 8 0))
+    (define-fringe-bitmap 'wrterm-tests--filled (make-vector 8 #xff))
+    (unwind-protect
+        (with-selected-frame frame
+          (with-temp-buffer
+            (switch-to-buffer (current-buffer))
+            (let ((screenshot
+                   (lambda (bitmap)
+                     (erase-buffer)
+                     (insert (propertize " " 'display
+                                         `(left-fringe ,bitmap error)))
+                     (wrterm-tests--screenshot frame file))))
+              (should-not (equal (funcall screenshot 'wrterm-tests--empty)
+                                 (funcall screenshot 'wrterm-tests--filled))))))
+      (destroy-fringe-bitmap 'wrterm-tests--empty)
+      (destroy-fringe-bitmap 'wrterm-tests--filled)
+      (delete-frame frame)
+      (delete-file file))))
+
+(ert-deftest wrterm-test-headless-boxed-mode-line ()
+  (let ((frame (wrterm-tests--headless-frame '((width . 40) (height . 10))))
+        (file (make-temp-file "wrterm-test" nil ".png")))
+    (unwind-protect
+        (with-selected-frame frame
+          (with-temp-buffer
+            (switch-to-buffer (current-buffer))
+            ;; Strings of different widths are drawn over each other,
+            ;; each leaving its box behind if it isn't dropped.
+            (dotimes (i 50)
+              (setq mode-line-format
+                    (propertize (make-string (1+ (% i 7)) ?x)
+                                'face '(:box (:line-width 2 :color "red"))))
+              (force-mode-line-update)
+              (wrterm-tests--screenshot frame file))
+            (let ((redrawn (wrterm-tests--screenshot frame file)))
+              (redraw-frame frame)
+              (should (equal redrawn (wrterm-tests--screenshot frame file))))))
+      (delete-frame frame)
+      (delete-file file))))
+
+;; Hidden tooltip frames are reused or deleted, and either way only
+;; the first `x-hide-tip' finds a tooltip to hide.
+(ert-deftest wrterm-test-headless-tooltip ()
+  (let ((frame (wrterm-tests--headless-frame '((width . 40) (height . 10)))))
+    (unwind-protect
+        (dolist (tooltip-reuse-hidden-frame '(nil t))
+          (should-not (x-show-tip "tooltip" frame '((left . 0) (top . 0)) 60))
+          (should (x-hide-tip))
+          (should-not (x-hide-tip)))
+      (x-hide-tip)
+      (delete-frame frame))))
+
+(ert-deftest wrterm-test-headless-parent-frame ()
+  (let* ((parent (wrterm-tests--headless-frame '((width . 40) (height . 10))))
+         (other (wrterm-tests--headless-frame '((width . 40) (height . 10))))
+         (child (wrterm-tests--headless-frame
+                 `((width . 20) (height . 5) (parent-frame . ,parent)))))
+    (unwind-protect
+        (progn
+          (should (eq (frame-parent child) parent))
+          (should-error (set-frame-parameter child 'parent-frame 'parent))
+          (should (eq (frame-parent child) parent))
+          ;; A child frame may move to another parent...
+          (set-frame-parameter child 'parent-frame other)
+          (should (eq (frame-parent child) other))
+          ;; ...but its window stays that of a child frame.
+          (should-error (set-frame-parameter child 'parent-frame nil))
+          (should (eq (frame-parent child) other))
+          (should-error (set-frame-parameter parent 'parent-frame other))
+          (should-not (frame-parent parent)))
+      (dolist (frame (list child other parent))
+        (when (frame-live-p frame)
+          (delete-frame frame))))))
+
+(provide 'wrterm-tests)
+;;; wrterm-tests.el ends here